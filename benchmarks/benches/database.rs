@@ -0,0 +1,318 @@
+use diesel::{Connection, PgConnection};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use divan::{black_box, Bencher};
+use feature_database::{
+    imagedb::{Image, ImageDatabase},
+    keypointdb::{Keypoint, KeypointDatabase},
+    models::{InsertImage, InsertKeypoint},
+};
+use feature_extraction::get_knn_matches;
+use homographier::homographier::{pnp_solver_ransac, Cmat, ImgObjCorrespondence, PnpRansacOptions};
+use opencv::{
+    calib3d::project_points,
+    core::{Mat, Point2d, Point3d, Scalar, Vector, CV_8UC1},
+    prelude::*,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{net::TcpListener, path::PathBuf, process::Command, sync::OnceLock};
+use tempfile::TempDir;
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("../feature_database/migrations");
+
+/// The length of an AKAZE MLDB descriptor in bytes
+const DESCRIPTOR_SIZE: usize = 61;
+/// The size of a tile of the reference mosaic in pixels of its level of detail
+const TILE_SIZE: i32 = 1024;
+/// The amount of keypoints stored for every tile, about what the preprocessor finds in a textured tile
+const KEYPOINTS_PER_TILE: usize = 2000;
+/// The amount of tiles along each side of level of detail 0, every following level has half as many
+const TILES_PER_SIDE: i32 = 8;
+const LEVELS_OF_DETAIL: i32 = 3;
+/// The amount of keypoints inserted at a time, to stay below the parameter limit of Postgres
+const INSERT_CHUNK_SIZE: usize = 5000;
+
+static DATABASE_URL: OnceLock<String> = OnceLock::new();
+
+fn main() {
+    let postgres = LocalPostgres::start();
+    let conn = &mut postgres.connect();
+
+    conn.run_pending_migrations(MIGRATIONS)
+        .expect("Could not run migrations");
+    populate_database(conn);
+
+    DATABASE_URL
+        .set(postgres.url.clone())
+        .expect("Database url already set");
+
+    divan::main();
+
+    // The server is stopped when `postgres` is dropped.
+}
+
+/// A throwaway Postgres server in a temporary directory, stopped when dropped.
+struct LocalPostgres {
+    data_dir: TempDir,
+    url: String,
+}
+
+impl LocalPostgres {
+    /// Initializes and starts a server with `initdb` and `pg_ctl`, which must be on the path.
+    fn start() -> LocalPostgres {
+        let data_dir = tempfile::tempdir().expect("Could not create data directory");
+        let data = data_dir.path().join("data");
+
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("Could not find a free port")
+            .port();
+
+        run(Command::new("initdb")
+            .arg("-D")
+            .arg(&data)
+            .args(["-U", "postgres", "-A", "trust"]));
+
+        run(Command::new("pg_ctl")
+            .arg("-D")
+            .arg(&data)
+            .arg("-l")
+            .arg(data_dir.path().join("postgres.log"))
+            .arg("-o")
+            .arg(format!(
+                "-p {port} -k {} -c listen_addresses=127.0.0.1 -c fsync=off",
+                data_dir.path().display()
+            ))
+            .args(["-w", "start"]));
+
+        LocalPostgres {
+            data_dir,
+            url: format!("postgres://postgres@127.0.0.1:{port}/postgres"),
+        }
+    }
+
+    fn connect(&self) -> PgConnection {
+        PgConnection::establish(&self.url)
+            .unwrap_or_else(|_| panic!("Error connecting to {}", self.url))
+    }
+
+    fn data(&self) -> PathBuf {
+        self.data_dir.path().join("data")
+    }
+}
+
+impl Drop for LocalPostgres {
+    fn drop(&mut self) {
+        let _ = Command::new("pg_ctl")
+            .arg("-D")
+            .arg(self.data())
+            .args(["-m", "immediate", "stop"])
+            .status();
+    }
+}
+
+fn run(command: &mut Command) {
+    let status = command
+        .status()
+        .unwrap_or_else(|e| panic!("Could not run {:?}: {}", command.get_program(), e));
+
+    assert!(
+        status.success(),
+        "{:?} failed with {}",
+        command.get_program(),
+        status
+    );
+}
+
+fn connect() -> PgConnection {
+    let url = DATABASE_URL.get().expect("Database not started");
+
+    PgConnection::establish(url).unwrap_or_else(|_| panic!("Error connecting to {}", url))
+}
+
+fn random_descriptors(rng: &mut StdRng, count: usize) -> Vec<u8> {
+    (0..count * DESCRIPTOR_SIZE).map(|_| rng.gen()).collect()
+}
+
+/// Fills every level of detail with tiles of randomly placed keypoints.
+/// Keypoints are stored in full resolution coordinates, like the preprocessor does.
+fn populate_database(conn: &mut PgConnection) {
+    let mut rng = StdRng::seed_from_u64(0);
+
+    for level_of_detail in 0..LEVELS_OF_DETAIL {
+        let tiles = TILES_PER_SIDE >> level_of_detail;
+        let scale = 1 << level_of_detail;
+        let full_tile_size = TILE_SIZE * scale;
+
+        for tile in 0..tiles * tiles {
+            let (x_start, y_start) = (
+                (tile % tiles) * full_tile_size,
+                (tile / tiles) * full_tile_size,
+            );
+            let (x_end, y_end) = (x_start + full_tile_size, y_start + full_tile_size);
+
+            let image_id = Image::create_image(
+                conn,
+                Image::One(InsertImage {
+                    x_start: &x_start,
+                    y_start: &y_start,
+                    x_end: &x_end,
+                    y_end: &y_end,
+                    level_of_detail: &level_of_detail,
+                }),
+            )
+            .expect("Could not insert tile");
+
+            let values: Vec<(f32, f32, f32, f32, f32)> = (0..KEYPOINTS_PER_TILE)
+                .map(|_| {
+                    (
+                        rng.gen_range(x_start as f32..x_end as f32),
+                        rng.gen_range(y_start as f32..y_end as f32),
+                        rng.gen_range(2f32..40f32),
+                        rng.gen_range(0f32..360f32),
+                        rng.gen_range(0.001f32..0.1f32),
+                    )
+                })
+                .collect();
+            let descriptors = random_descriptors(&mut rng, KEYPOINTS_PER_TILE);
+            let (octave, class_id) = (0, -1);
+
+            let keypoints: Vec<InsertKeypoint> = values
+                .iter()
+                .zip(descriptors.chunks_exact(DESCRIPTOR_SIZE))
+                .map(
+                    |((x, y, size, angle, response), descriptor)| InsertKeypoint {
+                        x_coord: x,
+                        y_coord: y,
+                        size,
+                        angle,
+                        response,
+                        octave: &octave,
+                        class_id: &class_id,
+                        descriptor,
+                        image_id: &image_id,
+                    },
+                )
+                .collect();
+
+            for chunk in keypoints.chunks(INSERT_CHUNK_SIZE) {
+                Keypoint::create_keypoint(conn, Keypoint::Multiple(chunk.to_vec()))
+                    .expect("Could not insert keypoints");
+            }
+        }
+    }
+}
+
+/// Reads a square window with the given side length in pixels of level of detail 0, from the middle of the mosaic.
+#[divan::bench(args = [256, 1024, 4096])]
+fn read_keypoints_from_coordinates(bencher: Bencher, window: i32) {
+    let conn = &mut connect();
+    let center = (TILES_PER_SIDE * TILE_SIZE / 2) as f32;
+    let half = window as f32 / 2f32;
+
+    bencher.bench_local(|| {
+        Keypoint::read_keypoints_from_coordinates(
+            conn,
+            black_box(center - half),
+            black_box(center - half),
+            black_box(center + half),
+            black_box(center + half),
+            0,
+        )
+        .expect("Could not read keypoints")
+    });
+}
+
+#[divan::bench(args = [0, 1, 2])]
+fn read_keypoints_from_lod(bencher: Bencher, level_of_detail: i32) {
+    let conn = &mut connect();
+
+    bencher.bench_local(|| {
+        Keypoint::read_keypoints_from_lod(conn, black_box(level_of_detail))
+            .expect("Could not read keypoints")
+    });
+}
+
+fn descriptor_mat(descriptors: &[u8]) -> Mat {
+    let rows = (descriptors.len() / DESCRIPTOR_SIZE) as i32;
+    let mut mat =
+        Mat::new_rows_cols_with_default(rows, DESCRIPTOR_SIZE as i32, CV_8UC1, Scalar::all(0f64))
+            .expect("Could not allocate descriptors");
+
+    mat.data_bytes_mut()
+        .expect("Descriptors are not continuous")
+        .copy_from_slice(descriptors);
+
+    mat
+}
+
+/// Matches the descriptors of a query image against a growing amount of reference descriptors.
+#[divan::bench(args = [2000, 16000, 128000])]
+fn knn_matches(bencher: Bencher, reference_count: usize) {
+    let mut rng = StdRng::seed_from_u64(1);
+    let query = descriptor_mat(&random_descriptors(&mut rng, KEYPOINTS_PER_TILE));
+    let reference = descriptor_mat(&random_descriptors(&mut rng, reference_count));
+
+    bencher.bench_local(|| {
+        get_knn_matches(black_box(&query), black_box(&reference), 2, 0.7)
+            .expect("Could not match descriptors")
+    });
+}
+
+/// Synthetic correspondences of a nadir camera, where 30% of the image points are outliers.
+fn correspondences(count: usize) -> (Vec<ImgObjCorrespondence>, Cmat<f64>) {
+    let mut rng = StdRng::seed_from_u64(2);
+    let camera = Cmat::from_2d_slice(&[
+        [2000f64, 0f64, 1024f64],
+        [0f64, 2000f64, 1024f64],
+        [0f64, 0f64, 1f64],
+    ])
+    .expect("Could not create camera matrix");
+
+    let obj_points: Vector<Point3d> = (0..count)
+        .map(|_| {
+            Point3d::new(
+                rng.gen_range(-5000f64..5000f64),
+                rng.gen_range(-5000f64..5000f64),
+                rng.gen_range(-100f64..100f64),
+            )
+        })
+        .collect();
+    let mut img_points: Vector<Point2d> = Vector::new();
+
+    project_points(
+        &obj_points,
+        &Vector::from_slice(&[0.02f64, -0.01, 0.5]),
+        &Vector::from_slice(&[10f64, -20f64, 10000f64]),
+        &camera,
+        &Mat::default(),
+        &mut img_points,
+        &mut Mat::default(),
+        0f64,
+    )
+    .expect("Could not project points");
+
+    let correspondences = obj_points
+        .iter()
+        .zip(img_points.iter())
+        .map(|(obj, img)| match rng.gen_bool(0.3) {
+            true => ImgObjCorrespondence::new(
+                obj,
+                Point2d::new(rng.gen_range(0f64..2048f64), rng.gen_range(0f64..2048f64)),
+            ),
+            false => ImgObjCorrespondence::new(obj, img),
+        })
+        .collect();
+
+    (correspondences, camera)
+}
+
+#[divan::bench(args = [50, 200, 1000, 5000])]
+fn pnp_ransac(bencher: Bencher, count: usize) {
+    let (correspondences, camera) = correspondences(count);
+    let options = PnpRansacOptions::default();
+
+    bencher.bench_local(|| {
+        pnp_solver_ransac(black_box(&correspondences), &camera, None, &options)
+            .expect("Could not solve PnP")
+    });
+}
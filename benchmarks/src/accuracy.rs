@@ -0,0 +1,389 @@
+use std::{
+    fmt,
+    io::{self, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use diesel::PgConnection;
+use feature_extraction::get_mat_from_dir;
+use homographier::homographier::{MatError, Pose};
+use localizer::{
+    coarse_to_fine::{localize_coarse_to_fine, CoarseOptions},
+    localize, LocalizationError, LocalizationOptions, StageTimings,
+};
+use nalgebra::Rotation3;
+use opencv::prelude::*;
+use query_preprocessor::QueryOptions;
+use synthesizer::manifest::ManifestEntry;
+
+/// Options for the accuracy benchmark.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccuracyOptions {
+    pub localization: LocalizationOptions,
+    /// Localizes coarse to fine if set, otherwise against the whole level of detail
+    pub coarse: Option<CoarseOptions>,
+    /// The largest angular error in degrees of a successful localization
+    pub max_angular_error: f64,
+    /// The largest position error in meters of a successful localization
+    pub max_position_error: f64,
+}
+
+impl Default for AccuracyOptions {
+    fn default() -> Self {
+        AccuracyOptions {
+            localization: LocalizationOptions::default(),
+            coarse: None,
+            max_angular_error: 1.0,
+            max_position_error: 1000.0,
+        }
+    }
+}
+
+/// The result of localizing a single query image of the manifest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryResult {
+    pub image: PathBuf,
+    /// The angle in degrees of the rotation between the estimated and the known attitude, [`None`] if no pose was found
+    pub angular_error: Option<f64>,
+    /// The distance in meters between the estimated and the known camera position, [`None`] if no pose was found
+    pub position_error: Option<f64>,
+    pub correspondences: usize,
+    pub inliers: usize,
+    pub timings: StageTimings,
+}
+
+impl QueryResult {
+    /// Whether a pose was found within the error limits of `options`
+    pub fn is_success(&self, options: &AccuracyOptions) -> bool {
+        match (self.angular_error, self.position_error) {
+            (Some(angular), Some(position)) => {
+                angular <= options.max_angular_error && position <= options.max_position_error
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The angular error in degrees and position error in meters between two poses.
+pub fn pose_errors(estimated: &Pose, truth: &Pose) -> (f64, f64) {
+    let (estimated_rotation, true_rotation) = (
+        Rotation3::from_scaled_axis(estimated.rvec),
+        Rotation3::from_scaled_axis(truth.rvec),
+    );
+
+    let angular = (estimated_rotation * true_rotation.inverse())
+        .angle()
+        .to_degrees();
+
+    // The positions of the cameras are compared, rather than the translation vectors which depend on the rotation.
+    let estimated_position = -(estimated_rotation.inverse() * estimated.tvec);
+    let true_position = -(true_rotation.inverse() * truth.tvec);
+
+    (angular, (estimated_position - true_position).norm())
+}
+
+/// Localizes a query image of the manifest and compares the pose with the known pose.
+///
+/// ## Errors
+/// If the image can not be read, or the localization fails.
+pub fn run_query(
+    conn: &mut PgConnection,
+    entry: &ManifestEntry,
+    query: &QueryOptions,
+    options: &AccuracyOptions,
+) -> Result<QueryResult, LocalizationError> {
+    let img =
+        get_mat_from_dir(&entry.image.to_string_lossy()).map_err(LocalizationError::Opencv)?;
+
+    if img.empty() {
+        return Err(LocalizationError::Mat(MatError::Empty));
+    }
+
+    let localization = match &options.coarse {
+        Some(coarse) => localize_coarse_to_fine(conn, &img, query, coarse, &options.localization),
+        None => localize(conn, &img, query, &options.localization),
+    }?;
+
+    let (errors, inliers) = match &localization.solution {
+        Some(solution) => {
+            let pose = solution.pose().map_err(LocalizationError::Mat)?;
+            (
+                Some(pose_errors(&pose, &entry.pose)),
+                solution.inliers.rows().max(0) as usize,
+            )
+        }
+        None => (None, 0),
+    };
+
+    Ok(QueryResult {
+        image: entry.image.clone(),
+        angular_error: errors.map(|errors| errors.0),
+        position_error: errors.map(|errors| errors.1),
+        correspondences: localization.correspondences.len(),
+        inliers,
+        timings: localization.timings,
+    })
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000f64
+}
+
+/// Writes a row for every query as CSV, with the runtime of every stage in milliseconds.
+pub fn write_results_csv(
+    writer: &mut impl Write,
+    results: &[QueryResult],
+    options: &AccuracyOptions,
+) -> io::Result<()> {
+    writeln!(
+        writer,
+        "image,success,angular_error,position_error,correspondences,inliers,coarse_ms,extraction_ms,database_ms,matching_ms,world_coordinates_ms,pose_ms,total_ms"
+    )?;
+
+    let optional = |value: Option<f64>| value.map(|value| value.to_string()).unwrap_or_default();
+
+    for result in results {
+        let timings = &result.timings;
+
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            result.image.display(),
+            result.is_success(options),
+            optional(result.angular_error),
+            optional(result.position_error),
+            result.correspondences,
+            result.inliers,
+            milliseconds(timings.coarse),
+            milliseconds(timings.extraction),
+            milliseconds(timings.database),
+            milliseconds(timings.matching),
+            milliseconds(timings.world_coordinates),
+            milliseconds(timings.pose),
+            milliseconds(timings.total()),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Aggregated results of the benchmark.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub queries: usize,
+    /// The amount of queries where a pose was found, whether or not it is within the error limits
+    pub found: usize,
+    pub successes: usize,
+    /// The median and 90th percentile of the angular error in degrees, over the queries where a pose was found
+    pub angular_error: Option<(f64, f64)>,
+    /// The median and 90th percentile of the position error in meters, over the queries where a pose was found
+    pub position_error: Option<(f64, f64)>,
+    pub mean_inliers: f64,
+    /// The mean runtime of every stage
+    pub mean_timings: StageTimings,
+}
+
+impl Summary {
+    pub fn success_rate(&self) -> f64 {
+        match self.queries {
+            0 => 0f64,
+            queries => self.successes as f64 / queries as f64,
+        }
+    }
+}
+
+/// The median and 90th percentile of the values, [`None`] if there are none.
+fn median_and_p90(mut values: Vec<f64>) -> Option<(f64, f64)> {
+    if values.is_empty() {
+        return None;
+    }
+
+    values.sort_by(f64::total_cmp);
+    let at = |fraction: f64| values[((values.len() - 1) as f64 * fraction).round() as usize];
+
+    Some((at(0.5), at(0.9)))
+}
+
+pub fn summarize(results: &[QueryResult], options: &AccuracyOptions) -> Summary {
+    let queries = results.len();
+    let divisor = queries.max(1) as u32;
+
+    let sum = |stage: fn(&StageTimings) -> Duration| {
+        results
+            .iter()
+            .map(|result| stage(&result.timings))
+            .sum::<Duration>()
+            / divisor
+    };
+
+    Summary {
+        queries,
+        found: results
+            .iter()
+            .filter(|result| result.angular_error.is_some())
+            .count(),
+        successes: results
+            .iter()
+            .filter(|result| result.is_success(options))
+            .count(),
+        angular_error: median_and_p90(
+            results
+                .iter()
+                .filter_map(|result| result.angular_error)
+                .collect(),
+        ),
+        position_error: median_and_p90(
+            results
+                .iter()
+                .filter_map(|result| result.position_error)
+                .collect(),
+        ),
+        mean_inliers: results.iter().map(|result| result.inliers).sum::<usize>() as f64
+            / divisor as f64,
+        mean_timings: StageTimings {
+            coarse: sum(|timings| timings.coarse),
+            extraction: sum(|timings| timings.extraction),
+            database: sum(|timings| timings.database),
+            matching: sum(|timings| timings.matching),
+            world_coordinates: sum(|timings| timings.world_coordinates),
+            pose: sum(|timings| timings.pose),
+        },
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pair = |value: Option<(f64, f64)>| match value {
+            Some((median, p90)) => format!("{median:.4} / {p90:.4}"),
+            None => "-".to_string(),
+        };
+        let timings = &self.mean_timings;
+
+        writeln!(f, "{:<32} {:>20}", "Queries", self.queries)?;
+        writeln!(f, "{:<32} {:>20}", "Poses found", self.found)?;
+        writeln!(
+            f,
+            "{:<32} {:>19.1}%",
+            "Success rate",
+            self.success_rate() * 100f64
+        )?;
+        writeln!(
+            f,
+            "{:<32} {:>20}",
+            "Angular error [deg] (p50 / p90)",
+            pair(self.angular_error)
+        )?;
+        writeln!(
+            f,
+            "{:<32} {:>20}",
+            "Position error [m] (p50 / p90)",
+            pair(self.position_error)
+        )?;
+        writeln!(f, "{:<32} {:>20.1}", "Mean inliers", self.mean_inliers)?;
+
+        for (stage, duration) in [
+            ("Coarse [ms]", timings.coarse),
+            ("Extraction [ms]", timings.extraction),
+            ("Database [ms]", timings.database),
+            ("Matching [ms]", timings.matching),
+            ("World coordinates [ms]", timings.world_coordinates),
+            ("Pose [ms]", timings.pose),
+            ("Total [ms]", timings.total()),
+        ] {
+            writeln!(f, "{:<32} {:>20.1}", stage, milliseconds(duration))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector3;
+
+    fn result(
+        angular_error: Option<f64>,
+        position_error: Option<f64>,
+        inliers: usize,
+    ) -> QueryResult {
+        QueryResult {
+            image: PathBuf::from("query.png"),
+            angular_error,
+            position_error,
+            correspondences: 100,
+            inliers,
+            timings: StageTimings {
+                extraction: Duration::from_millis(10),
+                pose: Duration::from_millis(4),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn errors_of_identical_poses() {
+        let pose = Pose {
+            rvec: Vector3::new(0.1, 0.2, 0.3),
+            tvec: Vector3::new(1.0, 2.0, 3.0),
+        };
+
+        let (angular, position) = pose_errors(&pose, &pose);
+
+        assert!(angular.abs() < 1e-9);
+        assert!(position.abs() < 1e-9);
+    }
+
+    #[test]
+    fn errors_are_in_degrees_and_meters() {
+        let truth = Pose {
+            rvec: Vector3::zeros(),
+            tvec: Vector3::new(0.0, 0.0, 100.0),
+        };
+        let estimated = Pose {
+            rvec: Vector3::new(0.0, 0.0, 2f64.to_radians()),
+            tvec: Vector3::new(0.0, 0.0, 103.0),
+        };
+
+        let (angular, position) = pose_errors(&estimated, &truth);
+
+        assert!((angular - 2.0).abs() < 1e-9);
+        assert!((position - 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn summary_of_results() {
+        let options = AccuracyOptions::default();
+        let results = [
+            result(Some(0.1), Some(10.0), 50),
+            result(Some(5.0), Some(10.0), 20),
+            result(None, None, 0),
+            result(Some(0.2), Some(20.0), 30),
+        ];
+
+        let summary = summarize(&results, &options);
+
+        assert_eq!(summary.found, 3);
+        assert_eq!(summary.successes, 2);
+        assert_eq!(summary.success_rate(), 0.5);
+        assert_eq!(summary.angular_error, Some((0.2, 5.0)));
+        assert_eq!(summary.mean_inliers, 25.0);
+        assert_eq!(summary.mean_timings.total(), Duration::from_millis(14));
+    }
+
+    #[test]
+    fn results_as_csv() {
+        let options = AccuracyOptions::default();
+        let mut csv = Vec::new();
+
+        write_results_csv(&mut csv, &[result(None, None, 0)], &options).unwrap();
+
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].split(',').count(), lines[1].split(',').count());
+        assert!(lines[1].starts_with("query.png,false,,,100,0,"));
+    }
+}
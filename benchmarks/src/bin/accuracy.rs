@@ -0,0 +1,168 @@
+use std::{env, fs::File, io::BufWriter, path::PathBuf};
+
+use benchmarks::accuracy::{run_query, summarize, write_results_csv, AccuracyOptions, QueryResult};
+use clap::Parser;
+use diesel::{Connection, PgConnection};
+use dotenvy::dotenv;
+use feature_extraction::normalization::NormalizationOptions;
+use geotiff_lib::masking::MaskOptions;
+use homographier::homographier::{Cmat, PnpRansacOptions};
+use localizer::{coarse_to_fine::CoarseOptions, LocalizationOptions, StageTimings};
+use query_preprocessor::{CameraModel, QueryOptions};
+use synthesizer::manifest::read_manifest;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Measure the accuracy of the localization over query images with known poses", long_about = None)]
+struct Args {
+    /// A CSV manifest with the columns image,rx,ry,rz,tx,ty,tz, as written by the synthesizer
+    manifest_path: PathBuf,
+
+    /// The path the results of every query are written to as CSV
+    #[arg(short, long, default_value = "accuracy.csv")]
+    output: PathBuf,
+
+    /// The database url to connect to. Can also be provided by setting environment variable: DATABASE_URL
+    #[arg(long)]
+    database_url: Option<String>,
+
+    /// The camera calibration as found by the calibrator
+    #[arg(long, required = true, num_args = 4, value_names = ["FX", "FY", "CX", "CY"])]
+    intrinsics: Vec<f64>,
+
+    /// The distortion coefficients from the camera calibration
+    #[arg(long, num_args = 1.., default_values_t = [0.0, 0.0, 0.0, 0.0], allow_negative_numbers = true)]
+    dist_coeffs: Vec<f64>,
+
+    /// The ground sampling distance of the query images in meters per pixel
+    #[arg(long)]
+    query_gsd: f64,
+
+    /// The ground sampling distance of the full resolution reference mosaic in meters per pixel
+    #[arg(long)]
+    reference_gsd: f64,
+
+    /// The level of detail the pose is estimated from
+    #[arg(short, long, default_value_t = 0)]
+    lod: u64,
+
+    /// Find the footprint of the query images in this level of detail before estimating the pose
+    #[arg(long)]
+    coarse_lod: Option<u64>,
+
+    /// How much the footprint is grown on every side before the fine lookup, as a fraction of its size
+    #[arg(long, default_value_t = 0.25)]
+    margin: f64,
+
+    /// The threshold used in Lowe's ratio test when matching descriptors
+    #[arg(long, default_value_t = 0.7)]
+    ratio: f32,
+
+    /// The maximum amount of features extracted from the query images
+    #[arg(long)]
+    max_features: Option<i32>,
+
+    /// Use the world coordinates the preprocessor stored with the keypoints, instead of the elevation data
+    #[arg(long)]
+    stored_world_coordinates: bool,
+
+    /// Convert the query images to grayscale, should match the preprocessor
+    #[arg(long)]
+    grayscale: bool,
+
+    /// The maximum reprojection error in pixels of a RANSAC inlier
+    #[arg(long, default_value_t = 8.0)]
+    reproj_threshold: f32,
+
+    /// The largest angular error in degrees of a successful localization
+    #[arg(long, default_value_t = 1.0)]
+    max_angular_error: f64,
+
+    /// The largest position error in meters of a successful localization
+    #[arg(long, default_value_t = 1000.0)]
+    max_position_error: f64,
+}
+
+fn main() {
+    dotenv().ok();
+
+    let args = Args::parse();
+
+    let database_url = args
+        .database_url
+        .clone()
+        .unwrap_or_else(|| env::var("DATABASE_URL").expect("DATABASE_URL must be set"));
+    let conn = &mut PgConnection::establish(&database_url)
+        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url));
+
+    let entries = read_manifest(&args.manifest_path).expect("Could not read manifest");
+
+    let (fx, fy, cx, cy) = (
+        args.intrinsics[0],
+        args.intrinsics[1],
+        args.intrinsics[2],
+        args.intrinsics[3],
+    );
+    let camera = CameraModel {
+        intrinsic: Cmat::from_2d_slice(&[[fx, 0.0, cx], [0.0, fy, cy], [0.0, 0.0, 1.0]])
+            .expect("Could not create camera matrix"),
+        dist_coeffs: args.dist_coeffs.clone(),
+    };
+
+    let query = QueryOptions {
+        camera: Some(&camera),
+        query_gsd: args.query_gsd,
+        reference_gsd: args.reference_gsd,
+        level_of_detail: args.lod,
+        normalization: NormalizationOptions {
+            grayscale: args.grayscale,
+            ..Default::default()
+        },
+        mask: MaskOptions::default(),
+    };
+
+    let options = AccuracyOptions {
+        localization: LocalizationOptions {
+            level_of_detail: args.lod,
+            ratio: args.ratio,
+            max_features: args.max_features,
+            stored_world_coordinates: args.stored_world_coordinates,
+            pnp: PnpRansacOptions {
+                reproj_thres: args.reproj_threshold,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        coarse: args.coarse_lod.map(|level_of_detail| CoarseOptions {
+            level_of_detail,
+            margin: args.margin,
+            ..Default::default()
+        }),
+        max_angular_error: args.max_angular_error,
+        max_position_error: args.max_position_error,
+    };
+
+    // Queries that fail with an error are counted as failed localizations.
+    let results: Vec<QueryResult> = entries
+        .iter()
+        .map(|entry| {
+            run_query(conn, entry, &query, &options).unwrap_or_else(|e| {
+                eprintln!("Could not localize {}: {:?}", entry.image.display(), e);
+
+                QueryResult {
+                    image: entry.image.clone(),
+                    angular_error: None,
+                    position_error: None,
+                    correspondences: 0,
+                    inliers: 0,
+                    timings: StageTimings::default(),
+                }
+            })
+        })
+        .collect();
+
+    let mut writer =
+        BufWriter::new(File::create(&args.output).expect("Could not create output file"));
+    write_results_csv(&mut writer, &results, &options).expect("Could not write results");
+
+    print!("{}", summarize(&results, &options));
+}
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "geotransform" DROP COLUMN "spatial_ref";
+ALTER TABLE "elevation_properties" DROP COLUMN "height_reference";
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+
+ALTER TABLE "geotransform" ADD COLUMN "spatial_ref" text;
+
+ALTER TABLE "elevation_properties" ADD COLUMN "height_reference" VARCHAR(16) NOT NULL DEFAULT 'ellipsoid';
@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "keypoint"
+  DROP COLUMN "ecef_x",
+  DROP COLUMN "ecef_y",
+  DROP COLUMN "ecef_z",
+  DROP COLUMN "latitude",
+  DROP COLUMN "longitude",
+  DROP COLUMN "height";
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+
+ALTER TABLE "keypoint"
+  ADD COLUMN "ecef_x" float,
+  ADD COLUMN "ecef_y" float,
+  ADD COLUMN "ecef_z" float,
+  ADD COLUMN "latitude" float,
+  ADD COLUMN "longitude" float,
+  ADD COLUMN "height" float;
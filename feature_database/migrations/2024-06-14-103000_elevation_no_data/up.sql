@@ -0,0 +1,3 @@
+-- Your SQL goes here
+
+ALTER TABLE "elevation_properties" ADD COLUMN "no_data" float8;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+
+ALTER TABLE "elevation_properties" ADD COLUMN "source_datum" TEXT;
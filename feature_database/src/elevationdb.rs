@@ -7,25 +7,35 @@ use diesel::result::Error as DieselError;
 pub enum Errors {
     Gdal(gdal::errors::GdalError),
     Diesel(DieselError),
+    /// The height reference stored in the database is not known
+    UnknownHeightReference(String),
 }
 
+pub use geotiff_lib::elevation::VerticalDatum;
+pub use geotiff_lib::geodesy::HeightReference;
+
 pub mod geotransform {
     use super::*;
     use crate::schema::geotransform::dsl;
     use gdal::GeoTransform;
     use gdal::GeoTransformEx;
-    use gdal::spatial_ref::{SpatialRef, CoordTransform};
+    use geotiff_lib::geodesy::{self, OutputFrame};
 
     /// Stores a geotransform in the dataset. The name is not choosable by the user.
     /// The name of the transform should be either "dataset" or "elevation".
+    ///
+    /// `spatial_ref` is the CRS of the dataset as WKT or any definition GDAL understands, such as "EPSG:25832".
+    /// [`geodesy::DEFAULT_SPATIAL_REF`] is assumed if it is [`None`].
     pub fn create_geotransform(
         conn: &mut PgConnection,
         name: &str,
         transform: GeoTransform,
+        spatial_ref: Option<&str>,
     ) -> Result<(), DieselError> {
         let insert_transform = models::InsertGeoTransform {
             dataset_name: name,
             transform: &transform,
+            spatial_ref,
         };
 
         diesel::insert_into(crate::schema::geotransform::table)
@@ -35,12 +45,23 @@ pub mod geotransform {
         Ok(())
     }
 
-    fn read_geotransform(conn: &mut PgConnection, name: &str) -> Result<GeoTransform, DieselError> {
+    /// Reads a geotransform stored by [`create_geotransform`].
+    pub fn read_geotransform(conn: &mut PgConnection, name: &str) -> Result<GeoTransform, DieselError> {
+        read_georeference(conn, name).map(|(transform, _)| transform)
+    }
+
+    /// Reads a geotransform stored by [`create_geotransform`] together with the CRS of its dataset.
+    pub fn read_georeference(
+        conn: &mut PgConnection,
+        name: &str,
+    ) -> Result<(GeoTransform, String), DieselError> {
         let transform: models::GeoTransform = dsl::geotransform
             .filter(dsl::dataset_name.eq(name))
             .select(models::GeoTransform::as_select())
             .first(conn)?;
 
+        let spatial_ref = geodesy::spatial_ref_or_default(transform.spatial_ref);
+
         // If the transform is in the database then everything works and unwrap is alright.
         let transform: Vec<f64> = transform
             .transform
@@ -51,56 +72,137 @@ pub mod geotransform {
             .try_into()
             .expect("Could not convert from vector to array");
 
-        Ok(transform)
+        Ok((transform, spatial_ref))
     }
 
     /// Returns the 3d world coordinates from image pixel coordinates
     /// # Input
-    /// The inputs provided are x and y pixel coordinates from the reference image dataset.
+    /// The inputs provided are x and y pixel coordinates from the reference image dataset,
+    /// and the frame the coordinates are returned in.
     /// # Returns
-    /// A 3D point in space calculated from the center of earth.
+    /// A 3D point in `frame`, e.g. calculated from the center of earth with [`OutputFrame::Ecef`].
     ///
     /// Return type: Triple of f64.
+    ///
+    /// The pixel is converted with the CRS of the reference dataset, and the height is looked up in the
+    /// elevation dataset through its own CRS with bilinear interpolation. Without elevation data the point is placed on the ellipsoid.
+    /// This agrees with `MosaicDataset::get_world_coordinates` on the datasets the database was built from.
+    ///
+    /// Every call reads the geotransforms again, use [`Georeferences`] to convert many pixels.
+    /// ## Errors
+    /// [`DieselError::NotFound`] if the pixel is outside the elevation dataset or only surrounded by nodata.
     pub fn get_world_coordinates(
         conn: &mut PgConnection,
         x: f64,
         y: f64,
+        frame: OutputFrame,
     ) -> Result<(f64, f64, f64), Errors> {
-        let transform = read_geotransform(conn, "dataset").map_err(Errors::Diesel)?;
+        let coordinates = Georeferences::read(conn)?.world_coordinates(conn, &[(x, y)], frame)?;
 
-        let coordinates = transform.apply(x, y);
-
-        let elevation_transform = match read_geotransform(conn, "elevation") {
-            Ok(transform) => transform,
-            Err(_e) => return Ok(convert_coordinates(coordinates.1, coordinates.0, 0.0)),
-        };
+        coordinates[0].ok_or(Errors::Diesel(DieselError::NotFound))
+    }
 
-        let inv_ele = elevation_transform
-            .invert()
-            .expect("Could not inverse transform");
+    /// The geotransforms, CRSs and height reference needed to turn reference pixels into world coordinates,
+    /// read from the database once so they can be reused for any amount of pixels.
+    #[derive(Debug, Clone)]
+    pub struct Georeferences {
+        transform: GeoTransform,
+        spatial_ref: String,
+        elevation: Option<ElevationReference>,
+    }
 
-        let elevation_pixels = inv_ele.apply(coordinates.0, coordinates.1);
+    #[derive(Debug, Clone)]
+    struct ElevationReference {
+        /// From the CRS of the elevation dataset to its pixels
+        inverse: GeoTransform,
+        spatial_ref: String,
+        properties: models::ElevationProperties,
+        heights: HeightReference,
+    }
 
-        let height = super::elevation::get_elevation(conn, elevation_pixels.0, elevation_pixels.1)
-            .map_err(Errors::Diesel)?;
+    impl Georeferences {
+        /// Reads the geotransforms of the reference and elevation datasets.
+        /// The elevation is left out if the database has none.
+        pub fn read(conn: &mut PgConnection) -> Result<Georeferences, Errors> {
+            let (transform, spatial_ref) = read_georeference(conn, "dataset").map_err(Errors::Diesel)?;
+
+            let elevation = match read_georeference(conn, "elevation") {
+                Ok((elevation_transform, elevation_ref)) => Some(ElevationReference {
+                    inverse: elevation_transform.invert().map_err(Errors::Gdal)?,
+                    spatial_ref: elevation_ref,
+                    properties: super::elevation::read_properties(conn).map_err(Errors::Diesel)?,
+                    heights: super::elevation::read_height_reference(conn)?,
+                }),
+                Err(DieselError::NotFound) => None,
+                Err(e) => return Err(Errors::Diesel(e)),
+            };
 
-        let coordinates = convert_coordinates(coordinates.1, coordinates.0, height);
+            Ok(Georeferences {
+                transform,
+                spatial_ref,
+                elevation,
+            })
+        }
 
-        Ok(coordinates)
-    }
+        /// Converts full resolution pixels of the reference dataset to world coordinates in `frame`.
+        ///
+        /// The heights of all pixels are fetched with a single query, and the points are transformed as one batch.
+        /// ## Returns
+        /// The world coordinates in the order of `pixels`, [`None`] for a pixel outside the elevation dataset or only surrounded by nodata.
+        /// ## Errors
+        /// If the heights can not be read, or the points can not be transformed.
+        pub fn world_coordinates(
+            &self,
+            conn: &mut PgConnection,
+            pixels: &[(f64, f64)],
+            frame: OutputFrame,
+        ) -> Result<Vec<Option<(f64, f64, f64)>>, Errors> {
+            let (x, y): (Vec<f64>, Vec<f64>) = pixels
+                .iter()
+                .map(|(x, y)| self.transform.apply(*x, *y))
+                .unzip();
+
+            let (z, heights) = match &self.elevation {
+                Some(elevation) => {
+                    let (mut elevation_x, mut elevation_y) = (x.clone(), y.clone());
+                    geodesy::transform_points(
+                        &self.spatial_ref,
+                        &elevation.spatial_ref,
+                        &mut elevation_x,
+                        &mut elevation_y,
+                    )
+                    .map_err(Errors::Gdal)?;
+
+                    let elevation_pixels: Vec<(f64, f64)> = elevation_x
+                        .iter()
+                        .zip(&elevation_y)
+                        .map(|(x, y)| elevation.inverse.apply(*x, *y))
+                        .collect();
+
+                    let z = super::elevation::get_elevations(conn, &elevation.properties, &elevation_pixels)
+                        .map_err(Errors::Diesel)?;
+
+                    (z, elevation.heights)
+                }
+                None => (vec![Some(0f64); pixels.len()], HeightReference::Ellipsoid),
+            };
 
-    fn convert_coordinates(x: f64, y: f64, z: f64) -> (f64, f64, f64) {
-        let mut x = [x];
-        let mut y = [y];
-        let mut z = [z];
-        let epsg_4326 = SpatialRef::from_epsg(4326).expect("Could not find spatialref");
-        let epsg_4978 = SpatialRef::from_epsg(4978).expect("Could not find spatialref");
+            // Only the pixels with a height are transformed, the others stay without world coordinates.
+            let resolved: Vec<usize> = (0..pixels.len()).filter(|&i| z[i].is_some()).collect();
+            let mut resolved_x: Vec<f64> = resolved.iter().map(|&i| x[i]).collect();
+            let mut resolved_y: Vec<f64> = resolved.iter().map(|&i| y[i]).collect();
+            let mut resolved_z: Vec<f64> = resolved.iter().filter_map(|&i| z[i]).collect();
 
-        let transformer = CoordTransform::new(&epsg_4326, &epsg_4978).expect("Could not make coordtransform");
+            geodesy::convert(&self.spatial_ref, heights, frame, &mut resolved_x, &mut resolved_y, &mut resolved_z)
+                .map_err(Errors::Gdal)?;
 
-        transformer.transform_coords(&mut x, &mut y, &mut z).expect("Could not convert coordinates");
+            let mut world = vec![None; pixels.len()];
+            for (j, &i) in resolved.iter().enumerate() {
+                world[i] = Some((resolved_x[j], resolved_y[j], resolved_z[j]));
+            }
 
-        (x[0], y[0], z[0])
+            Ok(world)
+        }
     }
 
     #[cfg(test)]
@@ -116,7 +218,7 @@ pub mod geotransform {
 
             let transform: [f64; 6] = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
 
-            create_geotransform(connection, "dataset", transform).unwrap();
+            create_geotransform(connection, "dataset", transform, None).unwrap();
 
             let fetched_transform: models::GeoTransform = dsl::geotransform
                 .find(1)
@@ -143,6 +245,7 @@ pub mod geotransform {
             let insert_tranform = models::InsertGeoTransform {
                 dataset_name: "dataset",
                 transform: &transform,
+                spatial_ref: None,
             };
 
             diesel::insert_into(crate::schema::geotransform::table)
@@ -166,17 +269,151 @@ pub mod geotransform {
         }
 
         #[test]
-        fn coordinate_converter() {
-            let himmel_x = 56.105169;
-            let himmel_y = 9.68505;
+        fn read_spatial_ref_from_database() {
+            let _lock = obtain_lock();
+            let connection = &mut setup_database();
+
+            let transform: [f64; 6] = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+
+            create_geotransform(connection, "dataset", transform, Some("EPSG:25832")).unwrap();
+            create_geotransform(connection, "elevation", transform, None).unwrap();
+
+            let (_, dataset_ref) = read_georeference(connection, "dataset").unwrap();
+            let (_, elevation_ref) = read_georeference(connection, "elevation").unwrap();
+
+            assert_eq!(dataset_ref, "EPSG:25832");
+            assert_eq!(elevation_ref, geodesy::DEFAULT_SPATIAL_REF);
+        }
+
+        #[test]
+        fn world_coordinates_of_projected_dataset() {
+            let _lock = obtain_lock();
+            let connection = &mut setup_database();
+
+            // A 10 m grid in UTM zone 32 around Himmelbjerget.
+            let transform: [f64; 6] = [540_000.0, 10.0, 0.0, 6_220_000.0, 0.0, -10.0];
+            create_geotransform(connection, "dataset", transform, Some("EPSG:25832")).unwrap();
+
+            let coordinates = get_world_coordinates(connection, 100.0, 200.0, OutputFrame::Ecef).unwrap();
+
+            let (mut x, mut y, mut z) = ([541_000.0], [6_218_000.0], [0.0]);
+            geodesy::to_ecef("EPSG:25832", HeightReference::Ellipsoid, &mut x, &mut y, &mut z).unwrap();
+
+            assert!((coordinates.0 - x[0]).abs() < 1e-3);
+            assert!((coordinates.1 - y[0]).abs() < 1e-3);
+            assert!((coordinates.2 - z[0]).abs() < 1e-3);
+        }
+
+        #[test]
+        fn batch_matches_single_pixels() {
+            let _lock = obtain_lock();
+            let connection = &mut setup_database();
+            let mut current_dir = std::env::current_dir().expect("Current directory not set.");
+
+            current_dir.pop();
+            let path = "resources/test/Geotiff/Elevation_test/elevation/Copernicus_DSM_COG_30_N56_00_E009_00_DEM.tif";
+            current_dir.push(path);
+            let ds = gdal::Dataset::open(current_dir).unwrap();
+
+            // The elevation dataset doubles as the reference dataset.
+            let transform = ds.geo_transform().unwrap();
+            create_geotransform(connection, "dataset", transform, None).unwrap();
+            create_geotransform(connection, "elevation", transform, None).unwrap();
+            super::super::elevation::add_elevation_data(connection, &ds, HeightReference::Ellipsoid, None).unwrap();
+
+            let pixels = [(549.04, 1073.7972), (10.0, 20.0), (700.2, 3.9)];
+            let batch = Georeferences::read(connection)
+                .unwrap()
+                .world_coordinates(connection, &pixels, OutputFrame::Ecef)
+                .unwrap();
+
+            for (point, (x, y)) in batch.iter().zip(pixels) {
+                let point = point.unwrap();
+                let single = get_world_coordinates(connection, x, y, OutputFrame::Ecef).unwrap();
+
+                assert!((point.0 - single.0).abs() < 1e-6);
+                assert!((point.1 - single.1).abs() < 1e-6);
+                assert!((point.2 - single.2).abs() < 1e-6);
+            }
+        }
+
+        #[test]
+        fn pixels_over_no_data_have_no_world_coordinates() {
+            let _lock = obtain_lock();
+            let connection = &mut setup_database();
+
+            let driver = gdal::DriverManager::get_driver_by_name("MEM").unwrap();
+            let ds = driver.create_with_band_type::<f64, _>("", 2, 2, 1).unwrap();
+            let mut band = ds.rasterband(1).unwrap();
+            band.set_no_data_value(Some(-9999.0)).unwrap();
+            band.write((0, 0), (2, 2), &gdal::raster::Buffer::new((2, 2), vec![10.0, 20.0, 30.0, -9999.0]))
+                .unwrap();
+
+            let transform: [f64; 6] = [9.0, 0.1, 0.0, 56.0, 0.0, -0.1];
+            create_geotransform(connection, "dataset", transform, None).unwrap();
+            create_geotransform(connection, "elevation", transform, None).unwrap();
+            super::super::elevation::add_elevation_data(connection, &ds, HeightReference::Ellipsoid, None).unwrap();
+
+            let world = Georeferences::read(connection)
+                .unwrap()
+                .world_coordinates(connection, &[(0.5, 0.5), (1.5, 1.5), (1.0, 0.5)], OutputFrame::Native)
+                .unwrap();
+
+            let (x, y, z) = world[0].unwrap();
+            assert!((x - 9.05).abs() < 1e-9 && (y - 55.95).abs() < 1e-9);
+            assert_eq!(z, 10.0);
+            assert_eq!(world[1], None);
+            assert_eq!(world[2].map(|point| point.2), Some(15.0));
+            assert!(get_world_coordinates(connection, 1.5, 1.5, OutputFrame::Native).is_err());
+        }
+
+        #[test]
+        fn database_agrees_with_mosaic() {
+            use geotiff_lib::elevation::ElevationOptions;
+            use geotiff_lib::image_extractor::{Datasets, MosaicDataset, RawDataset};
+
+            let _lock = obtain_lock();
+            let connection = &mut setup_database();
+            let temp_dir = tempfile::tempdir().unwrap();
+            let mut current_dir = std::env::current_dir().expect("Current directory not set.");
+            current_dir.pop();
+
+            let mut mosaic = RawDataset::import_datasets(
+                &current_dir.join("resources/test/Geotiff/Elevation_test/map_data").to_string_lossy(),
+            )
+            .unwrap()
+            .to_vrt_dataset()
+            .unwrap();
+            mosaic
+                .set_elevation_dataset(
+                    &current_dir.join("resources/test/Geotiff/Elevation_test/elevation").to_string_lossy(),
+                    &temp_dir.path().to_string_lossy(),
+                    &ElevationOptions::default(),
+                )
+                .unwrap();
 
+            // Stores the mosaic the way the preprocessor does.
+            let elevation = mosaic.elevation.as_ref().unwrap();
+            let wkt = |dataset: &gdal::Dataset| dataset.spatial_ref().unwrap().to_wkt().unwrap();
+            create_geotransform(connection, "dataset", mosaic.dataset.geo_transform().unwrap(), Some(&wkt(&mosaic.dataset))).unwrap();
+            create_geotransform(connection, "elevation", elevation.geo_transform().unwrap(), Some(&wkt(elevation))).unwrap();
+            super::super::elevation::add_elevation_data(connection, elevation, HeightReference::Ellipsoid, None).unwrap();
 
-            let converted_coords = convert_coordinates(himmel_x, himmel_y, 0.0);
+            let pixels = [(8220.6, 12000.0 - 1262.028), (100.0, 250.0), (5000.5, 6000.5)];
+            let georeferences = Georeferences::read(connection).unwrap();
 
-            dbg!(&converted_coords);
+            for frame in [OutputFrame::Native, OutputFrame::Geodetic, OutputFrame::Ecef] {
+                let database = georeferences.world_coordinates(connection, &pixels, frame).unwrap();
 
-            assert!(converted_coords.0 - 3514316.2468943615 < f64::EPSILON);
-            assert!(converted_coords.1 - 599769.3477405359 < f64::EPSILON);
+                for (point, (x, y)) in database.iter().zip(pixels) {
+                    let point = point.unwrap();
+                    let dataset = mosaic.get_world_coordinates(x, y, frame).unwrap();
+
+                    assert!((point.0 - dataset.0).abs() < 1e-6, "{frame:?}");
+                    assert!((point.1 - dataset.1).abs() < 1e-6, "{frame:?}");
+                    assert!((point.2 - dataset.2).abs() < 1e-6, "{frame:?}");
+                }
+            }
         }
     }
 }
@@ -185,16 +422,31 @@ pub mod elevation {
     use super::*;
     use crate::schema::{elevation, elevation_properties};
     use gdal::Dataset;
+    use geotiff_lib::elevation::BilinearSample;
+    use std::collections::HashMap;
 
     const DIESEL_LIMIT: usize = 65535;
 
-    pub fn add_elevation_data(conn: &mut PgConnection, dataset: &Dataset) -> Result<(), Errors> {
+    /// Stores the heights of the first band of `dataset`, measured from `heights`, together with its nodata value.
+    ///
+    /// `source_datum` is the vertical datum the heights were converted from before they were stored, if any.
+    pub fn add_elevation_data(
+        conn: &mut PgConnection,
+        dataset: &Dataset,
+        heights: HeightReference,
+        source_datum: Option<&VerticalDatum>,
+    ) -> Result<(), Errors> {
         let rasterband = dataset.rasterband(1).map_err(Errors::Gdal)?;
         let dimensions = rasterband.size();
+        let no_data = rasterband.no_data_value();
+        let source_datum = source_datum.map(VerticalDatum::name);
 
         let insert_properties = models::InsertElevationProperties {
             x_size: &(dimensions.0 as i32),
             y_size: &(dimensions.1 as i32),
+            height_reference: heights.name(),
+            no_data: no_data.as_ref(),
+            source_datum: source_datum.as_deref(),
         };
 
         let image: Vec<f64> = rasterband
@@ -231,17 +483,75 @@ pub mod elevation {
         Ok(())
     }
 
+    /// Looks up the height at a position in the elevation dataset, see [`get_elevations`].
+    /// ## Errors
+    /// [`DieselError::NotFound`] if the position is outside the elevation dataset or only surrounded by nodata.
     pub fn get_elevation(conn: &mut PgConnection, x: f64, y: f64) -> Result<f64, DieselError> {
-        let properties = elevation_properties::dsl::elevation_properties
-            .select(models::ElevationProperties::as_select())
-            .first(conn)?;
+        let properties = read_properties(conn)?;
+
+        get_elevations(conn, &properties, &[(x, y)])?[0].ok_or(DieselError::NotFound)
+    }
+
+    /// Looks up the heights at many positions in the elevation dataset with a single query, in the order of `pixels`.
+    ///
+    /// The heights are interpolated bilinearly between the centers of the surrounding pixels, leaving out nodata pixels,
+    /// the same way as `MosaicDataset::get_world_coordinates`. `properties` are read with [`read_properties`].
+    /// ## Returns
+    /// The heights in the order of `pixels`, [`None`] for a position outside the elevation dataset or only surrounded by nodata.
+    pub fn get_elevations(
+        conn: &mut PgConnection,
+        properties: &models::ElevationProperties,
+        pixels: &[(f64, f64)],
+    ) -> Result<Vec<Option<f64>>, DieselError> {
+        let width = properties.x_size as usize;
+        let id = |(x, y): (usize, usize)| (y * width + x + 1) as i32;
 
-        let height: models::Elevation = elevation::dsl::elevation
-            .find(y.round() as i32 * properties.x_size + x.round() as i32 + 1)
+        let samples: Vec<Option<BilinearSample>> = pixels
+            .iter()
+            .map(|(x, y)| BilinearSample::new(*x, *y, width, properties.y_size as usize))
+            .collect();
+
+        let ids: Vec<i32> = samples
+            .iter()
+            .flatten()
+            .flat_map(|sample| sample.pixels.map(id))
+            .collect();
+
+        let heights: HashMap<i32, f64> = elevation::dsl::elevation
+            .filter(elevation::dsl::id.eq_any(&ids))
             .select(models::Elevation::as_select())
-            .first(conn)?;
+            .load(conn)?
+            .into_iter()
+            .map(|elevation| (elevation.id, elevation.height))
+            .collect();
+
+        Ok(samples
+            .iter()
+            .map(|sample| {
+                let sample = sample.as_ref()?;
+                let mut neighbours = [0f64; 4];
+                for (height, pixel) in neighbours.iter_mut().zip(sample.pixels) {
+                    *height = *heights.get(&id(pixel))?;
+                }
+
+                sample.interpolate(neighbours, properties.no_data)
+            })
+            .collect())
+    }
+
+    /// Reads the size and height reference of the stored elevation dataset.
+    pub fn read_properties(conn: &mut PgConnection) -> Result<models::ElevationProperties, DieselError> {
+        elevation_properties::dsl::elevation_properties
+            .select(models::ElevationProperties::as_select())
+            .first(conn)
+    }
 
-        Ok(height.height)
+    /// Reads the surface the stored heights are measured from.
+    pub fn read_height_reference(conn: &mut PgConnection) -> Result<HeightReference, Errors> {
+        let properties = read_properties(conn).map_err(Errors::Diesel)?;
+
+        HeightReference::from_name(&properties.height_reference)
+            .ok_or(Errors::UnknownHeightReference(properties.height_reference))
     }
 
     #[cfg(test)]
@@ -263,7 +573,7 @@ pub mod elevation {
             current_dir.push(path);
             let ds = Dataset::open(current_dir).unwrap();
 
-            add_elevation_data(connection, &ds).unwrap();
+            add_elevation_data(connection, &ds, HeightReference::Egm2008, None).unwrap();
 
             let elevation_db: models::Elevation = crate::schema::elevation::dsl::elevation
                 .find(himmel_y * 800 + himmel_x + 1)
@@ -273,7 +583,8 @@ pub mod elevation {
 
             dbg!(&elevation_db.height);
 
-            assert!((elevation_db.height - 147.0).abs() < 2.0)
+            assert!((elevation_db.height - 147.0).abs() < 2.0);
+            assert_eq!(read_height_reference(connection).unwrap(), HeightReference::Egm2008);
         }
 
         #[test]
@@ -289,7 +600,7 @@ pub mod elevation {
             current_dir.push(path);
             let ds = Dataset::open(current_dir).unwrap();
 
-            add_elevation_data(connection, &ds).unwrap(); // I know it makes it dependent on another function but it's a nightmare to do it directly in diesel, soo......
+            add_elevation_data(connection, &ds, HeightReference::Egm2008, None).unwrap(); // I know it makes it dependent on another function but it's a nightmare to do it directly in diesel, soo......
 
             let elevation_db = get_elevation(connection, himmel_x, himmel_y).unwrap();
 
@@ -297,5 +608,73 @@ pub mod elevation {
 
             assert!((elevation_db - 147.0).abs() < 2.0)
         }
+
+        #[test]
+        fn get_many_elevations_from_db() {
+            let _lock = obtain_lock();
+            let connection = &mut setup_database();
+            let mut current_dir = env::current_dir().expect("Current directory not set.");
+
+            current_dir.pop();
+            let path = "resources/test/Geotiff/Elevation_test/elevation/Copernicus_DSM_COG_30_N56_00_E009_00_DEM.tif";
+            current_dir.push(path);
+            let ds = Dataset::open(current_dir).unwrap();
+
+            add_elevation_data(connection, &ds, HeightReference::Egm2008, None).unwrap();
+
+            let pixels = [(549.04, 1073.7972), (10.0, 20.0), (549.04, 1073.7972), (700.2, 3.9)];
+            let properties = read_properties(connection).unwrap();
+
+            let heights = get_elevations(connection, &properties, &pixels).unwrap();
+
+            assert_eq!(heights.len(), pixels.len());
+            for (height, (x, y)) in heights.iter().zip(pixels) {
+                assert_eq!(*height, Some(get_elevation(connection, x, y).unwrap()));
+            }
+
+            let outside = get_elevations(connection, &properties, &[(10.0, 20.0), (10.0, 1e6)]).unwrap();
+            assert_eq!(outside, vec![heights[1], None]);
+            assert!(matches!(get_elevation(connection, 10.0, 1e6), Err(DieselError::NotFound)));
+        }
+
+        #[test]
+        fn elevations_skip_no_data() {
+            let _lock = obtain_lock();
+            let connection = &mut setup_database();
+
+            let driver = gdal::DriverManager::get_driver_by_name("MEM").unwrap();
+            let ds = driver.create_with_band_type::<f64, _>("", 2, 2, 1).unwrap();
+            let mut band = ds.rasterband(1).unwrap();
+            band.set_no_data_value(Some(-9999.0)).unwrap();
+            band.write((0, 0), (2, 2), &gdal::raster::Buffer::new((2, 2), vec![10.0, 20.0, 30.0, -9999.0]))
+                .unwrap();
+
+            add_elevation_data(connection, &ds, HeightReference::Ellipsoid, None).unwrap();
+            let properties = read_properties(connection).unwrap();
+
+            assert_eq!(properties.no_data, Some(-9999.0));
+
+            let heights = get_elevations(connection, &properties, &[(0.5, 0.5), (1.0, 0.5), (1.0, 1.0)]).unwrap();
+            assert_eq!(heights, vec![Some(10.0), Some(15.0), Some(20.0)]);
+
+            let missing = get_elevations(connection, &properties, &[(1.5, 1.5)]).unwrap();
+            assert_eq!(missing, vec![None]);
+        }
+
+        #[test]
+        fn source_datum_is_stored() {
+            let _lock = obtain_lock();
+            let connection = &mut setup_database();
+
+            let driver = gdal::DriverManager::get_driver_by_name("MEM").unwrap();
+            let ds = driver.create_with_band_type::<f64, _>("", 1, 1, 1).unwrap();
+            let datum = VerticalDatum::GeoidGrid("/usr/share/proj/dk_sdfe_dvr90.tif".into());
+
+            add_elevation_data(connection, &ds, HeightReference::Ellipsoid, Some(&datum)).unwrap();
+            let properties = read_properties(connection).unwrap();
+
+            assert_eq!(properties.source_datum.as_deref(), Some("geoid:dk_sdfe_dvr90.tif"));
+            assert_eq!(read_height_reference(connection).unwrap(), HeightReference::Ellipsoid);
+        }
     }
 }
@@ -7,9 +7,13 @@ use diesel::result::Error as DieselError;
 pub enum Keypoint<'a> {
     One(models::InsertKeypoint<'a>),
     Multiple(Vec<models::InsertKeypoint<'a>>),
+    /// Keypoints with their world coordinates, see [`KeypointDatabase::read_keypoints_with_world_coordinates`]
+    WithWorldCoordinates(Vec<models::InsertWorldKeypoint<'a>>),
 }
 
 const OPENCV_KEYPOINT_LIMIT: i64 = 2_i64.pow(18) - 1;
+/// Diesel has a limit of max 65535 parameters, and a keypoint with world coordinates has 15.
+const WORLD_KEYPOINT_CHUNK_SIZE: usize = 65535 / 15;
 
 impl<'a> KeypointDatabase for Keypoint<'a> {
     fn create_keypoint(
@@ -21,6 +25,13 @@ impl<'a> KeypointDatabase for Keypoint<'a> {
             Keypoint::Multiple(multiple_images) => {
                 create_keypoint_in_database(conn, &multiple_images)?
             }
+            Keypoint::WithWorldCoordinates(keypoints) => {
+                for chunk in keypoints.chunks(WORLD_KEYPOINT_CHUNK_SIZE) {
+                    diesel::insert_into(crate::schema::keypoint::table)
+                        .values(chunk)
+                        .execute(conn)?;
+                }
+            }
         }
         Ok(())
     }
@@ -89,6 +100,74 @@ impl<'a> KeypointDatabase for Keypoint<'a> {
         Ok(keypoints_vec)
     }
 
+    fn read_keypoints_from_lod_uniform(
+        conn: &mut PgConnection,
+        level_of_detail: i32,
+        cell_size: f32,
+        per_cell: i64,
+    ) -> Result<Vec<models::Keypoint>, DieselError> {
+        use diesel::sql_types::{BigInt, Float, Integer};
+
+        // Ranks the keypoints within each grid cell, so the strongest keypoints of every cell can be selected.
+        diesel::sql_query(
+            "SELECT id, x_coord, y_coord, size, angle, response, octave, class_id, descriptor, image_id FROM (
+                SELECT keypoint.*, ROW_NUMBER() OVER (
+                    PARTITION BY FLOOR(keypoint.x_coord / $2), FLOOR(keypoint.y_coord / $2)
+                    ORDER BY keypoint.response DESC
+                ) AS cell_rank
+                FROM keypoint
+                INNER JOIN ref_image ON keypoint.image_id = ref_image.id
+                WHERE ref_image.level_of_detail = $1
+            ) AS ranked
+            WHERE cell_rank <= $3
+            ORDER BY response DESC
+            LIMIT $4",
+        )
+        .bind::<Integer, _>(level_of_detail)
+        .bind::<Float, _>(cell_size)
+        .bind::<BigInt, _>(per_cell)
+        .bind::<BigInt, _>(OPENCV_KEYPOINT_LIMIT)
+        .load(conn)
+    }
+
+    fn read_keypoints_with_world_coordinates(
+        conn: &mut PgConnection,
+        level_of_detail: i32,
+        bounds: Option<(f32, f32, f32, f32)>,
+    ) -> Result<Vec<(models::Keypoint, models::WorldCoordinates)>, DieselError> {
+        use crate::schema::ref_image;
+
+        let mut query = dsl::keypoint
+            .inner_join(ref_image::dsl::ref_image)
+            .filter(ref_image::dsl::level_of_detail.eq(level_of_detail))
+            .filter(dsl::ecef_x.is_not_null())
+            .select((
+                models::Keypoint::as_select(),
+                (
+                    dsl::ecef_x.assume_not_null(),
+                    dsl::ecef_y.assume_not_null(),
+                    dsl::ecef_z.assume_not_null(),
+                    dsl::latitude.assume_not_null(),
+                    dsl::longitude.assume_not_null(),
+                    dsl::height.assume_not_null(),
+                ),
+            ))
+            .into_boxed();
+
+        if let Some((x_start, y_start, x_end, y_end)) = bounds {
+            query = query
+                .filter(dsl::x_coord.ge(x_start.floor()))
+                .filter(dsl::x_coord.le(x_end.ceil()))
+                .filter(dsl::y_coord.ge(y_start.floor()))
+                .filter(dsl::y_coord.le(y_end.ceil()));
+        }
+
+        query
+            .order(dsl::response.desc())
+            .limit(OPENCV_KEYPOINT_LIMIT)
+            .load(conn)
+    }
+
     fn delete_keypoint(conn: &mut PgConnection, id: i32) -> Result<(), DieselError> {
         match diesel::delete(dsl::keypoint.find(id)).execute(conn) {
             Ok(_) => Ok(()),
@@ -133,6 +212,22 @@ pub trait KeypointDatabase {
         y_end: f32,
         level_of_detail: i32,
     ) -> Result<Vec<models::Keypoint>, DieselError>;
+    /// Reads the keypoints of a level of detail, keeping at most `per_cell` keypoints with the highest response in every `cell_size` square.
+    /// This avoids the keypoints clustering in high-texture areas.
+    fn read_keypoints_from_lod_uniform(
+        conn: &mut PgConnection,
+        level_of_detail: i32,
+        cell_size: f32,
+        per_cell: i64,
+    ) -> Result<Vec<models::Keypoint>, DieselError>;
+    /// Reads the keypoints of a level of detail that were stored with world coordinates, optionally only within
+    /// `bounds` given as `(x_start, y_start, x_end, y_end)` in full resolution pixels.
+    /// Keypoints stored without world coordinates are left out.
+    fn read_keypoints_with_world_coordinates(
+        conn: &mut PgConnection,
+        level_of_detail: i32,
+        bounds: Option<(f32, f32, f32, f32)>,
+    ) -> Result<Vec<(models::Keypoint, models::WorldCoordinates)>, DieselError>;
     fn delete_keypoint(conn: &mut PgConnection, id: i32) -> Result<(), DieselError>;
 }
 
@@ -601,6 +696,150 @@ mod tests {
         assert_eq!(fetched_keypoints.len(), 2);
     }
 
+    #[test]
+    fn keypoints_fetched_from_lod_uniform() {
+        let _lock = obtain_lock();
+        let connection = &mut setup_database();
+
+        generate_images_in_database(connection, 1);
+
+        diesel::update(crate::schema::ref_image::table)
+            .set(crate::schema::ref_image::dsl::level_of_detail.eq(1))
+            .execute(connection)
+            .expect("Could not update image");
+
+        let keypoint_vec = vec![
+            models::InsertKeypoint {
+                x_coord: &1.0,
+                y_coord: &1.0,
+                size: &2.0,
+                angle: &2.5,
+                response: &3.0,
+                octave: &4,
+                class_id: &5,
+                descriptor: &[6_u8],
+                image_id: &1,
+            },
+            models::InsertKeypoint {
+                x_coord: &2.0,
+                y_coord: &2.0,
+                size: &2.0,
+                angle: &2.5,
+                response: &5.0,
+                octave: &4,
+                class_id: &5,
+                descriptor: &[6_u8],
+                image_id: &1,
+            },
+            models::InsertKeypoint {
+                x_coord: &15.0,
+                y_coord: &2.0,
+                size: &2.0,
+                angle: &2.5,
+                response: &1.0,
+                octave: &4,
+                class_id: &5,
+                descriptor: &[6_u8],
+                image_id: &1,
+            },
+        ];
+
+        keypoint_vec.into_iter().for_each(|single_keypoint| {
+            diesel::insert_into(crate::schema::keypoint::table)
+                .values(&single_keypoint)
+                .returning(models::Keypoint::as_returning())
+                .get_result(connection)
+                .expect("Error saving new keypoint");
+        });
+
+        let fetched_keypoints = Keypoint::read_keypoints_from_lod_uniform(connection, 1, 10.0, 1)
+            .expect("Could not fetch keypoints");
+
+        assert_eq!(fetched_keypoints.len(), 2);
+        assert_eq!(fetched_keypoints[0].id, 2);
+        assert_eq!(fetched_keypoints[1].id, 3);
+    }
+
+    #[test]
+    fn keypoints_fetched_with_world_coordinates() {
+        let _lock = obtain_lock();
+        let connection = &mut setup_database();
+
+        let insert_image = InsertImage {
+            x_start: &0,
+            y_start: &0,
+            x_end: &10,
+            y_end: &10,
+            level_of_detail: &1,
+        };
+
+        diesel::insert_into(crate::schema::ref_image::table)
+            .values(&insert_image)
+            .execute(connection)
+            .expect("Could not insert image into database");
+
+        let plain = models::InsertKeypoint {
+            x_coord: &1.0,
+            y_coord: &1.5,
+            size: &2.0,
+            angle: &2.5,
+            response: &3.0,
+            octave: &4,
+            class_id: &5,
+            descriptor: &[6_u8],
+            image_id: &1,
+        };
+        let world = vec![
+            models::InsertWorldKeypoint {
+                keypoint: models::InsertKeypoint {
+                    x_coord: &2.0,
+                    response: &5.0,
+                    ..plain.clone()
+                },
+                ecef_x: &3514316.0,
+                ecef_y: &599769.0,
+                ecef_z: &5269000.0,
+                latitude: &56.1,
+                longitude: &9.7,
+                height: &147.0,
+            },
+            models::InsertWorldKeypoint {
+                keypoint: models::InsertKeypoint {
+                    x_coord: &8.0,
+                    response: &4.0,
+                    ..plain.clone()
+                },
+                ecef_x: &3514400.0,
+                ecef_y: &599800.0,
+                ecef_z: &5269100.0,
+                latitude: &56.2,
+                longitude: &9.8,
+                height: &120.0,
+            },
+        ];
+
+        Keypoint::create_keypoint(connection, Keypoint::One(plain)).unwrap();
+        Keypoint::create_keypoint(connection, Keypoint::WithWorldCoordinates(world)).unwrap();
+
+        let fetched = Keypoint::read_keypoints_with_world_coordinates(connection, 1, None)
+            .expect("Could not fetch keypoints");
+
+        assert_eq!(fetched.len(), 2);
+        assert_eq!(fetched[0].0.x_coord, 2.0);
+        assert_eq!(fetched[0].1.ecef_x, 3514316.0);
+        assert_eq!(fetched[0].1.height, 147.0);
+
+        let fetched = Keypoint::read_keypoints_with_world_coordinates(
+            connection,
+            1,
+            Some((5.0, 0.0, 10.0, 10.0)),
+        )
+        .expect("Could not fetch keypoints");
+
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].1.latitude, 56.2);
+    }
+
     #[test]
     fn deleting_keypoint() {
         let _lock = obtain_lock();
@@ -24,7 +24,7 @@ pub struct InsertImage<'a> {
     pub level_of_detail: &'a i32,
 }
 
-#[derive(Queryable, Selectable, Clone, Debug)]
+#[derive(Queryable, QueryableByName, Selectable, Clone, Debug)]
 #[diesel(table_name = keypoint)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Keypoint {
@@ -54,6 +54,33 @@ pub struct InsertKeypoint<'a> {
     pub image_id: &'a i32,
 }
 
+/// The position of a keypoint on the earth, computed by the preprocessor.
+#[derive(Queryable, Clone, Copy, Debug, PartialEq)]
+pub struct WorldCoordinates {
+    pub ecef_x: f64,
+    pub ecef_y: f64,
+    pub ecef_z: f64,
+    /// In degrees
+    pub latitude: f64,
+    /// In degrees
+    pub longitude: f64,
+    /// Above the WGS84 ellipsoid in meters
+    pub height: f64,
+}
+
+#[derive(Insertable, Clone, Debug)]
+#[diesel(table_name = keypoint)]
+pub struct InsertWorldKeypoint<'a> {
+    #[diesel(embed)]
+    pub keypoint: InsertKeypoint<'a>,
+    pub ecef_x: &'a f64,
+    pub ecef_y: &'a f64,
+    pub ecef_z: &'a f64,
+    pub latitude: &'a f64,
+    pub longitude: &'a f64,
+    pub height: &'a f64,
+}
+
 #[derive(Queryable, Selectable, Clone, Debug)]
 #[diesel(table_name = geotransform)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
@@ -61,6 +88,7 @@ pub struct GeoTransform {
     pub id: i32,
     pub dataset_name: String,
     pub transform: Vec<Option<f64>>,
+    pub spatial_ref: Option<String>,
 }
 
 #[derive(Insertable, Clone, Debug)]
@@ -68,6 +96,7 @@ pub struct GeoTransform {
 pub struct InsertGeoTransform<'a> {
     pub dataset_name: &'a str,
     pub transform: &'a [f64],
+    pub spatial_ref: Option<&'a str>,
 }
 
 #[derive(Queryable, Selectable, Clone, Debug)]
@@ -91,6 +120,9 @@ pub struct ElevationProperties {
     pub id: i32,
     pub x_size: i32,
     pub y_size: i32,
+    pub height_reference: String,
+    pub no_data: Option<f64>,
+    pub source_datum: Option<String>,
 }
 
 #[derive(Insertable, Clone, Debug)]
@@ -98,4 +130,7 @@ pub struct ElevationProperties {
 pub struct InsertElevationProperties<'a> {
     pub x_size: &'a i32,
     pub y_size: &'a i32,
+    pub height_reference: &'a str,
+    pub no_data: Option<&'a f64>,
+    pub source_datum: Option<&'a str>,
 }
@@ -12,6 +12,10 @@ diesel::table! {
         id -> Int4,
         x_size -> Int4,
         y_size -> Int4,
+        #[max_length = 16]
+        height_reference -> Varchar,
+        no_data -> Nullable<Float8>,
+        source_datum -> Nullable<Text>,
     }
 }
 
@@ -21,6 +25,7 @@ diesel::table! {
         #[max_length = 64]
         dataset_name -> Varchar,
         transform -> Array<Nullable<Float8>>,
+        spatial_ref -> Nullable<Text>,
     }
 }
 
@@ -36,6 +41,12 @@ diesel::table! {
         class_id -> Int4,
         descriptor -> Bytea,
         image_id -> Int4,
+        ecef_x -> Nullable<Float8>,
+        ecef_y -> Nullable<Float8>,
+        ecef_z -> Nullable<Float8>,
+        latitude -> Nullable<Float8>,
+        longitude -> Nullable<Float8>,
+        height -> Nullable<Float8>,
     }
 }
 
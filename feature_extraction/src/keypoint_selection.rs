@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use opencv::{
+    core::{KeyPoint, Mat, Vector},
+    prelude::*,
+    Error,
+};
+
+use crate::ExtractedKeyPoint;
+
+/// Robustness factor used by adaptive non-maximal suppression.
+/// A keypoint is only suppressed by neighbours that are at least this much stronger.
+const ANMS_ROBUSTNESS: f32 = 0.9;
+
+/// Options for spreading keypoints uniformly over an image.
+///
+/// The image is divided into square cells of `cell_size` pixels, and only the `per_cell` keypoints with the highest response are kept in each cell.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GridSelection {
+    pub cell_size: f32,
+    pub per_cell: usize,
+}
+
+impl GridSelection {
+    pub fn new(cell_size: f32, per_cell: usize) -> GridSelection {
+        GridSelection {
+            cell_size,
+            per_cell,
+        }
+    }
+}
+
+/// Returns the indices of the points that survive grid bucketing, in ascending order.
+///
+/// `key` should return the x coordinate, y coordinate and response of a point.
+pub fn grid_bucket_indices<T>(
+    points: &[T],
+    selection: GridSelection,
+    key: impl Fn(&T) -> (f32, f32, f32),
+) -> Vec<usize> {
+    if selection.cell_size <= 0.0 {
+        return (0..points.len()).collect();
+    }
+
+    let mut cells: HashMap<(i64, i64), Vec<(usize, f32)>> = HashMap::new();
+
+    for (i, point) in points.iter().enumerate() {
+        let (x, y, response) = key(point);
+        let cell = (
+            (x / selection.cell_size).floor() as i64,
+            (y / selection.cell_size).floor() as i64,
+        );
+
+        cells.entry(cell).or_default().push((i, response));
+    }
+
+    let mut indices: Vec<usize> = cells
+        .into_values()
+        .flat_map(|mut cell| {
+            cell.sort_by(|a, b| b.1.total_cmp(&a.1));
+            cell.truncate(selection.per_cell);
+            cell.into_iter().map(|(i, _)| i)
+        })
+        .collect();
+
+    indices.sort_unstable();
+
+    indices
+}
+
+/// Keeps the strongest keypoints in each grid cell. See [`GridSelection`].
+pub fn grid_bucketing<T>(
+    points: Vec<T>,
+    selection: GridSelection,
+    key: impl Fn(&T) -> (f32, f32, f32),
+) -> Vec<T> {
+    let indices = grid_bucket_indices(&points, selection, key);
+
+    retain_indices(points, &indices)
+}
+
+/// Returns the indices of the `count` points selected by adaptive non-maximal suppression, in ascending order.
+///
+/// Every point is given a suppression radius equal to the distance to the nearest point that is sufficiently stronger,
+/// and the points with the largest radii are kept.
+/// # Notes
+/// The runtime is quadratic in the amount of points, use [`grid_bucket_indices`] for very large sets.
+pub fn anms_indices<T>(
+    points: &[T],
+    count: usize,
+    key: impl Fn(&T) -> (f32, f32, f32),
+) -> Vec<usize> {
+    if points.len() <= count {
+        return (0..points.len()).collect();
+    }
+
+    let mut sorted: Vec<(usize, (f32, f32, f32))> = points.iter().map(&key).enumerate().collect();
+    sorted.sort_by(|a, b| b.1 .2.total_cmp(&a.1 .2));
+
+    let mut radii: Vec<(usize, f32)> = Vec::with_capacity(sorted.len());
+
+    for (i, (index, (x, y, response))) in sorted.iter().enumerate() {
+        let radius = sorted[..i]
+            .iter()
+            .filter(|(_, stronger)| *response < ANMS_ROBUSTNESS * stronger.2)
+            .map(|(_, stronger)| (stronger.0 - x).powi(2) + (stronger.1 - y).powi(2))
+            .fold(f32::INFINITY, f32::min);
+
+        radii.push((*index, radius));
+    }
+
+    radii.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut indices: Vec<usize> = radii.into_iter().take(count).map(|(i, _)| i).collect();
+
+    indices.sort_unstable();
+
+    indices
+}
+
+/// Keeps the `count` points selected by adaptive non-maximal suppression. See [`anms_indices`].
+pub fn anms<T>(points: Vec<T>, count: usize, key: impl Fn(&T) -> (f32, f32, f32)) -> Vec<T> {
+    let indices = anms_indices(&points, count, key);
+
+    retain_indices(points, &indices)
+}
+
+/// Assumes that `indices` is sorted in ascending order.
+fn retain_indices<T>(points: Vec<T>, indices: &[usize]) -> Vec<T> {
+    let mut indices = indices.iter().peekable();
+
+    points
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, point)| match indices.peek() {
+            Some(&&next) if next == i => {
+                indices.next();
+                Some(point)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// The key used when selecting among opencv keypoints.
+pub fn keypoint_key(keypoint: &KeyPoint) -> (f32, f32, f32) {
+    (keypoint.pt().x, keypoint.pt().y, keypoint.response())
+}
+
+impl ExtractedKeyPoint {
+    /// Keeps the strongest keypoints in each grid cell, together with their descriptors.
+    pub fn grid_bucketing(&self, selection: GridSelection) -> Result<ExtractedKeyPoint, Error> {
+        let keypoints = self.keypoints.to_vec();
+        let indices = grid_bucket_indices(&keypoints, selection, keypoint_key);
+
+        self.select(&indices)
+    }
+
+    /// Keeps `count` keypoints chosen by adaptive non-maximal suppression, together with their descriptors.
+    pub fn anms(&self, count: usize) -> Result<ExtractedKeyPoint, Error> {
+        let keypoints = self.keypoints.to_vec();
+        let indices = anms_indices(&keypoints, count, keypoint_key);
+
+        self.select(&indices)
+    }
+
+    /// Returns a new [`ExtractedKeyPoint`] containing only the keypoints and descriptor rows at `indices`.
+    pub fn select(&self, indices: &[usize]) -> Result<ExtractedKeyPoint, Error> {
+        let mut keypoints: Vector<KeyPoint> = Vector::with_capacity(indices.len());
+        let mut rows: Vec<Vec<u8>> = Vec::with_capacity(indices.len());
+
+        for &i in indices {
+            keypoints.push(self.keypoints.get(i)?);
+            rows.push(self.descriptors.at_row::<u8>(i.try_into()?)?.to_vec());
+        }
+
+        let descriptors = match rows.is_empty() {
+            true => Mat::default(),
+            false => Mat::from_slice_2d(&rows)?,
+        };
+
+        Ok(ExtractedKeyPoint {
+            keypoints,
+            descriptors,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(point: &(f32, f32, f32)) -> (f32, f32, f32) {
+        *point
+    }
+
+    #[test]
+    fn grid_keeps_strongest_per_cell() {
+        let points = vec![
+            (1.0, 1.0, 0.1),
+            (2.0, 2.0, 0.5),
+            (3.0, 3.0, 0.3),
+            (15.0, 1.0, 0.01),
+        ];
+
+        let selected = grid_bucketing(points, GridSelection::new(10.0, 1), key);
+
+        assert_eq!(selected, vec![(2.0, 2.0, 0.5), (15.0, 1.0, 0.01)]);
+    }
+
+    #[test]
+    fn grid_keeps_budget_per_cell() {
+        let points: Vec<(f32, f32, f32)> = (0..100)
+            .map(|i| ((i % 10) as f32, (i / 10) as f32, i as f32))
+            .collect();
+
+        let indices = grid_bucket_indices(&points, GridSelection::new(5.0, 3), key);
+
+        assert_eq!(indices.len(), 4 * 3);
+        assert!(indices.contains(&99));
+    }
+
+    #[test]
+    fn anms_spreads_points() {
+        // A strong cluster in one corner and a weak point far away.
+        let points = vec![
+            (0.0, 0.0, 1.0),
+            (0.5, 0.0, 0.5),
+            (0.0, 0.5, 0.4),
+            (100.0, 100.0, 0.1),
+        ];
+
+        let selected = anms(points, 2, key);
+
+        assert_eq!(selected, vec![(0.0, 0.0, 1.0), (100.0, 100.0, 0.1)]);
+    }
+
+    #[test]
+    fn anms_keeps_all_under_budget() {
+        let points = vec![(0.0, 0.0, 1.0), (0.5, 0.0, 0.9)];
+
+        assert_eq!(anms_indices(&points, 5, key), vec![0, 1]);
+    }
+}
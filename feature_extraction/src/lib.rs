@@ -9,6 +9,9 @@ use opencv::core::Ptr;
 
 use opencv::{self as cv, prelude::*};
 
+pub mod keypoint_selection;
+pub mod normalization;
+
 pub const MAX_POINTS_SHIFT: i32 = 18;
 pub const MAX_POINTS: i32 = (1 << MAX_POINTS_SHIFT) - 1;
     
@@ -31,6 +34,21 @@ pub struct DbKeypoints {
 }
 
 impl ExtractedKeyPoint {
+    pub fn new(keypoints: Vector<KeyPoint>, descriptors: Mat) -> ExtractedKeyPoint {
+        ExtractedKeyPoint {
+            keypoints,
+            descriptors,
+        }
+    }
+
+    pub fn keypoints(&self) -> &Vector<KeyPoint> {
+        &self.keypoints
+    }
+
+    pub fn descriptors(&self) -> &Mat {
+        &self.descriptors
+    }
+
     pub fn to_db_type(&self, image_id: i32) -> Vec<DbKeypoints> {
         let keypoints = self.keypoints.to_vec();
 
@@ -59,6 +77,12 @@ impl ExtractedKeyPoint {
 }
 
 pub fn akaze_keypoint_descriptor_extraction_def(img: &Mat, max_points: Option<i32>) -> Result<ExtractedKeyPoint, Error> {
+    akaze_keypoint_descriptor_extraction(img, &Mat::default(), max_points)
+}
+
+/// Extracts keypoints and descriptors, only in the areas of the image where `mask` is non-zero.
+/// The mask must be an 8-bit single channel image with the same size as `img`, or empty to extract from the whole image.
+pub fn akaze_keypoint_descriptor_extraction(img: &Mat, mask: &Mat, max_points: Option<i32>) -> Result<ExtractedKeyPoint, Error> {
     //let img: Mat = cv::imgcodecs::imread(file_location, cv::imgcodecs::IMREAD_COLOR).unwrap();
 
     let mut akaze: Ptr<AKAZE> = <AKAZE>::create(
@@ -74,9 +98,8 @@ pub fn akaze_keypoint_descriptor_extraction_def(img: &Mat, max_points: Option<i3
 
     let mut akaze_keypoints = Vector::default();
     let mut akaze_desc: Mat = Mat::default();
-    let mask = Mat::default();
 
-    akaze.detect_and_compute(&img, &mask, &mut akaze_keypoints, &mut akaze_desc, false)?;
+    akaze.detect_and_compute(&img, mask, &mut akaze_keypoints, &mut akaze_desc, false)?;
     // cv::features2d::draw_keypoints(
     //     &img,
     //     &akaze_keypoints,
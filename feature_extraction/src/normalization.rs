@@ -0,0 +1,305 @@
+use opencv::{
+    core::{self, Mat, Size, StsBadArg, StsUnsupportedFormat, Vector, CV_16U, CV_8U},
+    imgproc::{self, COLOR_BGR2GRAY, COLOR_BGRA2GRAY},
+    prelude::*,
+    Error,
+};
+
+const HISTOGRAM_BINS: usize = 256;
+
+/// The radiometric normalization applied to an image before feature extraction.
+/// The same normalization should be used for reference tiles and query images, otherwise their descriptors are not comparable.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Normalization {
+    #[default]
+    None,
+    /// Stretches each channel between the `lower` and `upper` percentile (0-100), values outside are clipped.
+    PercentileClip { lower: f64, upper: f64 },
+    /// Contrast limited adaptive histogram equalization.
+    Clahe {
+        clip_limit: f64,
+        tile_grid_size: i32,
+    },
+    /// Matches the histogram of each channel to a reference histogram, see [`reference_histogram`].
+    HistogramMatching(Vec<f64>),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct NormalizationOptions {
+    pub method: Normalization,
+    /// Converts the image to a single channel before normalizing it.
+    pub grayscale: bool,
+}
+
+impl NormalizationOptions {
+    /// Checks that the normalization supports images with the element depth `depth`, such as `CV_16U`.
+    /// Used to reject a combination before any image is read.
+    ///
+    /// ## Errors
+    /// If the normalization does not support the depth, see [`normalize_image`].
+    pub fn check_depth(&self, depth: i32) -> Result<(), Error> {
+        match supports_depth(&self.method, depth) {
+            true => Ok(()),
+            false => Err(Error::new(
+                StsUnsupportedFormat,
+                format!(
+                    "{:?} normalization does not support images of depth {depth}",
+                    self.method
+                ),
+            )),
+        }
+    }
+}
+
+/// Normalizes an image with 1, 3 or 4 channels.
+///
+/// ## Notes
+/// The alpha channel of a 4 channel image is left untouched.
+/// Percentile clipping and histogram matching require an 8-bit image, CLAHE also supports 16-bit images.
+/// Grayscale conversion supports any depth.
+pub fn normalize_image(img: &Mat, options: &NormalizationOptions) -> Result<Mat, Error> {
+    let img = match options.grayscale {
+        true => to_grayscale(img)?,
+        false => img.try_clone()?,
+    };
+
+    if options.method == Normalization::None {
+        return Ok(img);
+    }
+
+    let mut channels: Vector<Mat> = Vector::new();
+    core::split(&img, &mut channels)?;
+
+    let mut normalized: Vector<Mat> = Vector::with_capacity(channels.len());
+
+    for (i, channel) in channels.iter().enumerate() {
+        match i {
+            0..=2 => normalized.push(normalize_channel(&channel, &options.method)?),
+            _ => normalized.push(channel),
+        }
+    }
+
+    let mut out = Mat::default();
+    core::merge(&normalized, &mut out)?;
+
+    Ok(out)
+}
+
+/// Converts a BGR or BGRA image to a single channel image.
+pub fn to_grayscale(img: &Mat) -> Result<Mat, Error> {
+    let code = match img.channels() {
+        1 => return img.try_clone(),
+        3 => COLOR_BGR2GRAY,
+        4 => COLOR_BGRA2GRAY,
+        _ => return Err(Error::new(StsBadArg, "Image must have 1, 3 or 4 channels")),
+    };
+
+    let mut gray = Mat::default();
+    imgproc::cvt_color(img, &mut gray, code, 0)?;
+
+    Ok(gray)
+}
+
+/// Computes the grayscale histogram of an image, used as the target for [`Normalization::HistogramMatching`].
+pub fn reference_histogram(img: &Mat) -> Result<Vec<f64>, Error> {
+    histogram(&to_grayscale(img)?)
+}
+
+fn normalize_channel(channel: &Mat, method: &Normalization) -> Result<Mat, Error> {
+    let mut out = Mat::default();
+
+    if !supports_depth(method, channel.depth()) {
+        return Err(Error::new(
+            StsUnsupportedFormat,
+            "The normalization does not support the depth of the image",
+        ));
+    }
+
+    match method {
+        Normalization::None => return channel.try_clone(),
+        Normalization::PercentileClip { lower, upper } => {
+            let lut = percentile_lut(&histogram(channel)?, *lower, *upper);
+            core::lut(channel, &Vector::from_slice(&lut), &mut out)?;
+        }
+        Normalization::Clahe {
+            clip_limit,
+            tile_grid_size,
+        } => {
+            let mut clahe =
+                imgproc::create_clahe(*clip_limit, Size::new(*tile_grid_size, *tile_grid_size))?;
+            clahe.apply(channel, &mut out)?;
+        }
+        Normalization::HistogramMatching(reference) => {
+            let lut = matching_lut(&histogram(channel)?, reference);
+            core::lut(channel, &Vector::from_slice(&lut), &mut out)?;
+        }
+    }
+
+    Ok(out)
+}
+
+/// The histograms of percentile clipping and histogram matching have a bin per 8-bit value.
+fn supports_depth(method: &Normalization, depth: i32) -> bool {
+    match method {
+        Normalization::None => true,
+        Normalization::Clahe { .. } => [CV_8U, CV_16U].contains(&depth),
+        Normalization::PercentileClip { .. } | Normalization::HistogramMatching(_) => {
+            depth == CV_8U
+        }
+    }
+}
+
+/// Assumes a single channel 8-bit image.
+fn histogram(channel: &Mat) -> Result<Vec<f64>, Error> {
+    let mut histogram = vec![0f64; HISTOGRAM_BINS];
+
+    for row in 0..channel.rows() {
+        for value in channel.at_row::<u8>(row)? {
+            histogram[*value as usize] += 1.0;
+        }
+    }
+
+    Ok(histogram)
+}
+
+/// Returns the normalized cumulative distribution of a histogram.
+fn cumulative_distribution(histogram: &[f64]) -> Vec<f64> {
+    let total: f64 = histogram.iter().sum();
+
+    histogram
+        .iter()
+        .scan(0f64, |acc, count| {
+            *acc += count;
+            Some(match total > 0.0 {
+                true => *acc / total,
+                false => 0.0,
+            })
+        })
+        .collect()
+}
+
+fn percentile_lut(histogram: &[f64], lower: f64, upper: f64) -> Vec<u8> {
+    let cdf = cumulative_distribution(histogram);
+
+    let find = |percentile: f64| {
+        cdf.iter()
+            .position(|&p| p >= percentile / 100.0)
+            .unwrap_or(HISTOGRAM_BINS - 1) as f64
+    };
+
+    let low = find(lower);
+    let high = find(upper);
+
+    if high <= low {
+        return (0..HISTOGRAM_BINS).map(|v| v as u8).collect();
+    }
+
+    (0..HISTOGRAM_BINS)
+        .map(|v| (((v as f64 - low) / (high - low)).clamp(0.0, 1.0) * u8::MAX as f64).round() as u8)
+        .collect()
+}
+
+fn matching_lut(source: &[f64], reference: &[f64]) -> Vec<u8> {
+    let source_cdf = cumulative_distribution(source);
+    let reference_cdf = cumulative_distribution(reference);
+
+    source_cdf
+        .iter()
+        .map(|&p| {
+            reference_cdf
+                .iter()
+                .position(|&r| r >= p)
+                .unwrap_or(HISTOGRAM_BINS - 1) as u8
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_lut_clips_outliers() {
+        let mut histogram = vec![0f64; HISTOGRAM_BINS];
+        histogram[50] = 1.0;
+        histogram[100] = 98.0;
+        histogram[150] = 1.0;
+        histogram[255] = 1.0;
+
+        let lut = percentile_lut(&histogram, 0.5, 99.0);
+
+        assert_eq!(lut[50], 0);
+        assert_eq!(lut[150], 255);
+        assert_eq!(lut[255], 255);
+    }
+
+    #[test]
+    fn percentile_lut_identity_on_flat_image() {
+        let mut histogram = vec![0f64; HISTOGRAM_BINS];
+        histogram[100] = 10.0;
+
+        let lut = percentile_lut(&histogram, 2.0, 98.0);
+
+        assert_eq!(lut[100], 100);
+    }
+
+    #[test]
+    fn matching_lut_identical_histograms() {
+        let histogram: Vec<f64> = (0..HISTOGRAM_BINS).map(|_| 1.0).collect();
+
+        let lut = matching_lut(&histogram, &histogram);
+
+        assert!(lut.iter().enumerate().all(|(i, v)| i == *v as usize));
+    }
+
+    #[test]
+    fn percentile_clip_rejects_16_bit() {
+        let img = Mat::new_rows_cols_with_default(4, 4, core::CV_16UC1, core::Scalar::all(10.0))
+            .expect("Could not create image");
+        let options = NormalizationOptions {
+            method: Normalization::PercentileClip {
+                lower: 2.0,
+                upper: 98.0,
+            },
+            grayscale: false,
+        };
+
+        assert!(normalize_image(&img, &options).is_err());
+    }
+
+    #[test]
+    fn depth_is_checked_up_front() {
+        let percentile = NormalizationOptions {
+            method: Normalization::PercentileClip {
+                lower: 2.0,
+                upper: 98.0,
+            },
+            grayscale: false,
+        };
+        let clahe = NormalizationOptions {
+            method: Normalization::Clahe {
+                clip_limit: 2.0,
+                tile_grid_size: 8,
+            },
+            grayscale: false,
+        };
+
+        assert!(percentile.check_depth(CV_8U).is_ok());
+        assert!(percentile.check_depth(CV_16U).is_err());
+        assert!(clahe.check_depth(CV_16U).is_ok());
+        assert!(clahe.check_depth(core::CV_32F).is_err());
+        assert!(NormalizationOptions::default()
+            .check_depth(core::CV_32F)
+            .is_ok());
+    }
+
+    #[test]
+    fn grayscale_single_channel() {
+        let img = Mat::new_rows_cols_with_default(4, 4, core::CV_8UC4, core::Scalar::all(10.0))
+            .expect("Could not create image");
+
+        let gray = to_grayscale(&img).expect("Could not convert image");
+
+        assert_eq!(gray.channels(), 1);
+    }
+}
@@ -0,0 +1,662 @@
+use crate::geodesy::{self, HeightReference, OutputFrame, GEOGRAPHIC};
+use gdal::errors::GdalError;
+use gdal::raster::Buffer;
+use gdal::spatial_ref::SpatialRef;
+use gdal::{Dataset, DriverManager, GeoTransform, GeoTransformEx};
+use gdal_sys::{CPLErr, GDALResampleAlg};
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
+
+/// The nodata value of warped elevation datasets
+pub const NO_DATA: f64 = -32768.0;
+/// The side length in elevation pixels of the blocks the coverage is reported in, and the amount of rows read at a time
+const COVERAGE_BLOCK_SIZE: usize = 64;
+
+/// How the elevation is resampled when it is warped onto the grid of the mosaic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ElevationResampling {
+    Nearest,
+    #[default]
+    Bilinear,
+    Cubic,
+}
+
+impl ElevationResampling {
+    fn to_gdal(self) -> GDALResampleAlg::Type {
+        match self {
+            ElevationResampling::Nearest => GDALResampleAlg::GRA_NearestNeighbour,
+            ElevationResampling::Bilinear => GDALResampleAlg::GRA_Bilinear,
+            ElevationResampling::Cubic => GDALResampleAlg::GRA_Cubic,
+        }
+    }
+}
+
+/// The vertical datum heights of elevation data are measured in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerticalDatum {
+    /// Heights measured from a surface PROJ knows
+    Reference(HeightReference),
+    /// Orthometric heights above a national geoid, such as DVR90 of the Danish DHM.
+    /// The grid is a raster of the heights of the geoid above the ellipsoid, e.g. `dk_sdfe_dvr90.tif` from PROJ-data
+    GeoidGrid(PathBuf),
+}
+
+impl VerticalDatum {
+    /// The name the datum is stored with in the database. Geoid grids are named after their file.
+    pub fn name(&self) -> String {
+        match self {
+            VerticalDatum::Reference(heights) => heights.name().to_string(),
+            VerticalDatum::GeoidGrid(path) => format!(
+                "geoid:{}",
+                path.file_name()
+                    .map(|name| name.to_string_lossy())
+                    .unwrap_or_default()
+            ),
+        }
+    }
+}
+
+/// Options for warping elevation data onto the grid of a mosaic.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ElevationOptions {
+    /// The size of an elevation pixel in mosaic pixels. 1.0 warps the elevation onto the grid of the mosaic,
+    /// larger values onto a coarser grid aligned with it, which is usually enough since DEMs are far coarser than imagery.
+    /// Must be positive and finite
+    pub scale: f64,
+    pub resampling: ElevationResampling,
+    /// The vertical datum of the elevation data. If set, the heights are converted to heights above the ellipsoid
+    /// while warping, otherwise they are kept as they are
+    pub vertical_datum: Option<VerticalDatum>,
+}
+
+impl Default for ElevationOptions {
+    fn default() -> Self {
+        ElevationOptions {
+            scale: 8.0,
+            resampling: ElevationResampling::default(),
+            vertical_datum: None,
+        }
+    }
+}
+
+/// A part of the mosaic with missing elevation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoverageGap {
+    /// The top left full resolution mosaic pixel
+    pub window: (isize, isize),
+    /// The size in full resolution mosaic pixels
+    pub window_size: (usize, usize),
+    /// The fraction of the window without elevation
+    pub missing: f64,
+}
+
+/// How much of the mosaic has elevation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ElevationCoverage {
+    /// The fraction of the mosaic with elevation
+    pub covered: f64,
+    /// The parts of the mosaic with missing elevation
+    pub gaps: Vec<CoverageGap>,
+}
+
+/// Warps `source` into the CRS of `mosaic`, on a grid aligned with the mosaic where every pixel is `options.scale` mosaic pixels.
+/// The result is written to a GeoTIFF at `path`.
+///
+/// ## Errors
+/// [`GdalError::BadArgument`] if `options.scale` is not positive and finite, or the mosaic has no geotransform.
+/// Otherwise if `source` has no spatial reference, or the warp fails.
+/// ## Notes
+/// Pixels of the mosaic without elevation, or where `source` is nodata, are set to [`NO_DATA`].
+/// A mosaic without a spatial reference is assumed to be geographic, see [`geodesy::dataset_spatial_ref`].
+/// The heights are converted with [`to_ellipsoidal_heights`] if `options.vertical_datum` is set.
+pub fn warp_elevation(
+    source: &Dataset,
+    mosaic: &Dataset,
+    path: &Path,
+    options: &ElevationOptions,
+) -> Result<Dataset, GdalError> {
+    let scale = check_scale(options.scale)?;
+
+    let transform = mosaic.geo_transform().map_err(|_| {
+        GdalError::BadArgument(
+            "The mosaic has no geotransform, which is needed to place the elevation".to_string(),
+        )
+    })?;
+    let spatial_ref = SpatialRef::from_definition(&geodesy::dataset_spatial_ref(mosaic))?;
+    let (width, height) = mosaic.raster_size();
+
+    let warped_transform: GeoTransform = [
+        transform[0],
+        transform[1] * scale,
+        transform[2] * scale,
+        transform[3],
+        transform[4] * scale,
+        transform[5] * scale,
+    ];
+    let size = (
+        ((width as f64 / scale).ceil() as isize).max(1),
+        ((height as f64 / scale).ceil() as isize).max(1),
+    );
+
+    let driver = DriverManager::get_driver_by_name("GTiff")?;
+    let mut warped = driver.create_with_band_type::<f32, _>(path, size.0, size.1, 1)?;
+    warped.set_geo_transform(&warped_transform)?;
+    warped.set_spatial_ref(&spatial_ref)?;
+
+    {
+        let mut band = warped.rasterband(1)?;
+        band.set_no_data_value(Some(NO_DATA))?;
+        band.fill(NO_DATA, None)?;
+    }
+
+    // GDALReprojectImage honours the nodata values of both bands, and leaves the rest of the output untouched.
+    let result = unsafe {
+        gdal_sys::GDALReprojectImage(
+            source.c_dataset(),
+            std::ptr::null(),
+            warped.c_dataset(),
+            std::ptr::null(),
+            options.resampling.to_gdal(),
+            0.0,
+            0.0,
+            None,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+
+    if result != CPLErr::CE_None {
+        let msg = unsafe { CStr::from_ptr(gdal_sys::CPLGetLastErrorMsg()) }
+            .to_string_lossy()
+            .into_owned();
+
+        return Err(GdalError::CplError {
+            class: result,
+            number: unsafe { gdal_sys::CPLGetLastErrorNo() },
+            msg,
+        });
+    }
+
+    if let Some(datum) = &options.vertical_datum {
+        to_ellipsoidal_heights(&warped, datum)?;
+    }
+
+    Ok(warped)
+}
+
+/// Returns `scale` if it can be used as [`ElevationOptions::scale`], otherwise [`GdalError::BadArgument`].
+pub fn check_scale(scale: f64) -> Result<f64, GdalError> {
+    if scale <= 0.0 || !scale.is_finite() {
+        return Err(GdalError::BadArgument(format!(
+            "The elevation scale must be positive and finite, got {scale}"
+        )));
+    }
+
+    Ok(scale)
+}
+
+/// Converts the heights of the first band of `elevation` in place from `datum` to heights above the WGS84 ellipsoid.
+///
+/// ## Errors
+/// If the geoid grid can not be opened, or the heights can not be transformed.
+/// ## Notes
+/// Pixels outside of a geoid grid are set to nodata. Geoid grids are sampled bilinearly in their own CRS,
+/// so grids with longitudes from 0° to 360° only work east of Greenwich.
+pub fn to_ellipsoidal_heights(elevation: &Dataset, datum: &VerticalDatum) -> Result<(), GdalError> {
+    let transform = elevation.geo_transform()?;
+    let spatial_ref = geodesy::dataset_spatial_ref(elevation);
+    let conversion = match datum {
+        VerticalDatum::Reference(reference) => Conversion::Reference(*reference),
+        VerticalDatum::GeoidGrid(path) => Conversion::Geoid(GeoidGrid::open(path)?),
+    };
+
+    let mut band = elevation.rasterband(1)?;
+    let (width, height) = band.size();
+    // Pixels outside of a geoid grid become nodata, so the band needs a nodata value.
+    let no_data = match band.no_data_value() {
+        Some(no_data) => no_data,
+        None => {
+            band.set_no_data_value(Some(NO_DATA))?;
+            NO_DATA
+        }
+    };
+
+    // The elevation is converted a row at a time, so only a strip of the geoid grid is in memory.
+    for row in 0..height {
+        let mut heights = band
+            .read_as::<f64>((0, row as isize), (width, 1), (width, 1), None)?
+            .data;
+        let valid: Vec<bool> = heights
+            .iter()
+            .map(|height| is_valid(*height, Some(no_data)))
+            .collect();
+
+        let (mut x, mut y): (Vec<f64>, Vec<f64>) = (0..width)
+            .map(|column| transform.apply(column as f64 + 0.5, row as f64 + 0.5))
+            .unzip();
+
+        match &conversion {
+            Conversion::Reference(reference) => {
+                geodesy::convert(
+                    &spatial_ref,
+                    *reference,
+                    OutputFrame::Geodetic,
+                    &mut x,
+                    &mut y,
+                    &mut heights,
+                )?;
+            }
+            Conversion::Geoid(geoid) => {
+                let undulations = geoid.undulations(&spatial_ref, &mut x, &mut y)?;
+
+                for (height, undulation) in heights.iter_mut().zip(undulations) {
+                    *height = undulation.map_or(no_data, |undulation| *height + undulation);
+                }
+            }
+        }
+
+        for (height, valid) in heights.iter_mut().zip(valid) {
+            if !valid {
+                *height = no_data;
+            }
+        }
+
+        band.write(
+            (0, row as isize),
+            (width, 1),
+            &Buffer::new((width, 1), heights),
+        )?;
+    }
+
+    Ok(())
+}
+
+enum Conversion {
+    Reference(HeightReference),
+    Geoid(GeoidGrid),
+}
+
+/// A raster of the heights of a geoid above the ellipsoid.
+struct GeoidGrid {
+    dataset: Dataset,
+    /// Grids without a CRS are assumed to be geographic
+    spatial_ref: String,
+    inverse: GeoTransform,
+}
+
+impl GeoidGrid {
+    fn open(path: &Path) -> Result<GeoidGrid, GdalError> {
+        let dataset = Dataset::open(path)?;
+        let spatial_ref = dataset
+            .spatial_ref()
+            .and_then(|spatial_ref| spatial_ref.to_wkt())
+            .unwrap_or_else(|_| GEOGRAPHIC.to_string());
+        let inverse = dataset.geo_transform()?.invert()?;
+
+        Ok(GeoidGrid {
+            dataset,
+            spatial_ref,
+            inverse,
+        })
+    }
+
+    /// The bilinearly interpolated geoid heights at points in `spatial_ref`, or [`None`] outside of the grid.
+    /// The points are transformed in place to the CRS of the grid.
+    fn undulations(
+        &self,
+        spatial_ref: &str,
+        x: &mut [f64],
+        y: &mut [f64],
+    ) -> Result<Vec<Option<f64>>, GdalError> {
+        geodesy::transform_points(spatial_ref, &self.spatial_ref, x, y)?;
+
+        let band = self.dataset.rasterband(1)?;
+        let (width, height) = band.size();
+
+        let samples: Vec<Option<BilinearSample>> = x
+            .iter()
+            .zip(y.iter())
+            .map(|(x, y)| {
+                let (x, y) = self.inverse.apply(*x, *y);
+                BilinearSample::new(x, y, width, height)
+            })
+            .collect();
+
+        // Only the window around the samples is read.
+        let (start, end) = samples.iter().flatten().fold(
+            ((usize::MAX, usize::MAX), (0, 0)),
+            |(start, end), sample| {
+                let (first, last) = (sample.pixels[0], sample.pixels[3]);
+                (
+                    (start.0.min(first.0), start.1.min(first.1)),
+                    (end.0.max(last.0), end.1.max(last.1)),
+                )
+            },
+        );
+
+        if start.0 > end.0 || start.1 > end.1 {
+            return Ok(vec![None; samples.len()]);
+        }
+
+        let size = (end.0 - start.0 + 1, end.1 - start.1 + 1);
+        let window = band
+            .read_as::<f64>((start.0 as isize, start.1 as isize), size, size, None)?
+            .data;
+        let no_data = band.no_data_value();
+
+        Ok(samples
+            .iter()
+            .map(|sample| {
+                sample.and_then(|sample| {
+                    let heights = sample
+                        .pixels
+                        .map(|(x, y)| window[(y - start.1) * size.0 + (x - start.0)]);
+
+                    sample.interpolate(heights, no_data)
+                })
+            })
+            .collect())
+    }
+}
+
+/// Reports the parts of the mosaic without elevation, for an elevation dataset made by [`warp_elevation`] with `scale`.
+/// The heights are read a row of blocks at a time, so large elevation datasets are never fully in memory.
+pub fn elevation_coverage(elevation: &Dataset, scale: f64) -> Result<ElevationCoverage, GdalError> {
+    let band = elevation.rasterband(1)?;
+    let (width, height) = band.size();
+
+    coverage_from_strips(width, height, band.no_data_value(), scale, |y, rows| {
+        Ok(band
+            .read_as::<f64>((0, y as isize), (width, rows), (width, rows), None)?
+            .data)
+    })
+}
+
+/// Finds the coverage from strips of [`COVERAGE_BLOCK_SIZE`] rows, where `read_strip` returns `rows` rows from row `y`.
+fn coverage_from_strips(
+    width: usize,
+    height: usize,
+    no_data: Option<f64>,
+    scale: f64,
+    mut read_strip: impl FnMut(usize, usize) -> Result<Vec<f64>, GdalError>,
+) -> Result<ElevationCoverage, GdalError> {
+    let valid = |height: f64| is_valid(height, no_data);
+    let mut gaps = Vec::new();
+    let mut covered = 0;
+
+    for block_y in (0..height).step_by(COVERAGE_BLOCK_SIZE) {
+        let block_height = COVERAGE_BLOCK_SIZE.min(height - block_y);
+        let heights = read_strip(block_y, block_height)?;
+
+        covered += heights.iter().filter(|height| valid(**height)).count();
+
+        for block_x in (0..width).step_by(COVERAGE_BLOCK_SIZE) {
+            let block_width = COVERAGE_BLOCK_SIZE.min(width - block_x);
+
+            let missing = (0..block_height)
+                .flat_map(|y| (block_x..block_x + block_width).map(move |x| y * width + x))
+                .filter(|&i| !valid(heights[i]))
+                .count();
+
+            if missing > 0 {
+                gaps.push(CoverageGap {
+                    window: (
+                        (block_x as f64 * scale) as isize,
+                        (block_y as f64 * scale) as isize,
+                    ),
+                    window_size: (
+                        (block_width as f64 * scale).ceil() as usize,
+                        (block_height as f64 * scale).ceil() as usize,
+                    ),
+                    missing: missing as f64 / (block_width * block_height) as f64,
+                });
+            }
+        }
+    }
+
+    let covered = match width * height {
+        0 => 0.0,
+        total => covered as f64 / total as f64,
+    };
+
+    Ok(ElevationCoverage { covered, gaps })
+}
+
+fn is_valid(height: f64, no_data: Option<f64>) -> bool {
+    !height.is_nan() && Some(height) != no_data
+}
+
+/// The four pixels around a position in a raster, and their bilinear weights.
+/// Heights are sampled at the center of the pixels, like GDAL.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BilinearSample {
+    /// The top left, top right, bottom left and bottom right pixel, clamped to the raster
+    pub pixels: [(usize, usize); 4],
+    pub weights: [f64; 4],
+}
+
+impl BilinearSample {
+    /// The sample at pixel position `(x, y)` of a `width` × `height` raster, or [`None`] outside of it.
+    pub fn new(x: f64, y: f64, width: usize, height: usize) -> Option<BilinearSample> {
+        if width == 0 || height == 0 || x < 0.0 || y < 0.0 || x > width as f64 || y > height as f64
+        {
+            return None;
+        }
+
+        let (x, y) = (x - 0.5, y - 0.5);
+        let x0 = x.floor().clamp(0.0, (width - 1) as f64);
+        let y0 = y.floor().clamp(0.0, (height - 1) as f64);
+        let (fx, fy) = ((x - x0).clamp(0.0, 1.0), (y - y0).clamp(0.0, 1.0));
+
+        let (x0, y0) = (x0 as usize, y0 as usize);
+        let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+
+        Some(BilinearSample {
+            pixels: [(x0, y0), (x1, y0), (x0, y1), (x1, y1)],
+            weights: [
+                (1.0 - fx) * (1.0 - fy),
+                fx * (1.0 - fy),
+                (1.0 - fx) * fy,
+                fx * fy,
+            ],
+        })
+    }
+
+    /// Interpolates the heights of [`BilinearSample::pixels`], leaving out nodata heights.
+    /// Returns [`None`] if every pixel with weight is nodata.
+    pub fn interpolate(&self, heights: [f64; 4], no_data: Option<f64>) -> Option<f64> {
+        let (sum, weight) = heights
+            .iter()
+            .zip(self.weights)
+            .filter(|(height, weight)| *weight > 0.0 && is_valid(**height, no_data))
+            .fold((0.0, 0.0), |(sum, total), (height, weight)| {
+                (sum + height * weight, total + weight)
+            });
+
+        match weight > 0.0 {
+            true => Some(sum / weight),
+            false => None,
+        }
+    }
+}
+
+/// Samples the first band of `dataset` bilinearly at pixel position `(x, y)`.
+/// Returns [`None`] outside of the dataset or where it is nodata.
+pub fn sample_elevation(dataset: &Dataset, x: f64, y: f64) -> Result<Option<f64>, GdalError> {
+    let band = dataset.rasterband(1)?;
+    let (width, height) = band.size();
+
+    let sample = match BilinearSample::new(x, y, width, height) {
+        Some(sample) => sample,
+        None => return Ok(None),
+    };
+
+    let (x0, y0) = sample.pixels[0];
+    let (x1, y1) = sample.pixels[3];
+    let size = (x1 - x0 + 1, y1 - y0 + 1);
+    let window = band
+        .read_as::<f64>((x0 as isize, y0 as isize), size, size, None)?
+        .data;
+
+    let heights = sample
+        .pixels
+        .map(|(x, y)| window[(y - y0) * size.0 + (x - x0)]);
+
+    Ok(sample.interpolate(heights, band.no_data_value()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bilinear_at_pixel_centers_is_exact() {
+        let sample = BilinearSample::new(1.5, 0.5, 3, 2).unwrap();
+        assert_eq!(sample.pixels[0], (1, 0));
+        assert_eq!(
+            sample.interpolate([10.0, 20.0, 30.0, 40.0], None),
+            Some(10.0)
+        );
+
+        let sample = BilinearSample::new(2.0, 1.0, 3, 2).unwrap();
+        assert_eq!(
+            sample.interpolate([10.0, 20.0, 30.0, 40.0], None),
+            Some(25.0)
+        );
+    }
+
+    #[test]
+    fn bilinear_skips_no_data() {
+        let sample = BilinearSample::new(2.0, 1.0, 3, 2).unwrap();
+
+        assert_eq!(
+            sample.interpolate([10.0, NO_DATA, 10.0, 10.0], Some(NO_DATA)),
+            Some(10.0)
+        );
+        assert_eq!(
+            sample.interpolate([f64::NAN, 20.0, 20.0, 20.0], None),
+            Some(20.0)
+        );
+        assert_eq!(sample.interpolate([NO_DATA; 4], Some(NO_DATA)), None);
+    }
+
+    #[test]
+    fn bilinear_outside_raster() {
+        assert!(BilinearSample::new(-0.1, 1.0, 3, 2).is_none());
+        assert!(BilinearSample::new(1.0, 2.1, 3, 2).is_none());
+
+        // The outer half pixels are clamped to the edge.
+        let sample = BilinearSample::new(0.1, 0.1, 3, 2).unwrap();
+        assert_eq!(sample.interpolate([5.0, 6.0, 7.0, 8.0], None), Some(5.0));
+    }
+
+    #[test]
+    fn geoid_grid_raises_heights() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let geoid_path = temp_dir.path().join("geoid.tif");
+        let wgs84 = gdal::spatial_ref::SpatialRef::from_epsg(4326).unwrap();
+
+        // A constant geoid 40 m above the ellipsoid, covering the elevation.
+        let mut geoid = DriverManager::get_driver_by_name("GTiff")
+            .unwrap()
+            .create_with_band_type::<f32, _>(&geoid_path, 4, 4, 1)
+            .unwrap();
+        geoid
+            .set_geo_transform(&[9.0, 0.5, 0.0, 57.0, 0.0, -0.5])
+            .unwrap();
+        geoid.set_spatial_ref(&wgs84).unwrap();
+        geoid.rasterband(1).unwrap().fill(40.0, None).unwrap();
+        drop(geoid);
+
+        let mut elevation = DriverManager::get_driver_by_name("MEM")
+            .unwrap()
+            .create_with_band_type::<f64, _>("", 3, 1, 1)
+            .unwrap();
+        elevation
+            .set_geo_transform(&[9.5, 0.1, 0.0, 56.5, 0.0, -0.1])
+            .unwrap();
+        elevation.set_spatial_ref(&wgs84).unwrap();
+        let mut band = elevation.rasterband(1).unwrap();
+        band.set_no_data_value(Some(NO_DATA)).unwrap();
+        band.write(
+            (0, 0),
+            (3, 1),
+            &Buffer::new((3, 1), vec![10.0, NO_DATA, 147.0]),
+        )
+        .unwrap();
+
+        to_ellipsoidal_heights(&elevation, &VerticalDatum::GeoidGrid(geoid_path)).unwrap();
+
+        let heights = band
+            .read_as::<f64>((0, 0), (3, 1), (3, 1), None)
+            .unwrap()
+            .data;
+        assert_eq!(heights, vec![50.0, NO_DATA, 187.0]);
+    }
+
+    #[test]
+    fn vertical_datum_names() {
+        assert_eq!(
+            VerticalDatum::Reference(HeightReference::Egm2008).name(),
+            "egm2008"
+        );
+        assert_eq!(
+            VerticalDatum::GeoidGrid(PathBuf::from("/usr/share/proj/dk_sdfe_dvr90.tif")).name(),
+            "geoid:dk_sdfe_dvr90.tif"
+        );
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected_before_warping() {
+        let driver = DriverManager::get_driver_by_name("MEM").unwrap();
+        let dataset = driver.create("", 1, 1, 1).unwrap();
+        let path = Path::new("/nonexistent/elevation.tif");
+
+        for scale in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let options = ElevationOptions {
+                scale,
+                ..Default::default()
+            };
+
+            assert!(matches!(
+                warp_elevation(&dataset, &dataset, path, &options),
+                Err(GdalError::BadArgument(_))
+            ));
+        }
+
+        // The mosaic has no geotransform.
+        assert!(matches!(
+            warp_elevation(&dataset, &dataset, path, &ElevationOptions::default()),
+            Err(GdalError::BadArgument(_))
+        ));
+    }
+
+    #[test]
+    fn coverage_reports_gaps() {
+        let (width, height) = (100, 70);
+        let mut heights = vec![10.0; width * height];
+
+        // The right half of the last block row is missing.
+        for y in 64..70 {
+            for x in 50..100 {
+                heights[y * width + x] = NO_DATA;
+            }
+        }
+
+        let mut strips = Vec::new();
+        let coverage = coverage_from_strips(width, height, Some(NO_DATA), 4.0, |y, rows| {
+            strips.push((y, rows));
+            Ok(heights[y * width..(y + rows) * width].to_vec())
+        })
+        .unwrap();
+
+        // The heights are read a row of blocks at a time.
+        assert_eq!(strips, vec![(0, 64), (64, 6)]);
+        assert!((coverage.covered - (1.0 - 6.0 * 50.0 / 7000.0)).abs() < 1e-12);
+        assert_eq!(coverage.gaps.len(), 2);
+        assert_eq!(coverage.gaps[0].window, (0, 256));
+        assert_eq!(coverage.gaps[1].window, (256, 256));
+        assert_eq!(coverage.gaps[1].window_size, (144, 24));
+        assert!((coverage.gaps[1].missing - 1.0).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,377 @@
+use gdal::errors::GdalError;
+use gdal::spatial_ref::{CoordTransform, SpatialRef};
+use gdal::Dataset;
+use gdal_sys::OSRAxisMappingStrategy::OAMS_TRADITIONAL_GIS_ORDER;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// The CRS of datasets without one, which were always geographic.
+pub const DEFAULT_SPATIAL_REF: &str = "EPSG:4326";
+/// WGS84 longitude and latitude in degrees.
+pub const GEOGRAPHIC: &str = "EPSG:4326";
+/// WGS84 earth centered, earth fixed coordinates in meters.
+pub const ECEF: &str = "EPSG:4978";
+/// The semi-major axis of the WGS84 ellipsoid in meters
+pub const WGS84_A: f64 = 6_378_137.0;
+/// The flattening of the WGS84 ellipsoid
+pub const WGS84_F: f64 = 1.0 / 298.257_223_563;
+
+thread_local! {
+    /// Coordinate transforms by source and target definition, since creating them is expensive.
+    /// `CoordTransform` is not `Send`, so every thread keeps its own.
+    static TRANSFORMS: RefCell<HashMap<(String, String), Rc<CoordTransform>>> = RefCell::new(HashMap::new());
+}
+
+/// The surface the heights of an elevation dataset are measured from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum HeightReference {
+    /// Heights above the WGS84 ellipsoid
+    #[default]
+    Ellipsoid,
+    /// Orthometric heights above the EGM96 geoid
+    Egm96,
+    /// Orthometric heights above the EGM2008 geoid, used by the Copernicus DEM
+    Egm2008,
+}
+
+impl HeightReference {
+    /// The name the height reference is stored with in the database.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HeightReference::Ellipsoid => "ellipsoid",
+            HeightReference::Egm96 => "egm96",
+            HeightReference::Egm2008 => "egm2008",
+        }
+    }
+
+    /// Parses a name returned by [`HeightReference::name`].
+    pub fn from_name(name: &str) -> Option<HeightReference> {
+        match name {
+            "ellipsoid" => Some(HeightReference::Ellipsoid),
+            "egm96" => Some(HeightReference::Egm96),
+            "egm2008" => Some(HeightReference::Egm2008),
+            _ => None,
+        }
+    }
+
+    /// The geographic CRS with this vertical datum, as understood by GDAL.
+    fn definition(&self) -> &'static str {
+        match self {
+            HeightReference::Ellipsoid => "EPSG:4979",
+            HeightReference::Egm96 => "EPSG:4326+5773",
+            HeightReference::Egm2008 => "EPSG:4326+3855",
+        }
+    }
+}
+
+/// The frame world coordinates are returned in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFrame {
+    /// The CRS of the reference dataset, with the height as stored in the elevation data
+    Native,
+    /// Longitude and latitude in degrees, and height above the WGS84 ellipsoid in meters
+    Geodetic,
+    /// Earth centered, earth fixed coordinates in meters
+    #[default]
+    Ecef,
+}
+
+/// Converts a batch of points in place from the CRS of a dataset to `frame`.
+///
+/// ## Parameters
+/// * spatial_ref: the CRS of the points as WKT or any definition GDAL understands
+/// * heights: the surface `z` is measured from
+/// * frame: the frame the points are converted to
+/// * x, y: the coordinates in the order of a GDAL geotransform, e.g. longitude before latitude or easting before northing
+/// * z: the heights in meters
+/// ## Errors
+/// If the CRS can not be parsed or a point can not be transformed.
+/// ## Notes
+/// Geoid heights need the PROJ grid of the geoid, either installed or fetched with `PROJ_NETWORK=ON`.
+pub fn convert(
+    spatial_ref: &str,
+    heights: HeightReference,
+    frame: OutputFrame,
+    x: &mut [f64],
+    y: &mut [f64],
+    z: &mut [f64],
+) -> Result<(), GdalError> {
+    match frame {
+        OutputFrame::Native => Ok(()),
+        OutputFrame::Geodetic => {
+            transform_points(spatial_ref, GEOGRAPHIC, x, y)?;
+            transform_coords(
+                heights.definition(),
+                HeightReference::Ellipsoid.definition(),
+                x,
+                y,
+                z,
+            )
+        }
+        OutputFrame::Ecef => to_ecef(spatial_ref, heights, x, y, z),
+    }
+}
+
+/// Converts a batch of points in place from `spatial_ref` to earth centered, earth fixed coordinates.
+/// See [`convert`] for the parameters.
+pub fn to_ecef(
+    spatial_ref: &str,
+    heights: HeightReference,
+    x: &mut [f64],
+    y: &mut [f64],
+    z: &mut [f64],
+) -> Result<(), GdalError> {
+    // The horizontal coordinates are moved to WGS84 first, since the native CRS can be projected or use another datum.
+    transform_points(spatial_ref, GEOGRAPHIC, x, y)?;
+
+    transform_coords(heights.definition(), ECEF, x, y, z)
+}
+
+/// Converts a batch of earth centered, earth fixed points in place to longitude and latitude in degrees,
+/// and height above the WGS84 ellipsoid in meters.
+pub fn ecef_to_geographic(x: &mut [f64], y: &mut [f64], z: &mut [f64]) -> Result<(), GdalError> {
+    transform_coords(ECEF, HeightReference::Ellipsoid.definition(), x, y, z)
+}
+
+/// Transforms a batch of 2D points in place between two CRSs, both with the axis order of a GDAL geotransform.
+pub fn transform_points(
+    source: &str,
+    target: &str,
+    x: &mut [f64],
+    y: &mut [f64],
+) -> Result<(), GdalError> {
+    // The heights are not used, but some transforms between datums shift them.
+    let mut z = vec![0f64; x.len()];
+
+    transform_coords(source, target, x, y, &mut z)
+}
+
+fn transform_coords(
+    source: &str,
+    target: &str,
+    x: &mut [f64],
+    y: &mut [f64],
+    z: &mut [f64],
+) -> Result<(), GdalError> {
+    if source == target || x.is_empty() {
+        return Ok(());
+    }
+
+    cached_transform(source, target)?.transform_coords(x, y, z)
+}
+
+/// The CRS of a dataset as WKT, or [`DEFAULT_SPATIAL_REF`] if the dataset has none.
+pub fn dataset_spatial_ref(dataset: &Dataset) -> String {
+    let projection = dataset.projection();
+
+    spatial_ref_or_default((!projection.is_empty()).then_some(projection))
+}
+
+/// A stored CRS, or [`DEFAULT_SPATIAL_REF`] for data stored without one.
+pub fn spatial_ref_or_default(spatial_ref: Option<String>) -> String {
+    spatial_ref.unwrap_or_else(|| DEFAULT_SPATIAL_REF.to_string())
+}
+
+/// Parses a CRS with the axis order of a GDAL geotransform, instead of the order of the authority.
+/// Without this, EPSG:4326 expects latitude before longitude.
+pub fn spatial_ref(definition: &str) -> Result<SpatialRef, GdalError> {
+    let spatial_ref = SpatialRef::from_definition(definition)?;
+    spatial_ref.set_axis_mapping_strategy(OAMS_TRADITIONAL_GIS_ORDER);
+
+    Ok(spatial_ref)
+}
+
+fn cached_transform(source: &str, target: &str) -> Result<Rc<CoordTransform>, GdalError> {
+    let key = (source.to_string(), target.to_string());
+
+    if let Some(transform) = TRANSFORMS.with(|transforms| transforms.borrow().get(&key).cloned()) {
+        return Ok(transform);
+    }
+
+    let transform = Rc::new(CoordTransform::new(
+        &spatial_ref(source)?,
+        &spatial_ref(target)?,
+    )?);
+    TRANSFORMS.with(|transforms| transforms.borrow_mut().insert(key, transform.clone()));
+
+    Ok(transform)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coordinate_converter() {
+        let himmel_lon = 9.68505;
+        let himmel_lat = 56.105169;
+
+        let (mut x, mut y, mut z) = ([himmel_lon], [himmel_lat], [0.0]);
+        to_ecef(
+            "EPSG:4326",
+            HeightReference::Ellipsoid,
+            &mut x,
+            &mut y,
+            &mut z,
+        )
+        .unwrap();
+
+        assert!((x[0] - 3514316.2468943615).abs() < 1e-3);
+        assert!((y[0] - 599769.3477405359).abs() < 1e-3);
+    }
+
+    #[test]
+    fn projected_coordinates_match_geographic() {
+        let mut lon = [9.68505, 10.2, 8.5];
+        let mut lat = [56.105169, 55.4, 57.0];
+        let (mut easting, mut northing) = (lon, lat);
+
+        transform_points("EPSG:4326", "EPSG:25832", &mut easting, &mut northing).unwrap();
+
+        // UTM zone 32 eastings are around 500 km, so the axes were not swapped.
+        assert!(easting.iter().all(|e| (100_000.0..900_000.0).contains(e)));
+
+        let mut geographic_z = [50.0; 3];
+        let mut projected_z = geographic_z;
+        to_ecef(
+            "EPSG:4326",
+            HeightReference::Ellipsoid,
+            &mut lon,
+            &mut lat,
+            &mut geographic_z,
+        )
+        .unwrap();
+        to_ecef(
+            "EPSG:25832",
+            HeightReference::Ellipsoid,
+            &mut easting,
+            &mut northing,
+            &mut projected_z,
+        )
+        .unwrap();
+
+        for i in 0..3 {
+            let distance = ((lon[i] - easting[i]).powi(2)
+                + (lat[i] - northing[i]).powi(2)
+                + (geographic_z[i] - projected_z[i]).powi(2))
+            .sqrt();
+
+            assert!(distance < 1.0, "{distance}");
+        }
+    }
+
+    #[test]
+    fn output_frames_agree() {
+        let (native_x, native_y, native_z) = (
+            [541_000.0, 560_000.0],
+            [6_218_000.0, 6_200_000.0],
+            [147.0, 20.0],
+        );
+
+        let (mut gx, mut gy, mut gz) = (native_x, native_y, native_z);
+        convert(
+            "EPSG:25832",
+            HeightReference::Ellipsoid,
+            OutputFrame::Geodetic,
+            &mut gx,
+            &mut gy,
+            &mut gz,
+        )
+        .unwrap();
+
+        let (mut ex, mut ey, mut ez) = (native_x, native_y, native_z);
+        convert(
+            "EPSG:25832",
+            HeightReference::Ellipsoid,
+            OutputFrame::Ecef,
+            &mut ex,
+            &mut ey,
+            &mut ez,
+        )
+        .unwrap();
+        ecef_to_geographic(&mut ex, &mut ey, &mut ez).unwrap();
+
+        let (mut nx, mut ny, mut nz) = (native_x, native_y, native_z);
+        convert(
+            "EPSG:25832",
+            HeightReference::Ellipsoid,
+            OutputFrame::Native,
+            &mut nx,
+            &mut ny,
+            &mut nz,
+        )
+        .unwrap();
+
+        assert_eq!((nx, ny, nz), (native_x, native_y, native_z));
+        for i in 0..2 {
+            assert!((gx[i] - ex[i]).abs() < 1e-9 && (gy[i] - ey[i]).abs() < 1e-9);
+            assert!((gz[i] - native_z[i]).abs() < 1e-6 && (ez[i] - native_z[i]).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn geographic_round_trip() {
+        let (mut x, mut y, mut z) = ([9.68505, -70.5], [56.105169, -33.4], [147.0, 2500.0]);
+
+        to_ecef(
+            "EPSG:4326",
+            HeightReference::Ellipsoid,
+            &mut x,
+            &mut y,
+            &mut z,
+        )
+        .unwrap();
+        ecef_to_geographic(&mut x, &mut y, &mut z).unwrap();
+
+        assert!((x[0] - 9.68505).abs() < 1e-9 && (y[0] - 56.105169).abs() < 1e-9);
+        assert!((x[1] + 70.5).abs() < 1e-9 && (y[1] + 33.4).abs() < 1e-9);
+        assert!((z[0] - 147.0).abs() < 1e-6 && (z[1] - 2500.0).abs() < 1e-6);
+    }
+
+    #[test]
+    #[ignore = "Needs the PROJ grid of EGM2008, installed or fetched with PROJ_NETWORK=ON"]
+    fn geoid_heights_are_raised_to_ellipsoid() {
+        let (mut x, mut y, mut z) = ([9.68505], [56.105169], [0.0]);
+
+        convert(
+            "EPSG:4326",
+            HeightReference::Egm2008,
+            OutputFrame::Geodetic,
+            &mut x,
+            &mut y,
+            &mut z,
+        )
+        .unwrap();
+
+        // The geoid lies about 40 m above the ellipsoid in Denmark.
+        assert!((z[0] - 40.0).abs() < 5.0, "{}", z[0]);
+    }
+
+    #[test]
+    fn datasets_without_crs_are_geographic() {
+        let driver = gdal::DriverManager::get_driver_by_name("MEM").unwrap();
+        let mut dataset = driver.create("", 1, 1, 1).unwrap();
+
+        assert_eq!(dataset_spatial_ref(&dataset), DEFAULT_SPATIAL_REF);
+
+        dataset
+            .set_spatial_ref(&SpatialRef::from_epsg(25832).unwrap())
+            .unwrap();
+
+        assert!(dataset_spatial_ref(&dataset).contains("25832"));
+    }
+
+    #[test]
+    fn height_reference_names() {
+        for heights in [
+            HeightReference::Ellipsoid,
+            HeightReference::Egm96,
+            HeightReference::Egm2008,
+        ] {
+            assert_eq!(HeightReference::from_name(heights.name()), Some(heights));
+        }
+
+        assert_eq!(HeightReference::from_name("navd88"), None);
+    }
+}
@@ -2,17 +2,23 @@ use gdal::errors;
 use gdal::raster::{ColorInterpretation, RasterCreationOption, StatisticsMinMax};
 use gdal::{Dataset, GeoTransformEx};
 
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 use gdal::raster::ResampleAlg;
 
 use gdal::programs::raster::build_vrt;
 
+use crate::elevation::{elevation_coverage, sample_elevation, warp_elevation, ElevationCoverage, ElevationOptions};
+use crate::geodesy::{self, HeightReference, OutputFrame};
+use crate::masking::{build_mask, MaskInputs, MaskOptions};
+
 #[cfg(test)]
 use mockall::{automock, predicate::*};
 
 const GAMMA_VALUE: f32 = 1.0 / 2.2;
-const U8_MAX: f32 = u8::MAX as f32;
+// The maximum resolution of the overview read when computing percentiles.
+const PERCENTILE_SAMPLE_SIZE: usize = 2048;
 
 // A struct for handling raw datasets from disk in Geotiff format
 pub struct RawDataset {
@@ -25,6 +31,19 @@ pub struct DatasetOptions {
     pub red_band_index: isize,
     pub green_band_index: isize,
     pub blue_band_index: isize,
+    pub stretch: Stretch,
+    /// The surface the heights of the elevation dataset are measured from
+    pub height_reference: HeightReference,
+}
+
+/// How band values are stretched to the 8-bit range.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Stretch {
+    /// Stretches between the minimum and maximum value of each band.
+    #[default]
+    MinMax,
+    /// Stretches between the `lower` and `upper` percentile (0-100) of each band. Values outside are clipped.
+    Percentile { lower: f64, upper: f64 },
 }
 
 impl DatasetOptions {
@@ -39,6 +58,8 @@ pub struct DatasetOptionsBuilder {
     pub red_band_index: Option<isize>,
     pub green_band_index: Option<isize>,
     pub blue_band_index: Option<isize>,
+    pub stretch: Option<Stretch>,
+    pub height_reference: Option<HeightReference>,
 }
 
 impl DatasetOptionsBuilder {
@@ -63,12 +84,24 @@ impl DatasetOptionsBuilder {
         self
     }
 
+    pub fn set_stretch(mut self, stretch: Stretch) -> DatasetOptionsBuilder {
+        self.stretch = Some(stretch);
+        self
+    }
+
+    pub fn set_height_reference(mut self, height_reference: HeightReference) -> DatasetOptionsBuilder {
+        self.height_reference = Some(height_reference);
+        self
+    }
+
     pub fn build(self) -> DatasetOptions {
         DatasetOptions {
             scaling: self.scaling.unwrap_or((1024, 1024)),
             red_band_index: self.red_band_index.unwrap_or(1),
             green_band_index: self.green_band_index.unwrap_or(2),
             blue_band_index: self.blue_band_index.unwrap_or(3),
+            stretch: self.stretch.unwrap_or_default(),
+            height_reference: self.height_reference.unwrap_or_default(),
         }
     }
 }
@@ -79,6 +112,99 @@ pub struct MosaicedDataset {
     pub options: DatasetOptions,
     pub min_max: Option<BandsMinMax>,
     pub elevation: Option<Dataset>,
+    pub mask: Option<Dataset>,
+    /// Cached minimum and maximum values of each band, keyed by band index
+    pub band_min_max: HashMap<isize, (f64, f64)>,
+}
+
+/// An 8-bit raster with an arbitrary amount of bands.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BandRaster {
+    /// Interleaved pixel values, in the order the bands were requested
+    pub data: Vec<u8>,
+    /// 0 where every band is nodata, 255 otherwise
+    pub alpha: Vec<u8>,
+    pub channels: usize,
+}
+
+impl BandRaster {
+    /// Converts the raster to RGBA, used when masking.
+    /// A single band raster becomes gray, and only the first three bands of larger rasters are used.
+    pub fn to_rgba(&self) -> Vec<rgb::RGBA8> {
+        self.data
+            .chunks_exact(self.channels)
+            .zip(&self.alpha)
+            .map(|(pixel, alpha)| match pixel {
+                [value] => rgb::RGBA8::new(*value, *value, *value, *alpha),
+                [red, green] => rgb::RGBA8::new(*red, *green, 0, *alpha),
+                [red, green, blue, ..] => rgb::RGBA8::new(*red, *green, *blue, *alpha),
+                [] => rgb::RGBA8::new(0, 0, 0, *alpha),
+            })
+            .collect()
+    }
+}
+
+/// An element type that stretched band values can be stored as.
+/// Wider types keep more of the radiometric detail of the source bands.
+pub trait PixelDepth: Copy {
+    /// The value representing full intensity, and an opaque alpha.
+    const MAX: f32;
+
+    /// Converts a value in the range 0.0 to 1.0.
+    fn from_normalized(value: f32) -> Self;
+    /// Converts to a value in the range 0.0 to 1.0.
+    fn to_normalized(self) -> f32;
+}
+
+impl PixelDepth for u8 {
+    const MAX: f32 = u8::MAX as f32;
+
+    fn from_normalized(value: f32) -> Self {
+        (value * Self::MAX).round() as u8
+    }
+
+    fn to_normalized(self) -> f32 {
+        self as f32 / Self::MAX
+    }
+}
+
+impl PixelDepth for u16 {
+    const MAX: f32 = u16::MAX as f32;
+
+    fn from_normalized(value: f32) -> Self {
+        (value * Self::MAX).round() as u16
+    }
+
+    fn to_normalized(self) -> f32 {
+        self as f32 / Self::MAX
+    }
+}
+
+impl PixelDepth for f32 {
+    const MAX: f32 = 1.0;
+
+    fn from_normalized(value: f32) -> Self {
+        value
+    }
+
+    fn to_normalized(self) -> f32 {
+        self
+    }
+}
+
+/// Converts pixels of any depth to 8-bit, e.g. for masking.
+pub fn to_rgba8<T: PixelDepth>(pixels: &[rgb::RGBA<T>]) -> Vec<rgb::RGBA8> {
+    pixels
+        .iter()
+        .map(|pixel| {
+            rgb::RGBA8::new(
+                u8::from_normalized(pixel.r.to_normalized()),
+                u8::from_normalized(pixel.g.to_normalized()),
+                u8::from_normalized(pixel.b.to_normalized()),
+                u8::from_normalized(pixel.a.to_normalized()),
+            )
+        })
+        .collect()
 }
 
 #[cfg_attr(test, automock)]
@@ -100,11 +226,40 @@ pub trait MosaicDataset {
         window_size: (usize, usize),
         size: (usize, usize),
     ) -> Result<Vec<rgb::RGBA8>, errors::GdalError>;
+    fn to_rgb16(
+        &mut self,
+        window: (isize, isize),
+        window_size: (usize, usize),
+        size: (usize, usize),
+    ) -> Result<Vec<rgb::RGBA16>, errors::GdalError>;
+    fn to_rgb_f32(
+        &mut self,
+        window: (isize, isize),
+        window_size: (usize, usize),
+        size: (usize, usize),
+    ) -> Result<Vec<rgb::RGBA<f32>>, errors::GdalError>;
     fn detect_nodata(&self) -> bool;
     fn fill_nodata(&mut self);
     fn set_bands(&self, red_band: isize, green_band: isize, blue_band: isize);
-    fn set_elevation_dataset(&mut self, path: &str, output_path: &str) -> Result<(), errors::GdalError>;
-    fn get_world_coordinates(&self, x: f64, y: f64) -> Result<(f64,f64,f64), errors::GdalError>;
+    fn set_elevation_dataset(&mut self, path: &str, output_path: &str, options: &ElevationOptions) -> Result<ElevationCoverage, errors::GdalError>;
+    fn get_world_coordinates(&self, x: f64, y: f64, frame: OutputFrame) -> Result<(f64,f64,f64), errors::GdalError>;
+    fn set_mask_dataset(&mut self, path: &str) -> Result<(), errors::GdalError>;
+    fn bands_min_max(&mut self, bands: &[isize]) -> Result<Vec<(f64, f64)>, errors::GdalError>;
+    fn to_bands(
+        &mut self,
+        bands: &[isize],
+        window: (isize, isize),
+        window_size: (usize, usize),
+        size: (usize, usize),
+    ) -> Result<BandRaster, errors::GdalError>;
+    fn to_mask(
+        &mut self,
+        window: (isize, isize),
+        window_size: (usize, usize),
+        size: (usize, usize),
+        rgba: &[rgb::RGBA8],
+        options: &MaskOptions,
+    ) -> Result<Vec<u8>, errors::GdalError>;
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -160,6 +315,8 @@ impl Datasets for RawDataset {
             options: DatasetOptionsBuilder::new().build(),
             min_max: None,
             elevation: None,
+            mask: None,
+            band_min_max: HashMap::new(),
         })
     }
 
@@ -171,6 +328,8 @@ impl Datasets for RawDataset {
             options: DatasetOptionsBuilder::new().build(),
             min_max: None,
             elevation: None,
+            mask: None,
+            band_min_max: HashMap::new(),
         })
     }
 }
@@ -196,6 +355,25 @@ fn dataset_from_folder(path: &str) -> Result<Vec<Dataset>, errors::GdalError> {
     Ok(unwrapped_data)
 }
 
+/// Checks that `bands` is not empty and only contains 1-based band indexes of `dataset`.
+///
+/// ## Errors
+/// [`errors::GdalError::BadArgument`] naming the first missing band.
+pub fn check_band_indexes(dataset: &Dataset, bands: &[isize]) -> Result<(), errors::GdalError> {
+    let count = dataset.raster_count();
+
+    if bands.is_empty() {
+        return Err(errors::GdalError::BadArgument(String::from("No bands requested")));
+    }
+
+    match bands.iter().find(|&&index| index < 1 || index > count) {
+        Some(index) => Err(errors::GdalError::BadArgument(format!(
+            "Band {index} requested, but the dataset has {count} bands"
+        ))),
+        None => Ok(()),
+    }
+}
+
 impl MosaicDataset for MosaicedDataset {
     fn datasets_min_max(&mut self) -> Result<BandsMinMax, errors::GdalError> {
         if self.min_max.is_some() {
@@ -203,15 +381,10 @@ impl MosaicDataset for MosaicedDataset {
         }
 
         let dataset = &self.dataset;
+        let stretch = self.options.stretch;
 
         let min_max: Vec<StatisticsMinMax> = (1..4)
-            .map(|i| {
-                let ds_min_max = dataset.rasterband(i)?.compute_raster_min_max(true)?;
-                Ok::<StatisticsMinMax, errors::GdalError>(StatisticsMinMax {
-                    min: ds_min_max.min,
-                    max: ds_min_max.max,
-                })
-            })
+            .map(|i| band_min_max(&dataset.rasterband(i)?, stretch))
             .collect::<Result<Vec<StatisticsMinMax>, errors::GdalError>>()?;
 
         let min_max = BandsMinMax {
@@ -244,28 +417,27 @@ impl MosaicDataset for MosaicedDataset {
         window_size: (usize, usize),
         size: (usize, usize),
     ) -> Result<Vec<rgb::RGBA8>, errors::GdalError> {
-        let mut red_band = self.dataset.rasterband(1)?;
-        red_band.set_color_interpretation(ColorInterpretation::RedBand)?;
-        let red_converted = extract_band(&red_band, window, window_size, size)?;
-
-        let mut green_band = self.dataset.rasterband(2)?;
-        green_band.set_color_interpretation(ColorInterpretation::GreenBand)?;
-        let green_converted = extract_band(&green_band, window, window_size, size)?;
-
-        let mut blue_band = self.dataset.rasterband(3)?;
-        blue_band.set_color_interpretation(ColorInterpretation::BlueBand)?;
-        let blue_converted = extract_band(&blue_band, window, window_size, size)?;
-
-        let bands = vec![red_converted, green_converted, blue_converted];
-
-        let min_max = self.datasets_min_max()?;
+        self.to_rgb_depth(window, window_size, size)
+    }
 
-        let combined_bands = match band_merger(&bands, &min_max) {
-            Ok(combined_bands) => combined_bands,
-            Err(_) => return Err(errors::GdalError::CastToF64Error),
-        };
+    /// Like [MosaicDataset::to_rgb], but keeps 16 bits of each band.
+    fn to_rgb16(
+        &mut self,
+        window: (isize, isize),
+        window_size: (usize, usize),
+        size: (usize, usize),
+    ) -> Result<Vec<rgb::RGBA16>, errors::GdalError> {
+        self.to_rgb_depth(window, window_size, size)
+    }
 
-        Ok(combined_bands)
+    /// Like [MosaicDataset::to_rgb], but keeps the stretched bands as floats in the range 0.0 to 1.0.
+    fn to_rgb_f32(
+        &mut self,
+        window: (isize, isize),
+        window_size: (usize, usize),
+        size: (usize, usize),
+    ) -> Result<Vec<rgb::RGBA<f32>>, errors::GdalError> {
+        self.to_rgb_depth(window, window_size, size)
     }
 
     fn detect_nodata(&self) -> bool {
@@ -284,6 +456,8 @@ impl MosaicDataset for MosaicedDataset {
             options: DatasetOptionsBuilder::new().build(),
             min_max: None,
             elevation: None,
+            mask: None,
+            band_min_max: HashMap::new(),
         })
     }
 
@@ -291,41 +465,274 @@ impl MosaicDataset for MosaicedDataset {
         todo!()
     }
 
-    fn set_elevation_dataset(&mut self, path: &str, output_path: &str) -> Result<(), errors::GdalError> {
+    /// Sets the elevation of the mosaic from the elevation datasets in the folder at `path`.
+    ///
+    /// The datasets are warped into the CRS of the mosaic, on a grid aligned with it, and written to `output_path`.
+    /// If `options.vertical_datum` is set, the heights are converted to heights above the ellipsoid
+    /// and the height reference of the options is set to match.
+    /// Returns which parts of the mosaic have no elevation, since lookups there fail.
+    fn set_elevation_dataset(&mut self, path: &str, output_path: &str, options: &ElevationOptions) -> Result<ElevationCoverage, errors::GdalError> {
         let ds = match dataset_from_folder(path) {
             Ok(dataset) => dataset,
             Err(e) => return Err(e),
         };
 
         let mut vrt_path = PathBuf::from(&output_path);
-
         vrt_path.push("elevation.vrt");
 
         let result_vrt = build_vrt(Some(vrt_path.as_path()), &ds, None)?;
 
-        self.elevation = Some(result_vrt);
+        let mut warped_path = PathBuf::from(&output_path);
+        warped_path.push("elevation.tif");
+
+        let warped = warp_elevation(&result_vrt, &self.dataset, &warped_path, options)?;
+        let coverage = elevation_coverage(&warped, options.scale)?;
+
+        if options.vertical_datum.is_some() {
+            self.options.height_reference = HeightReference::Ellipsoid;
+        }
+
+        self.elevation = Some(warped);
+
+        Ok(coverage)
+    }
+
+    /// Returns the world coordinates of a full resolution pixel of the mosaic in `frame`.
+    ///
+    /// The pixel is converted with the CRS of the mosaic, and the height is looked up in the elevation dataset
+    /// through its own CRS with bilinear interpolation, using the height reference of the options.
+    /// Without elevation data the point is placed on the ellipsoid.
+    /// Datasets without a CRS are assumed to be geographic, like in the database, see [`geodesy::dataset_spatial_ref`].
+    ///
+    /// ## Errors
+    /// If the pixel is outside of the elevation dataset, or only surrounded by nodata.
+    fn get_world_coordinates(&self, x: f64, y: f64, frame: OutputFrame) -> Result<(f64,f64,f64), errors::GdalError> {
+        let spatial_ref = geodesy::dataset_spatial_ref(&self.dataset);
+        let coordinates = self.dataset.geo_transform()?.apply(x, y);
+
+        let (height, heights) = match &self.elevation {
+            Some(elevation) => {
+                let (mut elevation_x, mut elevation_y) = ([coordinates.0], [coordinates.1]);
+                geodesy::transform_points(&spatial_ref, &geodesy::dataset_spatial_ref(elevation), &mut elevation_x, &mut elevation_y)?;
+
+                let elev_pixels = elevation.geo_transform()?.invert()?.apply(elevation_x[0], elevation_y[0]);
+                let height = sample_elevation(elevation, elev_pixels.0, elev_pixels.1)?
+                    .ok_or_else(|| errors::GdalError::BadArgument(format!("No elevation at pixel ({x}, {y})")))?;
+
+                (height, self.options.height_reference)
+            }
+            None => (0.0, HeightReference::Ellipsoid),
+        };
+
+        let (mut x, mut y, mut z) = ([coordinates.0], [coordinates.1], [height]);
+        geodesy::convert(&spatial_ref, heights, frame, &mut x, &mut y, &mut z)?;
+
+        Ok((x[0], y[0], z[0]))
+    }
+
+    /// Sets the raster used to mask out pixels. Any non-zero value in its first band is masked.
+    /// The mask raster can have a different resolution, and pixels of the mosaic it does not cover are not masked.
+    fn set_mask_dataset(&mut self, path: &str) -> Result<(), errors::GdalError> {
+        self.mask = Some(Dataset::open(path)?);
 
         Ok(())
     }
 
-    fn get_world_coordinates(&self, x: f64, y: f64) -> Result<(f64,f64,f64), errors::GdalError> {
-        let geotransform = self.dataset.geo_transform()?;
+    /// Returns the minimum and maximum value of each band, in the order of `bands`.
+    fn bands_min_max(&mut self, bands: &[isize]) -> Result<Vec<(f64, f64)>, errors::GdalError> {
+        for &index in bands {
+            if !self.band_min_max.contains_key(&index) {
+                let min_max = band_min_max(&self.dataset.rasterband(index)?, self.options.stretch)?;
+                self.band_min_max.insert(index, (min_max.min, min_max.max));
+            }
+        }
 
-        let coordinates = geotransform.apply(x, y);
+        Ok(bands.iter().map(|index| self.band_min_max[index]).collect())
+    }
 
-        let elevation_transform = match &self.elevation {
-            Some(dataset) => dataset.geo_transform()?,
-            None => return Ok((coordinates.0, coordinates.1, 0.0))
+    /// Reads any combination of bands, such as a single panchromatic band or the bands of a multi-spectral image.
+    /// Each band is stretched to 8 bits using its own statistics.
+    fn to_bands(
+        &mut self,
+        bands: &[isize],
+        window: (isize, isize),
+        window_size: (usize, usize),
+        size: (usize, usize),
+    ) -> Result<BandRaster, errors::GdalError> {
+        check_band_indexes(&self.dataset, bands)?;
+
+        let min_max = self.bands_min_max(bands)?;
+
+        let band_data = bands
+            .iter()
+            .map(|&index| extract_band(&self.dataset.rasterband(index)?, window, window_size, size))
+            .collect::<Result<Vec<Vec<f32>>, errors::GdalError>>()?;
+
+        Ok(interleave_bands(&band_data, &min_max))
+    }
+
+    /// Builds a mask for a tile, where 255 is valid and 0 is masked.
+    /// `rgba` must be the tile returned by [MosaicDataset::to_rgb] for the same window.
+    fn to_mask(
+        &mut self,
+        window: (isize, isize),
+        window_size: (usize, usize),
+        size: (usize, usize),
+        rgba: &[rgb::RGBA8],
+        options: &MaskOptions,
+    ) -> Result<Vec<u8>, errors::GdalError> {
+        let mask_raster = match &self.mask {
+            Some(mask) => Some(read_mask_window(&self.dataset, mask, window, window_size, size)?),
+            None => None,
+        };
+
+        let (green, nir) = match (options.nir_band_index, options.ndwi_threshold) {
+            (Some(nir_index), Some(_)) => {
+                let green_band = self.dataset.rasterband(self.options.green_band_index)?;
+                let nir_band = self.dataset.rasterband(nir_index)?;
+
+                (
+                    Some(extract_band(&green_band, window, window_size, size)?),
+                    Some(extract_band(&nir_band, window, window_size, size)?),
+                )
+            }
+            _ => (None, None),
+        };
+
+        let inputs = MaskInputs {
+            rgba,
+            mask_raster: mask_raster.as_deref(),
+            green: green.as_deref(),
+            nir: nir.as_deref(),
         };
 
-        let invers_elev = elevation_transform.invert()?;
+        Ok(build_mask(&inputs, options))
+    }
+}
+
+/// Reads the part of the mask raster that covers a window of the dataset.
+/// Pixels of the window outside the mask raster are 0, so they are not masked.
+fn read_mask_window(
+    dataset: &Dataset,
+    mask: &Dataset,
+    window: (isize, isize),
+    window_size: (usize, usize),
+    size: (usize, usize),
+) -> Result<Vec<f32>, errors::GdalError> {
+    let transform = dataset.geo_transform()?;
+    let inverse_mask = mask.geo_transform()?.invert()?;
+    let mask_size = mask.raster_size();
+
+    let start = transform.apply(window.0 as f64, window.1 as f64);
+    let end = transform.apply(
+        (window.0 + window_size.0 as isize) as f64,
+        (window.1 + window_size.1 as isize) as f64,
+    );
+
+    let start = inverse_mask.apply(start.0, start.1);
+    let end = inverse_mask.apply(end.0, end.1);
+
+    let mut data = vec![0f32; size.0 * size.1];
+
+    let (x, y) = match (
+        clamp_mask_axis(start.0, end.0, mask_size.0, size.0),
+        clamp_mask_axis(start.1, end.1, mask_size.1, size.1),
+    ) {
+        (Some(x), Some(y)) => (x, y),
+        _ => return Ok(data),
+    };
+
+    let covered = mask
+        .rasterband(1)?
+        .read_as::<f32>(
+            (x.window, y.window),
+            (x.window_size, y.window_size),
+            (x.size, y.size),
+            Some(ResampleAlg::NearestNeighbour),
+        )?
+        .data;
+
+    for row in 0..y.size {
+        let start = (y.offset + row) * size.0 + x.offset;
+        data[start..start + x.size].copy_from_slice(&covered[row * x.size..(row + 1) * x.size]);
+    }
+
+    Ok(data)
+}
+
+/// The part of a window along one axis that is covered by the mask raster.
+#[derive(Debug, PartialEq)]
+struct MaskAxis {
+    /// The first mask pixel read
+    window: isize,
+    /// The amount of mask pixels read
+    window_size: usize,
+    /// The first output pixel covered by the mask
+    offset: usize,
+    /// The amount of output pixels covered by the mask
+    size: usize,
+}
+
+/// Clamps the window from mask pixel `start` to `end`, read into `size` output pixels, to a mask raster of `mask_size` pixels.
+/// Returns [`None`] if the window does not overlap the mask raster.
+fn clamp_mask_axis(start: f64, end: f64, mask_size: usize, size: usize) -> Option<MaskAxis> {
+    let clamped_start = start.max(0.0);
+    let clamped_end = end.min(mask_size as f64);
+
+    if mask_size == 0 || clamped_end <= clamped_start {
+        return None;
+    }
+
+    let scale = size as f64 / (end - start);
+    let offset = (((clamped_start - start) * scale).round() as usize).min(size);
+    let output_end = (((clamped_end - start) * scale).round() as usize).min(size);
 
-        let elev_pixels = invers_elev.apply(coordinates.0, coordinates.1);
+    if output_end <= offset {
+        return None;
+    }
 
-        let elevation = self.elevation.as_ref().unwrap().rasterband(1)?.read_as::<f64>((elev_pixels.0.round() as isize, elev_pixels.1.round() as isize), (1,1), (1,1), None)?.data;
+    let window = (clamped_start.round() as usize).min(mask_size - 1);
+    let window_end = (clamped_end.round() as usize).clamp(window + 1, mask_size);
 
-        Ok((coordinates.0, coordinates.1, elevation[0]))
+    Some(MaskAxis {
+        window: window as isize,
+        window_size: window_end - window,
+        offset,
+        size: output_end - offset,
+    })
+}
+
+impl MosaicedDataset {
+    fn to_rgb_depth<T: PixelDepth>(
+        &mut self,
+        window: (isize, isize),
+        window_size: (usize, usize),
+        size: (usize, usize),
+    ) -> Result<Vec<rgb::RGBA<T>>, errors::GdalError> {
+        check_band_indexes(&self.dataset, &[1, 2, 3])?;
+
+        let mut red_band = self.dataset.rasterband(1)?;
+        red_band.set_color_interpretation(ColorInterpretation::RedBand)?;
+        let red_converted = extract_band(&red_band, window, window_size, size)?;
+
+        let mut green_band = self.dataset.rasterband(2)?;
+        green_band.set_color_interpretation(ColorInterpretation::GreenBand)?;
+        let green_converted = extract_band(&green_band, window, window_size, size)?;
+
+        let mut blue_band = self.dataset.rasterband(3)?;
+        blue_band.set_color_interpretation(ColorInterpretation::BlueBand)?;
+        let blue_converted = extract_band(&blue_band, window, window_size, size)?;
+
+        let bands = vec![red_converted, green_converted, blue_converted];
+
+        let min_max = self.datasets_min_max()?;
+
+        let combined_bands = match band_merger_depth(&bands, &min_max) {
+            Ok(combined_bands) => combined_bands,
+            Err(_) => return Err(errors::GdalError::CastToF64Error),
+        };
 
+        Ok(combined_bands)
     }
 }
 
@@ -342,41 +749,133 @@ fn extract_band(
     Ok(band_vec)
 }
 
+fn band_min_max(
+    band: &gdal::raster::RasterBand,
+    stretch: Stretch,
+) -> Result<StatisticsMinMax, errors::GdalError> {
+    let min_max = match stretch {
+        Stretch::MinMax => band.compute_raster_min_max(true)?,
+        Stretch::Percentile { lower, upper } => band_percentiles(band, lower, upper)?,
+    };
+
+    Ok(StatisticsMinMax {
+        min: min_max.min,
+        max: min_max.max,
+    })
+}
+
+/// Finds the `lower` and `upper` percentile of a band from a downsampled read of the whole band.
+/// Nodata and NaN values are ignored.
+fn band_percentiles(
+    band: &gdal::raster::RasterBand,
+    lower: f64,
+    upper: f64,
+) -> Result<StatisticsMinMax, errors::GdalError> {
+    let band_size = band.size();
+    let sample_size = (
+        band_size.0.min(PERCENTILE_SAMPLE_SIZE),
+        band_size.1.min(PERCENTILE_SAMPLE_SIZE),
+    );
+    let no_data = band.no_data_value();
+
+    let mut values: Vec<f64> = band
+        .read_as::<f64>((0, 0), band_size, sample_size, Some(ResampleAlg::NearestNeighbour))?
+        .data
+        .into_iter()
+        .filter(|value| !value.is_nan() && Some(*value) != no_data)
+        .collect();
+
+    if values.is_empty() {
+        return band.compute_raster_min_max(true);
+    }
+
+    values.sort_by(f64::total_cmp);
+
+    Ok(StatisticsMinMax {
+        min: percentile(&values, lower),
+        max: percentile(&values, upper),
+    })
+}
+
+/// Assumes that `sorted` is sorted in ascending order and not empty.
+fn percentile(sorted: &[f64], percentile: f64) -> f64 {
+    let index = ((sorted.len() - 1) as f64 * percentile.clamp(0.0, 100.0) / 100.0).round();
+
+    sorted[index as usize]
+}
+
 /// Assumes a [Vec<Vec<u8>>] in the order R,G,B.
 fn band_merger(
     bands: &[Vec<f32>],
     min_max: &BandsMinMax,
 ) -> Result<Vec<rgb::RGBA8>, PixelConversion> {
-    let mut combined_bands: Vec<rgb::RGBA8> = Vec::with_capacity(bands[0].len() * 4);
+    band_merger_depth(bands, min_max)
+}
+
+/// Assumes a [Vec<Vec<u8>>] in the order R,G,B.
+fn band_merger_depth<T: PixelDepth>(
+    bands: &[Vec<f32>],
+    min_max: &BandsMinMax,
+) -> Result<Vec<rgb::RGBA<T>>, PixelConversion> {
+    let mut combined_bands: Vec<rgb::RGBA<T>> = Vec::with_capacity(bands[0].len() * 4);
+    let zero = T::from_normalized(0.0);
 
     for i in 0..bands[0].len() {
-        let mut alpha = 255;
+        let mut alpha = T::from_normalized(1.0);
 
         if bands.iter().fold(true, |acc, band| band[i].is_nan() && acc) {
-            alpha = 0;
+            alpha = zero;
         }
 
-        let red =
-            f32_to_u8(bands[0][i], min_max.red_min as f32, min_max.red_max as f32).unwrap_or(0);
-        let green = f32_to_u8(
+        let red = f32_to_pixel(bands[0][i], min_max.red_min as f32, min_max.red_max as f32)
+            .unwrap_or(zero);
+        let green = f32_to_pixel(
             bands[1][i],
             min_max.green_min as f32,
             min_max.green_max as f32,
         )
-        .unwrap_or(0);
-        let blue = f32_to_u8(
+        .unwrap_or(zero);
+        let blue = f32_to_pixel(
             bands[2][i],
             min_max.blue_min as f32,
             min_max.blue_max as f32,
         )
-        .unwrap_or(0);
+        .unwrap_or(zero);
 
-        combined_bands.push(rgb::RGBA8::new(red, green, blue, alpha));
+        combined_bands.push(rgb::RGBA::new(red, green, blue, alpha));
     }
 
     Ok(combined_bands)
 }
 
+/// Converts the bands to interleaved 8-bit values, in the same way as [band_merger].
+fn interleave_bands(bands: &[Vec<f32>], min_max: &[(f64, f64)]) -> BandRaster {
+    let pixels = bands.first().map_or(0, |band| band.len());
+
+    let mut data: Vec<u8> = Vec::with_capacity(pixels * bands.len());
+    let mut alpha: Vec<u8> = Vec::with_capacity(pixels);
+
+    for i in 0..pixels {
+        let mut pixel_alpha = 255;
+
+        if bands.iter().all(|band| band[i].is_nan()) {
+            pixel_alpha = 0;
+        }
+
+        alpha.push(pixel_alpha);
+
+        for (band, (min, max)) in bands.iter().zip(min_max) {
+            data.push(f32_to_u8(band[i], *min as f32, *max as f32).unwrap_or(0));
+        }
+    }
+
+    BandRaster {
+        data,
+        alpha,
+        channels: bands.len(),
+    }
+}
+
 fn creation_options() -> Vec<RasterCreationOption<'static>> {
     let create_options = vec![
         RasterCreationOption {
@@ -408,17 +907,20 @@ fn gamma_correction(input_value: f32) -> Result<f32, PixelConversion> {
 }
 
 fn f32_to_u8(input_value: f32, min: f32, max: f32) -> Result<u8, PixelConversion> {
+    f32_to_pixel(input_value, min, max)
+}
+
+fn f32_to_pixel<T: PixelDepth>(input_value: f32, min: f32, max: f32) -> Result<T, PixelConversion> {
     if input_value.is_nan() {
         return Err(PixelConversion::NotANumber);
     }
 
-    let float = (input_value - min) / (max - min);
+    // Values outside of the range are clipped, which happens when stretching between percentiles.
+    let float = ((input_value - min) / (max - min)).clamp(0.0, 1.0);
 
     let normal_float = gamma_correction(float)?;
 
-    let converted_integer = (normal_float * U8_MAX).round() as u8;
-
-    Ok(converted_integer)
+    Ok(T::from_normalized(normal_float))
 }
 
 #[cfg(test)]
@@ -502,7 +1004,9 @@ mod tests {
             dataset: ds,
             options: DatasetOptionsBuilder::new().build(),
             min_max: None,
-            elevation: None
+            elevation: None,
+            mask: None,
+            band_min_max: HashMap::new(),
         };
 
         let result = MosaicDataset::datasets_min_max(&mut dataset);
@@ -568,6 +1072,59 @@ mod tests {
         }));
     }
 
+    #[test]
+    fn convert_f32_to_u16() {
+        let output_value = f32_to_pixel::<u16>(0.5, 0.0, 1.0);
+
+        assert!(output_value.is_ok_and(|result| result == 47824));
+    }
+
+    #[test]
+    fn merging_bands_16_bit() {
+        let bands = vec![vec![0.5, f32::NAN], vec![0.5, f32::NAN], vec![0.5, f32::NAN]];
+
+        let min_max = BandsMinMax {
+            red_min: 0.0,
+            red_max: 1.0,
+            green_min: 0.0,
+            green_max: 1.0,
+            blue_min: 0.0,
+            blue_max: 1.0,
+        };
+
+        let merged_bands =
+            band_merger_depth::<u16>(&bands, &min_max).expect("Could not merge bands");
+
+        assert_eq!(merged_bands[0].a, u16::MAX);
+        assert_eq!(merged_bands[1].a, 0);
+        assert!(merged_bands[0].r > 255);
+    }
+
+    #[test]
+    fn pixels_to_rgba8() {
+        let pixels = vec![rgb::RGBA::<u16>::new(u16::MAX, 0, u16::MAX / 2, u16::MAX)];
+
+        let converted = to_rgba8(&pixels);
+
+        assert_eq!(converted, vec![rgb::RGBA8::new(255, 0, 127, 255)]);
+    }
+
+    #[test]
+    fn convert_f32_to_u8_clipped() {
+        let output_value = f32_to_u8(0.4, 0.1, 0.3);
+
+        assert!(output_value.is_ok_and(|result| result == 255));
+    }
+
+    #[test]
+    fn percentile_of_sorted() {
+        let values: Vec<f64> = (0..=100).map(|v| v as f64).collect();
+
+        assert_eq!(percentile(&values, 2.0), 2.0);
+        assert_eq!(percentile(&values, 98.0), 98.0);
+        assert_eq!(percentile(&values, 150.0), 100.0);
+    }
+
     #[test]
     fn dataset_to_rgb() {
         let mut current_dir = env::current_dir().expect("Current directory not set.");
@@ -584,6 +1141,8 @@ mod tests {
             options: DatasetOptionsBuilder::new().build(),
             min_max: None,
             elevation: None,
+            mask: None,
+            band_min_max: HashMap::new(),
         };
 
         let window_size = dataset.dataset.raster_size();
@@ -593,6 +1152,96 @@ mod tests {
         assert!(image_rgba.is_ok_and(|image_vec| image_vec.len() == 20 * 20));
     }
 
+    #[test]
+    fn mask_dataset_missing() {
+        let mut current_dir = env::current_dir().expect("Current directory not set.");
+
+        current_dir.pop();
+
+        current_dir.push("resources/test/Geotiff/gdal_tests/MOSAIC-0000018944-0000037888.tif");
+
+        let ds = Dataset::open(current_dir.as_path()).expect("Could not open dataset");
+
+        let mut dataset = MosaicedDataset {
+            dataset: ds,
+            options: DatasetOptionsBuilder::new().build(),
+            min_max: None,
+            elevation: None,
+            mask: None,
+            band_min_max: HashMap::new(),
+        };
+
+        let result = dataset.set_mask_dataset("/Nowhere/mask.tif");
+
+        assert!(result.is_err());
+        assert!(dataset.mask.is_none());
+    }
+
+    #[test]
+    fn tile_to_mask() {
+        let mut current_dir = env::current_dir().expect("Current directory not set.");
+
+        current_dir.pop();
+
+        current_dir.push("resources/test/Geotiff/gdal_tests/MOSAIC-0000018944-0000037888.tif");
+
+        let ds = Dataset::open(current_dir.as_path()).expect("Could not open dataset");
+
+        let mut dataset = MosaicedDataset {
+            dataset: ds,
+            options: DatasetOptionsBuilder::new().build(),
+            min_max: None,
+            elevation: None,
+            mask: None,
+            band_min_max: HashMap::new(),
+        };
+
+        let window_size = dataset.dataset.raster_size();
+
+        let rgba = dataset
+            .to_rgb((0, 0), window_size, (20, 20))
+            .expect("Could not read tile");
+
+        let mask = dataset
+            .to_mask((0, 0), window_size, (20, 20), &rgba, &MaskOptions::default())
+            .expect("Could not build mask");
+
+        assert_eq!(mask.len(), 20 * 20);
+    }
+
+    #[test]
+    fn mask_window_partly_outside_mask() {
+        let driver = gdal::DriverManager::get_driver_by_name("MEM").unwrap();
+
+        let mut dataset = driver.create_with_band_type::<u8, _>("", 8, 4, 1).unwrap();
+        dataset.set_geo_transform(&[0.0, 1.0, 0.0, 4.0, 0.0, -1.0]).unwrap();
+
+        // The mask only covers the left half of the dataset, and masks all of it.
+        let mut mask = driver.create_with_band_type::<f32, _>("", 4, 4, 1).unwrap();
+        mask.set_geo_transform(&[0.0, 1.0, 0.0, 4.0, 0.0, -1.0]).unwrap();
+        mask.rasterband(1).unwrap().fill(1.0, None).unwrap();
+
+        let data = read_mask_window(&dataset, &mask, (0, 0), (8, 4), (4, 2)).unwrap();
+        assert_eq!(data, vec![1.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0]);
+
+        let outside = read_mask_window(&dataset, &mask, (4, 0), (4, 4), (2, 2)).unwrap();
+        assert_eq!(outside, vec![0.0; 4]);
+    }
+
+    #[test]
+    fn mask_axis_clamping() {
+        let inside = clamp_mask_axis(2.0, 6.0, 10, 4).unwrap();
+        assert_eq!(inside, MaskAxis { window: 2, window_size: 4, offset: 0, size: 4 });
+
+        let before = clamp_mask_axis(-4.0, 4.0, 10, 4).unwrap();
+        assert_eq!(before, MaskAxis { window: 0, window_size: 4, offset: 2, size: 2 });
+
+        let after = clamp_mask_axis(8.0, 12.0, 10, 4).unwrap();
+        assert_eq!(after, MaskAxis { window: 8, window_size: 2, offset: 0, size: 2 });
+
+        assert!(clamp_mask_axis(10.0, 14.0, 10, 4).is_none());
+    }
+
     #[test]
     fn band_extraction() {
         let mut current_dir = env::current_dir().expect("Current directory not set.");
@@ -610,6 +1259,8 @@ mod tests {
             options: DatasetOptionsBuilder::new().build(),
             min_max: None,
             elevation: None,
+            mask: None,
+            band_min_max: HashMap::new(),
         };
 
         let red_band = dataset.dataset.rasterband(1).expect("Could not open band");
@@ -645,6 +1296,90 @@ mod tests {
         assert_eq!(merged_bands[0].r, 155);
     }
 
+    #[test]
+    fn interleaving_bands() {
+        let red = vec![0.0, 1.0];
+        let nir = vec![1.0, f32::NAN];
+        let pan = vec![f32::NAN, f32::NAN];
+
+        let raster = interleave_bands(&[red, nir], &[(0.0, 1.0), (0.0, 1.0)]);
+
+        assert_eq!(raster.channels, 2);
+        assert_eq!(raster.data, vec![0, 255, 255, 0]);
+        assert_eq!(raster.alpha, vec![255, 255]);
+
+        let raster = interleave_bands(&[pan], &[(0.0, 1.0)]);
+
+        assert_eq!(raster.channels, 1);
+        assert_eq!(raster.alpha, vec![0, 0]);
+    }
+
+    #[test]
+    fn band_raster_to_rgba() {
+        let raster = BandRaster {
+            data: vec![10, 20],
+            alpha: vec![255, 0],
+            channels: 1,
+        };
+
+        let rgba = raster.to_rgba();
+
+        assert_eq!(rgba, vec![rgb::RGBA8::new(10, 10, 10, 255), rgb::RGBA8::new(20, 20, 20, 0)]);
+    }
+
+    #[test]
+    fn band_indexes_are_checked() {
+        let driver = gdal::DriverManager::get_driver_by_name("MEM").unwrap();
+        let dataset = driver.create_with_band_type::<u8, _>("", 2, 2, 2).unwrap();
+
+        assert!(check_band_indexes(&dataset, &[2, 1]).is_ok());
+        assert!(matches!(check_band_indexes(&dataset, &[1, 2, 3]), Err(errors::GdalError::BadArgument(_))));
+        assert!(matches!(check_band_indexes(&dataset, &[0]), Err(errors::GdalError::BadArgument(_))));
+        assert!(matches!(check_band_indexes(&dataset, &[]), Err(errors::GdalError::BadArgument(_))));
+
+        let mut mosaic = MosaicedDataset {
+            dataset,
+            options: DatasetOptionsBuilder::new().build(),
+            min_max: None,
+            elevation: None,
+            mask: None,
+            band_min_max: HashMap::new(),
+        };
+
+        assert!(matches!(mosaic.to_bands(&[1, 2, 3], (0, 0), (2, 2), (2, 2)), Err(errors::GdalError::BadArgument(_))));
+        assert!(matches!(mosaic.to_rgb((0, 0), (2, 2), (2, 2)), Err(errors::GdalError::BadArgument(_))));
+    }
+
+    #[test]
+    fn single_band_to_bands() {
+        let mut current_dir = env::current_dir().expect("Current directory not set.");
+
+        current_dir.pop();
+
+        current_dir.push("resources/test/Geotiff/gdal_tests/MOSAIC-0000018944-0000037888.tif");
+
+        let ds = Dataset::open(current_dir.as_path()).expect("Could not open dataset");
+
+        let mut dataset = MosaicedDataset {
+            dataset: ds,
+            options: DatasetOptionsBuilder::new().build(),
+            min_max: None,
+            elevation: None,
+            mask: None,
+            band_min_max: HashMap::new(),
+        };
+
+        let window_size = dataset.dataset.raster_size();
+
+        let raster = dataset
+            .to_bands(&[2], (0, 0), window_size, (20, 20))
+            .expect("Could not read band");
+
+        assert_eq!(raster.channels, 1);
+        assert_eq!(raster.data.len(), 20 * 20);
+        assert!(dataset.band_min_max.contains_key(&2));
+    }
+
     #[test]
     fn option_builder_test() {
         let dataset_options = DatasetOptions {
@@ -652,11 +1387,21 @@ mod tests {
             red_band_index: 4,
             green_band_index: 3,
             blue_band_index: 2,
+            stretch: Stretch::Percentile {
+                lower: 2.0,
+                upper: 98.0,
+            },
+            height_reference: HeightReference::Egm2008,
         };
 
         let dataset_options_from_builder: DatasetOptions = DatasetOptionsBuilder::new()
             .set_scaling(2048, 1024)
             .set_band_indexes(4, 3, 2)
+            .set_stretch(Stretch::Percentile {
+                lower: 2.0,
+                upper: 98.0,
+            })
+            .set_height_reference(HeightReference::Egm2008)
             .build();
 
         assert_eq!(dataset_options, dataset_options_from_builder);
@@ -669,6 +1414,8 @@ mod tests {
             red_band_index: 1,
             green_band_index: 2,
             blue_band_index: 3,
+            stretch: Stretch::MinMax,
+            height_reference: HeightReference::Ellipsoid,
         };
 
         let dataset_options_from_builder: DatasetOptions = DatasetOptionsBuilder::new().build();
@@ -708,9 +1455,9 @@ mod tests {
 
         let options = DatasetOptions::builder().build();
 
-        let mosaic = MosaicedDataset { dataset: ds_vrt, options, min_max: None, elevation: Some(elevation_vrt) };
+        let mosaic = MosaicedDataset { dataset: ds_vrt, options, min_max: None, elevation: Some(elevation_vrt), mask: None, band_min_max: HashMap::new() };
 
-        let coordinates = mosaic.get_world_coordinates(8220.6, 12000.0 - 1262.028);
+        let coordinates = mosaic.get_world_coordinates(8220.6, 12000.0 - 1262.028, OutputFrame::Native);
 
         assert!(coordinates.is_ok());
         assert!((coordinates.as_ref().unwrap().0 - mountain_x).abs() <= f32::EPSILON.into());
@@ -718,6 +1465,21 @@ mod tests {
         assert!((coordinates.as_ref().unwrap().2 - mountain_height).abs() <= 2.0);
     }
 
+    #[test]
+    fn world_coordinates_without_crs_are_geographic() {
+        let driver = gdal::DriverManager::get_driver_by_name("MEM").unwrap();
+        let mut dataset = driver.create("", 10, 10, 1).unwrap();
+        dataset.set_geo_transform(&[9.0, 0.1, 0.0, 56.0, 0.0, -0.1]).unwrap();
+
+        let mosaic = MosaicedDataset { dataset, options: DatasetOptions::builder().build(), min_max: None, elevation: None, mask: None, band_min_max: HashMap::new() };
+
+        let (longitude, latitude, height) = mosaic.get_world_coordinates(5.0, 5.0, OutputFrame::Geodetic).unwrap();
+
+        assert!((longitude - 9.5).abs() < 1e-9);
+        assert!((latitude - 55.5).abs() < 1e-9);
+        assert!(height.abs() < 1e-9);
+    }
+
     #[test]
     fn elevation_vrt_creation() {
         let temp_dir = tempdir().unwrap();
@@ -741,17 +1503,21 @@ mod tests {
 
         let options = DatasetOptions::builder().build();
 
-        let mut mosaic = MosaicedDataset { dataset: ds_vrt, options, min_max: None, elevation: None };
+        let mut mosaic = MosaicedDataset { dataset: ds_vrt, options, min_max: None, elevation: None, mask: None, band_min_max: HashMap::new() };
 
         let elevation_vrt_path = temp_dir_path.clone();
 
-        let result = mosaic.set_elevation_dataset(elevation_path.to_str().unwrap(), elevation_vrt_path.to_str().unwrap());
-
-        assert!(result.is_ok());
+        let options = ElevationOptions { scale: 4.0, ..Default::default() };
+        let coverage = mosaic.set_elevation_dataset(elevation_path.to_str().unwrap(), elevation_vrt_path.to_str().unwrap(), &options).unwrap();
 
-        assert!(mosaic.elevation.is_some());
+        let elevation = mosaic.elevation.as_ref().unwrap();
+        let (width, height) = mosaic.dataset.raster_size();
 
-        assert_eq!(mosaic.elevation.unwrap().raster_count(), 1);
+        assert_eq!(elevation.raster_count(), 1);
+        assert_eq!(elevation.raster_size(), (width.div_ceil(4), height.div_ceil(4)));
+        assert_eq!(elevation.spatial_ref().unwrap().to_wkt().unwrap(), mosaic.dataset.spatial_ref().unwrap().to_wkt().unwrap());
+        assert!(coverage.covered > 0.0 && coverage.covered <= 1.0);
+        assert_eq!(coverage.gaps.is_empty(), coverage.covered == 1.0);
 
     }
 }
@@ -1 +1,4 @@
+pub mod elevation;
+pub mod geodesy;
 pub mod image_extractor;
+pub mod masking;
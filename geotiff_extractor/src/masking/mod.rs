@@ -0,0 +1,169 @@
+use rgb::RGBA8;
+
+/// Mask value of pixels where features should not be extracted.
+pub const MASKED: u8 = 0;
+/// Mask value of pixels where features can be extracted.
+pub const VALID: u8 = 255;
+
+/// Options for masking clouds, water and snow out of a tile.
+/// Every threshold is only applied when it is set.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MaskOptions {
+    /// The index of the near infrared band. Required by the NDWI threshold.
+    pub nir_band_index: Option<isize>,
+    /// Pixels with a normalized difference water index above the threshold are masked.
+    pub ndwi_threshold: Option<f32>,
+    /// Pixels where the mean of the 8-bit red, green and blue values is above the threshold are masked.
+    /// This catches clouds, snow and sea glint.
+    pub brightness_threshold: Option<u8>,
+}
+
+/// The per pixel data a mask is built from. Every provided slice must have the same length as `rgba`.
+pub struct MaskInputs<'a> {
+    /// The tile produced by the band merger. Pixels with an alpha of 0 are always masked.
+    pub rgba: &'a [RGBA8],
+    /// Values from a mask raster, where any non-zero value is masked.
+    pub mask_raster: Option<&'a [f32]>,
+    pub green: Option<&'a [f32]>,
+    pub nir: Option<&'a [f32]>,
+}
+
+/// Builds a mask with the same size as the tile, containing [`VALID`] or [`MASKED`] for each pixel.
+pub fn build_mask(inputs: &MaskInputs, options: &MaskOptions) -> Vec<u8> {
+    (0..inputs.rgba.len())
+        .map(|i| match is_masked(inputs, options, i) {
+            true => MASKED,
+            false => VALID,
+        })
+        .collect()
+}
+
+fn is_masked(inputs: &MaskInputs, options: &MaskOptions, i: usize) -> bool {
+    let pixel = inputs.rgba[i];
+
+    if pixel.a == 0 {
+        return true;
+    }
+
+    if let Some(mask_raster) = inputs.mask_raster {
+        if mask_raster[i] != 0.0 && !mask_raster[i].is_nan() {
+            return true;
+        }
+    }
+
+    if let Some(threshold) = options.brightness_threshold {
+        let brightness = (pixel.r as u16 + pixel.g as u16 + pixel.b as u16) / 3;
+
+        if brightness > threshold as u16 {
+            return true;
+        }
+    }
+
+    if let (Some(threshold), Some(green), Some(nir)) =
+        (options.ndwi_threshold, inputs.green, inputs.nir)
+    {
+        if ndwi(green[i], nir[i]).is_some_and(|index| index > threshold) {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// The normalized difference water index. Returns [`None`] if it is undefined for the pixel.
+pub fn ndwi(green: f32, nir: f32) -> Option<f32> {
+    let sum = green + nir;
+
+    if sum == 0.0 || sum.is_nan() {
+        return None;
+    }
+
+    Some((green - nir) / sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opaque(value: u8) -> RGBA8 {
+        RGBA8::new(value, value, value, 255)
+    }
+
+    #[test]
+    fn transparent_pixels_are_masked() {
+        let rgba = vec![opaque(10), RGBA8::new(10, 10, 10, 0)];
+        let inputs = MaskInputs {
+            rgba: &rgba,
+            mask_raster: None,
+            green: None,
+            nir: None,
+        };
+
+        let mask = build_mask(&inputs, &MaskOptions::default());
+
+        assert_eq!(mask, vec![VALID, MASKED]);
+    }
+
+    #[test]
+    fn mask_raster_is_applied() {
+        let rgba = vec![opaque(10), opaque(10), opaque(10)];
+        let mask_raster = vec![0.0, 1.0, f32::NAN];
+        let inputs = MaskInputs {
+            rgba: &rgba,
+            mask_raster: Some(&mask_raster),
+            green: None,
+            nir: None,
+        };
+
+        let mask = build_mask(&inputs, &MaskOptions::default());
+
+        assert_eq!(mask, vec![VALID, MASKED, VALID]);
+    }
+
+    #[test]
+    fn bright_pixels_are_masked() {
+        let rgba = vec![opaque(100), opaque(250)];
+        let inputs = MaskInputs {
+            rgba: &rgba,
+            mask_raster: None,
+            green: None,
+            nir: None,
+        };
+        let options = MaskOptions {
+            brightness_threshold: Some(240),
+            ..Default::default()
+        };
+
+        let mask = build_mask(&inputs, &options);
+
+        assert_eq!(mask, vec![VALID, MASKED]);
+    }
+
+    #[test]
+    fn water_is_masked() {
+        let rgba = vec![opaque(10), opaque(10)];
+        // Water reflects green light and absorbs near infrared, vegetation does the opposite.
+        let green = vec![0.1, 0.05];
+        let nir = vec![0.02, 0.4];
+        let inputs = MaskInputs {
+            rgba: &rgba,
+            mask_raster: None,
+            green: Some(&green),
+            nir: Some(&nir),
+        };
+        let options = MaskOptions {
+            nir_band_index: Some(4),
+            ndwi_threshold: Some(0.0),
+            ..Default::default()
+        };
+
+        let mask = build_mask(&inputs, &options);
+
+        assert_eq!(mask, vec![MASKED, VALID]);
+    }
+
+    #[test]
+    fn ndwi_undefined() {
+        assert!(ndwi(0.0, 0.0).is_none());
+    }
+}
@@ -0,0 +1,464 @@
+use nalgebra::{DMatrix, DVector, Matrix3, Rotation3, Vector3};
+use opencv::{
+    calib3d::undistort_points_def,
+    core::{Point2d, Vector},
+};
+use rand::{seq::index::sample, Rng};
+
+use super::{
+    dist_coeffs_mat, solution_quality, split_correspondences, Cmat, ImgObjCorrespondence, MatError,
+    PNPRANSACSolution, PnpRansacOptions, Pose, Vector3x1,
+};
+
+/// The amount of correspondences in a minimal sample, when either the rotation or the position is known
+const SAMPLE_SIZE: usize = 2;
+/// The minimum amount of inliers of a solution, so that at least one correspondence verifies the minimal sample
+const MIN_INLIERS: usize = SAMPLE_SIZE + 1;
+/// Singular values below this are treated as zero when solving for the translation
+const SVD_EPSILON: f64 = 1e-12;
+
+/// A correspondence with the image point in normalized camera coordinates
+struct Observation {
+    object: Vector3<f64>,
+    image: Vector3<f64>,
+}
+
+/// Estimates the translation of the camera when its rotation is known, e.g. from a star tracker.
+///
+/// ## Parameters
+/// * point_correspondences: a slice of 3d-to-2d point correspondences, at least 2 are needed
+/// * camera_intrinsic: the camera calibration matrix 3X3
+/// * dist_coeffs: distortion coefficients from camera calibration, if [`None`], zero distortion is assumed
+/// * rvec: the known rotation vector, from object coordinates to camera coordinates
+/// * options: only `iter_count`, `reproj_thres` and `confidence` are used, fewer iterations are run once `confidence` is reached
+/// * rng: draws the minimal samples, a seeded rng makes the solution reproducible
+/// ## Returns
+/// A solution with `rvec` and the estimated translation vector, returns `Ok(None)` if no solution has enough inliers
+/// ## Errors
+/// If there are too few correspondences, or the image points cannot be undistorted
+/// ## Notes
+/// The quality of the solution is found as for a full pose, so the covariance includes the rotation
+pub fn pnp_solver_known_rotation(
+    point_correspondences: &[ImgObjCorrespondence],
+    camera_intrinsic: &Cmat<f64>,
+    dist_coeffs: Option<&[f64]>,
+    rvec: &Vector3x1,
+    options: &PnpRansacOptions,
+    rng: &mut impl Rng,
+) -> Result<Option<PNPRANSACSolution>, MatError> {
+    let rotation = Rotation3::from_scaled_axis(*rvec);
+
+    constrained_ransac(
+        point_correspondences,
+        camera_intrinsic,
+        dist_coeffs,
+        options,
+        rng,
+        |observations| translation_from_rotation(&rotation, observations),
+    )
+}
+
+/// Estimates the rotation of the camera when its position is known, e.g. from GNSS or an ephemeris.
+///
+/// ## Parameters
+/// * point_correspondences: a slice of 3d-to-2d point correspondences, at least 2 are needed
+/// * camera_intrinsic: the camera calibration matrix 3X3
+/// * dist_coeffs: distortion coefficients from camera calibration, if [`None`], zero distortion is assumed
+/// * position: the known position of the camera center in object coordinates
+/// * options: only `iter_count`, `reproj_thres` and `confidence` are used, fewer iterations are run once `confidence` is reached
+/// * rng: draws the minimal samples, a seeded rng makes the solution reproducible
+/// ## Returns
+/// A solution with the estimated rotation vector, and the translation vector placing the camera at `position`.
+/// Returns `Ok(None)` if no solution has enough inliers
+/// ## Errors
+/// If there are too few correspondences, or the image points cannot be undistorted
+/// ## Notes
+/// The quality of the solution is found as for a full pose, so the covariance includes the translation
+pub fn pnp_solver_known_position(
+    point_correspondences: &[ImgObjCorrespondence],
+    camera_intrinsic: &Cmat<f64>,
+    dist_coeffs: Option<&[f64]>,
+    position: &Vector3x1,
+    options: &PnpRansacOptions,
+    rng: &mut impl Rng,
+) -> Result<Option<PNPRANSACSolution>, MatError> {
+    constrained_ransac(
+        point_correspondences,
+        camera_intrinsic,
+        dist_coeffs,
+        options,
+        rng,
+        |observations| rotation_from_position(position, observations),
+    )
+}
+
+/// Finds the pose with the most inliers from minimal samples, and refits it to the inliers.
+fn constrained_ransac(
+    point_correspondences: &[ImgObjCorrespondence],
+    camera_intrinsic: &Cmat<f64>,
+    dist_coeffs: Option<&[f64]>,
+    options: &PnpRansacOptions,
+    rng: &mut impl Rng,
+    fit: impl Fn(&[&Observation]) -> Option<Pose>,
+) -> Result<Option<PNPRANSACSolution>, MatError> {
+    if point_correspondences.len() < SAMPLE_SIZE {
+        return Err(MatError::TooFewCorrespondences {
+            required: SAMPLE_SIZE,
+            actual: point_correspondences.len(),
+        });
+    }
+
+    let dist_coeffs = dist_coeffs_mat(dist_coeffs)?;
+    let observations = normalize(point_correspondences, camera_intrinsic, &dist_coeffs)?;
+
+    // The threshold is moved to normalized coordinates with the mean focal length.
+    let camera = camera_intrinsic.to_smatrix::<3, 3>()?;
+    let threshold = options.reproj_thres as f64 / ((camera[(0, 0)] + camera[(1, 1)]) / 2f64);
+
+    let inliers_of = |pose: &Pose| -> Vec<usize> {
+        observations
+            .iter()
+            .enumerate()
+            .filter(|(_, observation)| {
+                reprojection_error(pose, observation).is_some_and(|error| error <= threshold)
+            })
+            .map(|(i, _)| i)
+            .collect()
+    };
+
+    let mut best: Vec<usize> = Vec::new();
+    let mut iterations = options.iter_count.max(0) as usize;
+    let mut i = 0;
+
+    while i < iterations {
+        i += 1;
+
+        let minimal: Vec<&Observation> = sample(rng, observations.len(), SAMPLE_SIZE)
+            .iter()
+            .map(|i| &observations[i])
+            .collect();
+
+        let inliers = match fit(&minimal) {
+            Some(pose) => inliers_of(&pose),
+            None => continue,
+        };
+
+        if inliers.len() > best.len() {
+            best = inliers;
+            iterations = iterations.min(required_iterations(
+                best.len() as f64 / observations.len() as f64,
+                options.confidence,
+            ));
+        }
+    }
+
+    if best.len() < MIN_INLIERS {
+        return Ok(None);
+    }
+
+    // Refit the pose to all of the inliers, and find the inliers of the refined pose.
+    let refit = |inliers: &[usize]| {
+        fit(&inliers
+            .iter()
+            .map(|&i| &observations[i])
+            .collect::<Vec<_>>())
+    };
+
+    let (pose, inliers) = match refit(&best) {
+        Some(pose) => {
+            let inliers = inliers_of(&pose);
+            (pose, inliers)
+        }
+        None => return Ok(None),
+    };
+
+    if inliers.len() < MIN_INLIERS {
+        return Ok(None);
+    }
+
+    let rvec = Cmat::from_nalgebra(&pose.rvec)?;
+    let tvec = Cmat::from_nalgebra(&pose.tvec)?;
+    let indices: Vec<[i32; 1]> = inliers.iter().map(|&i| [i as i32]).collect();
+    let inliers = Cmat::from_2d_slice(&indices)?;
+
+    let quality = solution_quality(
+        point_correspondences,
+        &inliers,
+        &rvec,
+        &tvec,
+        camera_intrinsic,
+        &dist_coeffs,
+        options.reproj_thres as f64,
+    )?;
+
+    Ok(Some(PNPRANSACSolution {
+        rvec,
+        tvec,
+        inliers,
+        quality,
+    }))
+}
+
+/// The amount of iterations needed to draw an outlier free sample with the given confidence
+fn required_iterations(inlier_ratio: f64, confidence: f64) -> usize {
+    let outlier_free = inlier_ratio.powi(SAMPLE_SIZE as i32);
+
+    if outlier_free >= 1f64 {
+        return 1;
+    }
+
+    ((1f64 - confidence).ln() / (1f64 - outlier_free).ln()).ceil() as usize
+}
+
+/// Undistorts the image points to normalized camera coordinates
+fn normalize(
+    point_correspondences: &[ImgObjCorrespondence],
+    camera_intrinsic: &Cmat<f64>,
+    dist_coeffs: &Cmat<f64>,
+) -> Result<Vec<Observation>, MatError> {
+    let (obj_points, img_points) = split_correspondences(point_correspondences.iter());
+    let mut normalized: Vector<Point2d> = Vector::new();
+
+    undistort_points_def(&img_points, &mut normalized, camera_intrinsic, dist_coeffs)
+        .map_err(MatError::Opencv)?;
+
+    Ok(obj_points
+        .iter()
+        .zip(normalized.iter())
+        .map(|(object, image)| Observation {
+            object: Vector3::new(object.x, object.y, object.z),
+            image: Vector3::new(image.x, image.y, 1f64),
+        })
+        .collect())
+}
+
+/// The reprojection error in normalized camera coordinates, or [`None`] if the point is behind the camera
+fn reprojection_error(pose: &Pose, observation: &Observation) -> Option<f64> {
+    let point = Rotation3::from_scaled_axis(pose.rvec) * observation.object + pose.tvec;
+
+    match point.z > 0f64 {
+        true => Some((point.xy() / point.z - observation.image.xy()).norm()),
+        false => None,
+    }
+}
+
+/// Solves `image × (R * object + t) = 0` for `t` in the least squares sense.
+/// Each observation gives two independent equations, so two observations are enough.
+fn translation_from_rotation(
+    rotation: &Rotation3<f64>,
+    observations: &[&Observation],
+) -> Option<Pose> {
+    let mut a = DMatrix::zeros(observations.len() * 2, 3);
+    let mut b = DVector::zeros(observations.len() * 2);
+
+    for (i, observation) in observations.iter().enumerate() {
+        let cross = observation.image.cross_matrix();
+        let rotated = rotation * observation.object;
+
+        for row in 0..2 {
+            a.row_mut(i * 2 + row).copy_from(&cross.row(row));
+            b[i * 2 + row] = -(cross.row(row) * rotated)[(0, 0)];
+        }
+    }
+
+    let svd = a.svd(true, true);
+
+    if svd.rank(SVD_EPSILON) < 3 {
+        return None;
+    }
+
+    let t = svd.solve(&b, SVD_EPSILON).ok()?;
+
+    Some(Pose {
+        rvec: rotation.scaled_axis(),
+        tvec: Vector3::new(t[0], t[1], t[2]),
+    })
+}
+
+/// Finds the rotation aligning the directions from the camera to the object points with the viewing rays,
+/// by solving Wahba's problem with an SVD. The translation is `-R * position`.
+fn rotation_from_position(position: &Vector3x1, observations: &[&Observation]) -> Option<Pose> {
+    let mut correlation = Matrix3::zeros();
+
+    for observation in observations {
+        let direction = (observation.object - position).try_normalize(f64::EPSILON)?;
+        let ray = observation.image.normalize();
+
+        correlation += ray * direction.transpose();
+    }
+
+    let svd = correlation.svd(true, true);
+    let (u, v_t) = (svd.u?, svd.v_t?);
+
+    // Two parallel directions do not determine the rotation about them.
+    if svd.singular_values[1] < SVD_EPSILON {
+        return None;
+    }
+
+    let mut correction = Matrix3::identity();
+    correction[(2, 2)] = (u * v_t).determinant().signum();
+
+    let rotation = Rotation3::from_matrix_unchecked(u * correction * v_t);
+
+    Some(Pose {
+        rvec: rotation.scaled_axis(),
+        tvec: -(rotation * position),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::{
+        calib3d::project_points,
+        core::{Mat, Point3d},
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    const RVEC: [f64; 3] = [0.1, -0.2, 0.05];
+    const TVEC: [f64; 3] = [0.5, -0.3, 2.0];
+
+    /// Correspondences of a camera at `RVEC` and `TVEC`, where every fifth image point is moved far away
+    fn correspondences(outliers: bool) -> (Vec<ImgObjCorrespondence>, Cmat<f64>) {
+        let camera = Cmat::from_2d_slice(&[
+            [800f64, 0f64, 320f64],
+            [0f64, 800f64, 240f64],
+            [0f64, 0f64, 1f64],
+        ])
+        .unwrap();
+        let obj_points: Vector<Point3d> = (0..40)
+            .map(|i| {
+                let (x, y) = ((i % 8) as f64 - 4f64, (i / 8) as f64 - 2f64);
+                Point3d::new(x, y, 10f64 + 0.5 * (x + y).cos())
+            })
+            .collect();
+        let mut img_points: Vector<Point2d> = Vector::new();
+
+        project_points(
+            &obj_points,
+            &Vector::from_slice(&RVEC),
+            &Vector::from_slice(&TVEC),
+            &camera,
+            &Mat::default(),
+            &mut img_points,
+            &mut Mat::default(),
+            0f64,
+        )
+        .unwrap();
+
+        let correspondences = obj_points
+            .iter()
+            .zip(img_points.iter())
+            .enumerate()
+            .map(|(i, (obj, img))| match outliers && i % 5 == 0 {
+                true => ImgObjCorrespondence::new(obj, Point2d::new(img.x + 150f64, img.y - 90f64)),
+                false => ImgObjCorrespondence::new(obj, img),
+            })
+            .collect();
+
+        (correspondences, camera)
+    }
+
+    fn assert_pose(solution: &PNPRANSACSolution) {
+        let pose = solution.pose().unwrap();
+
+        assert!(
+            (pose.rvec - Vector3::from(RVEC)).norm() < 1e-6,
+            "{:?}",
+            pose.rvec
+        );
+        assert!(
+            (pose.tvec - Vector3::from(TVEC)).norm() < 1e-6,
+            "{:?}",
+            pose.tvec
+        );
+    }
+
+    #[test]
+    fn known_rotation() {
+        let (correspondences, camera) = correspondences(false);
+
+        let solution = pnp_solver_known_rotation(
+            &correspondences,
+            &camera,
+            None,
+            &Vector3::from(RVEC),
+            &PnpRansacOptions::default(),
+            &mut StdRng::seed_from_u64(0),
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_pose(&solution);
+        assert_eq!(solution.inliers.rows(), 40);
+    }
+
+    #[test]
+    fn known_rotation_rejects_outliers() {
+        let (correspondences, camera) = correspondences(true);
+
+        let solution = pnp_solver_known_rotation(
+            &correspondences,
+            &camera,
+            None,
+            &Vector3::from(RVEC),
+            &PnpRansacOptions::default(),
+            &mut StdRng::seed_from_u64(0),
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_pose(&solution);
+        assert_eq!(solution.inliers.rows(), 32);
+    }
+
+    #[test]
+    fn known_position_rejects_outliers() {
+        let (correspondences, camera) = correspondences(true);
+        let rotation = Rotation3::from_scaled_axis(Vector3::from(RVEC));
+        let position = -(rotation.inverse() * Vector3::from(TVEC));
+
+        let solution = pnp_solver_known_position(
+            &correspondences,
+            &camera,
+            None,
+            &position,
+            &PnpRansacOptions::default(),
+            &mut StdRng::seed_from_u64(0),
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_pose(&solution);
+        assert_eq!(solution.inliers.rows(), 32);
+    }
+
+    #[test]
+    fn too_few_correspondences() {
+        let (correspondences, camera) = correspondences(false);
+
+        let result = pnp_solver_known_position(
+            &correspondences[..1],
+            &camera,
+            None,
+            &Vector3::zeros(),
+            &PnpRansacOptions::default(),
+            &mut StdRng::seed_from_u64(0),
+        );
+
+        assert!(matches!(
+            result,
+            Err(MatError::TooFewCorrespondences {
+                required: 2,
+                actual: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn iterations_from_inlier_ratio() {
+        assert_eq!(required_iterations(1.0, 0.99), 1);
+        // log(0.01) / log(1 - 0.25) = 16.008
+        assert_eq!(required_iterations(0.5, 0.99), 17);
+    }
+}
@@ -1,10 +1,15 @@
 use std::marker::PhantomData;
 
+use nalgebra::{DMatrix, Dim, Matrix, Matrix3, Matrix6, RawStorage, SMatrix, Vector3};
 use opencv::{
-    calib3d::{find_homography, solve_pnp_ransac, SolvePnPMethod, RANSAC},
+    calib3d::{
+        find_homography, project_points, solve_pnp, solve_pnp_ransac, solve_pnp_ransac_1,
+        solve_pnp_refine_lm_def, solve_pnp_refine_vvs_def, RANSAC,
+    },
     core::{
-        Point2d, Point2f, Point3d, Scalar, Size2i, ToInputArray, ToOutputArray, Vec4b, Vector,
-        BORDER_CONSTANT, CV_8UC4,
+        gemm, invert, transpose, Point2d, Point2f, Point3d, Scalar, Size2i, ToInputArray,
+        ToOutputArray, Vec4b, VecN, Vector, BORDER_CONSTANT, CV_16UC4, CV_32FC4, CV_8U, CV_8UC1,
+        CV_8UC4, CV_CN_SHIFT, DECOMP_LU,
     },
     imgproc::{warp_perspective, INTER_LINEAR},
     prelude::*,
@@ -12,6 +17,11 @@ use opencv::{
 };
 use rgb::*;
 
+use pnp_method::{PnpMethod, RansacSampler};
+
+pub mod constrained;
+pub mod pnp_method;
+
 pub trait PixelElemType {
     fn to_cv_const(&self) -> i32;
 }
@@ -21,6 +31,18 @@ impl PixelElemType for BGRA {
         CV_8UC4
     }
 }
+pub struct BGRA16;
+impl PixelElemType for BGRA16 {
+    fn to_cv_const(&self) -> i32 {
+        CV_16UC4
+    }
+}
+pub struct BGRAF32;
+impl PixelElemType for BGRAF32 {
+    fn to_cv_const(&self) -> i32 {
+        CV_32FC4
+    }
+}
 
 #[derive(Clone, Copy)]
 pub enum HomographyMethod {
@@ -39,17 +61,159 @@ pub enum MatError {
     Empty,
     /// Matrix is not rectangular (columns or rows with differing lengths)
     Jagged,
+    /// The row or column is outside of the matrix
+    OutOfBounds { row: i32, col: i32 },
+    /// The element type does not match the type of the inner Mat
+    TypeMismatch { expected: i32, actual: i32 },
+    /// The dimensions of the matrices are incompatible with the operation
+    DimensionMismatch,
+    /// The matrix cannot be inverted
+    Singular,
+    /// Too few point correspondences for the chosen method
+    TooFewCorrespondences { required: usize, actual: usize },
+    /// The chosen method requires coplanar object points
+    NotCoplanar,
     /// An unknown error
     Unknown,
 }
 
+/// A 3x3 matrix, such as the camera calibration matrix or a homography
+pub type CameraMatrix = Matrix3<f64>;
+/// A 3x1 column vector, such as a rotation or translation vector
+pub type Vector3x1 = Vector3<f64>;
+
+/// The 6x6 covariance of a pose, ordered as the rotation vector followed by the translation vector
+pub type PoseCovariance = Matrix6<f64>;
+
+/// The maximum reprojection RMS of a [`QualityFlag::Good`] solution, as a fraction of the RANSAC reprojection threshold
+const GOOD_RMS_FRACTION: f64 = 0.5;
+/// The minimum inlier ratio of a [`QualityFlag::Good`] solution
+const GOOD_INLIER_RATIO: f64 = 0.5;
+/// Solutions with an inlier ratio below this are [`QualityFlag::Poor`]
+const POOR_INLIER_RATIO: f64 = 0.2;
+/// The number of pose parameters estimated by PnP
+const POSE_DOF: usize = 6;
+
+/// The minimum amount of inliers needed to refine a pose
+pub const MIN_REFINEMENT_POINTS: usize = 4;
+
 #[derive(Debug)]
 pub struct PNPRANSACSolution {
     pub rvec: Cmat<f64>,
     pub tvec: Cmat<f64>,
     pub inliers: Cmat<i32>,
+    pub quality: SolutionQuality,
+}
+
+impl PNPRANSACSolution {
+    /// The pose of the solution, e.g. to use as the extrinsic guess for the next frame
+    pub fn pose(&self) -> Result<Pose, MatError> {
+        Ok(Pose {
+            rvec: self.rvec.to_smatrix()?,
+            tvec: self.tvec.to_smatrix()?,
+        })
+    }
+}
+
+/// A rotation vector and translation vector, as used by OpenCV
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pose {
+    pub rvec: Vector3x1,
+    pub tvec: Vector3x1,
+}
+
+/// Non-linear refinement of a pose over the RANSAC inliers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PnpRefinement {
+    /// Levenberg-Marquardt minimization, see `solvePnPRefineLM`
+    LevenbergMarquardt,
+    /// Virtual visual servoing, see `solvePnPRefineVVS`
+    VirtualVisualServoing,
+}
+
+/// Parameters for [`pnp_solver_ransac`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PnpRansacOptions {
+    /// How many iteration the ransac algorithm should perform, 1000 by default
+    pub iter_count: i32,
+    /// The maximum reprojection error in pixels for a correspondence to be an inlier
+    pub reproj_thres: f32,
+    /// The probability that the algorithm produces a useful result
+    pub confidence: f64,
+    /// The method used to solve the PnP problem, ignored by the USAC samplers
+    pub method: PnpMethod,
+    /// How hypotheses are sampled and scored
+    pub sampler: RansacSampler,
+    /// Refines the solution over the inliers if set
+    pub refinement: Option<PnpRefinement>,
+    /// Seeds the solver with a prior pose, e.g. the pose of the previous frame.
+    /// Only iterative methods make use of the guess, and it is ignored by the USAC samplers
+    pub extrinsic_guess: Option<Pose>,
+}
+
+/// Parameters for [`pnp_solver`]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PnpOptions {
+    pub method: PnpMethod,
+    /// Refines the solution if set
+    pub refinement: Option<PnpRefinement>,
+    /// Seeds the solver with a prior pose. Only iterative methods make use of the guess
+    pub extrinsic_guess: Option<Pose>,
+}
+
+impl Default for PnpRansacOptions {
+    fn default() -> Self {
+        PnpRansacOptions {
+            iter_count: 1000,
+            reproj_thres: 8.0,
+            confidence: 0.99,
+            method: PnpMethod::default(),
+            sampler: RansacSampler::default(),
+            refinement: None,
+            extrinsic_guess: None,
+        }
+    }
+}
+
+/// A coarse classification of how trustworthy a PnP solution is, ordered from best to worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QualityFlag {
+    /// Low reprojection error and a majority of inliers
+    Good,
+    /// Usable, but should be given less weight
+    Degraded,
+    /// Too few inliers to trust the solution
+    Poor,
+}
+
+/// How well a PnP solution fits its correspondences
+#[derive(Debug, Clone, PartialEq)]
+pub struct SolutionQuality {
+    /// The root mean square reprojection error over the inliers in pixels
+    pub reprojection_rms: f64,
+    /// The covariance of the pose, estimated from the Jacobian of the reprojection.
+    /// [`None`] if there are too few inliers to estimate it, or the pose is not observable
+    pub covariance: Option<PoseCovariance>,
+    /// The amount of inliers divided by the amount of correspondences
+    pub inlier_ratio: f64,
+    pub flag: QualityFlag,
+}
+
+impl SolutionQuality {
+    /// The 3x3 covariance of the rotation vector in radians squared
+    pub fn attitude_covariance(&self) -> Option<Matrix3<f64>> {
+        self.covariance
+            .map(|covariance| covariance.fixed_view::<3, 3>(0, 0).into_owned())
+    }
+
+    /// The 3x3 covariance of the translation vector in object point units squared
+    pub fn position_covariance(&self) -> Option<Matrix3<f64>> {
+        self.covariance
+            .map(|covariance| covariance.fixed_view::<3, 3>(3, 3).into_owned())
+    }
 }
 /// 3D object point and its corresponding 2d image point
+#[derive(Debug, Clone, Copy)]
 pub struct ImgObjCorrespondence {
     pub obj_point: Point3d,
     pub img_point: Point2d,
@@ -90,11 +254,19 @@ impl<T> Cmat<T> {
         }
     }
 
-    /// Creates a Cmat from a copied 1-dimensional slice
+    /// Creates a Cmat from a copied 2-dimensional slice
+    ///
+    /// ## Errors
+    /// Will return [`MatError::Jagged`] if the rows have differing lengths
     pub fn from_2d_slice(slice: &[impl AsRef<[T]>]) -> Result<Self, MatError>
     where
         T: DataType,
     {
+        let width = slice.first().map(|row| row.as_ref().len());
+        if slice.iter().any(|row| Some(row.as_ref().len()) != width) {
+            return Err(MatError::Jagged);
+        }
+
         let mat = Mat::from_slice_2d::<T>(slice).map_err(MatError::Opencv)?;
         Cmat::new(mat)
     }
@@ -114,7 +286,10 @@ impl<T: DataType> Cmat<T> {
     pub fn new(mat: Mat) -> Result<Self, MatError> {
         match T::opencv_type() == mat.typ() {
             true => Ok(Cmat::from_mat(mat)?),
-            false => Err(MatError::Empty),
+            false => Err(MatError::TypeMismatch {
+                expected: T::opencv_type(),
+                actual: mat.typ(),
+            }),
         }
     }
 
@@ -122,20 +297,179 @@ impl<T: DataType> Cmat<T> {
         // let res =
         Cmat::new(opencv::imgcodecs::imread(filename, flags).map_err(MatError::Opencv)?)
     }
+
+    /// The number of rows in the matrix
+    pub fn rows(&self) -> i32 {
+        self.mat.rows()
+    }
+
+    /// The number of columns in the matrix
+    pub fn cols(&self) -> i32 {
+        self.mat.cols()
+    }
+
+    fn check_bounds(&self, row: i32, col: i32) -> Result<(), MatError> {
+        match (0..self.rows()).contains(&row) && (0..self.cols()).contains(&col) {
+            true => Ok(()),
+            false => Err(MatError::OutOfBounds { row, col }),
+        }
+    }
+
     /// Checked element access
     ///
     /// ## Errors
-    /// Will return [`MatError::OutOfBounds`] if either row or column exceeds matrix width and size respectively.
-    /// If the type T does not match the inner Mat's type, an error is returned
+    /// Will return [`MatError::OutOfBounds`] if either row or column exceeds the number of rows and columns respectively.
     pub fn at_2d(&self, row: i32, col: i32) -> Result<&T, MatError> {
-        let size = self.mat.size().map_err(|_err| MatError::Unknown)?;
-        if (row > size.width) || (col > size.height) {
-            return Err(MatError::Opencv(Error::new(-211, "")));
-        }
+        self.check_bounds(row, col)?;
 
         self.mat.at_2d::<T>(row, col).map_err(MatError::Opencv)
     }
 
+    /// Checked mutable element access
+    ///
+    /// ## Errors
+    /// Will return [`MatError::OutOfBounds`] if either row or column exceeds the number of rows and columns respectively.
+    pub fn at_2d_mut(&mut self, row: i32, col: i32) -> Result<&mut T, MatError> {
+        self.check_bounds(row, col)?;
+
+        self.mat.at_2d_mut::<T>(row, col).map_err(MatError::Opencv)
+    }
+
+    /// Returns the elements of a row
+    ///
+    /// ## Errors
+    /// Will return [`MatError::OutOfBounds`] if the row does not exist
+    pub fn row(&self, row: i32) -> Result<&[T], MatError> {
+        self.check_bounds(row, 0)?;
+
+        self.mat.at_row::<T>(row).map_err(MatError::Opencv)
+    }
+
+    /// Returns a copy of the elements of a column
+    ///
+    /// ## Errors
+    /// Will return [`MatError::OutOfBounds`] if the column does not exist
+    pub fn col(&self, col: i32) -> Result<Vec<T>, MatError> {
+        (0..self.rows())
+            .map(|row| self.at_2d(row, col).copied())
+            .collect()
+    }
+
+    /// Iterates over the rows of the matrix
+    pub fn iter_rows(&self) -> impl Iterator<Item = Result<&[T], MatError>> + '_ {
+        (0..self.rows()).map(|row| self.row(row))
+    }
+
+    /// Iterates over copies of the columns of the matrix
+    pub fn iter_cols(&self) -> impl Iterator<Item = Result<Vec<T>, MatError>> + '_ {
+        (0..self.cols()).map(|col| self.col(col))
+    }
+
+    /// Copies the matrix into a vector of rows
+    pub fn to_vec_2d(&self) -> Result<Vec<Vec<T>>, MatError> {
+        self.iter_rows().map(|row| Ok(row?.to_vec())).collect()
+    }
+
+    /// Converts the matrix to another element type with the same number of channels
+    ///
+    /// Every element is computed as `alpha * element + beta`, e.g. an alpha of `1.0 / 257.0` converts 16-bit to 8-bit.
+    pub fn convert_to<U: DataType>(&self, alpha: f64, beta: f64) -> Result<Cmat<U>, MatError> {
+        let mut mat = Mat::default();
+        self.mat
+            .convert_to(&mut mat, U::opencv_type(), alpha, beta)
+            .map_err(MatError::Opencv)?;
+        Cmat::new(mat)
+    }
+
+    /// Returns the transposed matrix
+    pub fn transpose(&self) -> Result<Cmat<T>, MatError> {
+        let mut mat = Mat::default();
+        transpose(self, &mut mat).map_err(MatError::Opencv)?;
+        Cmat::new(mat)
+    }
+
+    /// Computes the matrix product `self * rhs`
+    ///
+    /// ## Errors
+    /// Will return [`MatError::DimensionMismatch`] if the number of columns in `self` differs from the number of rows in `rhs`.
+    /// Only floating point matrices (`f32` and `f64`) are supported by OpenCV, other types will return an error.
+    pub fn matmul(&self, rhs: &Cmat<T>) -> Result<Cmat<T>, MatError> {
+        if self.cols() != rhs.rows() {
+            return Err(MatError::DimensionMismatch);
+        }
+
+        let mut mat = Mat::default();
+        gemm(self, rhs, 1f64, &Mat::default(), 0f64, &mut mat, 0).map_err(MatError::Opencv)?;
+        Cmat::new(mat)
+    }
+
+    /// Computes the inverse of a square matrix using LU decomposition
+    ///
+    /// ## Errors
+    /// Will return [`MatError::DimensionMismatch`] if the matrix is not square, and [`MatError::Singular`] if it cannot be inverted.
+    /// Only floating point matrices (`f32` and `f64`) are supported by OpenCV, other types will return an error.
+    pub fn inverse(&self) -> Result<Cmat<T>, MatError> {
+        if self.rows() != self.cols() {
+            return Err(MatError::DimensionMismatch);
+        }
+
+        let mut mat = Mat::default();
+        let determinant = invert(self, &mut mat, DECOMP_LU).map_err(MatError::Opencv)?;
+
+        if determinant == 0f64 {
+            return Err(MatError::Singular);
+        }
+
+        Cmat::new(mat)
+    }
+
+    /// Converts the matrix to a fixed size nalgebra matrix
+    ///
+    /// ## Errors
+    /// Will return [`MatError::DimensionMismatch`] if the matrix is not `R`x`C`
+    pub fn to_smatrix<const R: usize, const C: usize>(&self) -> Result<SMatrix<T, R, C>, MatError>
+    where
+        T: nalgebra::Scalar,
+    {
+        if self.rows() as usize != R || self.cols() as usize != C {
+            return Err(MatError::DimensionMismatch);
+        }
+
+        let rows = self.to_vec_2d()?;
+
+        Ok(SMatrix::from_fn(|row, col| rows[row][col].clone()))
+    }
+
+    /// Converts the matrix to a dynamically sized nalgebra matrix
+    pub fn to_dmatrix(&self) -> Result<DMatrix<T>, MatError>
+    where
+        T: nalgebra::Scalar,
+    {
+        let rows = self.to_vec_2d()?;
+
+        Ok(DMatrix::from_fn(
+            self.rows() as usize,
+            self.cols() as usize,
+            |row, col| rows[row][col].clone(),
+        ))
+    }
+
+    /// Creates a Cmat from any nalgebra matrix, e.g. a [`CameraMatrix`] or a [`Vector3x1`]
+    pub fn from_nalgebra<R, C, S>(matrix: &Matrix<T, R, C, S>) -> Result<Self, MatError>
+    where
+        T: nalgebra::Scalar,
+        R: Dim,
+        C: Dim,
+        S: RawStorage<T, R, C>,
+    {
+        let rows: Vec<Vec<T>> = matrix
+            .row_iter()
+            .map(|row| row.iter().cloned().collect())
+            .collect();
+
+        Cmat::from_2d_slice(&rows)
+    }
+
     pub fn zeros(rows: i32, cols: i32) -> Result<Cmat<T>, MatError> {
         let mat = Mat::zeros(rows, cols, T::opencv_type())
             .map_err(MatError::Opencv)?
@@ -145,6 +479,22 @@ impl<T: DataType> Cmat<T> {
     }
 }
 
+impl<T: DataType> TryFrom<Vec<Vec<T>>> for Cmat<T> {
+    type Error = MatError;
+
+    fn try_from(value: Vec<Vec<T>>) -> Result<Self, Self::Error> {
+        Cmat::from_2d_slice(&value)
+    }
+}
+
+impl<T: DataType> TryFrom<&Cmat<T>> for Vec<Vec<T>> {
+    type Error = MatError;
+
+    fn try_from(value: &Cmat<T>) -> Result<Self, Self::Error> {
+        value.to_vec_2d()
+    }
+}
+
 impl<T> ToInputArray for Cmat<T> {
     fn input_array(&self) -> opencv::Result<opencv::core::_InputArray> {
         self.check().map_err(|err| match err {
@@ -181,42 +531,130 @@ impl<T> ToOutputArray for Cmat<T> {
 /// ## Errors
 /// Errors if pixel length != `w*h`
 pub fn raster_to_mat(pixels: &[RGBA8], w: i32, h: i32) -> Result<Cmat<Vec4b>, MatError> {
-    //RGBA<u8> is equivalent to opencv's Vec4b, which implements DataType
-    if pixels.len() != (w * h) as usize {
-        return Err(MatError::Unknown);
+    raster_to_mat_depth(pixels, w, h)
+}
+
+/// Converts a slice of RGBA pixels of any depth to a BGRA matrix with the same depth
+///
+/// Like [`raster_to_mat`], but also supports 16-bit ([`rgb::RGBA16`]) and float pixels, which keeps the radiometric detail of the source.
+/// ## Errors
+/// Errors if pixel length != `w*h`
+pub fn raster_to_mat_depth<T>(
+    pixels: &[RGBA<T>],
+    w: i32,
+    h: i32,
+) -> Result<Cmat<VecN<T, 4>>, MatError>
+where
+    T: Copy,
+    VecN<T, 4>: DataType,
+{
+    let mut mat = raster_mat(pixels.len(), w, h, VecN::<T, 4>::opencv_type())?;
+    let data = mat
+        .data_typed_mut::<VecN<T, 4>>()
+        .map_err(MatError::Opencv)?;
+
+    // The pixels are written directly into the matrix buffer, swapping red and blue on the way.
+    for (dst, pixel) in data.iter_mut().zip(pixels) {
+        *dst = VecN::from_array([pixel.b, pixel.g, pixel.r, pixel.a]);
     }
 
-    let rows = raster_1d_to_2d(pixels, w, None).map_err(|_err| MatError::Jagged)?;
+    Cmat::new(mat)
+}
 
-    let converted: Vec<Vec<Vec4b>> = rows
-        .into_iter()
-        .map(|row| row.into_iter().map(rbga8_to_vec4b).collect())
-        .collect();
+/// Converts a slice of [`RGBA8`] to a single channel grayscale [`Cmat<u8>`], ignoring the alpha channel
+///
+/// The luminance is computed with the same fixed point weights as OpenCV's `COLOR_RGBA2GRAY`,
+/// so the result is identical to converting with [`raster_to_mat`] followed by `cvt_color`, without the intermediate BGRA matrix.
+/// ## Errors
+/// Errors if pixel length != `w*h`
+pub fn raster_to_gray_mat(pixels: &[RGBA8], w: i32, h: i32) -> Result<Cmat<u8>, MatError> {
+    let mut mat = raster_mat(pixels.len(), w, h, CV_8UC1)?;
+    let data = mat.data_typed_mut::<u8>().map_err(MatError::Opencv)?;
+
+    for (dst, pixel) in data.iter_mut().zip(pixels) {
+        *dst = luminance(pixel);
+    }
 
-    Cmat::from_2d_slice(&converted)
+    Cmat::new(mat)
 }
 
-fn raster_1d_to_2d(
-    pixels: &[RGBA8],
-    w: i32,
-    vec: Option<Vec<Vec<RGBA8>>>,
-) -> Result<Vec<Vec<RGBA8>>, ()> {
-    let (first_row, rest) = pixels.split_at(w as usize);
-    let mut vec = vec.unwrap_or_default();
+/// Allocates a continuous `h` by `w` matrix for a raster of `len` pixels
+fn raster_mat(len: usize, w: i32, h: i32, typ: i32) -> Result<Mat, MatError> {
+    if w <= 0 || h <= 0 || len != w as usize * h as usize {
+        return Err(MatError::Unknown);
+    }
+
+    Mat::new_rows_cols_with_default(h, w, typ, Scalar::all(0f64)).map_err(MatError::Opencv)
+}
 
-    vec.push(first_row.to_vec());
+fn luminance(pixel: &RGBA8) -> u8 {
+    // BT.601 weights scaled by 2^14, as used by OpenCV
+    const R: u32 = 4899;
+    const G: u32 = 9617;
+    const B: u32 = 1868;
+    const SHIFT: u32 = 14;
 
-    let len = pixels.len() % (w as usize);
+    ((pixel.r as u32 * R + pixel.g as u32 * G + pixel.b as u32 * B + (1 << (SHIFT - 1))) >> SHIFT)
+        as u8
+}
 
-    match rest.len() {
-        0 => Ok(vec),
-        _ if len == 0 => raster_1d_to_2d(rest, w, Some(vec)),
-        _ => Err(()), // if there is not enough pixels to fill a row
+/// Converts a slice of mask values to a single channel [`Cmat<u8>`]
+///
+/// ## Parameters
+/// mask: the mask values in row major order, the slice length should be equal to `w*h`
+/// w: the width of the mask, or the number of columns
+/// h: the height of the mask, or the number of rows
+/// ## Errors
+/// Errors if mask length != `w*h`
+pub fn mask_to_mat(mask: &[u8], w: i32, h: i32) -> Result<Cmat<u8>, MatError> {
+    if mask.len() != (w * h) as usize {
+        return Err(MatError::Unknown);
     }
+
+    let mat = Mat::from_slice_rows_cols(mask, h as usize, w as usize).map_err(MatError::Opencv)?;
+
+    Cmat::new(mat)
 }
 
-fn rbga8_to_vec4b(pixel: RGBA8) -> Vec4b {
-    Vec4b::new(pixel.b, pixel.g, pixel.r, pixel.a)
+/// Converts interleaved 8-bit band values to a [`Mat`] with one channel per band
+///
+/// ## Parameters
+/// data: the interleaved band values in row major order, the slice length should be equal to `w*h*channels`
+/// channels: the number of bands, either 1 or 3
+/// w: the width of the image, or the number of columns
+/// h: the height of the image, or the number of rows
+/// ## Notes
+/// A 3 band raster is assumed to be in R,G,B order, and is converted to BGR like [`raster_to_mat`]
+/// ## Errors
+/// * [`MatError::DimensionMismatch`] if there are not 1 or 3 channels, since other layouts are not supported by the feature extraction
+/// * [`MatError::Unknown`] if data length != `w*h*channels`
+pub fn bands_to_mat(data: &[u8], channels: usize, w: i32, h: i32) -> Result<Mat, MatError> {
+    if channels != 1 && channels != 3 {
+        return Err(MatError::DimensionMismatch);
+    }
+
+    if data.len() != w as usize * h as usize * channels {
+        return Err(MatError::Unknown);
+    }
+
+    let typ = CV_8U + ((channels as i32 - 1) << CV_CN_SHIFT);
+
+    let mut mat =
+        Mat::new_rows_cols_with_default(h, w, typ, Scalar::all(0f64)).map_err(MatError::Opencv)?;
+    let bytes = mat.data_bytes_mut().map_err(MatError::Opencv)?;
+
+    match channels {
+        3 => {
+            for (dst, src) in bytes.chunks_exact_mut(3).zip(data.chunks_exact(3)) {
+                dst[0] = src[2];
+                dst[1] = src[1];
+                dst[2] = src[0];
+            }
+        }
+        _ => bytes.copy_from_slice(data),
+    }
+
+    Ok(mat)
 }
 
 /// Estimates the homography between 2 planes, this matrix is always 3x3 `CV_F64C1`
@@ -304,14 +742,12 @@ pub fn warp_image_perspective<T: DataType>(
 /// ## Parameters
 /// * point_correspondences: a slice of 3d-to-2d point correspondences, minimum length is 4 (even in the P3P case, where the 4th point is used to find the solution with least reprojection error)
 /// * camera_intrinsic: the camera calibration matrix 3X3
-/// * iter_count: How many iteration the ransac algorithm should perform
-/// * reproj_thres:
-/// * confidence: //TODO
-/// * dist_coeffs: distortion coefficients from camera calibration, if [`None`], a zero length vector is assumed
+/// * dist_coeffs: distortion coefficients from camera calibration, if [`None`], zero distortion is assumed
+/// * options: see [`PnpRansacOptions`]
 /// ## Returns
-/// A solution, consisting of a rotation and translation matrix, and the indices of inliers used for the solution, returns `Ok(None)` if no solution was found
+/// A solution, consisting of a rotation and translation matrix, the indices of inliers used for the solution and the quality of the solution, returns `Ok(None)` if no solution was found
 /// ## Errors
-/// If the `point_correspondences` has less than 4 elements
+/// If the correspondences do not fulfill the preconditions of the method, see [`PnpMethod::validate`]. At least 4 correspondences are always required
 /// # Notes
 /// Since ransac randomly chooses a subset of points as the basis for a solution, the function behaves nondeterministiaclly.
 /// As such there is no gurantee that produces the same solution with the same parameters
@@ -320,52 +756,365 @@ pub fn warp_image_perspective<T: DataType>(
 pub fn pnp_solver_ransac(
     point_correspondences: &[ImgObjCorrespondence],
     camera_intrinsic: &Cmat<f64>,
-    iter_count: i32,
-    reproj_thres: f32,
-    confidence: f64,
     dist_coeffs: Option<&[f64]>,
-    method: Option<SolvePnPMethod>,
+    options: &PnpRansacOptions,
 ) -> Result<Option<PNPRANSACSolution>, MatError> {
-    let (obj_points, img_points): (Vec<_>, Vec<_>) = point_correspondences
-        .iter()
-        .map(|p| (p.obj_point, p.img_point))
-        .unzip();
-
-    let obj_points = Vector::from_slice(&obj_points);
-    let img_points = Vector::from_slice(&img_points);
+    let usac_params = options.sampler.usac_params(
+        options.iter_count,
+        options.reproj_thres as f64,
+        options.confidence,
+    )?;
+
+    // USAC selects its own minimal solver, so only the amount of points is checked.
+    match usac_params {
+        Some(_) => PnpMethod::Epnp.validate(point_correspondences, true, false)?,
+        None => options.method.validate(
+            point_correspondences,
+            true,
+            options.extrinsic_guess.is_some(),
+        )?,
+    }
 
-    // output parameters
-    let mut rvec = Cmat::<f64>::zeros(3, 1)?;
+    let (obj_points, img_points) = split_correspondences(point_correspondences.iter());
 
-    let mut tvec = Cmat::<f64>::zeros(3, 1)?;
+    // output parameters, which are also used as the initial guess
+    let (mut rvec, mut tvec) = initial_pose(options.extrinsic_guess.as_ref())?;
 
     let mut inliers = Cmat::<i32>::zeros(1, 1)?;
 
-    let dist_coeffs = Cmat::<f64>::zeros(4, 1)?;
+    let dist_coeffs = dist_coeffs_mat(dist_coeffs)?;
 
     // i think that Ok(false) means that there is no solution, but no errors happened
-    let res = solve_pnp_ransac(
+    let res = match usac_params {
+        Some(params) => {
+            // The USAC overload takes the camera matrix as an input/output array
+            let mut camera = camera_intrinsic.mat.try_clone().map_err(MatError::Opencv)?;
+
+            solve_pnp_ransac_1(
+                &obj_points,
+                &img_points,
+                &mut camera,
+                &dist_coeffs,
+                &mut rvec,
+                &mut tvec,
+                &mut inliers,
+                &params,
+            )
+        }
+        None => solve_pnp_ransac(
+            &obj_points,
+            &img_points,
+            camera_intrinsic,
+            &dist_coeffs,
+            &mut rvec,
+            &mut tvec,
+            options.extrinsic_guess.is_some(),
+            options.iter_count,
+            options.reproj_thres,
+            options.confidence,
+            &mut inliers,
+            options.method.to_cv() as i32,
+        ),
+    }
+    .map_err(MatError::Opencv)?;
+
+    if !res {
+        return Ok(None);
+    }
+
+    if let Some(refinement) = options.refinement {
+        refine_pose(
+            point_correspondences,
+            &inliers,
+            camera_intrinsic,
+            &dist_coeffs,
+            &mut rvec,
+            &mut tvec,
+            refinement,
+        )?;
+    }
+
+    let quality = solution_quality(
+        point_correspondences,
+        &inliers,
+        &rvec,
+        &tvec,
+        camera_intrinsic,
+        &dist_coeffs,
+        options.reproj_thres as f64,
+    )?;
+
+    Ok(Some(PNPRANSACSolution {
+        rvec,
+        tvec,
+        inliers,
+        quality,
+    }))
+}
+
+/// Estimates the pose of the camera using all of the provided correspondences, without outlier rejection.
+///
+/// Should only be used with clean correspondences, e.g. the inliers of a previous solution or synthetic data.
+/// ## Parameters
+/// * point_correspondences: a slice of 3d-to-2d point correspondences, see [`PnpMethod`] for the requirements of each method
+/// * camera_intrinsic: the camera calibration matrix 3X3
+/// * dist_coeffs: distortion coefficients from camera calibration, if [`None`], zero distortion is assumed
+/// * options: see [`PnpOptions`]
+/// ## Returns
+/// A solution where every correspondence is an inlier, returns `Ok(None)` if no solution was found.
+/// The quality is classified with `reproj_thres`, as no correspondences are rejected by it
+/// ## Errors
+/// If the correspondences do not fulfill the preconditions of the method, see [`PnpMethod::validate`]
+pub fn pnp_solver(
+    point_correspondences: &[ImgObjCorrespondence],
+    camera_intrinsic: &Cmat<f64>,
+    dist_coeffs: Option<&[f64]>,
+    options: &PnpOptions,
+    reproj_thres: f64,
+) -> Result<Option<PNPRANSACSolution>, MatError> {
+    options.method.validate(
+        point_correspondences,
+        false,
+        options.extrinsic_guess.is_some(),
+    )?;
+
+    let (obj_points, img_points) = split_correspondences(point_correspondences.iter());
+    let (mut rvec, mut tvec) = initial_pose(options.extrinsic_guess.as_ref())?;
+    let dist_coeffs = dist_coeffs_mat(dist_coeffs)?;
+
+    let res = solve_pnp(
         &obj_points,
         &img_points,
         camera_intrinsic,
         &dist_coeffs,
         &mut rvec,
         &mut tvec,
-        false,
-        iter_count,
-        reproj_thres,
-        confidence,
-        &mut inliers,
-        method.unwrap_or(SolvePnPMethod::SOLVEPNP_EPNP) as i32,
+        options.extrinsic_guess.is_some(),
+        options.method.to_cv() as i32,
     )
     .map_err(MatError::Opencv)?;
-    let solution = PNPRANSACSolution {
+
+    if !res {
+        return Ok(None);
+    }
+
+    let indices: Vec<[i32; 1]> = (0..point_correspondences.len() as i32)
+        .map(|i| [i])
+        .collect();
+    let inliers = Cmat::from_2d_slice(&indices)?;
+
+    if let Some(refinement) = options.refinement {
+        refine_pose(
+            point_correspondences,
+            &inliers,
+            camera_intrinsic,
+            &dist_coeffs,
+            &mut rvec,
+            &mut tvec,
+            refinement,
+        )?;
+    }
+
+    let quality = solution_quality(
+        point_correspondences,
+        &inliers,
+        &rvec,
+        &tvec,
+        camera_intrinsic,
+        &dist_coeffs,
+        reproj_thres,
+    )?;
+
+    Ok(Some(PNPRANSACSolution {
         rvec,
         tvec,
         inliers,
+        quality,
+    }))
+}
+
+fn split_correspondences<'a>(
+    correspondences: impl Iterator<Item = &'a ImgObjCorrespondence>,
+) -> (Vector<Point3d>, Vector<Point2d>) {
+    let (obj_points, img_points): (Vec<_>, Vec<_>) =
+        correspondences.map(|c| (c.obj_point, c.img_point)).unzip();
+
+    (
+        Vector::from_slice(&obj_points),
+        Vector::from_slice(&img_points),
+    )
+}
+
+/// The rotation and translation vectors given to OpenCV, which are zero unless a guess is provided
+fn initial_pose(guess: Option<&Pose>) -> Result<(Cmat<f64>, Cmat<f64>), MatError> {
+    match guess {
+        Some(pose) => Ok((
+            Cmat::from_nalgebra(&pose.rvec)?,
+            Cmat::from_nalgebra(&pose.tvec)?,
+        )),
+        None => Ok((Cmat::zeros(3, 1)?, Cmat::zeros(3, 1)?)),
+    }
+}
+
+fn dist_coeffs_mat(dist_coeffs: Option<&[f64]>) -> Result<Cmat<f64>, MatError> {
+    match dist_coeffs {
+        Some(coeffs) => Cmat::from_2d_slice(&coeffs.iter().map(|c| [*c]).collect::<Vec<_>>()),
+        None => Cmat::zeros(4, 1),
+    }
+}
+
+/// Refines a pose by minimizing the reprojection error of the inliers, starting from `rvec` and `tvec`.
+///
+/// The pose is left untouched if there are less than [`MIN_REFINEMENT_POINTS`] inliers.
+pub fn refine_pose(
+    point_correspondences: &[ImgObjCorrespondence],
+    inliers: &Cmat<i32>,
+    camera_intrinsic: &Cmat<f64>,
+    dist_coeffs: &Cmat<f64>,
+    rvec: &mut Cmat<f64>,
+    tvec: &mut Cmat<f64>,
+    refinement: PnpRefinement,
+) -> Result<(), MatError> {
+    let inlier_correspondences = select_inliers(point_correspondences, inliers)?;
+
+    if inlier_correspondences.len() < MIN_REFINEMENT_POINTS {
+        return Ok(());
+    }
+
+    let (obj_points, img_points) = split_correspondences(inlier_correspondences.into_iter());
+
+    match refinement {
+        PnpRefinement::LevenbergMarquardt => solve_pnp_refine_lm_def(
+            &obj_points,
+            &img_points,
+            camera_intrinsic,
+            dist_coeffs,
+            rvec,
+            tvec,
+        ),
+        PnpRefinement::VirtualVisualServoing => solve_pnp_refine_vvs_def(
+            &obj_points,
+            &img_points,
+            camera_intrinsic,
+            dist_coeffs,
+            rvec,
+            tvec,
+        ),
+    }
+    .map_err(MatError::Opencv)
+}
+
+/// Returns the correspondences at the indices in `inliers`
+fn select_inliers<'a>(
+    point_correspondences: &'a [ImgObjCorrespondence],
+    inliers: &Cmat<i32>,
+) -> Result<Vec<&'a ImgObjCorrespondence>, MatError> {
+    inliers
+        .mat
+        .data_typed::<i32>()
+        .map_err(MatError::Opencv)?
+        .iter()
+        .map(|&i| {
+            usize::try_from(i)
+                .ok()
+                .and_then(|i| point_correspondences.get(i))
+                .ok_or(MatError::OutOfBounds { row: i, col: 0 })
+        })
+        .collect()
+}
+
+/// Computes the quality of a pose from the reprojection of its inliers.
+///
+/// The covariance is `s² (JᵀJ)⁻¹`, where `J` is the Jacobian of the reprojected inliers with respect to the pose,
+/// and `s²` is the reprojection variance corrected for the 6 estimated parameters.
+/// ## Parameters
+/// * inliers: the indices of the inliers in `point_correspondences`, as returned by [`solve_pnp_ransac`]
+/// * reproj_thres: the RANSAC reprojection threshold, used to classify the solution
+pub fn solution_quality(
+    point_correspondences: &[ImgObjCorrespondence],
+    inliers: &Cmat<i32>,
+    rvec: &Cmat<f64>,
+    tvec: &Cmat<f64>,
+    camera_intrinsic: &Cmat<f64>,
+    dist_coeffs: &Cmat<f64>,
+    reproj_thres: f64,
+) -> Result<SolutionQuality, MatError> {
+    let inlier_correspondences = select_inliers(point_correspondences, inliers)?;
+
+    let obj_points: Vector<Point3d> = inlier_correspondences.iter().map(|c| c.obj_point).collect();
+
+    let mut projected: Vector<Point2d> = Vector::new();
+    let mut jacobian = Mat::default();
+
+    project_points(
+        &obj_points,
+        rvec,
+        tvec,
+        camera_intrinsic,
+        dist_coeffs,
+        &mut projected,
+        &mut jacobian,
+        0f64,
+    )
+    .map_err(MatError::Opencv)?;
+
+    let squared_error: f64 = projected
+        .iter()
+        .zip(&inlier_correspondences)
+        .map(|(p, c)| (p.x - c.img_point.x).powi(2) + (p.y - c.img_point.y).powi(2))
+        .sum();
+
+    let inlier_count = inlier_correspondences.len();
+    let reprojection_rms = match inlier_count {
+        0 => f64::INFINITY,
+        n => (squared_error / n as f64).sqrt(),
+    };
+    let inlier_ratio = match point_correspondences.len() {
+        0 => 0f64,
+        n => inlier_count as f64 / n as f64,
+    };
+
+    // Every inlier gives 2 residuals, which must be more than the 6 estimated parameters.
+    let covariance = match 2 * inlier_count > POSE_DOF {
+        true => pose_covariance(&Cmat::new(jacobian)?, squared_error, 2 * inlier_count)?,
+        false => None,
+    };
+
+    let flag = match (inlier_ratio, reprojection_rms) {
+        (ratio, _) if ratio < POOR_INLIER_RATIO || covariance.is_none() => QualityFlag::Poor,
+        (ratio, rms) if ratio >= GOOD_INLIER_RATIO && rms <= reproj_thres * GOOD_RMS_FRACTION => {
+            QualityFlag::Good
+        }
+        _ => QualityFlag::Degraded,
     };
-    let solution = res.then_some(solution);
-    Ok(solution)
+
+    Ok(SolutionQuality {
+        reprojection_rms,
+        covariance,
+        inlier_ratio,
+        flag,
+    })
+}
+
+/// The first 6 columns of the Jacobian from [`project_points`] are the derivatives with respect to the rotation and translation vectors.
+fn pose_covariance(
+    jacobian: &Cmat<f64>,
+    squared_error: f64,
+    residuals: usize,
+) -> Result<Option<PoseCovariance>, MatError> {
+    let jacobian = jacobian.to_dmatrix()?;
+
+    if jacobian.ncols() < POSE_DOF {
+        return Err(MatError::DimensionMismatch);
+    }
+
+    let pose_jacobian = jacobian.columns(0, POSE_DOF);
+    let information: PoseCovariance = (pose_jacobian.transpose() * pose_jacobian)
+        .fixed_view::<6, 6>(0, 0)
+        .into_owned();
+    let variance = squared_error / (residuals - POSE_DOF) as f64;
+
+    Ok(information.try_inverse().map(|inverse| inverse * variance))
 }
 #[allow(unused_variables)]
 #[allow(unused_imports)]
@@ -602,28 +1351,236 @@ mod test {
         )
     }
 
+    #[test]
+    fn raster_to_mat_tall_image() {
+        const H: usize = 100_000;
+        let image: Vec<RGBA8> = (0..H)
+            .map(|i| RGBA8::new(0, 0, (i % 256) as u8, 255))
+            .collect();
+
+        let image = raster_to_mat(&image, 1, H as i32).unwrap();
+
+        assert_eq!(image.mat.rows(), H as i32);
+        assert_eq!(
+            *image.mat.at_2d::<Vec4b>((H - 1) as i32, 0).unwrap(),
+            Vec4b::new(((H - 1) % 256) as u8, 0, 0, 255)
+        );
+    }
+
+    #[test]
+    fn raster_to_mat_wrong_size() {
+        let image = vec![RGBA8::new(1, 1, 1, 1); 5];
+
+        assert!(raster_to_mat(&image, 2, 2).is_err());
+        assert!(raster_to_mat(&image, 0, 5).is_err());
+    }
+
+    #[test]
+    fn raster_to_gray_mat_matches_opencv() {
+        let image: Vec<RGBA8> = (0..16)
+            .map(|i| RGBA8::new(i * 16, 255 - i * 16, i * 7, 255))
+            .collect();
+
+        let gray = raster_to_gray_mat(&image, 4, 4).unwrap();
+
+        let bgra = raster_to_mat(&image, 4, 4).unwrap();
+        let mut expected = Mat::default();
+        opencv::imgproc::cvt_color(
+            &bgra.mat,
+            &mut expected,
+            opencv::imgproc::COLOR_BGRA2GRAY,
+            0,
+        )
+        .unwrap();
+
+        assert_eq!(gray.mat.channels(), 1);
+        assert_eq!(
+            gray.mat.data_typed::<u8>().unwrap(),
+            expected.data_typed::<u8>().unwrap()
+        );
+    }
+
+    #[test]
+    fn mask_to_mat_works() {
+        const W: usize = 4;
+        const H: usize = 4;
+        let mask: Vec<u8> = (0..W * H).map(|i| (i % 2) as u8 * 255).collect();
+
+        let mat = mask_to_mat(&mask, W as i32, H as i32);
+
+        assert!(mat.is_ok());
+        let mat = mat.unwrap();
+        assert_eq!(mat.mat.channels(), 1);
+        assert_eq!(mat.at_2d(0, 0).unwrap().clone(), 0);
+        assert_eq!(mat.at_2d(1, 3).unwrap().clone(), 255);
+    }
+
+    #[test]
+    fn raster_to_mat_16_bit() {
+        let pixels = vec![RGBA16::new(1000, 2000, 3000, u16::MAX); 4];
+
+        let image = raster_to_mat_depth(&pixels, 2, 2).unwrap();
+
+        assert_eq!(image.mat.typ(), CV_16UC4);
+        assert_eq!(
+            image.at_2d(1, 1).unwrap().clone(),
+            Vec4w::new(3000, 2000, 1000, u16::MAX)
+        );
+    }
+
+    #[test]
+    fn cmat_convert_to_8_bit() {
+        let pixels = vec![RGBA16::new(u16::MAX, 0, 257, u16::MAX); 4];
+        let image = raster_to_mat_depth(&pixels, 2, 2).unwrap();
+
+        let converted = image.convert_to::<Vec4b>(1.0 / 257.0, 0.0).unwrap();
+
+        assert_eq!(
+            converted.at_2d(0, 0).unwrap().clone(),
+            Vec4b::new(1, 0, 255, 255)
+        );
+    }
+
+    #[test]
+    fn bands_to_mat_works() {
+        let pan: Vec<u8> = vec![1, 2, 3, 4];
+        let rgb: Vec<u8> = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+        let pan = bands_to_mat(&pan, 1, 2, 2).unwrap();
+        let rgb = bands_to_mat(&rgb, 3, 2, 2).unwrap();
+
+        assert_eq!(pan.channels(), 1);
+        assert_eq!(*pan.at_2d::<u8>(1, 1).unwrap(), 4);
+        assert_eq!(rgb.channels(), 3);
+        assert_eq!(*rgb.at_2d::<Vec3b>(0, 0).unwrap(), Vec3b::new(3, 2, 1));
+        assert!(matches!(
+            bands_to_mat(&[1, 2, 3], 1, 2, 2),
+            Err(MatError::Unknown)
+        ));
+        // Neither 2 nor 4 bands can be given to the feature extraction, and a data band should never be used as alpha
+        assert!(matches!(
+            bands_to_mat(&[0; 8], 2, 2, 2),
+            Err(MatError::DimensionMismatch)
+        ));
+        assert!(matches!(
+            bands_to_mat(&[0; 16], 4, 2, 2),
+            Err(MatError::DimensionMismatch)
+        ));
+    }
+
+    #[test]
+    fn mask_to_mat_wrong_size() {
+        let mask = vec![255u8; 5];
+
+        assert!(mask_to_mat(&mask, 2, 2).is_err());
+    }
+
     #[test]
     fn cmat_at_2d_works() {
         const IMG_SIZE: usize = 4;
         let image = test_image(IMG_SIZE);
 
-        assert!(image
-            .at_2d(3, 5)
-            .is_err_and(|x| if let MatError::Opencv(e) = x {
-                e.code_as_enum() == Some(Code::StsOutOfRange)
-            } else {
-                false
-            }));
-        assert!(image
-            .at_2d(5, 3)
-            .is_err_and(|x| if let MatError::Opencv(e) = x {
-                e.code_as_enum() == Some(Code::StsOutOfRange)
-            } else {
-                false
-            }));
+        assert!(matches!(
+            image.at_2d(3, 5),
+            Err(MatError::OutOfBounds { row: 3, col: 5 })
+        ));
+        assert!(matches!(
+            image.at_2d(5, 3),
+            Err(MatError::OutOfBounds { .. })
+        ));
+        assert!(matches!(
+            image.at_2d(-1, 0),
+            Err(MatError::OutOfBounds { .. })
+        ));
+        assert!(matches!(
+            image.at_2d(4, 0),
+            Err(MatError::OutOfBounds { .. })
+        ));
         assert_eq!(image.at_2d(3, 3).unwrap().clone(), Vec4b::new(4, 4, 1, 1));
     }
 
+    #[test]
+    fn cmat_at_2d_non_square() {
+        let mut mat = Cmat::<u8>::zeros(2, 5).unwrap();
+
+        *mat.at_2d_mut(1, 4).unwrap() = 7;
+
+        assert_eq!(*mat.at_2d(1, 4).unwrap(), 7);
+        assert!(mat.at_2d_mut(2, 4).is_err());
+    }
+
+    #[test]
+    fn cmat_type_mismatch() {
+        let mat = Mat::new_rows_cols_with_default(2, 2, CV_8UC1, Scalar::all(0f64)).unwrap();
+
+        assert!(matches!(
+            Cmat::<f64>::new(mat),
+            Err(MatError::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn cmat_rows_and_cols() {
+        let mat = Cmat::try_from(vec![vec![1f64, 2f64, 3f64], vec![4f64, 5f64, 6f64]]).unwrap();
+
+        let rows: Vec<&[f64]> = mat.iter_rows().map(|row| row.unwrap()).collect();
+        let cols: Vec<Vec<f64>> = mat.iter_cols().map(|col| col.unwrap()).collect();
+
+        assert_eq!(rows, vec![[1f64, 2f64, 3f64], [4f64, 5f64, 6f64]]);
+        assert_eq!(cols[2], vec![3f64, 6f64]);
+        assert_eq!(
+            Vec::<Vec<f64>>::try_from(&mat).unwrap(),
+            vec![vec![1f64, 2f64, 3f64], vec![4f64, 5f64, 6f64]]
+        );
+    }
+
+    #[test]
+    fn cmat_jagged() {
+        assert!(matches!(
+            Cmat::try_from(vec![vec![1f64, 2f64], vec![3f64]]),
+            Err(MatError::Jagged)
+        ));
+    }
+
+    #[test]
+    fn cmat_arithmetic() {
+        let a = Cmat::from_2d_slice(&[[2f64, 0f64], [1f64, 1f64]]).unwrap();
+        let b = Cmat::from_2d_slice(&[[1f64], [3f64]]).unwrap();
+
+        let product = a.matmul(&b).unwrap();
+        let inverse = a.inverse().unwrap();
+        let identity = a.matmul(&inverse).unwrap();
+
+        assert_eq!(product.col(0).unwrap(), vec![2f64, 4f64]);
+        assert_eq!(b.transpose().unwrap().row(0).unwrap(), [1f64, 3f64]);
+        assert!((identity.at_2d(0, 0).unwrap() - 1f64).abs() < 1e-12);
+        assert!((identity.at_2d(1, 0).unwrap()).abs() < 1e-12);
+        assert!(matches!(b.matmul(&b), Err(MatError::DimensionMismatch)));
+        assert!(matches!(
+            Cmat::from_2d_slice(&[[1f64, 1f64], [1f64, 1f64]])
+                .unwrap()
+                .inverse(),
+            Err(MatError::Singular)
+        ));
+    }
+
+    #[test]
+    fn cmat_nalgebra_round_trip() {
+        let camera = camera_matrix();
+
+        let matrix: CameraMatrix = camera.to_smatrix().unwrap();
+        let back = Cmat::from_nalgebra(&matrix).unwrap();
+
+        assert_eq!(matrix[(0, 0)], 8.64f64);
+        assert_eq!(matrix[(2, 2)], 1f64);
+        assert_eq!(back.to_vec_2d().unwrap(), camera.to_vec_2d().unwrap());
+        assert!(camera.to_smatrix::<3, 1>().is_err());
+
+        let vector = Cmat::from_nalgebra(&Vector3x1::new(1f64, 2f64, 3f64)).unwrap();
+        assert_eq!((vector.rows(), vector.cols()), (3, 1));
+        assert_eq!(vector.to_dmatrix().unwrap()[(2, 0)], 3f64);
+    }
+
     #[test]
     fn pnp_solver_ransac_no_work_lthan_3_points() {
         let corres_1 =
@@ -632,11 +1589,229 @@ mod test {
             ImgObjCorrespondence::new(Point3d::new(4f64, 5f64, 6f64), Point2d::new(4f64, 5f64));
         let corres_v = vec![corres_1, corres_2];
         let camera_intrinsic = Cmat::<f64>::zeros(3, 3).unwrap();
-        let res = pnp_solver_ransac(&corres_v, &camera_intrinsic, 50, 2.0, 0.99, None, None);
+        let options = PnpRansacOptions {
+            iter_count: 50,
+            reproj_thres: 2.0,
+            ..Default::default()
+        };
+        let res = pnp_solver_ransac(&corres_v, &camera_intrinsic, None, &options);
 
         assert!(res.is_err(), "{:?}", res);
     }
 
+    /// Projects a grid of object points with a known pose, returning the correspondences and the camera matrix
+    fn synthetic_correspondences(
+        rvec: [f64; 3],
+        tvec: [f64; 3],
+    ) -> (Vec<ImgObjCorrespondence>, Cmat<f64>) {
+        let camera = Cmat::from_2d_slice(&[
+            [800f64, 0f64, 320f64],
+            [0f64, 800f64, 240f64],
+            [0f64, 0f64, 1f64],
+        ])
+        .unwrap();
+        let obj_points: Vector<Point3d> = (0..25)
+            .map(|i| {
+                let (x, y) = ((i % 5) as f64 - 2f64, (i / 5) as f64 - 2f64);
+                Point3d::new(x, y, 10f64 + 0.3 * (x * y).sin())
+            })
+            .collect();
+        let mut img_points: Vector<Point2d> = Vector::new();
+
+        project_points(
+            &obj_points,
+            &Vector::from_slice(&rvec),
+            &Vector::from_slice(&tvec),
+            &camera,
+            &Mat::default(),
+            &mut img_points,
+            &mut Mat::default(),
+            0f64,
+        )
+        .unwrap();
+
+        let correspondences = obj_points
+            .iter()
+            .zip(img_points.iter())
+            .map(|(obj, img)| ImgObjCorrespondence::new(obj, img))
+            .collect();
+
+        (correspondences, camera)
+    }
+
+    #[test]
+    fn pnp_solution_quality() {
+        let (mut correspondences, camera) =
+            synthetic_correspondences([0.05f64, -0.02f64, 0.1f64], [0.5f64, -0.3f64, 1f64]);
+        // Slightly perturb the image points, so the covariance is not zero
+        for (i, correspondence) in correspondences.iter_mut().enumerate() {
+            correspondence.img_point.x += 0.1 * ((i % 3) as f64 - 1f64);
+        }
+        // An outlier
+        correspondences[0].img_point = Point2d::new(0f64, 0f64);
+
+        let options = PnpRansacOptions {
+            reproj_thres: 2.0,
+            ..Default::default()
+        };
+        let solution = pnp_solver_ransac(&correspondences, &camera, None, &options)
+            .unwrap()
+            .unwrap();
+        let quality = &solution.quality;
+
+        assert_eq!(quality.inlier_ratio, 24f64 / 25f64);
+        assert!(quality.reprojection_rms < 0.2, "{:?}", quality);
+        assert_eq!(quality.flag, QualityFlag::Good);
+        let attitude = quality.attitude_covariance().unwrap();
+        assert!((0..3).all(|i| attitude[(i, i)] > 0f64));
+    }
+
+    #[test]
+    fn pnp_solution_refined() {
+        let (mut correspondences, camera) =
+            synthetic_correspondences([0.05f64, -0.02f64, 0.1f64], [0.5f64, -0.3f64, 1f64]);
+        for (i, correspondence) in correspondences.iter_mut().enumerate() {
+            correspondence.img_point.y += 0.3 * ((i % 5) as f64 - 2f64);
+        }
+
+        for refinement in [
+            PnpRefinement::LevenbergMarquardt,
+            PnpRefinement::VirtualVisualServoing,
+        ] {
+            let options = PnpRansacOptions {
+                refinement: Some(refinement),
+                ..Default::default()
+            };
+            let unrefined = PnpRansacOptions::default();
+
+            let refined = pnp_solver_ransac(&correspondences, &camera, None, &options)
+                .unwrap()
+                .unwrap();
+            let unrefined = pnp_solver_ransac(&correspondences, &camera, None, &unrefined)
+                .unwrap()
+                .unwrap();
+
+            assert!(
+                refined.quality.reprojection_rms <= unrefined.quality.reprojection_rms + 1e-9,
+                "{:?}",
+                refinement
+            );
+        }
+    }
+
+    #[test]
+    fn pnp_solver_extrinsic_guess() {
+        let (correspondences, camera) =
+            synthetic_correspondences([0.05f64, -0.02f64, 0.1f64], [0.5f64, -0.3f64, 1f64]);
+        // 5 points that are not coplanar, too few for the iterative method to initialize the pose on its own
+        let correspondences: Vec<ImgObjCorrespondence> = [0, 4, 7, 12, 20]
+            .iter()
+            .map(|&i| correspondences[i])
+            .collect();
+        let guess = Pose {
+            rvec: Vector3x1::new(0.04, -0.02, 0.1),
+            tvec: Vector3x1::new(0.5, -0.2, 1.1),
+        };
+        let unguided = PnpOptions {
+            method: PnpMethod::Iterative,
+            ..Default::default()
+        };
+        let guided = PnpOptions {
+            extrinsic_guess: Some(guess),
+            ..unguided
+        };
+
+        assert!(matches!(
+            pnp_solver(&correspondences, &camera, None, &unguided, 2.0),
+            Err(MatError::TooFewCorrespondences { required: 6, .. })
+        ));
+
+        let solution = pnp_solver(&correspondences, &camera, None, &guided, 2.0)
+            .unwrap()
+            .unwrap();
+        let pose = solution.pose().unwrap();
+
+        // Converged from the guess to the true pose
+        assert!((pose.rvec - Vector3x1::new(0.05, -0.02, 0.1)).norm() < 1e-6);
+        assert!((pose.tvec - Vector3x1::new(0.5, -0.3, 1.0)).norm() < 1e-6);
+        assert!((pose.tvec - guess.tvec).norm() > 0.1);
+    }
+
+    #[test]
+    fn pnp_solver_usac() {
+        let (mut correspondences, camera) =
+            synthetic_correspondences([0.05f64, -0.02f64, 0.1f64], [0.5f64, -0.3f64, 1f64]);
+        correspondences[3].img_point = Point2d::new(0f64, 0f64);
+
+        for sampler in [RansacSampler::Magsac, RansacSampler::Prosac] {
+            let options = PnpRansacOptions {
+                sampler,
+                ..Default::default()
+            };
+
+            let solution = pnp_solver_ransac(&correspondences, &camera, None, &options)
+                .unwrap()
+                .unwrap();
+
+            assert!(solution.quality.inlier_ratio < 1f64, "{:?}", sampler);
+            assert!(solution.quality.reprojection_rms < 1e-3, "{:?}", sampler);
+        }
+    }
+
+    #[test]
+    fn pnp_solver_clean_correspondences() {
+        let (correspondences, camera) =
+            synthetic_correspondences([0.05f64, -0.02f64, 0.1f64], [0.5f64, -0.3f64, 1f64]);
+
+        let solution = pnp_solver(&correspondences, &camera, None, &PnpOptions::default(), 2.0)
+            .unwrap()
+            .unwrap();
+        let pose = solution.pose().unwrap();
+
+        assert_eq!(solution.quality.inlier_ratio, 1f64);
+        assert!((pose.tvec - Vector3x1::new(0.5, -0.3, 1.0)).norm() < 1e-6);
+    }
+
+    #[test]
+    fn pnp_solver_rejects_too_few_points() {
+        let (correspondences, camera) =
+            synthetic_correspondences([0f64, 0f64, 0f64], [0f64, 0f64, 0f64]);
+        let options = PnpOptions {
+            method: PnpMethod::P3p,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            pnp_solver(&correspondences[..3], &camera, None, &options, 2.0),
+            Err(MatError::TooFewCorrespondences { .. })
+        ));
+        assert!(pnp_solver(&correspondences[..4], &camera, None, &options, 2.0).is_ok());
+    }
+
+    #[test]
+    fn pnp_solution_quality_few_inliers() {
+        let (correspondences, camera) =
+            synthetic_correspondences([0f64, 0f64, 0f64], [0f64, 0f64, 0f64]);
+        let inliers = Cmat::from_2d_slice(&[[0], [1], [2]]).unwrap();
+        let rvec = Cmat::from_2d_slice(&[[0f64], [0f64], [0f64]]).unwrap();
+        let tvec = Cmat::from_2d_slice(&[[0f64], [0f64], [0f64]]).unwrap();
+
+        let quality = solution_quality(
+            &correspondences,
+            &inliers,
+            &rvec,
+            &tvec,
+            &camera,
+            &Cmat::zeros(4, 1).unwrap(),
+            2.0,
+        )
+        .unwrap();
+
+        assert!(quality.reprojection_rms < 1e-9);
+        assert!(quality.covariance.is_none());
+        assert_eq!(quality.flag, QualityFlag::Poor);
+    }
+
     #[ignore = "Skal bruge Akaze keypoints"]
     #[test]
     fn pnp_solver_works() {
@@ -664,16 +1839,14 @@ mod test {
         let corres_v = vec![corres_1, corres_2, corres_3, corres_4, corres_5];
         let camera_intrinsic = camera_matrix();
 
-        let res = pnp_solver_ransac(
-            &corres_v,
-            &camera_intrinsic,
-            10000,
-            100.0,
-            0.5,
-            None,
-            Some(SolvePnPMethod::SOLVEPNP_P3P),
-        );
-        // no errors during solving
+        let options = PnpRansacOptions {
+            iter_count: 10000,
+            reproj_thres: 100.0,
+            confidence: 0.5,
+            method: PnpMethod::P3p,
+            ..Default::default()
+        };
+        let res = pnp_solver_ransac(&corres_v, &camera_intrinsic, None, &options); // no errors during solving
         assert!(res.is_ok(), "{:?}", res);
         let res = res.unwrap();
         assert!(res.is_some(), "No solution was found to the PNP problem");
@@ -0,0 +1,297 @@
+use nalgebra::{Matrix3xX, Vector3};
+use opencv::{
+    calib3d::{LocalOptimMethod, SamplingMethod, ScoreMethod, SolvePnPMethod, UsacParams},
+    core::Point3d,
+    prelude::*,
+};
+
+use super::{ImgObjCorrespondence, MatError};
+
+/// The ratio between the smallest and largest singular value of the centered object points, below which they are considered coplanar
+const COPLANAR_TOLERANCE: f64 = 1e-3;
+/// The tolerance, relative to the side length, when checking the layout of the square used by [`PnpMethod::IppeSquare`]
+const SQUARE_TOLERANCE: f64 = 1e-6;
+
+/// The algorithms that can solve the PnP problem, see OpenCV's `SolvePnPMethod`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PnpMethod {
+    /// Levenberg-Marquardt minimization of the reprojection error.
+    /// Needs 6 points, or 4 if they are coplanar or an extrinsic guess is provided
+    Iterative,
+    /// Efficient PnP, needs 4 points
+    #[default]
+    Epnp,
+    /// Needs exactly 4 points when used without RANSAC, the 4th point selects between the solutions
+    P3p,
+    /// Algebraic P3P, with the same constraints as [`PnpMethod::P3p`]
+    Ap3p,
+    /// Broken in OpenCV 4, where it falls back to EPnP
+    Dls,
+    /// Broken in OpenCV 4, where it falls back to EPnP
+    Upnp,
+    /// Infinitesimal plane-based pose estimation, needs 4 coplanar points
+    Ippe,
+    /// A special case of [`PnpMethod::Ippe`] for the 4 corners of a square marker, see [`PnpMethod::validate`]
+    IppeSquare,
+    /// Needs 3 points
+    Sqpnp,
+}
+
+/// How the RANSAC hypotheses are sampled and scored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RansacSampler {
+    /// The classic RANSAC, using the [`PnpMethod`] of the options
+    #[default]
+    Ransac,
+    /// USAC with MAGSAC++ scoring, which is less sensitive to the reprojection threshold
+    Magsac,
+    /// USAC with PROSAC sampling, which samples the correspondences in order and should be given correspondences sorted by match quality
+    Prosac,
+}
+
+impl PnpMethod {
+    pub fn to_cv(self) -> SolvePnPMethod {
+        match self {
+            PnpMethod::Iterative => SolvePnPMethod::SOLVEPNP_ITERATIVE,
+            PnpMethod::Epnp => SolvePnPMethod::SOLVEPNP_EPNP,
+            PnpMethod::P3p => SolvePnPMethod::SOLVEPNP_P3P,
+            PnpMethod::Ap3p => SolvePnPMethod::SOLVEPNP_AP3P,
+            PnpMethod::Dls => SolvePnPMethod::SOLVEPNP_DLS,
+            PnpMethod::Upnp => SolvePnPMethod::SOLVEPNP_UPNP,
+            PnpMethod::Ippe => SolvePnPMethod::SOLVEPNP_IPPE,
+            PnpMethod::IppeSquare => SolvePnPMethod::SOLVEPNP_IPPE_SQUARE,
+            PnpMethod::Sqpnp => SolvePnPMethod::SOLVEPNP_SQPNP,
+        }
+    }
+
+    /// The minimum amount of correspondences needed by the method
+    pub fn min_points(self) -> usize {
+        match self {
+            PnpMethod::Sqpnp => 3,
+            _ => 4,
+        }
+    }
+
+    /// Checks that the correspondences fulfill the preconditions of the method.
+    ///
+    /// ## Parameters
+    /// * ransac: whether the method is used within RANSAC, where the minimal sample is solved by OpenCV and the method is used on the inliers
+    /// * extrinsic_guess: whether an extrinsic guess is provided
+    /// ## Errors
+    /// * [`MatError::TooFewCorrespondences`] if there are too few correspondences, or not exactly 4 for P3P, AP3P and IPPE square without RANSAC
+    /// * [`MatError::NotCoplanar`] if IPPE is used with points that are not coplanar, or IPPE square is used with points that are not the corners of a square centered in the origin of the z=0 plane,
+    ///   ordered (-l/2, l/2), (l/2, l/2), (l/2, -l/2), (-l/2, -l/2)
+    pub fn validate(
+        self,
+        point_correspondences: &[ImgObjCorrespondence],
+        ransac: bool,
+        extrinsic_guess: bool,
+    ) -> Result<(), MatError> {
+        let obj_points: Vec<Point3d> = point_correspondences.iter().map(|c| c.obj_point).collect();
+        let actual = obj_points.len();
+
+        // solvePnPRansac always needs 4 points, even for methods with a smaller minimum
+        let required = match ransac {
+            true => self.min_points().max(4),
+            false => self.min_points(),
+        };
+
+        let too_few = |required: usize| MatError::TooFewCorrespondences { required, actual };
+
+        if actual < required {
+            return Err(too_few(required));
+        }
+
+        match self {
+            PnpMethod::P3p | PnpMethod::Ap3p if !ransac && actual != 4 => Err(too_few(4)),
+            PnpMethod::Iterative if !extrinsic_guess && actual < 6 && !is_coplanar(&obj_points) => {
+                Err(too_few(6))
+            }
+            PnpMethod::Ippe if !is_coplanar(&obj_points) => Err(MatError::NotCoplanar),
+            PnpMethod::IppeSquare if ransac || actual != 4 => Err(too_few(4)),
+            PnpMethod::IppeSquare if !is_square(&obj_points) => Err(MatError::NotCoplanar),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl RansacSampler {
+    /// The USAC parameters of the sampler, or [`None`] for the classic RANSAC
+    pub fn usac_params(
+        self,
+        iter_count: i32,
+        reproj_thres: f64,
+        confidence: f64,
+    ) -> Result<Option<UsacParams>, MatError> {
+        let (sampler, score) = match self {
+            RansacSampler::Ransac => return Ok(None),
+            RansacSampler::Magsac => (
+                SamplingMethod::SAMPLING_UNIFORM,
+                ScoreMethod::SCORE_METHOD_MAGSAC,
+            ),
+            RansacSampler::Prosac => (
+                SamplingMethod::SAMPLING_PROSAC,
+                ScoreMethod::SCORE_METHOD_MSAC,
+            ),
+        };
+
+        let mut params = UsacParams::default().map_err(MatError::Opencv)?;
+        params.set_sampler(sampler);
+        params.set_score(score);
+        params.set_lo_method(LocalOptimMethod::LOCAL_OPTIM_INNER_LO);
+        params.set_max_iterations(iter_count);
+        params.set_threshold(reproj_thres);
+        params.set_confidence(confidence);
+
+        Ok(Some(params))
+    }
+}
+
+/// Whether the points lie in a plane, found from the singular values of the centered points
+fn is_coplanar(points: &[Point3d]) -> bool {
+    if points.len() <= 3 {
+        return true;
+    }
+
+    let centroid = points
+        .iter()
+        .fold(Vector3::zeros(), |acc, p| acc + Vector3::new(p.x, p.y, p.z))
+        / points.len() as f64;
+
+    let centered = Matrix3xX::from_iterator(
+        points.len(),
+        points
+            .iter()
+            .flat_map(|p| [p.x - centroid.x, p.y - centroid.y, p.z - centroid.z]),
+    );
+
+    let singular_values = centered.singular_values();
+    let largest = singular_values.max();
+
+    largest == 0f64 || singular_values.min() <= largest * COPLANAR_TOLERANCE
+}
+
+/// Whether the points are the corners of a square in the layout required by IPPE square
+fn is_square(points: &[Point3d]) -> bool {
+    let [a, b, c, d] = match points {
+        [a, b, c, d] => [a, b, c, d],
+        _ => return false,
+    };
+
+    let half = (b.x - a.x) / 2f64;
+    let tolerance = half.abs() * SQUARE_TOLERANCE;
+    let expected = [(-half, half), (half, half), (half, -half), (-half, -half)];
+
+    half > 0f64
+        && [a, b, c, d].iter().zip(expected).all(|(p, (x, y))| {
+            (p.x - x).abs() <= tolerance && (p.y - y).abs() <= tolerance && p.z.abs() <= tolerance
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::Point2d;
+
+    fn correspondences(points: &[(f64, f64, f64)]) -> Vec<ImgObjCorrespondence> {
+        points
+            .iter()
+            .map(|&(x, y, z)| ImgObjCorrespondence::new(Point3d::new(x, y, z), Point2d::new(x, y)))
+            .collect()
+    }
+
+    fn planar(n: usize) -> Vec<ImgObjCorrespondence> {
+        let points: Vec<(f64, f64, f64)> = (0..n)
+            .map(|i| ((i % 3) as f64, (i / 3) as f64, 0f64))
+            .collect();
+        correspondences(&points)
+    }
+
+    fn non_planar(n: usize) -> Vec<ImgObjCorrespondence> {
+        let points: Vec<(f64, f64, f64)> = (0..n)
+            .map(|i| ((i % 3) as f64, (i / 3) as f64, (i * i) as f64))
+            .collect();
+        correspondences(&points)
+    }
+
+    #[test]
+    fn too_few_points() {
+        assert!(matches!(
+            PnpMethod::Epnp.validate(&planar(3), false, false),
+            Err(MatError::TooFewCorrespondences {
+                required: 4,
+                actual: 3
+            })
+        ));
+        assert!(PnpMethod::Sqpnp.validate(&planar(3), false, false).is_ok());
+        assert!(PnpMethod::Sqpnp.validate(&planar(3), true, false).is_err());
+    }
+
+    #[test]
+    fn p3p_needs_exactly_4_points() {
+        assert!(PnpMethod::P3p
+            .validate(&non_planar(4), false, false)
+            .is_ok());
+        assert!(PnpMethod::P3p
+            .validate(&non_planar(5), false, false)
+            .is_err());
+        assert!(PnpMethod::P3p.validate(&non_planar(5), true, false).is_ok());
+    }
+
+    #[test]
+    fn iterative_non_planar_needs_6_points() {
+        assert!(PnpMethod::Iterative
+            .validate(&non_planar(5), false, false)
+            .is_err());
+        assert!(PnpMethod::Iterative
+            .validate(&non_planar(5), false, true)
+            .is_ok());
+        assert!(PnpMethod::Iterative
+            .validate(&planar(4), false, false)
+            .is_ok());
+        assert!(PnpMethod::Iterative
+            .validate(&non_planar(6), false, false)
+            .is_ok());
+    }
+
+    #[test]
+    fn ippe_needs_coplanar_points() {
+        assert!(PnpMethod::Ippe.validate(&planar(6), false, false).is_ok());
+        assert!(matches!(
+            PnpMethod::Ippe.validate(&non_planar(6), false, false),
+            Err(MatError::NotCoplanar)
+        ));
+    }
+
+    #[test]
+    fn ippe_square_layout() {
+        let square = correspondences(&[
+            (-1f64, 1f64, 0f64),
+            (1f64, 1f64, 0f64),
+            (1f64, -1f64, 0f64),
+            (-1f64, -1f64, 0f64),
+        ]);
+
+        assert!(PnpMethod::IppeSquare
+            .validate(&square, false, false)
+            .is_ok());
+        assert!(PnpMethod::IppeSquare
+            .validate(&square, true, false)
+            .is_err());
+        assert!(PnpMethod::IppeSquare
+            .validate(&planar(4), false, false)
+            .is_err());
+    }
+
+    #[test]
+    fn ransac_has_no_usac_params() {
+        assert!(RansacSampler::Ransac
+            .usac_params(100, 8.0, 0.99)
+            .unwrap()
+            .is_none());
+        assert!(RansacSampler::Magsac
+            .usac_params(100, 8.0, 0.99)
+            .unwrap()
+            .is_some());
+    }
+}
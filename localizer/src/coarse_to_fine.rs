@@ -0,0 +1,156 @@
+use std::time::Instant;
+
+use diesel::PgConnection;
+use feature_database::keypointdb::{Keypoint, KeypointDatabase};
+use opencv::core::Mat;
+use query_preprocessor::QueryOptions;
+
+use crate::{
+    estimate_pose, extract_query_features,
+    footprint::{estimate_footprint, FootprintEstimate},
+    reference, Localization, LocalizationError, LocalizationOptions, StageTimings,
+};
+
+/// Options for the coarse step of [`localize_coarse_to_fine`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoarseOptions {
+    /// The level of detail the footprint is found in, must be coarser than the level used for the pose
+    pub level_of_detail: u64,
+    /// How much the footprint is grown on every side before the fine lookup, as a fraction of its size
+    pub margin: f64,
+    /// The maximum reprojection error in reference pixels for a match to be a homography inlier
+    pub reproj_threshold: f64,
+}
+
+impl Default for CoarseOptions {
+    fn default() -> Self {
+        CoarseOptions {
+            level_of_detail: 2,
+            margin: 0.25,
+            reproj_threshold: 16.0,
+        }
+    }
+}
+
+/// Finds the footprint of a query image by estimating a homography between it and a coarse level of detail.
+///
+/// Returns `Ok(None)` if there are too few matches, or the homography does not give a valid footprint.
+/// ## Parameters
+/// * img: the query image
+/// * query: the options used when preprocessing the query image, the level of detail is taken from `options`
+/// * ratio: the threshold used in Lowe's ratio test when matching descriptors
+pub fn coarse_localization(
+    conn: &mut PgConnection,
+    img: &Mat,
+    query: &QueryOptions,
+    options: &CoarseOptions,
+    ratio: f32,
+) -> Result<Option<FootprintEstimate>, LocalizationError> {
+    let query = QueryOptions {
+        level_of_detail: options.level_of_detail,
+        ..query.clone()
+    };
+    let query_features = extract_query_features(img, &query, None)?;
+
+    let keypoints = Keypoint::read_keypoints_from_lod(conn, options.level_of_detail as i32)
+        .map_err(LocalizationError::Diesel)?;
+    let reference = reference::reference_features(&keypoints).map_err(LocalizationError::Opencv)?;
+    let matches = reference::match_features(&query_features.features, &reference, ratio)
+        .map_err(LocalizationError::Opencv)?;
+
+    estimate_footprint(
+        &matches,
+        query_features.width as f64,
+        query_features.height as f64,
+        options.reproj_threshold,
+    )
+}
+
+/// Estimates the pose of the camera by first finding the footprint of the query image in a coarse level of detail,
+/// and then only matching against the reference keypoints within the footprint at the level of detail in `options`.
+///
+/// If no footprint is found, no pose is estimated.
+/// The footprint of the result is found from the matches at the fine level of detail if possible, otherwise it is the coarse footprint.
+/// ## Errors
+/// * [`LocalizationError::CoarseLevelOfDetail`] if the coarse level of detail is not coarser than the level in `options`
+/// * If the query options have no camera, or the database, preprocessing or pose estimation fails
+pub fn localize_coarse_to_fine(
+    conn: &mut PgConnection,
+    img: &Mat,
+    query: &QueryOptions,
+    coarse: &CoarseOptions,
+    options: &LocalizationOptions,
+) -> Result<Localization, LocalizationError> {
+    check_levels_of_detail(coarse, options)?;
+
+    let start = Instant::now();
+    let coarse_localization = match coarse_localization(conn, img, query, coarse, options.ratio)? {
+        Some(coarse_localization) => coarse_localization,
+        None => {
+            return Ok(Localization {
+                footprint: None,
+                solution: None,
+                correspondences: Vec::new(),
+                timings: StageTimings {
+                    coarse: start.elapsed(),
+                    ..Default::default()
+                },
+            })
+        }
+    };
+    let coarse_time = start.elapsed();
+
+    let footprint = coarse_localization.footprint;
+    let localization = estimate_pose(
+        conn,
+        img,
+        query,
+        Some(footprint.bounds(coarse.margin)),
+        options,
+    )?;
+
+    Ok(Localization {
+        footprint: localization.footprint.or(Some(footprint)),
+        timings: StageTimings {
+            coarse: coarse_time,
+            ..localization.timings
+        },
+        ..localization
+    })
+}
+
+/// Checks that the coarse step searches a coarser level of detail than the pose is estimated from,
+/// otherwise the whole level of detail would be searched twice.
+fn check_levels_of_detail(
+    coarse: &CoarseOptions,
+    options: &LocalizationOptions,
+) -> Result<(), LocalizationError> {
+    match coarse.level_of_detail > options.level_of_detail {
+        true => Ok(()),
+        false => Err(LocalizationError::CoarseLevelOfDetail {
+            coarse: coarse.level_of_detail,
+            fine: options.level_of_detail,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coarse_level_of_detail_must_be_coarser() {
+        let options = |level_of_detail| LocalizationOptions {
+            level_of_detail,
+            ..Default::default()
+        };
+        let coarse = CoarseOptions::default();
+
+        assert!(check_levels_of_detail(&coarse, &options(0)).is_ok());
+        assert!(matches!(
+            check_levels_of_detail(&coarse, &options(2)),
+            Err(LocalizationError::CoarseLevelOfDetail { coarse: 2, fine: 2 })
+        ));
+        assert!(check_levels_of_detail(&coarse, &options(3)).is_err());
+    }
+}
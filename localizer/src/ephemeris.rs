@@ -0,0 +1,417 @@
+use chrono::NaiveDateTime;
+use diesel::PgConnection;
+use feature_database::elevationdb::geotransform::read_georeference;
+use gdal::GeoTransformEx;
+use geotiff_lib::geodesy::{transform_points, GEOGRAPHIC, WGS84_A, WGS84_F};
+use nalgebra::Vector3;
+use opencv::{
+    core::{Mat, Point2d},
+    prelude::*,
+};
+use query_preprocessor::QueryOptions;
+use sgp4::{Constants, Elements, MinutesSinceEpoch};
+
+use crate::{
+    estimate_pose, footprint::Footprint, Localization, LocalizationError, LocalizationOptions,
+};
+
+/// The Julian date of the J2000 epoch
+const J2000: f64 = 2_451_545.0;
+/// The Julian date of the unix epoch
+const UNIX_EPOCH: f64 = 2_440_587.5;
+
+#[derive(Debug)]
+pub enum EphemerisError {
+    /// The TLE could not be parsed or propagated
+    Sgp4(String),
+    /// The position is not above the surface of the ellipsoid
+    BelowSurface,
+    /// The off nadir angle in radians is negative, or the camera could see the horizon, so the ground it covers is unbounded
+    OffNadir(f64),
+}
+
+/// A position on the WGS84 ellipsoid.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Geodetic {
+    /// The latitude in degrees
+    pub latitude: f64,
+    /// The longitude in degrees
+    pub longitude: f64,
+    /// The height above the ellipsoid in meters
+    pub altitude: f64,
+}
+
+/// The known position of the camera.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ephemeris {
+    /// Earth centered, earth fixed coordinates in meters
+    Ecef(Vector3<f64>),
+    Geodetic(Geodetic),
+    /// A two line element set, propagated to `timestamp` (UTC) with SGP4
+    Tle {
+        line1: String,
+        line2: String,
+        timestamp: NaiveDateTime,
+    },
+}
+
+impl Ephemeris {
+    /// The geodetic position of the camera.
+    pub fn position(&self) -> Result<Geodetic, EphemerisError> {
+        match self {
+            Ephemeris::Ecef(position) => Ok(ecef_to_geodetic(position)),
+            Ephemeris::Geodetic(position) => Ok(*position),
+            Ephemeris::Tle {
+                line1,
+                line2,
+                timestamp,
+            } => propagate_tle(line1, line2, timestamp).map(|position| ecef_to_geodetic(&position)),
+        }
+    }
+}
+
+/// Options for finding the nadir footprint from an [`Ephemeris`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EphemerisOptions {
+    /// The largest expected angle between the optical axis and nadir in radians.
+    /// Must not be negative, and together with the field of view of the camera less than π/2
+    pub off_nadir: f64,
+    /// Added to the radius of the footprint in meters, to cover the error of the ephemeris
+    pub margin: f64,
+}
+
+impl Default for EphemerisOptions {
+    fn default() -> Self {
+        EphemerisOptions {
+            off_nadir: 0.0,
+            margin: 5000.0,
+        }
+    }
+}
+
+/// Converts earth centered, earth fixed coordinates in meters to a position on the WGS84 ellipsoid.
+pub fn ecef_to_geodetic(position: &Vector3<f64>) -> Geodetic {
+    let e2 = WGS84_F * (2f64 - WGS84_F);
+    let p = position.x.hypot(position.y);
+
+    let mut latitude = position.z.atan2(p * (1f64 - e2));
+    let mut altitude = 0f64;
+
+    // Converges to well below a millimeter within a few iterations for positions near the earth.
+    for _ in 0..10 {
+        let n = WGS84_A / (1f64 - e2 * latitude.sin().powi(2)).sqrt();
+        altitude = match latitude.cos().abs() > f64::EPSILON {
+            true => p / latitude.cos() - n,
+            false => position.z.abs() - n * (1f64 - e2),
+        };
+        latitude = position.z.atan2(p * (1f64 - e2 * n / (n + altitude)));
+    }
+
+    Geodetic {
+        latitude: latitude.to_degrees(),
+        longitude: position.y.atan2(position.x).to_degrees(),
+        altitude,
+    }
+}
+
+/// Converts a position on the WGS84 ellipsoid to earth centered, earth fixed coordinates in meters.
+pub fn geodetic_to_ecef(position: &Geodetic) -> Vector3<f64> {
+    let e2 = WGS84_F * (2f64 - WGS84_F);
+    let (latitude, longitude) = (
+        position.latitude.to_radians(),
+        position.longitude.to_radians(),
+    );
+    let n = WGS84_A / (1f64 - e2 * latitude.sin().powi(2)).sqrt();
+
+    Vector3::new(
+        (n + position.altitude) * latitude.cos() * longitude.cos(),
+        (n + position.altitude) * latitude.cos() * longitude.sin(),
+        (n * (1f64 - e2) + position.altitude) * latitude.sin(),
+    )
+}
+
+/// The Greenwich mean sidereal time in radians, using the IAU 1982 model.
+fn greenwich_sidereal_time(timestamp: &NaiveDateTime) -> f64 {
+    let julian_date = UNIX_EPOCH + timestamp.and_utc().timestamp_millis() as f64 / 86_400_000f64;
+    let t = (julian_date - J2000) / 36_525f64;
+
+    let seconds =
+        67_310.548_41 + (876_600f64 * 3600f64 + 8_640_184.812_866) * t + 0.093_104 * t.powi(2)
+            - 6.2e-6 * t.powi(3);
+
+    (seconds.rem_euclid(86_400f64) / 240f64).to_radians()
+}
+
+/// Propagates a TLE to `timestamp` and returns the position in earth centered, earth fixed coordinates in meters.
+///
+/// ## Notes
+/// SGP4 gives the position in the TEME frame, which is rotated to ECEF by the sidereal time. Polar motion is ignored,
+/// which is well below the error of a TLE.
+pub fn propagate_tle(
+    line1: &str,
+    line2: &str,
+    timestamp: &NaiveDateTime,
+) -> Result<Vector3<f64>, EphemerisError> {
+    let elements = Elements::from_tle(None, line1.as_bytes(), line2.as_bytes())
+        .map_err(|e| EphemerisError::Sgp4(e.to_string()))?;
+    let constants =
+        Constants::from_elements(&elements).map_err(|e| EphemerisError::Sgp4(e.to_string()))?;
+
+    let minutes = (*timestamp - elements.datetime).num_milliseconds() as f64 / 60_000f64;
+    let prediction = constants
+        .propagate(MinutesSinceEpoch(minutes))
+        .map_err(|e| EphemerisError::Sgp4(e.to_string()))?;
+
+    let teme = Vector3::from(prediction.position) * 1000f64;
+    let (sin, cos) = greenwich_sidereal_time(timestamp).sin_cos();
+
+    Ok(Vector3::new(
+        cos * teme.x + sin * teme.y,
+        -sin * teme.x + cos * teme.y,
+        teme.z,
+    ))
+}
+
+/// The corners of a square with the given radius in meters around a position, as `(longitude, latitude)` in degrees.
+///
+/// The corners are in the order north west, north east, south east and south west,
+/// which are the top left, top right, bottom right and bottom left of a north up mosaic.
+fn nadir_corners(position: &Geodetic, radius: f64) -> [(f64, f64); 4] {
+    let e2 = WGS84_F * (2f64 - WGS84_F);
+    let latitude = position.latitude.to_radians();
+    let denominator = 1f64 - e2 * latitude.sin().powi(2);
+
+    // The radii of curvature along the meridian and the prime vertical.
+    let meridian = WGS84_A * (1f64 - e2) / denominator.powf(1.5);
+    let prime_vertical = WGS84_A / denominator.sqrt();
+
+    let d_latitude = (radius / meridian).to_degrees();
+    let d_longitude = (radius / (prime_vertical * latitude.cos())).to_degrees();
+
+    let (lon, lat) = (position.longitude, position.latitude);
+
+    [
+        (lon - d_longitude, lat + d_latitude),
+        (lon + d_longitude, lat + d_latitude),
+        (lon + d_longitude, lat - d_latitude),
+        (lon - d_longitude, lat - d_latitude),
+    ]
+}
+
+/// The radius in meters of the ground seen by a `width` x `height` camera at `altitude`.
+///
+/// The heading of the camera is unknown, so the radius covers the diagonal of the image.
+fn ground_radius(
+    query: &QueryOptions,
+    width: f64,
+    height: f64,
+    altitude: f64,
+    options: &EphemerisOptions,
+) -> Result<f64, LocalizationError> {
+    let camera = query.camera.ok_or(LocalizationError::MissingCamera)?;
+    let intrinsic = camera
+        .intrinsic
+        .to_smatrix::<3, 3>()
+        .map_err(LocalizationError::Mat)?;
+
+    let half_angle = (width / 2f64 / intrinsic[(0, 0)])
+        .hypot(height / 2f64 / intrinsic[(1, 1)])
+        .atan();
+
+    view_radius(half_angle, altitude, options).map_err(LocalizationError::Ephemeris)
+}
+
+/// The radius in meters of the ground seen within `half_angle` of the optical axis, tilted up to the off nadir angle.
+fn view_radius(
+    half_angle: f64,
+    altitude: f64,
+    options: &EphemerisOptions,
+) -> Result<f64, EphemerisError> {
+    let angle = half_angle + options.off_nadir;
+
+    // NaN fails the range check as well.
+    if !(options.off_nadir >= 0f64 && angle < std::f64::consts::FRAC_PI_2) {
+        return Err(EphemerisError::OffNadir(options.off_nadir));
+    }
+
+    Ok(altitude * angle.tan() + options.margin)
+}
+
+/// Projects the area below the camera onto the reference mosaic, through the geotransform of the mosaic.
+///
+/// ## Parameters
+/// * query: the camera of the query options is used to find how much ground the image covers
+/// * width, height: the size of the query image before preprocessing
+/// * position: the position of the camera
+/// ## Errors
+/// If the query options have no camera, the position is below the surface, the off nadir angle is invalid,
+/// or the geotransform of the mosaic can not be read.
+/// ## Notes
+/// The corners are found in geographic coordinates and transformed to the CRS of the mosaic.
+pub fn nadir_footprint(
+    conn: &mut PgConnection,
+    query: &QueryOptions,
+    width: f64,
+    height: f64,
+    position: &Geodetic,
+    options: &EphemerisOptions,
+) -> Result<Footprint, LocalizationError> {
+    if position.altitude <= 0f64 {
+        return Err(LocalizationError::Ephemeris(EphemerisError::BelowSurface));
+    }
+
+    let radius = ground_radius(query, width, height, position.altitude, options)?;
+
+    let (transform, spatial_ref) =
+        read_georeference(conn, "dataset").map_err(LocalizationError::Diesel)?;
+    let inverse = transform.invert().map_err(LocalizationError::Gdal)?;
+
+    let geographic = nadir_corners(position, radius);
+    let (mut x, mut y) = (
+        geographic.map(|(lon, _)| lon),
+        geographic.map(|(_, lat)| lat),
+    );
+    transform_points(GEOGRAPHIC, &spatial_ref, &mut x, &mut y).map_err(LocalizationError::Gdal)?;
+
+    let corners: [Point2d; 4] = std::array::from_fn(|i| {
+        let (x, y) = inverse.apply(x[i], y[i]);
+        Point2d::new(x, y)
+    });
+
+    Ok(Footprint { corners })
+}
+
+/// Estimates the pose of the camera by only matching against the reference keypoints below the known position of the camera.
+///
+/// The footprint of the result is found from the matches if possible, otherwise it is the nadir footprint.
+/// ## Errors
+/// If the ephemeris can not be propagated, the query options have no camera, or the database, preprocessing or pose estimation fails.
+pub fn localize_with_ephemeris(
+    conn: &mut PgConnection,
+    img: &Mat,
+    query: &QueryOptions,
+    ephemeris: &Ephemeris,
+    ephemeris_options: &EphemerisOptions,
+    options: &LocalizationOptions,
+) -> Result<Localization, LocalizationError> {
+    let position = ephemeris.position().map_err(LocalizationError::Ephemeris)?;
+
+    let footprint = nadir_footprint(
+        conn,
+        query,
+        img.cols() as f64,
+        img.rows() as f64,
+        &position,
+        ephemeris_options,
+    )?;
+
+    let localization = estimate_pose(conn, img, query, Some(footprint.bounds(0f64)), options)?;
+
+    Ok(Localization {
+        footprint: localization.footprint.or(Some(footprint)),
+        ..localization
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    const ISS_LINE1: &str = "1 25544U 98067A   24001.50000000  .00016717  00000-0  10270-3 0  9009";
+    const ISS_LINE2: &str = "2 25544  51.6416 247.4627 0006703 130.5360 325.0288 15.72125391428289";
+
+    #[test]
+    fn geodetic_round_trip() {
+        let position = Geodetic {
+            latitude: 56.105169,
+            longitude: 9.68505,
+            altitude: 500_000.0,
+        };
+
+        let converted = ecef_to_geodetic(&geodetic_to_ecef(&position));
+
+        assert!((converted.latitude - position.latitude).abs() < 1e-9);
+        assert!((converted.longitude - position.longitude).abs() < 1e-9);
+        assert!((converted.altitude - position.altitude).abs() < 1e-3);
+    }
+
+    #[test]
+    fn ecef_on_equator() {
+        let position = ecef_to_geodetic(&Vector3::new(WGS84_A + 1000.0, 0.0, 0.0));
+
+        assert!(position.latitude.abs() < 1e-9);
+        assert!(position.longitude.abs() < 1e-9);
+        assert!((position.altitude - 1000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sidereal_time_at_j2000() {
+        let timestamp = NaiveDate::from_ymd_opt(2000, 1, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+
+        let gmst = greenwich_sidereal_time(&timestamp).to_degrees();
+
+        assert!((gmst - 280.460_618_37).abs() < 1e-6, "{gmst}");
+    }
+
+    #[test]
+    fn tle_is_propagated_to_orbit() {
+        let timestamp = NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .and_hms_opt(14, 0, 0)
+            .unwrap();
+
+        let position = ecef_to_geodetic(&propagate_tle(ISS_LINE1, ISS_LINE2, &timestamp).unwrap());
+
+        assert!(position.altitude > 350_000.0 && position.altitude < 450_000.0);
+        assert!(position.latitude.abs() < 52.0);
+    }
+
+    #[test]
+    fn invalid_tle() {
+        let timestamp = NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+
+        assert!(propagate_tle("1 25544U", ISS_LINE2, &timestamp).is_err());
+    }
+
+    #[test]
+    fn nadir_corners_are_square() {
+        let position = Geodetic {
+            latitude: 60.0,
+            longitude: 10.0,
+            altitude: 500_000.0,
+        };
+
+        let corners = nadir_corners(&position, 10_000.0);
+
+        // A degree of longitude is about half as long as a degree of latitude at 60 degrees.
+        let width = corners[1].0 - corners[0].0;
+        let height = corners[0].1 - corners[3].1;
+        assert!((width / height - 2.0).abs() < 0.02);
+        assert!((height - 2.0 * 10_000.0 / 111_412.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn off_nadir_is_validated() {
+        let options = |off_nadir| EphemerisOptions {
+            off_nadir,
+            margin: 0.0,
+        };
+
+        let radius = view_radius(0.1, 1000.0, &options(0.2)).unwrap();
+        assert!((radius - 1000.0 * 0.3f64.tan()).abs() < 1e-9);
+
+        assert!(matches!(
+            view_radius(0.1, 1000.0, &options(-0.2)),
+            Err(EphemerisError::OffNadir(_))
+        ));
+        assert!(view_radius(0.1, 1000.0, &options(std::f64::consts::FRAC_PI_2 - 0.1)).is_err());
+        assert!(view_radius(0.1, 1000.0, &options(f64::NAN)).is_err());
+    }
+}
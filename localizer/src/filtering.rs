@@ -0,0 +1,569 @@
+use std::io::{self, BufRead, Write};
+
+use homographier::homographier::{Pose, Vector3x1};
+use nalgebra::{Matrix3, Matrix3x6, Matrix6, Matrix6x3, UnitQuaternion, Vector3};
+
+use crate::tracking::TrackedPose;
+
+/// The 99% quantile of the chi-squared distribution with 3 degrees of freedom, used to gate measurements
+pub const CHI_SQUARED_3_99: f64 = 11.345;
+
+/// Noise parameters of an [`AttitudeFilter`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterOptions {
+    /// The spectral density of the angular acceleration in rad/s²/√Hz, which lets the angular rate drift
+    pub rate_noise_density: f64,
+    /// The standard deviation of the angular rate when the filter is initialized in rad/s
+    pub initial_rate_std: f64,
+    /// The standard deviation of a gyro sample in rad/s
+    pub gyro_std: f64,
+    /// The standard deviation of an attitude measurement in radians, used when a pose has no covariance
+    pub attitude_std: f64,
+    /// Measurements with a normalized innovation squared above the gate are rejected as outliers
+    pub gate: f64,
+}
+
+impl Default for FilterOptions {
+    fn default() -> Self {
+        FilterOptions {
+            rate_noise_density: 1e-3,
+            initial_rate_std: 0.1,
+            gyro_std: 1e-3,
+            attitude_std: 1e-2,
+            gate: CHI_SQUARED_3_99,
+        }
+    }
+}
+
+/// An angular rate measured by a gyro, in the camera frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GyroSample {
+    /// The time of the sample in seconds, on the same clock as the frames
+    pub timestamp: f64,
+    /// The angular rate in rad/s
+    pub rate: Vector3<f64>,
+}
+
+/// The filtered attitude at a point in time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AttitudeEstimate {
+    pub timestamp: f64,
+    /// The rotation from the camera frame to the world frame
+    pub attitude: UnitQuaternion<f64>,
+    /// The angular rate in the camera frame in rad/s
+    pub rate: Vector3<f64>,
+    /// The covariance of the attitude error followed by the rate error
+    pub covariance: Matrix6<f64>,
+}
+
+impl AttitudeEstimate {
+    /// The attitude as an OpenCV rotation vector, from the world frame to the camera frame
+    pub fn rvec(&self) -> Vector3x1 {
+        self.attitude.inverse().scaled_axis()
+    }
+}
+
+/// The innovation of a single measurement.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Innovation {
+    pub residual: Vector3<f64>,
+    /// The normalized innovation squared, which is chi-squared distributed with 3 degrees of freedom for a consistent filter
+    pub nis: f64,
+    /// Whether the measurement passed the gate and was fused
+    pub accepted: bool,
+}
+
+/// Running statistics of the innovations, to judge whether the filter is consistent.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct InnovationStatistics {
+    pub accepted: usize,
+    pub rejected: usize,
+    /// The sum of the normalized innovation squared of the accepted measurements
+    pub nis_sum: f64,
+}
+
+impl InnovationStatistics {
+    /// The mean normalized innovation squared of the accepted measurements, close to 3 for a consistent filter
+    pub fn mean_nis(&self) -> Option<f64> {
+        match self.accepted {
+            0 => None,
+            n => Some(self.nis_sum / n as f64),
+        }
+    }
+
+    fn add(&mut self, innovation: &Innovation) {
+        match innovation.accepted {
+            true => {
+                self.accepted += 1;
+                self.nis_sum += innovation.nis;
+            }
+            false => self.rejected += 1,
+        }
+    }
+}
+
+/// A multiplicative extended Kalman filter estimating the attitude and angular rate of the camera.
+///
+/// The attitude is kept as a unit quaternion, while the filter works on a 3 dimensional attitude error,
+/// which is folded back into the quaternion after every update. The angular rate is modelled as a random walk.
+/// ## Notes
+/// The filter is initialized by the first attitude measurement. Gyro samples before it are ignored.
+pub struct AttitudeFilter {
+    options: FilterOptions,
+    estimate: Option<AttitudeEstimate>,
+    statistics: InnovationStatistics,
+}
+
+impl AttitudeFilter {
+    pub fn new(options: FilterOptions) -> AttitudeFilter {
+        AttitudeFilter {
+            options,
+            estimate: None,
+            statistics: InnovationStatistics::default(),
+        }
+    }
+
+    pub fn estimate(&self) -> Option<&AttitudeEstimate> {
+        self.estimate.as_ref()
+    }
+
+    pub fn statistics(&self) -> &InnovationStatistics {
+        &self.statistics
+    }
+
+    /// Propagates the estimate to `timestamp` with the estimated angular rate.
+    /// Nothing happens if the filter is not initialized, or `timestamp` is not after the estimate.
+    pub fn predict(&mut self, timestamp: f64) {
+        let options = self.options;
+        let estimate = match self.estimate.as_mut() {
+            Some(estimate) if timestamp > estimate.timestamp => estimate,
+            _ => return,
+        };
+
+        let dt = timestamp - estimate.timestamp;
+        let rotation = UnitQuaternion::from_scaled_axis(estimate.rate * dt);
+
+        // The attitude error is rotated into the new camera frame, and grows with the rate error.
+        let mut transition = Matrix6::identity();
+        transition
+            .fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(rotation.inverse().to_rotation_matrix().matrix());
+        transition
+            .fixed_view_mut::<3, 3>(0, 3)
+            .copy_from(&(Matrix3::identity() * dt));
+
+        // Integrated white noise on the angular acceleration.
+        let q = options.rate_noise_density.powi(2);
+        let mut noise = Matrix6::zeros();
+        noise
+            .fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&(Matrix3::identity() * q * dt.powi(3) / 3f64));
+        noise
+            .fixed_view_mut::<3, 3>(0, 3)
+            .copy_from(&(Matrix3::identity() * q * dt.powi(2) / 2f64));
+        noise
+            .fixed_view_mut::<3, 3>(3, 0)
+            .copy_from(&(Matrix3::identity() * q * dt.powi(2) / 2f64));
+        noise
+            .fixed_view_mut::<3, 3>(3, 3)
+            .copy_from(&(Matrix3::identity() * q * dt));
+
+        estimate.attitude *= rotation;
+        estimate.covariance = transition * estimate.covariance * transition.transpose() + noise;
+        estimate.timestamp = timestamp;
+    }
+
+    /// Fuses an attitude measured by PnP.
+    ///
+    /// ## Parameters
+    /// * pose: the measured pose, only the rotation is used
+    /// * covariance: the covariance of the rotation vector, e.g. from [`homographier::homographier::SolutionQuality::attitude_covariance`].
+    ///   It is mapped onto the attitude error with [`rvec_error_jacobian`].
+    ///   [`FilterOptions::attitude_std`] is used if [`None`]
+    /// ## Returns
+    /// The innovation of the measurement, or [`None`] if the measurement initialized the filter
+    /// ## Notes
+    /// A measurement older than the estimate can not be fused without going back in time, so it is rejected
+    /// and counted in the statistics.
+    pub fn update_attitude(
+        &mut self,
+        timestamp: f64,
+        pose: &Pose,
+        covariance: Option<&Matrix3<f64>>,
+    ) -> Option<Innovation> {
+        if let Some(innovation) = self.reject_stale(timestamp) {
+            return Some(innovation);
+        }
+
+        let measured = UnitQuaternion::from_scaled_axis(pose.rvec).inverse();
+        let noise = match covariance {
+            Some(covariance) => {
+                let jacobian = rvec_error_jacobian(&pose.rvec);
+                jacobian * covariance * jacobian.transpose()
+            }
+            None => Matrix3::identity() * self.options.attitude_std.powi(2),
+        };
+
+        self.predict(timestamp);
+
+        let estimate = match self.estimate.as_mut() {
+            Some(estimate) => estimate,
+            None => {
+                let mut initial = Matrix6::zeros();
+                initial.fixed_view_mut::<3, 3>(0, 0).copy_from(&noise);
+                initial
+                    .fixed_view_mut::<3, 3>(3, 3)
+                    .copy_from(&(Matrix3::identity() * self.options.initial_rate_std.powi(2)));
+
+                self.estimate = Some(AttitudeEstimate {
+                    timestamp,
+                    attitude: measured,
+                    rate: Vector3::zeros(),
+                    covariance: initial,
+                });
+                return None;
+            }
+        };
+
+        // The attitude error that rotates the estimate onto the measurement, in the camera frame.
+        let residual = (estimate.attitude.inverse() * measured).scaled_axis();
+        let observation = Matrix3x6::from_fn(|row, col| if row == col { 1f64 } else { 0f64 });
+
+        let innovation = fuse(estimate, residual, &observation, &noise, self.options.gate);
+        self.statistics.add(&innovation);
+
+        Some(innovation)
+    }
+
+    /// Fuses an angular rate measured by a gyro. Returns [`None`] if the filter is not initialized.
+    /// Samples older than the estimate are rejected, like in [`AttitudeFilter::update_attitude`].
+    pub fn update_rate(&mut self, sample: &GyroSample) -> Option<Innovation> {
+        if let Some(innovation) = self.reject_stale(sample.timestamp) {
+            return Some(innovation);
+        }
+
+        self.predict(sample.timestamp);
+
+        let noise = Matrix3::identity() * self.options.gyro_std.powi(2);
+        let estimate = self.estimate.as_mut()?;
+
+        let residual = sample.rate - estimate.rate;
+        let observation = Matrix3x6::from_fn(|row, col| if row + 3 == col { 1f64 } else { 0f64 });
+
+        let innovation = fuse(estimate, residual, &observation, &noise, self.options.gate);
+        self.statistics.add(&innovation);
+
+        Some(innovation)
+    }
+
+    /// Rejects a measurement taken before the estimate, returning its innovation.
+    fn reject_stale(&mut self, timestamp: f64) -> Option<Innovation> {
+        match &self.estimate {
+            Some(estimate) if timestamp < estimate.timestamp => {
+                let innovation = Innovation {
+                    residual: Vector3::zeros(),
+                    nis: f64::INFINITY,
+                    accepted: false,
+                };
+                self.statistics.add(&innovation);
+
+                Some(innovation)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The jacobian of the attitude error with respect to the rotation vector of a measurement.
+///
+/// The rotation vector `r` rotates from the world frame to the camera frame, while the attitude error is the
+/// rotation from the camera to the world frame, perturbed on the right.
+/// Perturbing `r` by `δr` gives `exp(r + δr) ≈ exp(r)·exp(J_r(r)·δr)`, so the inverted attitude changes by
+/// `δθ = -exp(r)·J_r(r)·δr`, and the covariance of `r` maps to `J·Σ·Jᵀ` with the returned `J`.
+pub fn rvec_error_jacobian(rvec: &Vector3<f64>) -> Matrix3<f64> {
+    let rotation = UnitQuaternion::from_scaled_axis(*rvec).to_rotation_matrix();
+    -(rotation.matrix() * right_jacobian(rvec))
+}
+
+/// The right jacobian of SO(3) at the rotation vector `theta`.
+fn right_jacobian(theta: &Vector3<f64>) -> Matrix3<f64> {
+    let angle = theta.norm();
+    let skew = theta.cross_matrix();
+
+    // The series expansion avoids dividing by a vanishing angle.
+    if angle < 1e-6 {
+        return Matrix3::identity() - skew * 0.5 + skew * skew / 6f64;
+    }
+
+    Matrix3::identity() - skew * ((1f64 - angle.cos()) / angle.powi(2))
+        + skew * skew * ((angle - angle.sin()) / angle.powi(3))
+}
+
+/// The Kalman update of the error state, which is folded back into the attitude and rate if the measurement passes the gate.
+fn fuse(
+    estimate: &mut AttitudeEstimate,
+    residual: Vector3<f64>,
+    observation: &Matrix3x6<f64>,
+    noise: &Matrix3<f64>,
+    gate: f64,
+) -> Innovation {
+    let covariance = estimate.covariance;
+    let innovation_covariance = observation * covariance * observation.transpose() + noise;
+
+    let inverse = match innovation_covariance.try_inverse() {
+        Some(inverse) => inverse,
+        None => {
+            return Innovation {
+                residual,
+                nis: f64::INFINITY,
+                accepted: false,
+            }
+        }
+    };
+
+    let nis = (residual.transpose() * inverse * residual)[(0, 0)];
+
+    if nis > gate {
+        return Innovation {
+            residual,
+            nis,
+            accepted: false,
+        };
+    }
+
+    let gain: Matrix6x3<f64> = covariance * observation.transpose() * inverse;
+    let error = gain * residual;
+
+    estimate.attitude *= UnitQuaternion::from_scaled_axis(error.fixed_rows::<3>(0).into_owned());
+    estimate.rate += error.fixed_rows::<3>(3);
+
+    // The Joseph form keeps the covariance symmetric and positive definite.
+    let update = Matrix6::identity() - gain * observation;
+    estimate.covariance =
+        update * covariance * update.transpose() + gain * noise * gain.transpose();
+
+    Innovation {
+        residual,
+        nis,
+        accepted: true,
+    }
+}
+
+/// Filters a track of poses, fusing gyro samples in between the frames.
+///
+/// Returns an estimate for every frame of the track, together with the innovation statistics.
+/// Samples and poses are fused in order of their timestamps.
+pub fn filter_track(
+    track: &[TrackedPose],
+    gyro: &[GyroSample],
+    options: FilterOptions,
+) -> (Vec<AttitudeEstimate>, InnovationStatistics) {
+    let mut filter = AttitudeFilter::new(options);
+    let mut estimates = Vec::with_capacity(track.len());
+    let mut samples = gyro.iter().peekable();
+
+    for pose in track {
+        while let Some(sample) = samples.next_if(|sample| sample.timestamp <= pose.timestamp) {
+            filter.update_rate(sample);
+        }
+
+        filter.update_attitude(
+            pose.timestamp,
+            &pose.pose,
+            pose.quality.attitude_covariance().as_ref(),
+        );
+
+        if let Some(estimate) = filter.estimate() {
+            estimates.push(*estimate);
+        }
+    }
+
+    (estimates, *filter.statistics())
+}
+
+/// Reads gyro samples from CSV with the columns `timestamp,wx,wy,wz`, in seconds and rad/s.
+/// A header line and empty lines are skipped.
+pub fn read_gyro_csv(reader: impl BufRead) -> io::Result<Vec<GyroSample>> {
+    let mut samples = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let values: Result<Vec<f64>, _> =
+            line.split(',').map(|value| value.trim().parse()).collect();
+
+        match values {
+            Ok(values) if values.len() == 4 => samples.push(GyroSample {
+                timestamp: values[0],
+                rate: Vector3::new(values[1], values[2], values[3]),
+            }),
+            // The header
+            Err(_) if i == 0 => continue,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid gyro sample on line {}: {}", i + 1, line),
+                ))
+            }
+        }
+    }
+
+    samples.sort_by(|a, b| a.timestamp.total_cmp(&b.timestamp));
+
+    Ok(samples)
+}
+
+/// Writes filtered estimates as CSV, with the rotation vector, angular rate and attitude standard deviations.
+pub fn write_estimates_csv(
+    writer: &mut impl Write,
+    estimates: &[AttitudeEstimate],
+) -> io::Result<()> {
+    writeln!(writer, "timestamp,rx,ry,rz,wx,wy,wz,std_x,std_y,std_z")?;
+
+    for estimate in estimates {
+        let (r, w) = (estimate.rvec(), estimate.rate);
+        let std = estimate.covariance.diagonal().map(f64::sqrt);
+
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{},{}",
+            estimate.timestamp, r.x, r.y, r.z, w.x, w.y, w.z, std[0], std[1], std[2]
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pose(rvec: Vector3<f64>) -> Pose {
+        Pose {
+            rvec,
+            tvec: Vector3::zeros(),
+        }
+    }
+
+    /// The pose of a camera rotating about its z axis with a constant rate
+    fn rotating_pose(timestamp: f64, rate: f64) -> Pose {
+        let attitude = UnitQuaternion::from_scaled_axis(Vector3::new(0f64, 0f64, rate * timestamp));
+        pose(attitude.inverse().scaled_axis())
+    }
+
+    #[test]
+    fn first_measurement_initializes() {
+        let mut filter = AttitudeFilter::new(FilterOptions::default());
+        let rvec = Vector3::new(0.1, 0.2, 0.3);
+
+        assert!(filter.update_attitude(0.0, &pose(rvec), None).is_none());
+
+        let estimate = filter.estimate().unwrap();
+        assert!((estimate.rvec() - rvec).norm() < 1e-12);
+    }
+
+    #[test]
+    fn rate_is_estimated() {
+        let mut filter = AttitudeFilter::new(FilterOptions::default());
+
+        for i in 0..50 {
+            let timestamp = i as f64 * 0.5;
+            filter.update_attitude(timestamp, &rotating_pose(timestamp, 0.02), None);
+        }
+
+        let estimate = filter.estimate().unwrap();
+        assert!((estimate.rate.z - 0.02).abs() < 1e-3, "{:?}", estimate.rate);
+        assert!(filter.statistics().rejected == 0);
+        assert!(filter.statistics().mean_nis().is_some());
+    }
+
+    #[test]
+    fn outlier_is_rejected() {
+        let mut filter = AttitudeFilter::new(FilterOptions::default());
+
+        for i in 0..10 {
+            filter.update_attitude(i as f64, &pose(Vector3::zeros()), None);
+        }
+
+        let innovation = filter
+            .update_attitude(10.0, &pose(Vector3::new(0.5, 0.0, 0.0)), None)
+            .unwrap();
+
+        assert!(!innovation.accepted);
+        assert_eq!(filter.statistics().rejected, 1);
+        assert!(filter.estimate().unwrap().rvec().norm() < 1e-6);
+    }
+
+    #[test]
+    fn gyro_is_fused() {
+        let mut filter = AttitudeFilter::new(FilterOptions::default());
+        filter.update_attitude(0.0, &pose(Vector3::zeros()), None);
+
+        for i in 1..=10 {
+            let sample = GyroSample {
+                timestamp: i as f64 * 0.1,
+                rate: Vector3::new(0.0, 0.01, 0.0),
+            };
+            assert!(filter.update_rate(&sample).unwrap().accepted);
+        }
+
+        let estimate = filter.estimate().unwrap();
+        assert!((estimate.rate.y - 0.01).abs() < 1e-3);
+        // The attitude is propagated with the rate
+        assert!(estimate.attitude.scaled_axis().y > 0f64);
+    }
+
+    #[test]
+    fn rvec_covariance_is_mapped_to_attitude_error() {
+        let rvec = Vector3::new(0.4, -0.9, 1.3);
+        let jacobian = rvec_error_jacobian(&rvec);
+        let attitude = UnitQuaternion::from_scaled_axis(rvec).inverse();
+
+        for i in 0..3 {
+            let mut delta = Vector3::zeros();
+            delta[i] = 1e-6;
+
+            let perturbed = UnitQuaternion::from_scaled_axis(rvec + delta).inverse();
+            let error = (attitude.inverse() * perturbed).scaled_axis();
+
+            assert!((error - jacobian * delta).norm() < 1e-10, "{:?}", error);
+        }
+
+        // The mapping is only a rotation at the identity
+        let identity = rvec_error_jacobian(&Vector3::zeros());
+        assert!((identity + Matrix3::identity()).norm() < 1e-12);
+    }
+
+    #[test]
+    fn stale_measurement_is_rejected() {
+        let mut filter = AttitudeFilter::new(FilterOptions::default());
+        filter.update_attitude(1.0, &pose(Vector3::zeros()), None);
+
+        let innovation = filter
+            .update_attitude(0.5, &pose(Vector3::new(0.001, 0.0, 0.0)), None)
+            .unwrap();
+
+        assert!(!innovation.accepted);
+        assert_eq!(filter.statistics().rejected, 1);
+
+        let estimate = filter.estimate().unwrap();
+        assert_eq!(estimate.timestamp, 1.0);
+        assert_eq!(estimate.rvec(), Vector3::zeros());
+    }
+
+    #[test]
+    fn gyro_csv() {
+        let csv = "timestamp,wx,wy,wz\n1.0,0.1,0.2,0.3\n\n0.5,0,0,0\n";
+
+        let samples = read_gyro_csv(csv.as_bytes()).unwrap();
+
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].timestamp, 0.5);
+        assert_eq!(samples[1].rate, Vector3::new(0.1, 0.2, 0.3));
+        assert!(read_gyro_csv("1.0,0.1\n".as_bytes()).is_err());
+    }
+}
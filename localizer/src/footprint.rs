@@ -0,0 +1,212 @@
+use homographier::homographier::{
+    find_homography_mat, Cmat, HomographyMethod, MatError, Vector3x1,
+};
+use opencv::core::{count_non_zero, Point2d, Point2f};
+
+use crate::{reference::PointMatch, LocalizationError};
+
+/// The minimum amount of matches needed to estimate a homography.
+const MIN_HOMOGRAPHY_MATCHES: usize = 4;
+
+/// The area of the reference mosaic covered by a query image.
+///
+/// The corners are in full resolution reference pixel coordinates, in the order top left, top right, bottom right and bottom left of the query image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Footprint {
+    pub corners: [Point2d; 4],
+}
+
+impl Footprint {
+    /// Projects the corners of a `width` x `height` query image onto the reference with a homography.
+    ///
+    /// ## Errors
+    /// If the homography is not 3x3, or a corner is projected to infinity.
+    pub fn from_homography(
+        homography: &Cmat<f64>,
+        width: f64,
+        height: f64,
+    ) -> Result<Footprint, MatError> {
+        let homography = homography.to_smatrix::<3, 3>()?;
+
+        let project = |x: f64, y: f64| {
+            let point = homography * Vector3x1::new(x, y, 1f64);
+
+            match point.z.abs() > f64::EPSILON {
+                true => Ok(Point2d::new(point.x / point.z, point.y / point.z)),
+                false => Err(MatError::Unknown),
+            }
+        };
+
+        Ok(Footprint {
+            corners: [
+                project(0f64, 0f64)?,
+                project(width, 0f64)?,
+                project(width, height)?,
+                project(0f64, height)?,
+            ],
+        })
+    }
+
+    /// The signed area of the footprint, positive when the corners keep the orientation of the query image.
+    pub fn area(&self) -> f64 {
+        let corners = &self.corners;
+
+        (0..corners.len())
+            .map(|i| {
+                let (a, b) = (corners[i], corners[(i + 1) % corners.len()]);
+                a.x * b.y - b.x * a.y
+            })
+            .sum::<f64>()
+            / 2f64
+    }
+
+    /// Whether the footprint is a convex quadrilateral with the same orientation as the query image.
+    /// A footprint from a bad homography is usually twisted or mirrored.
+    pub fn is_valid(&self) -> bool {
+        let corners = &self.corners;
+
+        (0..corners.len()).all(|i| {
+            let (a, b, c) = (
+                corners[i],
+                corners[(i + 1) % corners.len()],
+                corners[(i + 2) % corners.len()],
+            );
+
+            (b.x - a.x) * (c.y - b.y) - (b.y - a.y) * (c.x - b.x) > 0f64
+        })
+    }
+
+    /// The axis aligned bounding box of the footprint as `(x_start, y_start, x_end, y_end)`.
+    ///
+    /// Each side is moved outwards by `margin` times the width or height of the box.
+    pub fn bounds(&self, margin: f64) -> (f64, f64, f64, f64) {
+        let xs = self.corners.map(|corner| corner.x);
+        let ys = self.corners.map(|corner| corner.y);
+
+        let x_start = xs.iter().copied().fold(f64::INFINITY, f64::min);
+        let x_end = xs.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let y_start = ys.iter().copied().fold(f64::INFINITY, f64::min);
+        let y_end = ys.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+
+        let x_margin = (x_end - x_start) * margin;
+        let y_margin = (y_end - y_start) * margin;
+
+        (
+            x_start - x_margin,
+            y_start - y_margin,
+            x_end + x_margin,
+            y_end + y_margin,
+        )
+    }
+
+    /// The footprint as a closed WKT polygon, which can be loaded in QGIS together with the reference mosaic.
+    pub fn to_wkt(&self) -> String {
+        let points: Vec<String> = self
+            .corners
+            .iter()
+            .chain(self.corners.first())
+            .map(|corner| format!("{} {}", corner.x, corner.y))
+            .collect();
+
+        format!("POLYGON (({}))", points.join(", "))
+    }
+}
+
+/// A footprint found from a homography between a query image and the reference.
+#[derive(Debug)]
+pub struct FootprintEstimate {
+    pub footprint: Footprint,
+    /// Maps pixels of the preprocessed query image to full resolution reference pixels
+    pub homography: Cmat<f64>,
+    pub inliers: usize,
+}
+
+/// Estimates the footprint of a `width` x `height` query image from its matches with the reference.
+///
+/// Returns `Ok(None)` if there are too few matches, or the homography does not give a valid footprint.
+/// ## Parameters
+/// * reproj_threshold: the maximum reprojection error in reference pixels for a match to be a homography inlier
+pub fn estimate_footprint(
+    matches: &[PointMatch],
+    width: f64,
+    height: f64,
+    reproj_threshold: f64,
+) -> Result<Option<FootprintEstimate>, LocalizationError> {
+    if matches.len() < MIN_HOMOGRAPHY_MATCHES {
+        return Ok(None);
+    }
+
+    let (query_points, reference_points): (Vec<Point2f>, Vec<Point2f>) =
+        matches.iter().map(|m| (m.query, m.reference)).unzip();
+
+    let (homography, mask) = find_homography_mat(
+        &query_points,
+        &reference_points,
+        Some(HomographyMethod::RANSAC),
+        Some(reproj_threshold),
+    )
+    .map_err(LocalizationError::Mat)?;
+
+    let inliers = match mask {
+        Some(mask) => count_non_zero(&mask).map_err(LocalizationError::Opencv)? as usize,
+        None => matches.len(),
+    };
+
+    let footprint = match Footprint::from_homography(&homography, width, height) {
+        Ok(footprint) if footprint.is_valid() => footprint,
+        _ => return Ok(None),
+    };
+
+    Ok(Some(FootprintEstimate {
+        footprint,
+        homography,
+        inliers,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn homography(slice: [[f64; 3]; 3]) -> Cmat<f64> {
+        Cmat::from_2d_slice(&slice).expect("Could not create homography")
+    }
+
+    #[test]
+    fn footprint_from_translation() {
+        let translation = homography([
+            [2f64, 0f64, 100f64],
+            [0f64, 2f64, 50f64],
+            [0f64, 0f64, 1f64],
+        ]);
+
+        let footprint =
+            Footprint::from_homography(&translation, 10f64, 5f64).expect("Could not project");
+
+        assert_eq!(footprint.corners[0], Point2d::new(100f64, 50f64));
+        assert_eq!(footprint.corners[2], Point2d::new(120f64, 60f64));
+        assert_eq!(footprint.area(), 200f64);
+        assert!(footprint.is_valid());
+        assert_eq!(footprint.bounds(0.5), (90f64, 45f64, 130f64, 65f64));
+    }
+
+    #[test]
+    fn mirrored_footprint_is_invalid() {
+        let mirror = homography([[-1f64, 0f64, 0f64], [0f64, 1f64, 0f64], [0f64, 0f64, 1f64]]);
+
+        let footprint =
+            Footprint::from_homography(&mirror, 10f64, 10f64).expect("Could not project");
+
+        assert!(!footprint.is_valid());
+    }
+
+    #[test]
+    fn footprint_to_wkt() {
+        let identity = homography([[1f64, 0f64, 0f64], [0f64, 1f64, 0f64], [0f64, 0f64, 1f64]]);
+
+        let footprint =
+            Footprint::from_homography(&identity, 1f64, 1f64).expect("Could not project");
+
+        assert_eq!(footprint.to_wkt(), "POLYGON ((0 0, 1 0, 1 1, 0 1, 0 0))");
+    }
+}
@@ -0,0 +1,319 @@
+use std::time::{Duration, Instant};
+
+use diesel::{result::Error as DieselError, PgConnection};
+use feature_database::{
+    elevationdb,
+    keypointdb::{Keypoint, KeypointDatabase},
+};
+use feature_extraction::{akaze_keypoint_descriptor_extraction, ExtractedKeyPoint};
+use homographier::homographier::{
+    constrained::{pnp_solver_known_position, pnp_solver_known_rotation},
+    pnp_solver_ransac, ImgObjCorrespondence, MatError, PNPRANSACSolution, PnpRansacOptions,
+    Vector3x1,
+};
+use opencv::{
+    core::{Mat, Vec4b},
+    prelude::*,
+};
+use query_preprocessor::{preprocess_query, QueryOptions};
+use rand::{rngs::StdRng, SeedableRng};
+
+use ephemeris::EphemerisError;
+use footprint::Footprint;
+
+pub mod coarse_to_fine;
+pub mod ephemeris;
+pub mod filtering;
+pub mod footprint;
+pub mod reference;
+pub mod tracking;
+
+/// The minimum amount of correspondences needed to estimate a pose.
+pub const MIN_CORRESPONDENCES: usize = 4;
+/// The homography threshold in pixels of the level of detail, used when finding the footprint from the matches of the pose.
+/// The threshold is generous since the terrain is not planar.
+const FOOTPRINT_THRESHOLD: f64 = 8.0;
+
+#[derive(Debug)]
+pub enum LocalizationError {
+    Opencv(opencv::Error),
+    Mat(MatError),
+    Diesel(DieselError),
+    /// The world coordinates of a reference keypoint could not be found
+    Elevation(elevationdb::Errors),
+    /// The query options have no camera model, which is required to estimate the pose
+    MissingCamera,
+    Gdal(gdal::errors::GdalError),
+    /// The position of the camera could not be found from the ephemeris
+    Ephemeris(EphemerisError),
+    /// The coarse level of detail is not coarser than the level of detail the pose is estimated from
+    CoarseLevelOfDetail {
+        coarse: u64,
+        fine: u64,
+    },
+}
+
+/// A part of the pose known from another sensor, so only the rest is estimated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PoseConstraint {
+    /// The rotation vector from world to camera coordinates, e.g. from a star tracker
+    Rotation(Vector3x1),
+    /// The position of the camera center in the world coordinates of the correspondences, e.g. from GNSS
+    Position(Vector3x1),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalizationOptions {
+    /// The level of detail the pose is estimated from
+    pub level_of_detail: u64,
+    /// The threshold used in Lowe's ratio test when matching descriptors
+    pub ratio: f32,
+    /// The maximum amount of features extracted from the query image, the extractor default is used if [`None`]
+    pub max_features: Option<i32>,
+    /// Use the world coordinates stored with the reference keypoints by the preprocessor, instead of looking them up
+    /// in the elevation data. Keypoints stored without world coordinates are not matched
+    pub stored_world_coordinates: bool,
+    /// `method`, `sampler`, `refinement` and `extrinsic_guess` are ignored when the pose is constrained
+    pub pnp: PnpRansacOptions,
+    /// Estimate only the part of the pose that is not known, with the solvers in [`homographier::homographier::constrained`]
+    pub constraint: Option<PoseConstraint>,
+    /// Seeds the sampling of the constrained solvers so the pose is reproducible, seeded from entropy if [`None`]
+    pub seed: Option<u64>,
+}
+
+impl Default for LocalizationOptions {
+    fn default() -> Self {
+        LocalizationOptions {
+            level_of_detail: 0,
+            ratio: 0.7,
+            max_features: None,
+            stored_world_coordinates: false,
+            pnp: PnpRansacOptions::default(),
+            constraint: None,
+            seed: None,
+        }
+    }
+}
+
+/// The result of localizing a query image.
+#[derive(Debug)]
+pub struct Localization {
+    /// The area of the reference covered by the query image, if it could be estimated
+    pub footprint: Option<Footprint>,
+    /// The estimated pose, or [`None`] if no pose could be found
+    pub solution: Option<PNPRANSACSolution>,
+    pub correspondences: Vec<ImgObjCorrespondence>,
+    pub timings: StageTimings,
+}
+
+/// How long each stage of a localization took.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct StageTimings {
+    /// Finding the footprint in a coarse level of detail, zero unless localized coarse to fine
+    pub coarse: Duration,
+    /// Preprocessing the query image and extracting its features
+    pub extraction: Duration,
+    /// Reading the reference keypoints from the database
+    pub database: Duration,
+    pub matching: Duration,
+    /// Looking up the world coordinates of the matched reference keypoints
+    pub world_coordinates: Duration,
+    /// Estimating the footprint and the pose
+    pub pose: Duration,
+}
+
+impl StageTimings {
+    pub fn total(&self) -> Duration {
+        self.coarse
+            + self.extraction
+            + self.database
+            + self.matching
+            + self.world_coordinates
+            + self.pose
+    }
+}
+
+/// Keypoints and descriptors of a preprocessed query image.
+pub struct QueryFeatures {
+    pub features: ExtractedKeyPoint,
+    /// The factor the undistorted query image was resized with
+    pub scale: f64,
+    /// The width of the preprocessed image
+    pub width: i32,
+    /// The height of the preprocessed image
+    pub height: i32,
+}
+
+/// Preprocesses a query image and extracts at most `max_features` features, or the extractor default if [`None`].
+pub fn extract_query_features(
+    img: &Mat,
+    options: &QueryOptions,
+    max_features: Option<i32>,
+) -> Result<QueryFeatures, LocalizationError> {
+    // The element type of the preprocessed image depends on the normalization.
+    let (image, mask, scale) = match options.normalization.grayscale {
+        true => {
+            let query = preprocess_query::<u8>(img, options).map_err(LocalizationError::Mat)?;
+            (query.image.mat, query.mask, query.scale)
+        }
+        false => {
+            let query = preprocess_query::<Vec4b>(img, options).map_err(LocalizationError::Mat)?;
+            (query.image.mat, query.mask, query.scale)
+        }
+    };
+
+    let features = akaze_keypoint_descriptor_extraction(&image, &mask.mat, max_features)
+        .map_err(LocalizationError::Opencv)?;
+
+    Ok(QueryFeatures {
+        features,
+        scale,
+        width: image.cols(),
+        height: image.rows(),
+    })
+}
+
+/// Estimates the pose of the camera by matching a query image against a whole level of detail.
+///
+/// ## Parameters
+/// * img: the query image, see [`preprocess_query`]
+/// * query: the options used when preprocessing the query image, the level of detail is taken from `options`
+/// * options: see [`LocalizationOptions`]
+/// ## Errors
+/// If the query options have no camera, or the database, preprocessing or pose estimation fails.
+pub fn localize(
+    conn: &mut PgConnection,
+    img: &Mat,
+    query: &QueryOptions,
+    options: &LocalizationOptions,
+) -> Result<Localization, LocalizationError> {
+    estimate_pose(conn, img, query, None, options)
+}
+
+/// Estimates the pose of the camera from the reference keypoints within `bounds`, or the whole level of detail if [`None`].
+pub(crate) fn estimate_pose(
+    conn: &mut PgConnection,
+    img: &Mat,
+    query: &QueryOptions,
+    bounds: Option<(f64, f64, f64, f64)>,
+    options: &LocalizationOptions,
+) -> Result<Localization, LocalizationError> {
+    let camera = query.camera.ok_or(LocalizationError::MissingCamera)?;
+
+    let query = QueryOptions {
+        level_of_detail: options.level_of_detail,
+        ..query.clone()
+    };
+    let start = Instant::now();
+    let query_features = extract_query_features(img, &query, options.max_features)?;
+    let extraction = start.elapsed();
+
+    let start = Instant::now();
+    let level_of_detail = options.level_of_detail as i32;
+    let bounds = bounds.map(|(x_start, y_start, x_end, y_end)| {
+        (x_start as f32, y_start as f32, x_end as f32, y_end as f32)
+    });
+    let (keypoints, world) = match (options.stored_world_coordinates, bounds) {
+        (true, _) => {
+            let (keypoints, world): (Vec<_>, Vec<_>) =
+                Keypoint::read_keypoints_with_world_coordinates(conn, level_of_detail, bounds)
+                    .map_err(LocalizationError::Diesel)?
+                    .into_iter()
+                    .unzip();
+            (keypoints, Some(world))
+        }
+        (false, Some((x_start, y_start, x_end, y_end))) => (
+            Keypoint::read_keypoints_from_coordinates(
+                conn,
+                x_start,
+                y_start,
+                x_end,
+                y_end,
+                level_of_detail,
+            )
+            .map_err(LocalizationError::Diesel)?,
+            None,
+        ),
+        (false, None) => (
+            Keypoint::read_keypoints_from_lod(conn, level_of_detail)
+                .map_err(LocalizationError::Diesel)?,
+            None,
+        ),
+    };
+    let database = start.elapsed();
+
+    let start = Instant::now();
+    let reference = reference::reference_features(&keypoints).map_err(LocalizationError::Opencv)?;
+    let matches = reference::match_features(&query_features.features, &reference, options.ratio)
+        .map_err(LocalizationError::Opencv)?;
+    let matching = start.elapsed();
+
+    let start = Instant::now();
+    let correspondences = match &world {
+        Some(world) => reference::stored_correspondences(world, &matches, query_features.scale),
+        None => reference::correspondences(conn, &matches, query_features.scale)?,
+    };
+    let world_coordinates = start.elapsed();
+
+    let start = Instant::now();
+    let mut timings = StageTimings {
+        extraction,
+        database,
+        matching,
+        world_coordinates,
+        ..Default::default()
+    };
+
+    let footprint = footprint::estimate_footprint(
+        &matches,
+        query_features.width as f64,
+        query_features.height as f64,
+        FOOTPRINT_THRESHOLD * 2_f64.powi(level_of_detail),
+    )?
+    .map(|estimate| estimate.footprint);
+
+    if correspondences.len() < MIN_CORRESPONDENCES {
+        timings.pose = start.elapsed();
+
+        return Ok(Localization {
+            footprint,
+            solution: None,
+            correspondences,
+            timings,
+        });
+    }
+
+    // The query image is undistorted during preprocessing, so no distortion coefficients are needed.
+    let mut rng = match options.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let solution = match &options.constraint {
+        None => pnp_solver_ransac(&correspondences, &camera.intrinsic, None, &options.pnp),
+        Some(PoseConstraint::Rotation(rvec)) => pnp_solver_known_rotation(
+            &correspondences,
+            &camera.intrinsic,
+            None,
+            rvec,
+            &options.pnp,
+            &mut rng,
+        ),
+        Some(PoseConstraint::Position(position)) => pnp_solver_known_position(
+            &correspondences,
+            &camera.intrinsic,
+            None,
+            position,
+            &options.pnp,
+            &mut rng,
+        ),
+    }
+    .map_err(LocalizationError::Mat)?;
+    timings.pose = start.elapsed();
+
+    Ok(Localization {
+        footprint,
+        solution,
+        correspondences,
+        timings,
+    })
+}
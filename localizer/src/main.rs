@@ -0,0 +1,429 @@
+use chrono::{DateTime, NaiveDateTime};
+use clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum};
+use diesel::{Connection, PgConnection};
+use dotenvy::dotenv;
+use feature_extraction::{get_mat_from_dir, normalization::NormalizationOptions};
+use geotiff_lib::masking::MaskOptions;
+use homographier::homographier::{
+    pnp_method::RansacSampler, Cmat, PnpRansacOptions, PnpRefinement,
+};
+use localizer::{
+    coarse_to_fine::{localize_coarse_to_fine, CoarseOptions},
+    ephemeris::{localize_with_ephemeris, Ephemeris, EphemerisOptions, Geodetic},
+    filtering::{filter_track, read_gyro_csv, write_estimates_csv, FilterOptions},
+    localize,
+    tracking::{read_frame_timestamps_csv, write_track_csv, Tracker, TrackerOptions},
+    LocalizationOptions, PoseConstraint,
+};
+use nalgebra::Vector3;
+use opencv::prelude::MatTraitConst;
+use query_preprocessor::{CameraModel, QueryOptions};
+use std::{
+    env, fs,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Estimate the pose of a camera from a query image", long_about = None)]
+struct Args {
+    /// The path to the query image, or a directory of frames which are tracked in the order of their timestamps
+    query_path: String,
+
+    /// A CSV file with the columns filename,timestamp giving the time of every frame in seconds, when tracking a directory of frames.
+    /// Frames without a timestamp are skipped
+    #[arg(long)]
+    frame_timestamps: Option<String>,
+
+    /// The time between frames in seconds, used to timestamp the frames in filename order when no --frame-timestamps are given
+    #[arg(long, default_value_t = 1.0, conflicts_with = "frame_timestamps")]
+    frame_interval: f64,
+
+    /// Smooth the tracked attitude with a Kalman filter and print the filtered estimates instead of the track
+    #[arg(long)]
+    filter: bool,
+
+    /// A CSV file of gyro samples with the columns timestamp,wx,wy,wz fused by the filter, on the same clock as the frames
+    #[arg(long, requires = "filter")]
+    gyro: Option<String>,
+
+    /// The database url to connect to. Can also be provided by setting environment variable: DATABASE_URL
+    #[arg(long)]
+    database_url: Option<String>,
+
+    /// The camera calibration as found by the calibrator
+    #[arg(long, required = true, num_args = 4, value_names = ["FX", "FY", "CX", "CY"])]
+    intrinsics: Vec<f64>,
+
+    /// The distortion coefficients from the camera calibration
+    #[arg(long, num_args = 1.., default_values_t = [0.0, 0.0, 0.0, 0.0])]
+    dist_coeffs: Vec<f64>,
+
+    /// The ground sampling distance of the query image in meters per pixel
+    #[arg(long)]
+    query_gsd: f64,
+
+    /// The ground sampling distance of the full resolution reference mosaic in meters per pixel
+    #[arg(long)]
+    reference_gsd: f64,
+
+    /// The level of detail the pose is estimated from
+    #[arg(short, long, default_value_t = 0)]
+    lod: u64,
+
+    /// Find the footprint of the query image in this level of detail before estimating the pose, must be coarser than --lod.
+    /// Can not be combined with a known position, which gives the footprint instead. Tracking reacquires two levels coarser than --lod by default
+    #[arg(long, conflicts_with_all = ["ecef", "geodetic", "tle"])]
+    coarse_lod: Option<u64>,
+
+    /// How much the footprint is grown on every side before the fine lookup, as a fraction of its size
+    #[arg(long, default_value_t = 0.25)]
+    margin: f64,
+
+    /// The threshold used in Lowe's ratio test when matching descriptors
+    #[arg(long, default_value_t = 0.7)]
+    ratio: f32,
+
+    /// The maximum amount of features extracted from the query image
+    #[arg(long)]
+    max_features: Option<i32>,
+
+    /// Use the world coordinates the preprocessor stored with the keypoints, instead of the elevation data
+    #[arg(long)]
+    stored_world_coordinates: bool,
+
+    /// Convert the query image to grayscale, should match the preprocessor
+    #[arg(long)]
+    grayscale: bool,
+
+    /// Refine the pose over the inliers after RANSAC
+    #[arg(long, value_enum, default_value_t = Refinement::None)]
+    refinement: Refinement,
+
+    /// The known position of the camera in earth centered, earth fixed coordinates in meters
+    #[arg(long, num_args = 3, value_names = ["X", "Y", "Z"], conflicts_with_all = ["geodetic", "tle"], allow_negative_numbers = true)]
+    ecef: Vec<f64>,
+
+    /// The known position of the camera as latitude and longitude in degrees, and height above the ellipsoid in meters
+    #[arg(long, num_args = 3, value_names = ["LAT", "LON", "ALT"], conflicts_with = "tle", allow_negative_numbers = true)]
+    geodetic: Vec<f64>,
+
+    /// A two line element set of the satellite, propagated to the time of the query image
+    #[arg(long, num_args = 2, value_names = ["LINE1", "LINE2"], requires = "timestamp")]
+    tle: Vec<String>,
+
+    /// The UTC time the query image was taken in RFC 3339, e.g. 2024-01-01T12:00:00Z
+    #[arg(long, value_parser = parse_timestamp)]
+    timestamp: Option<NaiveDateTime>,
+
+    /// The largest expected angle between the optical axis and nadir in degrees, when a position is given
+    #[arg(long, default_value_t = 0.0)]
+    off_nadir: f64,
+
+    /// The error of the given position in meters, which is added around the nadir footprint
+    #[arg(long, default_value_t = 5000.0)]
+    position_margin: f64,
+
+    /// How RANSAC hypotheses are sampled and scored
+    #[arg(long, value_enum, default_value_t = Sampler::Ransac)]
+    sampler: Sampler,
+
+    /// The maximum amount of RANSAC iterations
+    #[arg(long, default_value_t = 1000)]
+    iterations: i32,
+
+    /// The known rotation vector from world to camera coordinates, only the position is estimated
+    #[arg(long, num_args = 3, value_names = ["RX", "RY", "RZ"], conflicts_with = "known_position", allow_negative_numbers = true)]
+    known_rotation: Vec<f64>,
+
+    /// The known position of the camera in the world coordinates of the reference, only the rotation is estimated
+    #[arg(long, num_args = 3, value_names = ["X", "Y", "Z"], allow_negative_numbers = true)]
+    known_position: Vec<f64>,
+
+    /// Seeds the sampling when the rotation or position is known, so the pose is reproducible
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum Sampler {
+    Ransac,
+    Magsac,
+    Prosac,
+}
+
+impl From<Sampler> for RansacSampler {
+    fn from(sampler: Sampler) -> Self {
+        match sampler {
+            Sampler::Ransac => RansacSampler::Ransac,
+            Sampler::Magsac => RansacSampler::Magsac,
+            Sampler::Prosac => RansacSampler::Prosac,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum Refinement {
+    None,
+    /// Levenberg-Marquardt
+    Lm,
+    /// Virtual visual servoing
+    Vvs,
+}
+
+impl Refinement {
+    fn to_pnp_refinement(self) -> Option<PnpRefinement> {
+        match self {
+            Refinement::None => None,
+            Refinement::Lm => Some(PnpRefinement::LevenbergMarquardt),
+            Refinement::Vvs => Some(PnpRefinement::VirtualVisualServoing),
+        }
+    }
+}
+
+fn parse_timestamp(timestamp: &str) -> Result<NaiveDateTime, chrono::ParseError> {
+    DateTime::parse_from_rfc3339(timestamp).map(|timestamp| timestamp.naive_utc())
+}
+
+impl Args {
+    /// The part of the pose that is known, if any
+    fn constraint(&self) -> Option<PoseConstraint> {
+        match (&self.known_rotation[..], &self.known_position[..]) {
+            ([x, y, z], _) => Some(PoseConstraint::Rotation(Vector3::new(*x, *y, *z))),
+            (_, [x, y, z]) => Some(PoseConstraint::Position(Vector3::new(*x, *y, *z))),
+            _ => None,
+        }
+    }
+
+    /// The known position of the camera, if any was given
+    fn ephemeris(&self) -> Option<Ephemeris> {
+        if let [x, y, z] = self.ecef[..] {
+            return Some(Ephemeris::Ecef(Vector3::new(x, y, z)));
+        }
+
+        if let [latitude, longitude, altitude] = self.geodetic[..] {
+            return Some(Ephemeris::Geodetic(Geodetic {
+                latitude,
+                longitude,
+                altitude,
+            }));
+        }
+
+        match (&self.tle[..], self.timestamp) {
+            ([line1, line2], Some(timestamp)) => Some(Ephemeris::Tle {
+                line1: line1.clone(),
+                line2: line2.clone(),
+                timestamp,
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn main() {
+    dotenv().ok();
+
+    let args = Args::parse();
+
+    // The tracker only seeds from the coarse lookup, a position could not be used per frame.
+    if Path::new(&args.query_path).is_dir() && args.ephemeris().is_some() {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--ecef, --geodetic and --tle can not be used when tracking a directory of frames",
+            )
+            .exit();
+    }
+
+    if matches!(args.coarse_lod, Some(coarse_lod) if coarse_lod <= args.lod) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--coarse-lod must be a coarser level of detail than --lod",
+            )
+            .exit();
+    }
+
+    let database_url = args
+        .database_url
+        .clone()
+        .unwrap_or_else(|| env::var("DATABASE_URL").expect("DATABASE_URL must be set"));
+    let conn = &mut PgConnection::establish(&database_url)
+        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url));
+
+    let (fx, fy, cx, cy) = (
+        args.intrinsics[0],
+        args.intrinsics[1],
+        args.intrinsics[2],
+        args.intrinsics[3],
+    );
+    let camera = CameraModel {
+        intrinsic: Cmat::from_2d_slice(&[[fx, 0.0, cx], [0.0, fy, cy], [0.0, 0.0, 1.0]])
+            .expect("Could not create camera matrix"),
+        dist_coeffs: args.dist_coeffs.clone(),
+    };
+
+    let query = QueryOptions {
+        camera: Some(&camera),
+        query_gsd: args.query_gsd,
+        reference_gsd: args.reference_gsd,
+        level_of_detail: args.lod,
+        normalization: NormalizationOptions {
+            grayscale: args.grayscale,
+            ..Default::default()
+        },
+        mask: MaskOptions::default(),
+    };
+    let options = LocalizationOptions {
+        level_of_detail: args.lod,
+        ratio: args.ratio,
+        max_features: args.max_features,
+        stored_world_coordinates: args.stored_world_coordinates,
+        pnp: PnpRansacOptions {
+            iter_count: args.iterations,
+            refinement: args.refinement.to_pnp_refinement(),
+            sampler: args.sampler.into(),
+            ..Default::default()
+        },
+        constraint: args.constraint(),
+        seed: args.seed,
+    };
+
+    // The coarse level must be coarser than --lod, so the default is relative to it.
+    let coarse = CoarseOptions {
+        level_of_detail: args
+            .coarse_lod
+            .unwrap_or(args.lod + CoarseOptions::default().level_of_detail),
+        margin: args.margin,
+        ..Default::default()
+    };
+
+    if Path::new(&args.query_path).is_dir() {
+        let options = TrackerOptions {
+            coarse,
+            localization: options,
+            ..Default::default()
+        };
+
+        track_frames(conn, &args, query, options);
+        return;
+    }
+
+    let img = get_mat_from_dir(&args.query_path).expect("Could not read query image");
+
+    let ephemeris_options = EphemerisOptions {
+        off_nadir: args.off_nadir.to_radians(),
+        margin: args.position_margin,
+    };
+
+    let localization = match (args.ephemeris(), args.coarse_lod) {
+        (Some(ephemeris), _) => {
+            localize_with_ephemeris(conn, &img, &query, &ephemeris, &ephemeris_options, &options)
+        }
+        (None, Some(_)) => localize_coarse_to_fine(conn, &img, &query, &coarse, &options),
+        (None, None) => localize(conn, &img, &query, &options),
+    }
+    .expect("Could not localize query image");
+
+    if let Some(footprint) = &localization.footprint {
+        println!("Footprint: {}", footprint.to_wkt());
+    }
+
+    println!("Correspondences: {}", localization.correspondences.len());
+
+    match &localization.solution {
+        Some(solution) => {
+            println!(
+                "Rotation vector: {:?}",
+                solution.rvec.col(0).expect("Invalid rotation vector")
+            );
+            println!(
+                "Translation vector: {:?}",
+                solution.tvec.col(0).expect("Invalid translation vector")
+            );
+        }
+        None => println!("No pose found"),
+    }
+}
+
+/// Tracks the frames in the query directory and prints the track as CSV.
+fn track_frames(
+    conn: &mut PgConnection,
+    args: &Args,
+    query: QueryOptions,
+    options: TrackerOptions,
+) {
+    let mut paths: Vec<PathBuf> = fs::read_dir(&args.query_path)
+        .expect("Could not read frame directory")
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    let timestamps = args.frame_timestamps.as_ref().map(|path| {
+        let file = fs::File::open(path).expect("Could not open frame timestamps");
+        read_frame_timestamps_csv(BufReader::new(file)).expect("Could not read frame timestamps")
+    });
+
+    let mut timestamped: Vec<(f64, PathBuf)> = paths
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, path)| match &timestamps {
+            Some(timestamps) => {
+                let timestamp = path
+                    .file_name()
+                    .and_then(|name| timestamps.get(name.to_string_lossy().as_ref()));
+
+                match timestamp {
+                    Some(timestamp) => Some((*timestamp, path)),
+                    None => {
+                        eprintln!("Skipping {}, it has no timestamp", path.display());
+                        None
+                    }
+                }
+            }
+            None => Some((i as f64 * args.frame_interval, path)),
+        })
+        .collect();
+    timestamped.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    // Files that are not images, such as sidecar files, are skipped without shifting the timestamps of the other frames.
+    let frames = timestamped.into_iter().filter_map(|(timestamp, path)| {
+        match get_mat_from_dir(&path.to_string_lossy()) {
+            Ok(img) if !img.empty() => Some((timestamp, img)),
+            _ => {
+                eprintln!("Skipping {}, it is not a readable image", path.display());
+                None
+            }
+        }
+    });
+
+    let mut tracker = Tracker::new(query, options);
+    let track = tracker
+        .track_sequence(conn, frames)
+        .expect("Could not track frames");
+
+    if !args.filter {
+        write_track_csv(&mut io::stdout(), &track).expect("Could not write track");
+        return;
+    }
+
+    let gyro = match &args.gyro {
+        Some(path) => {
+            let file = fs::File::open(path).expect("Could not open gyro samples");
+            read_gyro_csv(BufReader::new(file)).expect("Could not read gyro samples")
+        }
+        None => Vec::new(),
+    };
+
+    let (estimates, statistics) = filter_track(&track, &gyro, FilterOptions::default());
+
+    write_estimates_csv(&mut io::stdout(), &estimates).expect("Could not write estimates");
+
+    eprintln!(
+        "Accepted {} measurements, rejected {}, mean NIS {:?}",
+        statistics.accepted,
+        statistics.rejected,
+        statistics.mean_nis()
+    );
+}
@@ -0,0 +1,190 @@
+use diesel::PgConnection;
+use feature_database::{elevationdb::geotransform, models};
+use feature_extraction::{get_knn_matches, ExtractedKeyPoint};
+use geotiff_lib::geodesy::OutputFrame;
+use homographier::homographier::ImgObjCorrespondence;
+use opencv::{
+    core::{KeyPoint, Mat, Point2d, Point2f, Point3d, Vector},
+    prelude::*,
+    Error,
+};
+
+use crate::LocalizationError;
+
+/// The amount of nearest neighbours found for every query descriptor, two are needed for the ratio test.
+const KNN_NEIGHBOURS: i32 = 2;
+
+/// A match between a query keypoint and a reference keypoint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PointMatch {
+    /// Pixel coordinates in the preprocessed query image
+    pub query: Point2f,
+    /// Full resolution pixel coordinates in the reference mosaic
+    pub reference: Point2f,
+    /// The index of the reference keypoint in the keypoints it was matched against
+    pub reference_index: usize,
+    /// The Hamming distance between the descriptors, lower is better
+    pub distance: f32,
+}
+
+/// Converts keypoints read from the database to keypoints and descriptors that can be matched against a query image.
+pub fn reference_features(keypoints: &[models::Keypoint]) -> Result<ExtractedKeyPoint, Error> {
+    let cv_keypoints = keypoints
+        .iter()
+        .map(|keypoint| {
+            KeyPoint::new_coords(
+                keypoint.x_coord,
+                keypoint.y_coord,
+                keypoint.size,
+                keypoint.angle,
+                keypoint.response,
+                keypoint.octave,
+                keypoint.class_id,
+            )
+        })
+        .collect::<Result<Vector<KeyPoint>, Error>>()?;
+
+    let rows: Vec<&[u8]> = keypoints
+        .iter()
+        .map(|keypoint| keypoint.descriptor.as_slice())
+        .collect();
+
+    let descriptors = match rows.is_empty() {
+        true => Mat::default(),
+        false => Mat::from_slice_2d(&rows)?,
+    };
+
+    Ok(ExtractedKeyPoint::new(cv_keypoints, descriptors))
+}
+
+/// Matches query features against reference features, keeping matches that pass Lowe's ratio test with `ratio`.
+///
+/// The matches are sorted by descriptor distance, best first, as PROSAC expects.
+pub fn match_features(
+    query: &ExtractedKeyPoint,
+    reference: &ExtractedKeyPoint,
+    ratio: f32,
+) -> Result<Vec<PointMatch>, Error> {
+    // The ratio test needs two neighbours for every query descriptor.
+    if query.keypoints().is_empty() || reference.keypoints().len() < KNN_NEIGHBOURS as usize {
+        return Ok(Vec::new());
+    }
+
+    let matches = get_knn_matches(
+        query.descriptors(),
+        reference.descriptors(),
+        KNN_NEIGHBOURS,
+        ratio,
+    )?;
+
+    let mut matches = matches
+        .iter()
+        .map(|m| {
+            let reference_index = m.train_idx.try_into()?;
+
+            Ok(PointMatch {
+                query: query.keypoints().get(m.query_idx.try_into()?)?.pt(),
+                reference: reference.keypoints().get(reference_index)?.pt(),
+                reference_index,
+                distance: m.distance,
+            })
+        })
+        .collect::<Result<Vec<PointMatch>, Error>>()?;
+
+    matches.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+
+    Ok(matches)
+}
+
+/// Builds 2D-3D correspondences from matches by looking up the world coordinates of every reference keypoint.
+///
+/// `scale` is the factor the query image was resized with during preprocessing, see [`query_preprocessor::PreprocessedQuery`].
+/// The image points are in the coordinates of the undistorted query image.
+/// The world coordinates of all matches are looked up as one batch, and matches without a height are left out.
+/// The correspondences keep the order of the matches, so they stay sorted for PROSAC.
+pub fn correspondences(
+    conn: &mut PgConnection,
+    matches: &[PointMatch],
+    scale: f64,
+) -> Result<Vec<ImgObjCorrespondence>, LocalizationError> {
+    if matches.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let pixels: Vec<(f64, f64)> = matches
+        .iter()
+        .map(|m| (m.reference.x as f64, m.reference.y as f64))
+        .collect();
+
+    let world = geotransform::Georeferences::read(conn)
+        .and_then(|georeferences| georeferences.world_coordinates(conn, &pixels, OutputFrame::Ecef))
+        .map_err(LocalizationError::Elevation)?;
+
+    Ok(matches
+        .iter()
+        .zip(world)
+        .filter_map(|(m, world)| {
+            let (x, y, z) = world?;
+
+            Some(ImgObjCorrespondence::new(
+                Point3d::new(x, y, z),
+                Point2d::new(m.query.x as f64 / scale, m.query.y as f64 / scale),
+            ))
+        })
+        .collect())
+}
+
+/// Builds 2D-3D correspondences from matches with the world coordinates stored with the reference keypoints,
+/// see [`feature_database::keypointdb::KeypointDatabase::read_keypoints_with_world_coordinates`].
+///
+/// `world` holds the world coordinates of the reference keypoints in the order they were matched against.
+pub fn stored_correspondences(
+    world: &[models::WorldCoordinates],
+    matches: &[PointMatch],
+    scale: f64,
+) -> Vec<ImgObjCorrespondence> {
+    matches
+        .iter()
+        .filter_map(|m| {
+            let world = world.get(m.reference_index)?;
+
+            Some(ImgObjCorrespondence::new(
+                Point3d::new(world.ecef_x, world.ecef_y, world.ecef_z),
+                Point2d::new(m.query.x as f64 / scale, m.query.y as f64 / scale),
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(points: &[(f32, f32)], descriptors: &[[u8; 1]]) -> ExtractedKeyPoint {
+        let keypoints = points
+            .iter()
+            .map(|(x, y)| KeyPoint::new_coords(*x, *y, 1.0, 0.0, 1.0, 0, -1).unwrap())
+            .collect();
+
+        ExtractedKeyPoint::new(keypoints, Mat::from_slice_2d(descriptors).unwrap())
+    }
+
+    #[test]
+    fn matches_are_sorted_by_distance() {
+        let query = features(&[(0.0, 0.0), (1.0, 1.0)], &[[0b0000_0011], [0b1111_1110]]);
+        let reference = features(
+            &[(10.0, 10.0), (20.0, 20.0), (30.0, 30.0)],
+            &[[0b0000_0000], [0b1111_1111], [0b1111_0000]],
+        );
+
+        let matches = match_features(&query, &reference, 0.7).unwrap();
+
+        // The second query keypoint is one bit from its match, the first is two bits from its match.
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].query, Point2f::new(1.0, 1.0));
+        assert_eq!(matches[0].reference_index, 1);
+        assert_eq!(matches[0].distance, 1.0);
+        assert_eq!(matches[1].reference_index, 0);
+        assert_eq!(matches[1].distance, 2.0);
+    }
+}
@@ -0,0 +1,433 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+};
+
+use diesel::PgConnection;
+use homographier::homographier::{
+    pnp_method::{PnpMethod, RansacSampler},
+    Pose, QualityFlag, SolutionQuality,
+};
+use nalgebra::Rotation3;
+use opencv::core::{Mat, Point2d};
+use query_preprocessor::QueryOptions;
+
+use crate::{
+    coarse_to_fine::{localize_coarse_to_fine, CoarseOptions},
+    estimate_pose,
+    footprint::Footprint,
+    LocalizationError, LocalizationOptions,
+};
+
+/// Options for a [`Tracker`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackerOptions {
+    /// Used when the tracker has no previous pose, and when reacquiring after tracking is lost
+    pub coarse: CoarseOptions,
+    pub localization: LocalizationOptions,
+    /// How much the predicted footprint is grown on every side before the database lookup, as a fraction of its size
+    pub margin: f64,
+    /// The amount of consecutive frames without a pose before the tracker is reset
+    pub max_lost_frames: usize,
+    /// Solutions with this quality or worse are treated as if no pose was found
+    pub reject_quality: QualityFlag,
+}
+
+impl Default for TrackerOptions {
+    fn default() -> Self {
+        TrackerOptions {
+            coarse: CoarseOptions::default(),
+            localization: LocalizationOptions::default(),
+            margin: 0.5,
+            max_lost_frames: 3,
+            reject_quality: QualityFlag::Poor,
+        }
+    }
+}
+
+/// A pose of the track.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackedPose {
+    /// The time of the frame in seconds
+    pub timestamp: f64,
+    pub pose: Pose,
+    pub quality: SolutionQuality,
+    pub footprint: Option<Footprint>,
+    /// Whether the frame was localized within the predicted footprint, or by searching the whole reference
+    pub predicted: bool,
+}
+
+/// A frame that was successfully localized, used to predict the next frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TrackState {
+    timestamp: f64,
+    pose: Pose,
+    /// [`None`] if the footprint could not be estimated, and there was no earlier footprint to fall back to
+    footprint: Option<Footprint>,
+}
+
+/// Localizes a sequence of query frames, using the previous frames to predict where the next frame is.
+///
+/// The pose and footprint are predicted with a constant rate model from the last two localized frames,
+/// the database lookup is restricted to the predicted footprint, and the predicted pose is used as the extrinsic guess of the iterative method.
+/// When no pose is found for [`TrackerOptions::max_lost_frames`] frames, the tracker is reset and the next frame is localized with [`localize_coarse_to_fine`].
+pub struct Tracker<'a> {
+    query: QueryOptions<'a>,
+    options: TrackerOptions,
+    last: Option<TrackState>,
+    previous: Option<TrackState>,
+    lost_frames: usize,
+}
+
+impl<'a> Tracker<'a> {
+    pub fn new(query: QueryOptions<'a>, options: TrackerOptions) -> Tracker<'a> {
+        Tracker {
+            query,
+            options,
+            last: None,
+            previous: None,
+            lost_frames: 0,
+        }
+    }
+
+    /// Whether the tracker has a previous pose to predict from
+    pub fn is_tracking(&self) -> bool {
+        self.last.is_some()
+    }
+
+    /// Forgets the previous frames, so the next frame is localized by searching the whole reference
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.previous = None;
+        self.lost_frames = 0;
+    }
+
+    /// Localizes the next frame of the sequence.
+    ///
+    /// Returns `Ok(None)` if no pose of sufficient quality was found for the frame.
+    /// ## Parameters
+    /// * timestamp: the time of the frame in seconds, should be increasing
+    /// * img: the query frame
+    pub fn track(
+        &mut self,
+        conn: &mut PgConnection,
+        timestamp: f64,
+        img: &Mat,
+    ) -> Result<Option<TrackedPose>, LocalizationError> {
+        let prediction = self.last.map(|last| match self.previous {
+            Some(previous) => predict(&previous, &last, timestamp),
+            None => (last.pose, last.footprint),
+        });
+
+        let localization = match prediction {
+            Some((pose, Some(footprint))) => {
+                let options = seeded_options(&self.options.localization, pose);
+
+                estimate_pose(
+                    conn,
+                    img,
+                    &self.query,
+                    Some(footprint.bounds(self.options.margin)),
+                    &options,
+                )?
+            }
+            _ => localize_coarse_to_fine(
+                conn,
+                img,
+                &self.query,
+                &self.options.coarse,
+                &self.options.localization,
+            )?,
+        };
+
+        let solution = localization
+            .solution
+            .filter(|solution| solution.quality.flag < self.options.reject_quality);
+
+        // A failed footprint homography does not invalidate the pose, so the predicted footprint is kept instead.
+        let footprint = localization
+            .footprint
+            .or_else(|| prediction.and_then(|(_, footprint)| footprint));
+
+        let solution = match solution {
+            Some(solution) => solution,
+            None => {
+                self.lost_frames += 1;
+
+                if self.lost_frames > self.options.max_lost_frames {
+                    self.reset();
+                }
+
+                return Ok(None);
+            }
+        };
+
+        let pose = solution.pose().map_err(LocalizationError::Mat)?;
+
+        self.previous = self.last;
+        self.last = Some(TrackState {
+            timestamp,
+            pose,
+            footprint,
+        });
+        self.lost_frames = 0;
+
+        Ok(Some(TrackedPose {
+            timestamp,
+            pose,
+            quality: solution.quality,
+            footprint,
+            predicted: matches!(prediction, Some((_, Some(_)))),
+        }))
+    }
+
+    /// Localizes every frame of a sequence, returning the frames where a pose was found.
+    pub fn track_sequence(
+        &mut self,
+        conn: &mut PgConnection,
+        frames: impl IntoIterator<Item = (f64, Mat)>,
+    ) -> Result<Vec<TrackedPose>, LocalizationError> {
+        let mut track = Vec::new();
+
+        for (timestamp, img) in frames {
+            if let Some(pose) = self.track(conn, timestamp, &img)? {
+                track.push(pose);
+            }
+        }
+
+        Ok(track)
+    }
+}
+
+/// The localization options of a frame with a predicted pose.
+///
+/// The pose is refined from the prediction with the iterative method, and the classic RANSAC sampler is used since USAC ignores the guess.
+fn seeded_options(options: &LocalizationOptions, prediction: Pose) -> LocalizationOptions {
+    let mut options = options.clone();
+
+    options.pnp.extrinsic_guess = Some(prediction);
+    options.pnp.method = PnpMethod::Iterative;
+    options.pnp.sampler = RansacSampler::Ransac;
+
+    options
+}
+
+/// Extrapolates the pose and footprint at `timestamp` from two earlier frames, assuming a constant angular and linear rate.
+/// The footprint of the last frame is kept if the previous frame has none.
+fn predict(previous: &TrackState, last: &TrackState, timestamp: f64) -> (Pose, Option<Footprint>) {
+    let interval = last.timestamp - previous.timestamp;
+
+    if interval <= 0f64 {
+        return (last.pose, last.footprint);
+    }
+
+    let factor = (timestamp - last.timestamp) / interval;
+
+    let previous_rotation = Rotation3::new(previous.pose.rvec);
+    let last_rotation = Rotation3::new(last.pose.rvec);
+    let rate = (last_rotation * previous_rotation.inverse()).scaled_axis();
+    let rotation = Rotation3::new(rate * factor) * last_rotation;
+
+    let pose = Pose {
+        rvec: rotation.scaled_axis(),
+        tvec: last.pose.tvec + (last.pose.tvec - previous.pose.tvec) * factor,
+    };
+
+    let footprint = match (previous.footprint, last.footprint) {
+        (Some(previous_footprint), Some(last_footprint)) => {
+            let mut corners = last_footprint.corners;
+            for (corner, previous_corner) in corners.iter_mut().zip(previous_footprint.corners) {
+                *corner = Point2d::new(
+                    corner.x + (corner.x - previous_corner.x) * factor,
+                    corner.y + (corner.y - previous_corner.y) * factor,
+                );
+            }
+
+            Some(Footprint { corners })
+        }
+        (_, footprint) => footprint,
+    };
+
+    (pose, footprint)
+}
+
+/// Writes a track as CSV, with the rotation and translation vectors, reprojection RMS, inlier ratio and quality of every pose.
+pub fn write_track_csv(writer: &mut impl Write, track: &[TrackedPose]) -> io::Result<()> {
+    writeln!(
+        writer,
+        "timestamp,rx,ry,rz,tx,ty,tz,reprojection_rms,inlier_ratio,quality,predicted"
+    )?;
+
+    for pose in track {
+        let (r, t) = (pose.pose.rvec, pose.pose.tvec);
+
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{},{:?},{}",
+            pose.timestamp,
+            r.x,
+            r.y,
+            r.z,
+            t.x,
+            t.y,
+            t.z,
+            pose.quality.reprojection_rms,
+            pose.quality.inlier_ratio,
+            pose.quality.flag,
+            pose.predicted
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Reads the time of every frame from CSV with the columns `filename,timestamp`, in seconds.
+/// A header line and empty lines are skipped.
+pub fn read_frame_timestamps_csv(reader: impl BufRead) -> io::Result<HashMap<String, f64>> {
+    let mut timestamps = HashMap::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let timestamp = line
+            .split_once(',')
+            .map(|(filename, timestamp)| (filename.trim(), timestamp.trim().parse::<f64>()));
+
+        match timestamp {
+            Some((filename, Ok(timestamp))) => {
+                timestamps.insert(filename.to_string(), timestamp);
+            }
+            // The header
+            Some((_, Err(_))) if i == 0 => continue,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid frame timestamp on line {}: {}", i + 1, line),
+                ))
+            }
+        }
+    }
+
+    Ok(timestamps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use homographier::homographier::{PnpRansacOptions, Vector3x1};
+
+    fn state(timestamp: f64, angle: f64, offset: f64) -> TrackState {
+        let corners = [(0f64, 0f64), (10f64, 0f64), (10f64, 10f64), (0f64, 10f64)]
+            .map(|(x, y)| Point2d::new(x + offset, y));
+
+        TrackState {
+            timestamp,
+            pose: Pose {
+                rvec: Vector3x1::new(0f64, 0f64, angle),
+                tvec: Vector3x1::new(offset, 0f64, 100f64),
+            },
+            footprint: Some(Footprint { corners }),
+        }
+    }
+
+    #[test]
+    fn constant_rate_prediction() {
+        let (pose, footprint) = predict(&state(0.0, 0.1, 0.0), &state(1.0, 0.2, 5.0), 2.0);
+
+        assert!((pose.rvec - Vector3x1::new(0f64, 0f64, 0.3)).norm() < 1e-12);
+        assert!((pose.tvec - Vector3x1::new(10f64, 0f64, 100f64)).norm() < 1e-12);
+        assert_eq!(footprint.unwrap().corners[0], Point2d::new(10f64, 0f64));
+    }
+
+    #[test]
+    fn prediction_keeps_last_footprint() {
+        let previous = TrackState {
+            footprint: None,
+            ..state(0.0, 0.1, 0.0)
+        };
+        let last = state(1.0, 0.2, 5.0);
+
+        let (_, footprint) = predict(&previous, &last, 2.0);
+
+        assert_eq!(footprint, last.footprint);
+    }
+
+    #[test]
+    fn worse_qualities_are_rejected() {
+        let reject = TrackerOptions::default().reject_quality;
+
+        assert!(QualityFlag::Good < reject);
+        assert!(QualityFlag::Degraded < reject);
+        assert!(QualityFlag::Poor >= reject);
+    }
+
+    #[test]
+    fn prediction_seeds_the_iterative_method() {
+        let prediction = state(1.0, 0.2, 5.0).pose;
+        let options = LocalizationOptions {
+            pnp: PnpRansacOptions {
+                sampler: RansacSampler::Magsac,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let seeded = seeded_options(&options, prediction);
+
+        assert_eq!(seeded.pnp.extrinsic_guess, Some(prediction));
+        assert_eq!(seeded.pnp.method, PnpMethod::Iterative);
+        assert_eq!(seeded.pnp.sampler, RansacSampler::Ransac);
+        assert_eq!(seeded.pnp.iter_count, options.pnp.iter_count);
+    }
+
+    #[test]
+    fn frame_timestamps_csv() {
+        let csv = "filename,timestamp\nframe_1.png,0.5\n\nframe_0.png, 0.25\n";
+
+        let timestamps = read_frame_timestamps_csv(csv.as_bytes()).unwrap();
+
+        assert_eq!(timestamps.len(), 2);
+        assert_eq!(timestamps.get("frame_0.png"), Some(&0.25));
+        assert!(read_frame_timestamps_csv("frame_0.png\n".as_bytes()).is_err());
+        assert!(read_frame_timestamps_csv("a,0\nb,c\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn prediction_without_interval() {
+        let last = state(1.0, 0.2, 5.0);
+
+        let (pose, footprint) = predict(&state(1.0, 0.1, 0.0), &last, 2.0);
+
+        assert_eq!(pose, last.pose);
+        assert_eq!(footprint, last.footprint);
+    }
+
+    #[test]
+    fn track_to_csv() {
+        let track = vec![TrackedPose {
+            timestamp: 1.5,
+            pose: state(1.5, 0.25, 0.0).pose,
+            quality: SolutionQuality {
+                reprojection_rms: 0.5,
+                covariance: None,
+                inlier_ratio: 0.75,
+                flag: QualityFlag::Good,
+            },
+            footprint: None,
+            predicted: true,
+        }];
+        let mut csv = Vec::new();
+
+        write_track_csv(&mut csv, &track).unwrap();
+
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(
+            csv.lines().nth(1),
+            Some("1.5,0,0,0.25,0,0,100,0.5,0.75,Good,true")
+        );
+    }
+}
@@ -1,23 +1,39 @@
 use diesel::PgConnection;
 use dotenvy::dotenv;
 use feature_database::{
-    imagedb, imagedb::ImageDatabase, keypointdb, keypointdb::KeypointDatabase, models,
+    elevationdb::{geotransform::Georeferences, HeightReference}, imagedb, imagedb::ImageDatabase, keypointdb, keypointdb::KeypointDatabase, models,
 };
-use feature_extraction::{akaze_keypoint_descriptor_extraction_def, DbKeypoints};
+use feature_extraction::{
+    akaze_keypoint_descriptor_extraction, get_mat_from_dir,
+    keypoint_selection::GridSelection,
+    normalization::{normalize_image, reference_histogram, Normalization, NormalizationOptions},
+    DbKeypoints,
+};
+use geotiff_lib::elevation::{check_scale, ElevationOptions, ElevationResampling, VerticalDatum};
+use geotiff_lib::geodesy::{dataset_spatial_ref, ecef_to_geographic, OutputFrame};
 use geotiff_lib::image_extractor;
-use geotiff_lib::image_extractor::{Datasets, MosaicDataset, MosaicedDataset};
-use homographier::homographier::raster_to_mat;
+use geotiff_lib::image_extractor::{
+    check_band_indexes, Datasets, MosaicDataset, MosaicedDataset, Stretch,
+};
+use geotiff_lib::masking::MaskOptions;
+use homographier::homographier::mask_to_mat;
 use indicatif::{MultiProgress, ProgressBar};
 use tempfile::tempdir;
 
 use level_of_detail::calculate_amount_of_levels;
+use tile::{Tile, TileDepth};
 use raycon::Scope;
 use rayon as raycon;
 
-use clap::{Parser, Subcommand};
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand, ValueEnum};
 use std::sync::{Arc, Mutex};
 
 pub mod level_of_detail;
+pub mod tile;
+
+const DEFAULT_CLIP_PERCENTILES: (f64, f64) = (2.0, 98.0);
+const CLAHE_CLIP_LIMIT: f64 = 2.0;
+const CLAHE_TILE_GRID_SIZE: i32 = 8;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -49,6 +65,142 @@ pub struct Args {
     /// The path to the optional elevation dataset
     #[arg(short, long)]
     elevation_path: Option<String>,
+
+    /// The surface the heights of the elevation dataset are measured from. The heights are converted to heights above the ellipsoid when they are added
+    #[arg(long, value_enum, default_value_t = HeightReferenceArg::Ellipsoid)]
+    height_reference: HeightReferenceArg,
+
+    /// A grid of geoid heights above the ellipsoid, for elevation datasets measured from a national geoid such as DVR90 of the Danish DHM.
+    /// Replaces --height-reference
+    #[arg(long, conflicts_with = "height_reference")]
+    geoid_grid: Option<String>,
+
+    /// The size of an elevation pixel in mosaic pixels. The elevation is warped onto a grid aligned with the mosaic
+    #[arg(long, default_value_t = ElevationOptions::default().scale, value_parser = parse_elevation_scale)]
+    elevation_scale: f64,
+
+    /// How the elevation is resampled when it is warped onto the grid of the mosaic
+    #[arg(long, value_enum, default_value_t = ResamplingArg::Bilinear)]
+    elevation_resampling: ResamplingArg,
+
+    /// Store the earth centered, earth fixed coordinates and latitude, longitude and height of every keypoint,
+    /// so localization does not need the elevation data. Keypoints are placed on the ellipsoid without elevation data
+    #[arg(long)]
+    world_coordinates: bool,
+
+    /// The maximum amount of keypoints kept in every grid cell of a tile. All keypoints are kept if not provided
+    #[arg(long)]
+    keypoints_per_cell: Option<usize>,
+
+    /// The side length in pixels of the grid cells used with keypoints_per_cell
+    #[arg(long, default_value_t = 64)]
+    cell_size: u32,
+
+    /// Stretch each band of the mosaic between a lower and upper percentile instead of its minimum and maximum
+    #[arg(long, num_args = 2, value_names = ["LOWER", "UPPER"])]
+    clip_percentiles: Option<Vec<f64>>,
+
+    /// The normalization applied to each tile before feature extraction
+    #[arg(long, value_enum, default_value_t = NormalizationMethod::None)]
+    normalization: NormalizationMethod,
+
+    /// The percentiles each tile is stretched between by the percentile normalization, independent of --clip-percentiles
+    #[arg(long, num_args = 2, value_names = ["LOWER", "UPPER"], default_values_t = [DEFAULT_CLIP_PERCENTILES.0, DEFAULT_CLIP_PERCENTILES.1])]
+    normalization_percentiles: Vec<f64>,
+
+    /// The image whose histogram tiles are matched to. Required by histogram-matching
+    #[arg(long)]
+    histogram_reference: Option<String>,
+
+    /// Extract features from a grayscale version of each tile
+    #[arg(long)]
+    grayscale: bool,
+
+    /// The path to an optional mask raster, where non-zero pixels are excluded from feature extraction
+    #[arg(long)]
+    mask_path: Option<String>,
+
+    /// The index of the near infrared band, used for water masking
+    #[arg(long)]
+    nir_band: Option<isize>,
+
+    /// Mask pixels with a normalized difference water index above the threshold. Requires nir_band
+    #[arg(long, requires = "nir_band")]
+    ndwi_threshold: Option<f32>,
+
+    /// Mask pixels with a mean 8-bit RGB value above the threshold, such as clouds and snow
+    #[arg(long)]
+    brightness_threshold: Option<u8>,
+
+    /// The band indexes to extract features from, either a single band such as panchromatic, or 3 bands used as red, green and blue such as 4 3 2 for false color.
+    /// Uses the RGB bands if not provided
+    #[arg(long, num_args = 1..=3)]
+    bands: Option<Vec<isize>>,
+
+    /// The depth of the tiles given to the feature extractor. Band combinations are always 8-bit, so it can not be combined with --bands
+    #[arg(long, value_enum, default_value_t = TileDepth::U8, conflicts_with = "bands")]
+    depth: TileDepth,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum NormalizationMethod {
+    /// Use the tile as is
+    None,
+    /// Stretch each tile between the normalization percentiles
+    Percentile,
+    /// Contrast limited adaptive histogram equalization
+    Clahe,
+    /// Match the histogram of each tile to the histogram reference
+    HistogramMatching,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum HeightReferenceArg {
+    /// Heights above the WGS84 ellipsoid
+    Ellipsoid,
+    /// Heights above the EGM96 geoid
+    Egm96,
+    /// Heights above the EGM2008 geoid, such as the Copernicus DEM
+    Egm2008,
+}
+
+impl From<HeightReferenceArg> for HeightReference {
+    fn from(value: HeightReferenceArg) -> Self {
+        match value {
+            HeightReferenceArg::Ellipsoid => HeightReference::Ellipsoid,
+            HeightReferenceArg::Egm96 => HeightReference::Egm96,
+            HeightReferenceArg::Egm2008 => HeightReference::Egm2008,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ResamplingArg {
+    Nearest,
+    Bilinear,
+    Cubic,
+}
+
+impl From<ResamplingArg> for ElevationResampling {
+    fn from(value: ResamplingArg) -> Self {
+        match value {
+            ResamplingArg::Nearest => ElevationResampling::Nearest,
+            ResamplingArg::Bilinear => ElevationResampling::Bilinear,
+            ResamplingArg::Cubic => ElevationResampling::Cubic,
+        }
+    }
+}
+
+/// Options applied to every tile during feature extraction.
+#[derive(Debug, Clone)]
+pub struct ExtractionOptions {
+    pub normalization: NormalizationOptions,
+    pub mask: MaskOptions,
+    pub selection: Option<GridSelection>,
+    pub bands: Option<Vec<isize>>,
+    pub depth: TileDepth,
+    /// Used to compute the world coordinates of the keypoints, which are not stored if [`None`]
+    pub georeferences: Option<Georeferences>,
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -72,10 +224,50 @@ fn main() {
 
     let args = Args::parse();
 
+    // The feature extraction only supports grayscale and color images.
+    if matches!(&args.bands, Some(bands) if bands.len() == 2) {
+        Args::command()
+            .error(ErrorKind::WrongNumberOfValues, "--bands takes either 1 or 3 band indexes")
+            .exit();
+    }
+
+    let percentiles = [
+        ("--clip-percentiles", args.clip_percentiles.as_deref()),
+        ("--normalization-percentiles", Some(args.normalization_percentiles.as_slice())),
+    ];
+    for (name, percentiles) in percentiles {
+        if let Some(Err(e)) = percentiles.map(check_percentiles) {
+            Args::command()
+                .error(ErrorKind::ValueValidation, format!("{}: {}", name, e))
+                .exit();
+        }
+    }
+
     if args.calculate_lod == true {
         level_of_detail::calculate_level_of_detail_resolution(&args);
     }
 
+    let stretch = stretch_from_args(&args);
+    let mut options = ExtractionOptions {
+        normalization: normalization_from_args(&args),
+        mask: MaskOptions {
+            nir_band_index: args.nir_band,
+            ndwi_threshold: args.ndwi_threshold,
+            brightness_threshold: args.brightness_threshold,
+        },
+        selection: args
+            .keypoints_per_cell
+            .map(|per_cell| GridSelection::new(args.cell_size as f32, per_cell)),
+        bands: args.bands.clone(),
+        depth: args.depth,
+        georeferences: None,
+    };
+
+    options
+        .normalization
+        .check_depth(options.depth.mat_depth())
+        .expect("The normalization does not support the depth of the tiles");
+
     // Must be in mutex since diesel is a sync library.
     let db_connection: DbType =
         Arc::new(Mutex::new(feature_database::db_helpers::setup_database()));
@@ -104,39 +296,152 @@ fn main() {
         DatasetPath::Mosaic { path } => read_dataset(None, Some(path), &temp_string).unwrap(),
     };
 
-    if args.elevation_path.is_some() {
-        mosaic.lock().unwrap().set_elevation_dataset(&args.elevation_path.expect("Elevation dataset path not found"), &temp_string).expect("Could not add elevation data to dataset");
+    // Tiles are read as RGB if no bands are given.
+    check_band_indexes(&mosaic.lock().unwrap().dataset, options.bands.as_deref().unwrap_or(&[1, 2, 3]))
+        .expect("The mosaic does not have the requested bands");
+
+    // Only the stretch is set, since the elevation sets other options of the mosaic.
+    if let Some(stretch) = stretch {
+        mosaic.lock().unwrap().options.stretch = stretch;
+    }
+
+    let vertical_datum = match (&args.geoid_grid, args.height_reference) {
+        (Some(path), _) => Some(VerticalDatum::GeoidGrid(path.into())),
+        (None, HeightReferenceArg::Ellipsoid) => None,
+        (None, reference) => Some(VerticalDatum::Reference(reference.into())),
+    };
+
+    if let Some(elevation_path) = &args.elevation_path {
+        let elevation_options = ElevationOptions {
+            scale: args.elevation_scale,
+            resampling: args.elevation_resampling.into(),
+            vertical_datum: vertical_datum.clone(),
+        };
+
+        let coverage = mosaic.lock().unwrap().set_elevation_dataset(elevation_path, temp_string, &elevation_options).expect("Could not add elevation data to dataset");
+
+        if !coverage.gaps.is_empty() {
+            println!("Elevation covers {:.2}% of the mosaic, world coordinates can not be computed in the missing areas:", coverage.covered * 100.0);
+
+            for gap in &coverage.gaps {
+                println!("  {}x{} pixels at {:?}: {:.1}% missing", gap.window_size.0, gap.window_size.1, gap.window, gap.missing * 100.0);
+            }
+        }
+    }
+
+    if let Some(path) = &args.mask_path {
+        mosaic
+            .lock()
+            .unwrap()
+            .set_mask_dataset(path)
+            .expect("Could not add mask to dataset");
+    }
+
+    let has_elevation = mosaic.lock().unwrap().elevation.is_some();
+
+    if has_elevation || args.world_coordinates {
+        add_dataset_geotransform(db_connection.clone(), mosaic.clone());
+    }
+
+    if has_elevation {
+        add_elevation(db_connection.clone(), mosaic.clone(), vertical_datum.as_ref());
     }
 
-    if mosaic.lock().unwrap().elevation.is_some() {
-        add_elevation(db_connection.clone(), mosaic.clone());
+    if args.world_coordinates {
+        options.georeferences = Some(
+            Georeferences::read(&mut db_connection.lock().unwrap())
+                .expect("Could not read geotransforms from database"),
+        );
     }
 
     thread_pool.scope(move |s| {
         // Scope prevents the main process from quiting before all threads are done.
         println!("Processing mosaic");
 
-        process_lod_from_mosaic(db_connection, mosaic, args.lod, s);
+        process_lod_from_mosaic(db_connection, mosaic, args.lod, options, s);
     });
 
 
     temp_dir.close().expect("Failed to delete temporary data");
 }
 
+fn stretch_from_args(args: &Args) -> Option<Stretch> {
+    args.clip_percentiles
+        .as_ref()
+        .map(|percentiles| Stretch::Percentile {
+            lower: percentiles[0],
+            upper: percentiles[1],
+        })
+}
+
+/// Checks that a pair of percentiles is ordered and within 0 to 100, returning them as `(lower, upper)`
+fn check_percentiles(percentiles: &[f64]) -> Result<(f64, f64), String> {
+    match percentiles {
+        [lower, upper] if 0.0 <= *lower && lower < upper && *upper <= 100.0 => Ok((*lower, *upper)),
+        _ => Err(format!("the percentiles must satisfy 0 <= LOWER < UPPER <= 100, got {:?}", percentiles)),
+    }
+}
+
+fn normalization_from_args(args: &Args) -> NormalizationOptions {
+    let (lower, upper) = (args.normalization_percentiles[0], args.normalization_percentiles[1]);
+
+    let method = match args.normalization {
+        NormalizationMethod::None => Normalization::None,
+        NormalizationMethod::Percentile => Normalization::PercentileClip { lower, upper },
+        NormalizationMethod::Clahe => Normalization::Clahe {
+            clip_limit: CLAHE_CLIP_LIMIT,
+            tile_grid_size: CLAHE_TILE_GRID_SIZE,
+        },
+        NormalizationMethod::HistogramMatching => {
+            let path = args
+                .histogram_reference
+                .as_ref()
+                .expect("histogram-matching requires --histogram-reference");
+            let reference = get_mat_from_dir(path).expect("Could not read histogram reference");
+
+            Normalization::HistogramMatching(
+                reference_histogram(&reference).expect("Could not compute histogram"),
+            )
+        }
+    };
+
+    NormalizationOptions {
+        method,
+        grayscale: args.grayscale,
+    }
+}
+
+
+fn parse_elevation_scale(value: &str) -> Result<f64, String> {
+    let scale: f64 = value.parse().map_err(|e: std::num::ParseFloatError| e.to_string())?;
+
+    check_scale(scale).map_err(|e| e.to_string())
+}
+
+fn add_dataset_geotransform(conn: DbType, mosaic: Arc<Mutex<MosaicedDataset>>) {
+    use feature_database::elevationdb::geotransform;
+    let mosaic = mosaic.lock().unwrap();
+    let conn = &mut conn.lock().unwrap();
+
+    let dataset_trans = mosaic.dataset.geo_transform().expect("Could not get geotransform from dataset");
+    let dataset_ref = dataset_spatial_ref(&mosaic.dataset);
+
+    geotransform::create_geotransform(conn, "dataset", dataset_trans, Some(&dataset_ref)).expect("Could not add dataset geotransform to database");
+}
 
 /// This function is only called when the elevation dataset is known to exist.
-fn add_elevation(conn: DbType, mosaic: Arc<Mutex<MosaicedDataset>>) {
+// The heights of the mosaic were converted to heights above the ellipsoid from `source_datum` when the elevation was set.
+fn add_elevation(conn: DbType, mosaic: Arc<Mutex<MosaicedDataset>>, source_datum: Option<&VerticalDatum>) {
     use feature_database::elevationdb::{geotransform, elevation};
     let mosaic = mosaic.lock().unwrap();
     let conn = &mut conn.lock().unwrap();
 
-    let dataset_trans = mosaic.dataset.geo_transform().expect("Could not get geotransform from dataset");
     let elevation_trans = mosaic.elevation.as_ref().unwrap().geo_transform().expect("Could not get geotransform from elevation");
+    let elevation_ref = dataset_spatial_ref(mosaic.elevation.as_ref().unwrap());
 
-    geotransform::create_geotransform(conn, "dataset", dataset_trans).expect("Could not add dataset geotransform to database");
-    geotransform::create_geotransform(conn, "elevation", elevation_trans).expect("Could not add dataset geotransform to database");
+    geotransform::create_geotransform(conn, "elevation", elevation_trans, Some(&elevation_ref)).expect("Could not add dataset geotransform to database");
 
-    elevation::add_elevation_data(conn, &mosaic.elevation.as_ref().expect("Elevation data not found")).expect("Elevation data could not be added to database");
+    elevation::add_elevation_data(conn, &mosaic.elevation.as_ref().expect("Elevation data not found"), HeightReference::Ellipsoid, source_datum).expect("Elevation data could not be added to database");
 }
 
 fn read_dataset(dataset_path: Option<String>, mosaic_path: Option<String>, temp_string: &str) -> Result<Arc<Mutex<MosaicedDataset>>, std::io::Error> {
@@ -165,6 +470,7 @@ fn process_lod_from_mosaic(
     conn: DbType,
     image: Arc<Mutex<MosaicedDataset>>,
     lod: u64,
+    options: ExtractionOptions,
     s: &Scope,
 ) {
     let image_resolution = image
@@ -187,6 +493,7 @@ fn process_lod_from_mosaic(
             image.clone(),
             lod,
             i,
+            options.clone(),
             multi_bar.clone(),
             s,
         )
@@ -199,6 +506,7 @@ fn downscale_from_lod(
     image: Arc<Mutex<MosaicedDataset>>,
     amount_lod: u64,
     lod: u64,
+    options: ExtractionOptions,
     multi_bar: MultiProgress,
     s: &Scope,
 ) {
@@ -229,6 +537,7 @@ fn downscale_from_lod(
             let conn = conn.clone();
             let image = image.clone();
             let bar = bar.clone();
+            let options = options.clone();
 
             s.spawn(move |_s| {
                 feature_extraction_to_database(
@@ -238,6 +547,7 @@ fn downscale_from_lod(
                     j,
                     i,
                     lod,
+                    options,
                     bar,
                 )
             });
@@ -252,29 +562,56 @@ fn feature_extraction_to_database(
     column: u64,
     row: u64,
     lod: u64,
+    options: ExtractionOptions,
     bar: ProgressBar,
 ) {
-    // Read a tile from the dataset.
-    let tile = image
-        .lock()
-        .unwrap()
-        .to_rgb(
-            (
-                (column * (tile_size.0 * 2_u64.pow(lod as u32))) as isize,
-                (row * (tile_size.1 * 2_u64.pow(lod as u32))) as isize,
-            ),
-            (
-                (tile_size.0 * 2_u64.pow(lod as u32)) as usize,
-                (tile_size.1 * 2_u64.pow(lod as u32)) as usize,
-            ),
-            (tile_size.0 as usize, tile_size.1 as usize),
-        )
-        .expect("Could not read tile from reference image");
-    // Convert the tile to an openCV mat
-    let tile_mat = raster_to_mat(&tile, tile_size.0 as i32, tile_size.1 as i32)
+    let window = (
+        (column * (tile_size.0 * 2_u64.pow(lod as u32))) as isize,
+        (row * (tile_size.1 * 2_u64.pow(lod as u32))) as isize,
+    );
+    let window_size = (
+        (tile_size.0 * 2_u64.pow(lod as u32)) as usize,
+        (tile_size.1 * 2_u64.pow(lod as u32)) as usize,
+    );
+    let size = (tile_size.0 as usize, tile_size.1 as usize);
+
+    // Read a tile and its mask from the dataset.
+    let (tile, mask) = {
+        let mut image = image.lock().unwrap();
+
+        let tile = Tile::read(
+            &mut image,
+            options.bands.as_deref(),
+            options.depth,
+            window,
+            window_size,
+            size,
+        );
+        let mask = image
+            .to_mask(window, window_size, size, &tile.to_rgba8(), &options.mask)
+            .expect("Could not read mask from reference image");
+
+        (tile, mask)
+    };
+    // Convert the tile and mask to openCV mats
+    let tile_mat = tile
+        .to_mat(tile_size.0 as i32, tile_size.1 as i32, options.normalization.grayscale)
         .expect("Could not convert tile to mat");
+    let mask_mat = mask_to_mat(&mask, tile_size.0 as i32, tile_size.1 as i32)
+        .expect("Could not convert mask to mat");
+    // Normalize the tile the same way query images are normalized.
+    let tile_mat = normalize_image(&tile_mat, &options.normalization)
+        .expect("Could not normalize tile");
     // Extract keypoints and descriptors
-    let keypoints = akaze_keypoint_descriptor_extraction_def(&tile_mat.mat, None).unwrap();
+    let keypoints = akaze_keypoint_descriptor_extraction(&tile_mat, &mask_mat.mat, None).unwrap();
+
+    // Spread the keypoints over the tile, so textured areas do not take up the whole budget.
+    let keypoints = match options.selection {
+        Some(selection) => keypoints
+            .grid_bucketing(selection)
+            .expect("Could not select keypoints"),
+        None => keypoints,
+    };
 
     // Insert the image into the database.
     let insert_image = models::InsertImage {
@@ -319,11 +656,132 @@ fn feature_extraction_to_database(
         });
     }
 
-    let db_insert_keypoints = keypointdb::Keypoint::Multiple(insert_keypoints);
+    let world = options
+        .georeferences
+        .as_ref()
+        .map(|georeferences| world_coordinates(&conn, georeferences, &db_keypoints));
 
-    keypointdb::Keypoint::create_keypoint(&mut conn.lock().unwrap(), db_insert_keypoints).unwrap();
+    let (world_keypoints, insert_keypoints) = match &world {
+        Some(Ok(world)) => split_world_keypoints(insert_keypoints, world),
+        Some(Err(e)) => {
+            bar.println(format!("Could not compute world coordinates of tile ({column}, {row}): {e:?}"));
+            (Vec::new(), insert_keypoints)
+        }
+        None => (Vec::new(), insert_keypoints),
+    };
+
+    keypointdb::Keypoint::create_keypoint(&mut conn.lock().unwrap(), keypointdb::Keypoint::WithWorldCoordinates(world_keypoints)).unwrap();
+    keypointdb::Keypoint::create_keypoint(&mut conn.lock().unwrap(), keypointdb::Keypoint::Multiple(insert_keypoints)).unwrap();
 
     bar.inc(1);
 }
 
+type WorldPoint = (f64, f64, f64);
+
+/// The earth centered, earth fixed coordinates of every keypoint, and its longitude, latitude and height.
+/// Keypoints outside the elevation data or over nodata have [`None`].
+fn world_coordinates(
+    conn: &DbType,
+    georeferences: &Georeferences,
+    keypoints: &[DbKeypoints],
+) -> Result<Vec<Option<(WorldPoint, WorldPoint)>>, feature_database::elevationdb::Errors> {
+    use feature_database::elevationdb::Errors;
+
+    if keypoints.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let pixels: Vec<(f64, f64)> = keypoints
+        .iter()
+        .map(|keypoint| (keypoint.x_coord as f64, keypoint.y_coord as f64))
+        .collect();
+
+    let ecef = georeferences.world_coordinates(&mut conn.lock().unwrap(), &pixels, OutputFrame::Ecef)?;
+    let resolved: Vec<WorldPoint> = ecef.iter().flatten().copied().collect();
+
+    let (mut x, mut y, mut z) = (
+        resolved.iter().map(|point| point.0).collect::<Vec<f64>>(),
+        resolved.iter().map(|point| point.1).collect::<Vec<f64>>(),
+        resolved.iter().map(|point| point.2).collect::<Vec<f64>>(),
+    );
+    ecef_to_geographic(&mut x, &mut y, &mut z).map_err(Errors::Gdal)?;
+
+    let mut geographic = x.into_iter().zip(y).zip(z).map(|((x, y), z)| (x, y, z));
+
+    Ok(ecef
+        .into_iter()
+        .map(|ecef| Some((ecef?, geographic.next()?)))
+        .collect())
+}
+
+/// Pairs the keypoints with their world coordinates, see [`world_coordinates`].
+/// Keypoints without world coordinates are returned on their own, so they are stored without them instead of losing the tile.
+fn split_world_keypoints<'a>(
+    keypoints: Vec<models::InsertKeypoint<'a>>,
+    world: &'a [Option<(WorldPoint, WorldPoint)>],
+) -> (Vec<models::InsertWorldKeypoint<'a>>, Vec<models::InsertKeypoint<'a>>) {
+    let mut world_keypoints = Vec::with_capacity(keypoints.len());
+    let mut plain_keypoints = Vec::new();
+
+    for (keypoint, world) in keypoints.into_iter().zip(world) {
+        match world {
+            Some((ecef, geographic)) => world_keypoints.push(models::InsertWorldKeypoint {
+                keypoint,
+                ecef_x: &ecef.0,
+                ecef_y: &ecef.1,
+                ecef_z: &ecef.2,
+                longitude: &geographic.0,
+                latitude: &geographic.1,
+                height: &geographic.2,
+            }),
+            None => plain_keypoints.push(keypoint),
+        }
+    }
+
+    (world_keypoints, plain_keypoints)
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_are_validated() {
+        assert_eq!(check_percentiles(&[2.0, 98.0]), Ok((2.0, 98.0)));
+        assert_eq!(check_percentiles(&[0.0, 100.0]), Ok((0.0, 100.0)));
+        assert!(check_percentiles(&[98.0, 2.0]).is_err());
+        assert!(check_percentiles(&[5.0, 5.0]).is_err());
+        assert!(check_percentiles(&[-1.0, 50.0]).is_err());
+        assert!(check_percentiles(&[50.0, 101.0]).is_err());
+        assert!(check_percentiles(&[f64::NAN, 50.0]).is_err());
+    }
+
+    #[test]
+    fn keypoints_over_no_data_are_stored_without_world_coordinates() {
+        let (value, id, descriptor) = (1f32, 1i32, vec![0u8; 61]);
+        let keypoint = models::InsertKeypoint {
+            x_coord: &value,
+            y_coord: &value,
+            size: &value,
+            angle: &value,
+            response: &value,
+            octave: &id,
+            class_id: &id,
+            descriptor: &descriptor,
+            image_id: &id,
+        };
+        // The second keypoint is over nodata.
+        let world = [
+            Some(((1.0, 2.0, 3.0), (9.0, 56.0, 147.0))),
+            None,
+            Some(((4.0, 5.0, 6.0), (9.5, 56.5, 60.0))),
+        ];
+
+        let (world_keypoints, plain_keypoints) = split_world_keypoints(vec![keypoint; 3], &world);
+
+        assert_eq!(world_keypoints.len(), 2);
+        assert_eq!(plain_keypoints.len(), 1);
+        assert_eq!(*world_keypoints[1].ecef_x, 4.0);
+        assert_eq!(*world_keypoints[1].height, 60.0);
+    }
+}
@@ -0,0 +1,100 @@
+use std::borrow::Cow;
+
+use clap::ValueEnum;
+use geotiff_lib::image_extractor::{to_rgba8, BandRaster, MosaicDataset, MosaicedDataset};
+use homographier::homographier::{
+    bands_to_mat, raster_to_gray_mat, raster_to_mat, raster_to_mat_depth, MatError,
+};
+use opencv::core::{Mat, CV_16U, CV_32F, CV_8U};
+
+/// The element depth of the tiles given to the feature extractor.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum TileDepth {
+    /// 8 bits per band
+    #[default]
+    U8,
+    /// 16 bits per band, keeps detail in dark and shadowed regions
+    U16,
+    /// 32-bit floats per band
+    F32,
+}
+
+impl TileDepth {
+    /// The openCV element depth of the mats tiles are converted to.
+    pub fn mat_depth(self) -> i32 {
+        match self {
+            TileDepth::U8 => CV_8U,
+            TileDepth::U16 => CV_16U,
+            TileDepth::F32 => CV_32F,
+        }
+    }
+}
+
+/// A tile read from the mosaic, before it is converted to a mat.
+pub enum Tile {
+    Rgba8(Vec<rgb::RGBA8>),
+    Rgba16(Vec<rgb::RGBA16>),
+    RgbaF32(Vec<rgb::RGBA<f32>>),
+    Bands(BandRaster),
+}
+
+impl Tile {
+    /// Reads a tile from the mosaic. A band combination is always read as 8-bit.
+    pub fn read(
+        image: &mut MosaicedDataset,
+        bands: Option<&[isize]>,
+        depth: TileDepth,
+        window: (isize, isize),
+        window_size: (usize, usize),
+        size: (usize, usize),
+    ) -> Tile {
+        if let Some(bands) = bands {
+            return Tile::Bands(
+                image
+                    .to_bands(bands, window, window_size, size)
+                    .expect("Could not read bands from reference image"),
+            );
+        }
+
+        match depth {
+            TileDepth::U8 => Tile::Rgba8(
+                image
+                    .to_rgb(window, window_size, size)
+                    .expect("Could not read tile from reference image"),
+            ),
+            TileDepth::U16 => Tile::Rgba16(
+                image
+                    .to_rgb16(window, window_size, size)
+                    .expect("Could not read tile from reference image"),
+            ),
+            TileDepth::F32 => Tile::RgbaF32(
+                image
+                    .to_rgb_f32(window, window_size, size)
+                    .expect("Could not read tile from reference image"),
+            ),
+        }
+    }
+
+    /// The 8-bit pixels of the tile, used when masking.
+    pub fn to_rgba8(&self) -> Cow<'_, [rgb::RGBA8]> {
+        match self {
+            Tile::Rgba8(pixels) => Cow::Borrowed(pixels),
+            Tile::Rgba16(pixels) => Cow::Owned(to_rgba8(pixels)),
+            Tile::RgbaF32(pixels) => Cow::Owned(to_rgba8(pixels)),
+            Tile::Bands(raster) => Cow::Owned(raster.to_rgba()),
+        }
+    }
+
+    /// Converts the tile to an openCV mat with the same depth as the tile.
+    /// With `grayscale`, 8-bit RGBA tiles are converted to a single channel in the same pass,
+    /// other tiles keep their channels and are converted during normalization.
+    pub fn to_mat(&self, w: i32, h: i32, grayscale: bool) -> Result<Mat, MatError> {
+        match self {
+            Tile::Rgba8(pixels) if grayscale => raster_to_gray_mat(pixels, w, h).map(|mat| mat.mat),
+            Tile::Rgba8(pixels) => raster_to_mat(pixels, w, h).map(|mat| mat.mat),
+            Tile::Rgba16(pixels) => raster_to_mat_depth(pixels, w, h).map(|mat| mat.mat),
+            Tile::RgbaF32(pixels) => raster_to_mat_depth(pixels, w, h).map(|mat| mat.mat),
+            Tile::Bands(raster) => bands_to_mat(&raster.data, raster.channels, w, h),
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use feature_extraction::normalization::{normalize_image, NormalizationOptions};
+use geotiff_lib::masking::{build_mask, MaskInputs, MaskOptions};
+use homographier::homographier::{mask_to_mat, Cmat, MatError};
+use opencv::{
+    calib3d::undistort_def,
+    core::{Mat, Size, Vec4b, Vector},
+    imgproc::{self, COLOR_BGR2BGRA, COLOR_GRAY2BGRA, INTER_AREA, INTER_LINEAR},
+    prelude::*,
+};
+use rgb::RGBA8;
+
+/// The calibrated camera model, as found by the calibrator.
+#[derive(Debug)]
+pub struct CameraModel {
+    /// The 3x3 camera calibration matrix
+    pub intrinsic: Cmat<f64>,
+    /// The distortion coefficients from the camera calibration
+    pub dist_coeffs: Vec<f64>,
+}
+
+/// Options for preprocessing a query image.
+/// The normalization and mask options should be the same as the ones used by the preprocessor for the reference mosaic.
+#[derive(Debug, Clone)]
+pub struct QueryOptions<'a> {
+    /// Undistorts the image if provided
+    pub camera: Option<&'a CameraModel>,
+    /// The ground sampling distance of the query image in meters per pixel
+    pub query_gsd: f64,
+    /// The ground sampling distance of the full resolution reference mosaic in meters per pixel
+    pub reference_gsd: f64,
+    /// The level of detail that the query image is resampled to
+    pub level_of_detail: u64,
+    pub normalization: NormalizationOptions,
+    pub mask: MaskOptions,
+}
+
+/// A query image ready for feature extraction.
+#[derive(Debug)]
+pub struct PreprocessedQuery<T> {
+    pub image: Cmat<T>,
+    /// 8-bit single channel mask with the same size as `image`, where 0 is masked
+    pub mask: Cmat<u8>,
+    /// The factor the undistorted query image was resized with
+    pub scale: f64,
+}
+
+impl<T> PreprocessedQuery<T> {
+    /// Converts pixel coordinates of the preprocessed image to pixel coordinates of the undistorted query image.
+    pub fn to_query_coordinates(&self, x: f64, y: f64) -> (f64, f64) {
+        (x / self.scale, y / self.scale)
+    }
+}
+
+/// Preprocesses a query image the same way the preprocessor handles reference tiles.
+///
+/// The image is undistorted, resampled to the ground sampling distance of the chosen level of detail, masked and normalized.
+/// ## Parameters
+/// * img: an 8-bit BGR, BGRA or grayscale image, such as the one returned by [`feature_extraction::get_mat_from_dir`]
+/// * options: see [`QueryOptions`]
+/// ## Errors
+/// If the element type `T` does not match the normalized image, e.g. `u8` is required when the normalization converts to grayscale and [`Vec4b`] otherwise.
+pub fn preprocess_query<T: DataType>(
+    img: &Mat,
+    options: &QueryOptions,
+) -> Result<PreprocessedQuery<T>, MatError> {
+    let img = to_bgra(img)?;
+
+    let img = match options.camera {
+        Some(camera) => undistort(&img, camera)?,
+        None => img,
+    };
+
+    let scale = resample_factor(
+        options.query_gsd,
+        options.reference_gsd,
+        options.level_of_detail,
+    );
+    let img = resample(&img, scale)?;
+
+    // Pixels outside of the undistorted image are transparent, and are masked like nodata in the reference.
+    let pixels = bgra_to_rgba8(&img)?;
+    let inputs = MaskInputs {
+        rgba: &pixels,
+        mask_raster: None,
+        green: None,
+        nir: None,
+    };
+    let mask = build_mask(&inputs, &options.mask);
+    let mask = mask_to_mat(&mask, img.cols(), img.rows())?;
+
+    let img = normalize_image(&img, &options.normalization).map_err(MatError::Opencv)?;
+
+    Ok(PreprocessedQuery {
+        image: Cmat::new(img)?,
+        mask,
+        scale,
+    })
+}
+
+/// The factor a query image should be resized with to match the ground sampling distance of a level of detail.
+pub fn resample_factor(query_gsd: f64, reference_gsd: f64, level_of_detail: u64) -> f64 {
+    query_gsd / (reference_gsd * 2_f64.powi(level_of_detail as i32))
+}
+
+/// Converts an image to BGRA, like the tiles produced by [`homographier::homographier::raster_to_mat`].
+fn to_bgra(img: &Mat) -> Result<Mat, MatError> {
+    let code = match img.channels() {
+        4 => return img.try_clone().map_err(MatError::Opencv),
+        3 => COLOR_BGR2BGRA,
+        1 => COLOR_GRAY2BGRA,
+        _ => return Err(MatError::Unknown),
+    };
+
+    let mut bgra = Mat::default();
+    imgproc::cvt_color(img, &mut bgra, code, 0).map_err(MatError::Opencv)?;
+
+    Ok(bgra)
+}
+
+fn undistort(img: &Mat, camera: &CameraModel) -> Result<Mat, MatError> {
+    let mut undistorted = Mat::default();
+
+    undistort_def(
+        img,
+        &mut undistorted,
+        &camera.intrinsic,
+        &Vector::from_slice(&camera.dist_coeffs),
+    )
+    .map_err(MatError::Opencv)?;
+
+    Ok(undistorted)
+}
+
+fn resample(img: &Mat, scale: f64) -> Result<Mat, MatError> {
+    let size = Size::new(
+        (img.cols() as f64 * scale).round() as i32,
+        (img.rows() as f64 * scale).round() as i32,
+    );
+
+    if size.width <= 0 || size.height <= 0 {
+        return Err(MatError::Empty);
+    }
+
+    // Area interpolation avoids aliasing when downscaling.
+    let interpolation = match scale < 1.0 {
+        true => INTER_AREA,
+        false => INTER_LINEAR,
+    };
+
+    let mut resampled = Mat::default();
+    imgproc::resize(img, &mut resampled, size, 0.0, 0.0, interpolation)
+        .map_err(MatError::Opencv)?;
+
+    Ok(resampled)
+}
+
+fn bgra_to_rgba8(img: &Mat) -> Result<Vec<RGBA8>, MatError> {
+    let mut pixels: Vec<RGBA8> = Vec::with_capacity(img.total());
+
+    for row in 0..img.rows() {
+        for pixel in img.at_row::<Vec4b>(row).map_err(MatError::Opencv)? {
+            pixels.push(RGBA8::new(pixel[2], pixel[1], pixel[0], pixel[3]));
+        }
+    }
+
+    Ok(pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::{Scalar, CV_8UC3};
+
+    fn test_image(size: i32) -> Mat {
+        Mat::new_rows_cols_with_default(size, size, CV_8UC3, Scalar::all(100.0))
+            .expect("Could not create image")
+    }
+
+    fn options<'a>() -> QueryOptions<'a> {
+        QueryOptions {
+            camera: None,
+            query_gsd: 5.0,
+            reference_gsd: 10.0,
+            level_of_detail: 0,
+            normalization: NormalizationOptions::default(),
+            mask: MaskOptions::default(),
+        }
+    }
+
+    #[test]
+    fn resample_factor_lod() {
+        assert_eq!(resample_factor(10.0, 10.0, 0), 1.0);
+        assert_eq!(resample_factor(10.0, 10.0, 2), 0.25);
+        assert_eq!(resample_factor(5.0, 10.0, 0), 0.5);
+    }
+
+    #[test]
+    fn query_is_resampled() {
+        let query = preprocess_query::<Vec4b>(&test_image(8), &options()).unwrap();
+
+        assert_eq!(query.image.mat.rows(), 4);
+        assert_eq!(query.image.mat.cols(), 4);
+        assert_eq!(query.mask.mat.rows(), 4);
+        assert_eq!(query.scale, 0.5);
+        assert_eq!(query.to_query_coordinates(1.0, 2.0), (2.0, 4.0));
+    }
+
+    #[test]
+    fn query_is_grayscale() {
+        let mut options = options();
+        options.normalization.grayscale = true;
+
+        let query = preprocess_query::<u8>(&test_image(8), &options).unwrap();
+
+        assert_eq!(query.image.mat.channels(), 1);
+    }
+
+    #[test]
+    fn query_wrong_element_type() {
+        let mut options = options();
+        options.normalization.grayscale = true;
+
+        assert!(preprocess_query::<Vec4b>(&test_image(8), &options).is_err());
+    }
+
+    #[test]
+    fn bright_query_is_masked() {
+        let mut options = options();
+        options.mask.brightness_threshold = Some(50);
+
+        let query = preprocess_query::<Vec4b>(&test_image(8), &options).unwrap();
+
+        assert_eq!(*query.mask.at_2d(0, 0).unwrap(), 0);
+    }
+}
@@ -0,0 +1,253 @@
+use homographier::homographier::{CameraMatrix, Cmat, MatError};
+use opencv::{
+    core::{merge, split, Size, Vec4b, Vector},
+    imgproc::gaussian_blur_def,
+    prelude::*,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{Distribution, Normal};
+
+/// The size in pixels of the coarsest cells of the cloud noise
+const CLOUD_CELL_SIZE: usize = 128;
+/// The amount of noise octaves summed for the clouds, each with half the cell size of the previous
+const CLOUD_OCTAVES: u32 = 3;
+/// The width of the transition between clear sky and cloud, in noise values
+const CLOUD_SOFTNESS: f32 = 0.08;
+/// The brightness of a cloud
+const CLOUD_VALUE: f32 = 245.0;
+
+/// Degradations applied to a rendered frame, to resemble a real sensor. Every effect is disabled by default.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct EffectOptions {
+    /// The standard deviation of the gaussian sensor noise, in 8-bit values
+    pub noise_std: f64,
+    /// The standard deviation of the gaussian blur in pixels
+    pub blur_sigma: f64,
+    /// How much the corners are darkened with the cos⁴ law, from 0.0 (none) to 1.0 (the full falloff)
+    pub vignetting: f64,
+    /// The approximate fraction of the frame covered by clouds, from 0.0 to 1.0
+    pub cloud_cover: f64,
+    /// Seeds the noise and clouds, so frames can be reproduced
+    pub seed: u64,
+}
+
+/// Applies the effects to a BGRA frame in the order clouds, vignetting, blur and noise.
+/// The alpha channel is left untouched.
+pub fn apply_effects(
+    image: &mut Cmat<Vec4b>,
+    camera: &CameraMatrix,
+    options: &EffectOptions,
+) -> Result<(), MatError> {
+    let (width, height) = (image.mat.cols() as usize, image.mat.rows() as usize);
+    let mut rng = StdRng::seed_from_u64(options.seed);
+
+    if options.cloud_cover > 0f64 {
+        let alpha = cloud_alpha(width, height, options.cloud_cover, &mut rng);
+        let data = image
+            .mat
+            .data_typed_mut::<Vec4b>()
+            .map_err(MatError::Opencv)?;
+
+        for (pixel, alpha) in data.iter_mut().zip(alpha) {
+            for value in pixel.0.iter_mut().take(3) {
+                *value = (*value as f32 * (1f32 - alpha) + CLOUD_VALUE * alpha) as u8;
+            }
+        }
+    }
+
+    if options.vignetting > 0f64 {
+        let data = image
+            .mat
+            .data_typed_mut::<Vec4b>()
+            .map_err(MatError::Opencv)?;
+
+        for (i, pixel) in data.iter_mut().enumerate() {
+            let factor = vignetting_factor(
+                camera,
+                (i % width) as f64,
+                (i / width) as f64,
+                options.vignetting,
+            );
+
+            for value in pixel.0.iter_mut().take(3) {
+                *value = (*value as f64 * factor).round() as u8;
+            }
+        }
+    }
+
+    if options.blur_sigma > 0f64 {
+        // Only the colour channels are blurred, so the alpha channel keeps its hard edges.
+        let mut channels: Vector<Mat> = Vector::new();
+        split(&image.mat, &mut channels).map_err(MatError::Opencv)?;
+
+        for i in 0..3 {
+            let mut blurred = Mat::default();
+            gaussian_blur_def(
+                &channels.get(i).map_err(MatError::Opencv)?,
+                &mut blurred,
+                Size::new(0, 0),
+                options.blur_sigma,
+            )
+            .map_err(MatError::Opencv)?;
+            channels.set(i, blurred).map_err(MatError::Opencv)?;
+        }
+
+        merge(&channels, &mut image.mat).map_err(MatError::Opencv)?;
+    }
+
+    if options.noise_std > 0f64 {
+        let normal = Normal::new(0f64, options.noise_std).map_err(|_| MatError::Unknown)?;
+        let data = image
+            .mat
+            .data_typed_mut::<Vec4b>()
+            .map_err(MatError::Opencv)?;
+
+        for pixel in data.iter_mut() {
+            for value in pixel.0.iter_mut().take(3) {
+                let noisy = *value as f64 + normal.sample(&mut rng);
+                *value = noisy.round().clamp(0f64, 255f64) as u8;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The brightness factor of a pixel, falling off with the fourth power of the cosine of the angle to the optical axis.
+fn vignetting_factor(camera: &CameraMatrix, x: f64, y: f64, strength: f64) -> f64 {
+    let (nx, ny) = (
+        (x - camera[(0, 2)]) / camera[(0, 0)],
+        (y - camera[(1, 2)]) / camera[(1, 1)],
+    );
+    let cos_squared = 1f64 / (1f64 + nx * nx + ny * ny);
+
+    1f64 - strength * (1f64 - cos_squared * cos_squared)
+}
+
+/// The opacity of the clouds for every pixel, from fractal value noise thresholded to cover about `cover` of the frame.
+fn cloud_alpha(width: usize, height: usize, cover: f64, rng: &mut StdRng) -> Vec<f32> {
+    let mut noise = vec![0f32; width * height];
+    let mut amplitude = 1f32;
+    let mut total = 0f32;
+
+    for octave in 0..CLOUD_OCTAVES {
+        let cell = (CLOUD_CELL_SIZE >> octave).max(1);
+        let (grid_width, grid_height) = (width / cell + 2, height / cell + 2);
+        let grid: Vec<f32> = (0..grid_width * grid_height).map(|_| rng.gen()).collect();
+
+        for (i, value) in noise.iter_mut().enumerate() {
+            let (x, y) = (
+                (i % width) as f32 / cell as f32,
+                (i / width) as f32 / cell as f32,
+            );
+            let (x0, y0) = (x as usize, y as usize);
+            let (fx, fy) = (smoothstep(x.fract()), smoothstep(y.fract()));
+
+            let at = |x: usize, y: usize| grid[y * grid_width + x];
+            let top = at(x0, y0) * (1f32 - fx) + at(x0 + 1, y0) * fx;
+            let bottom = at(x0, y0 + 1) * (1f32 - fx) + at(x0 + 1, y0 + 1) * fx;
+
+            *value += amplitude * (top * (1f32 - fy) + bottom * fy);
+        }
+
+        total += amplitude;
+        amplitude /= 2f32;
+    }
+
+    // The threshold is the quantile of the noise that leaves the requested fraction above it.
+    let mut sorted = noise.clone();
+    sorted.sort_by(f32::total_cmp);
+    let index = ((1f64 - cover.clamp(0f64, 1f64)) * sorted.len().saturating_sub(1) as f64) as usize;
+    let threshold = sorted.get(index).copied().unwrap_or(total);
+
+    noise
+        .iter()
+        .map(|value| {
+            smoothstep(((value - threshold) / (CLOUD_SOFTNESS * total) + 0.5).clamp(0f32, 1f32))
+        })
+        .collect()
+}
+
+fn smoothstep(t: f32) -> f32 {
+    t * t * (3f32 - 2f32 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::Scalar;
+
+    fn camera() -> CameraMatrix {
+        CameraMatrix::new(500.0, 0.0, 320.0, 0.0, 500.0, 240.0, 0.0, 0.0, 1.0)
+    }
+
+    #[test]
+    fn vignetting_darkens_corners() {
+        let camera = camera();
+
+        assert!((vignetting_factor(&camera, 320.0, 240.0, 1.0) - 1.0).abs() < 1e-12);
+        assert!(vignetting_factor(&camera, 0.0, 0.0, 1.0) < 0.8);
+        assert!(vignetting_factor(&camera, 0.0, 0.0, 0.0) == 1.0);
+    }
+
+    #[test]
+    fn clouds_cover_requested_fraction() {
+        let mut rng = StdRng::seed_from_u64(7);
+
+        let alpha = cloud_alpha(256, 256, 0.3, &mut rng);
+        let covered =
+            alpha.iter().filter(|alpha| **alpha > 0.5).count() as f64 / alpha.len() as f64;
+
+        assert!((covered - 0.3).abs() < 0.05, "{covered}");
+    }
+
+    #[test]
+    fn blur_leaves_alpha_untouched() {
+        let mut mat =
+            Mat::new_rows_cols_with_default(32, 32, Vec4b::opencv_type(), Scalar::all(0f64))
+                .unwrap();
+        for (i, pixel) in mat
+            .data_typed_mut::<Vec4b>()
+            .unwrap()
+            .iter_mut()
+            .enumerate()
+        {
+            let value = if i % 32 < 16 { 255 } else { 0 };
+            *pixel = Vec4b::from([value, value, value, 255 - value]);
+        }
+        let mut image = Cmat::<Vec4b>::new(mat).unwrap();
+        let options = EffectOptions {
+            blur_sigma: 2.0,
+            ..Default::default()
+        };
+
+        apply_effects(&mut image, &camera(), &options).unwrap();
+
+        let data = image.mat.data_typed::<Vec4b>().unwrap();
+        // The colour is smoothed across the edge, the alpha is not.
+        assert!(data[15][0] > 0 && data[15][0] < 255);
+        assert_eq!(data[15][3], 0);
+        assert_eq!(data[16][3], 255);
+    }
+
+    #[test]
+    fn effects_are_reproducible() {
+        let render = || {
+            let mat =
+                Mat::new_rows_cols_with_default(48, 64, Vec4b::opencv_type(), Scalar::all(128f64))
+                    .unwrap();
+            let mut image = Cmat::<Vec4b>::new(mat).unwrap();
+            let options = EffectOptions {
+                noise_std: 5.0,
+                cloud_cover: 0.5,
+                seed: 3,
+                ..Default::default()
+            };
+
+            apply_effects(&mut image, &camera(), &options).unwrap();
+            image.mat.data_typed::<Vec4b>().unwrap().to_vec()
+        };
+
+        assert_eq!(render(), render());
+    }
+}
@@ -0,0 +1,403 @@
+use gdal::{errors::GdalError, GeoTransformEx};
+use geotiff_lib::{
+    geodesy::{dataset_spatial_ref, transform_points, GEOGRAPHIC},
+    image_extractor::{MosaicDataset, MosaicedDataset},
+};
+use homographier::homographier::{raster_to_mat, Cmat, MatError, Pose};
+use localizer::ephemeris::Geodetic;
+use nalgebra::{Rotation3, Vector3};
+use opencv::{
+    calib3d::undistort_points_def,
+    core::{Mat, Point2d, Scalar, Vec4b, Vector, BORDER_CONSTANT, CV_32FC1},
+    imgproc::{remap, INTER_LINEAR},
+    prelude::*,
+};
+use query_preprocessor::CameraModel;
+
+use effects::{apply_effects, EffectOptions};
+use raycast::{cast_rays, ElevationModel};
+
+pub mod effects;
+pub mod manifest;
+pub mod raycast;
+
+/// How many reference pixels are read for every pixel of the rendered frame, at most
+const REFERENCE_OVERSAMPLING: usize = 2;
+/// How much the bounds of the elevation window are grown on every side, as a fraction of their size,
+/// since the terrain moves the rays away from where they hit the ellipsoid
+const ELEVATION_MARGIN: f64 = 0.1;
+
+#[derive(Debug)]
+pub enum SynthesisError {
+    Gdal(GdalError),
+    Opencv(opencv::Error),
+    Mat(MatError),
+    /// No pixel of the frame sees the reference mosaic
+    NoCoverage,
+}
+
+/// Options for rendering a synthetic frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderOptions {
+    /// The width of the frame in pixels
+    pub width: i32,
+    /// The height of the frame in pixels
+    pub height: i32,
+    pub effects: EffectOptions,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            width: 1024,
+            height: 1024,
+            effects: EffectOptions::default(),
+        }
+    }
+}
+
+/// A rendered frame with the pose it was rendered from.
+pub struct SyntheticFrame {
+    /// The frame as BGRA, where pixels that do not see the mosaic are transparent black
+    pub image: Cmat<Vec4b>,
+    pub pose: Pose,
+    /// The fraction of the pixels that see the mosaic
+    pub coverage: f64,
+}
+
+/// Renders the frame a camera at `pose` would see of the reference mosaic.
+///
+/// Every pixel is cast as a ray onto the terrain, or the ellipsoid if the mosaic has no elevation dataset,
+/// and the mosaic is sampled bilinearly where it hits.
+/// ## Parameters
+/// * mosaic: the reference mosaic, with the elevation dataset set if available
+/// * camera: the camera model, the frame is distorted with its distortion coefficients
+/// * pose: the pose of the camera, from earth centered, earth fixed coordinates to camera coordinates
+/// * options: see [`RenderOptions`]
+/// ## Errors
+/// If the mosaic or elevation can not be read, or no pixel sees the mosaic.
+/// ## Notes
+/// The mosaic and elevation can be in any CRS, datasets without one are assumed to be geographic. The heights are assumed to be above the ellipsoid.
+pub fn render(
+    mosaic: &mut MosaicedDataset,
+    camera: &CameraModel,
+    pose: &Pose,
+    options: &RenderOptions,
+) -> Result<SyntheticFrame, SynthesisError> {
+    let (width, height) = (options.width, options.height);
+    let rays = camera_rays(camera, pose, width, height)?;
+
+    let world_to_camera = Rotation3::from_scaled_axis(pose.rvec);
+    let center = -(world_to_camera.inverse() * pose.tvec);
+
+    let elevation = match &mosaic.elevation {
+        Some(dataset) => {
+            // The rays are first cast onto the ellipsoid, to find the part of the elevation needed.
+            let hits: Vec<Geodetic> = cast_rays(&center, &rays, None)
+                .map_err(SynthesisError::Gdal)?
+                .into_iter()
+                .flatten()
+                .collect();
+
+            match geodetic_bounds(&hits, ELEVATION_MARGIN) {
+                Some(bounds) => {
+                    Some(ElevationModel::read(dataset, bounds).map_err(SynthesisError::Gdal)?)
+                }
+                None => return Err(SynthesisError::NoCoverage),
+            }
+        }
+        None => None,
+    };
+
+    let inverse = mosaic
+        .dataset
+        .geo_transform()
+        .and_then(|transform| transform.invert())
+        .map_err(SynthesisError::Gdal)?;
+    let dimensions = mosaic.get_dimensions().map_err(SynthesisError::Gdal)?;
+
+    let hits = cast_rays(&center, &rays, elevation.as_ref()).map_err(SynthesisError::Gdal)?;
+
+    // The hits are transformed to the CRS of the mosaic before its geotransform is applied.
+    let (mut x, mut y): (Vec<f64>, Vec<f64>) = hits
+        .iter()
+        .flatten()
+        .map(|hit| (hit.longitude, hit.latitude))
+        .unzip();
+    transform_points(
+        GEOGRAPHIC,
+        &dataset_spatial_ref(&mosaic.dataset),
+        &mut x,
+        &mut y,
+    )
+    .map_err(SynthesisError::Gdal)?;
+    let mut projected = x.into_iter().zip(y);
+
+    // The full resolution mosaic pixel seen by every pixel of the frame.
+    let pixels: Vec<Option<(f64, f64)>> = hits
+        .iter()
+        .map(|hit| {
+            hit.and_then(|_| projected.next())
+                .map(|(x, y)| inverse.apply(x, y))
+        })
+        .map(|pixel| {
+            pixel.filter(|(x, y)| {
+                *x >= 0f64 && *y >= 0f64 && *x < dimensions.0 as f64 && *y < dimensions.1 as f64
+            })
+        })
+        .collect();
+
+    let covered = pixels.iter().flatten().count();
+
+    if covered == 0 {
+        return Err(SynthesisError::NoCoverage);
+    }
+
+    let reference = read_reference(
+        mosaic,
+        &pixels,
+        width.max(height) as usize * REFERENCE_OVERSAMPLING,
+    )?;
+
+    let mut map_x = Mat::new_rows_cols_with_default(height, width, CV_32FC1, Scalar::all(-1f64))
+        .map_err(SynthesisError::Opencv)?;
+    let mut map_y = map_x.try_clone().map_err(SynthesisError::Opencv)?;
+
+    for (map, coordinate) in [(&mut map_x, 0), (&mut map_y, 1)] {
+        let data = map
+            .data_typed_mut::<f32>()
+            .map_err(SynthesisError::Opencv)?;
+
+        for (value, pixel) in data.iter_mut().zip(&pixels) {
+            if let Some(pixel) = pixel {
+                let (x, y) = reference.to_window(pixel.0, pixel.1);
+                *value = [x, y][coordinate] as f32;
+            }
+        }
+    }
+
+    let mut frame = Mat::default();
+    remap(
+        &reference.image,
+        &mut frame,
+        &map_x,
+        &map_y,
+        INTER_LINEAR,
+        BORDER_CONSTANT,
+        Scalar::all(0f64),
+    )
+    .map_err(SynthesisError::Opencv)?;
+
+    let mut image = Cmat::new(frame).map_err(SynthesisError::Mat)?;
+    let intrinsic = camera
+        .intrinsic
+        .to_smatrix::<3, 3>()
+        .map_err(SynthesisError::Mat)?;
+
+    apply_effects(&mut image, &intrinsic, &options.effects).map_err(SynthesisError::Mat)?;
+
+    Ok(SyntheticFrame {
+        image,
+        pose: *pose,
+        coverage: covered as f64 / pixels.len() as f64,
+    })
+}
+
+/// The direction of the ray through every pixel in earth centered, earth fixed coordinates, row by row.
+fn camera_rays(
+    camera: &CameraModel,
+    pose: &Pose,
+    width: i32,
+    height: i32,
+) -> Result<Vec<Vector3<f64>>, SynthesisError> {
+    let pixels: Vector<Point2d> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| Point2d::new(x as f64, y as f64)))
+        .collect();
+    let dist_coeffs = Vector::from_slice(&camera.dist_coeffs);
+    let mut normalized: Vector<Point2d> = Vector::new();
+
+    undistort_points_def(&pixels, &mut normalized, &camera.intrinsic, &dist_coeffs)
+        .map_err(SynthesisError::Opencv)?;
+
+    let camera_to_world = Rotation3::from_scaled_axis(pose.rvec).inverse();
+
+    Ok(normalized
+        .iter()
+        .map(|point| camera_to_world * Vector3::new(point.x, point.y, 1f64))
+        .collect())
+}
+
+/// The bounds of the positions as `(min_lon, min_lat, max_lon, max_lat)`, grown by `margin` times their size on every side.
+fn geodetic_bounds(positions: &[Geodetic], margin: f64) -> Option<(f64, f64, f64, f64)> {
+    let first = positions.first()?;
+    let (mut min_lon, mut min_lat) = (first.longitude, first.latitude);
+    let (mut max_lon, mut max_lat) = (min_lon, min_lat);
+
+    for position in positions {
+        min_lon = min_lon.min(position.longitude);
+        max_lon = max_lon.max(position.longitude);
+        min_lat = min_lat.min(position.latitude);
+        max_lat = max_lat.max(position.latitude);
+    }
+
+    let (lon_margin, lat_margin) = ((max_lon - min_lon) * margin, (max_lat - min_lat) * margin);
+
+    Some((
+        min_lon - lon_margin,
+        min_lat - lat_margin,
+        max_lon + lon_margin,
+        max_lat + lat_margin,
+    ))
+}
+
+/// The part of the mosaic seen by a frame, possibly downsampled.
+struct ReferenceWindow {
+    image: Cmat<Vec4b>,
+    /// The full resolution pixel of the top left corner
+    origin: (f64, f64),
+    /// The size of a window pixel in full resolution pixels, along x and y
+    scale: (f64, f64),
+}
+
+impl ReferenceWindow {
+    fn to_window(&self, x: f64, y: f64) -> (f64, f64) {
+        (
+            (x - self.origin.0) / self.scale.0,
+            (y - self.origin.1) / self.scale.1,
+        )
+    }
+}
+
+/// Reads the bounding box of the pixels from the mosaic, downsampled so its longest side is at most `max_size`.
+fn read_reference(
+    mosaic: &mut MosaicedDataset,
+    pixels: &[Option<(f64, f64)>],
+    max_size: usize,
+) -> Result<ReferenceWindow, SynthesisError> {
+    let (mut x_start, mut y_start) = (f64::INFINITY, f64::INFINITY);
+    let (mut x_end, mut y_end) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+
+    for (x, y) in pixels.iter().flatten() {
+        x_start = x_start.min(*x);
+        y_start = y_start.min(*y);
+        x_end = x_end.max(*x);
+        y_end = y_end.max(*y);
+    }
+
+    let window = (x_start.floor() as isize, y_start.floor() as isize);
+    let window_size = (
+        (x_end.ceil() as isize - window.0 + 1) as usize,
+        (y_end.ceil() as isize - window.1 + 1) as usize,
+    );
+
+    let scale = (window_size.0.max(window_size.1) as f64 / max_size as f64).max(1f64);
+    let size = (
+        ((window_size.0 as f64 / scale).ceil() as usize).max(1),
+        ((window_size.1 as f64 / scale).ceil() as usize).max(1),
+    );
+
+    let rgba = mosaic
+        .to_rgb(window, window_size, size)
+        .map_err(SynthesisError::Gdal)?;
+    let image = raster_to_mat(&rgba, size.0 as i32, size.1 as i32).map_err(SynthesisError::Mat)?;
+
+    Ok(ReferenceWindow {
+        image,
+        origin: (window.0 as f64, window.1 as f64),
+        scale: (
+            window_size.0 as f64 / size.0 as f64,
+            window_size.1 as f64 / size.1 as f64,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gdal::{raster::Buffer, spatial_ref::SpatialRef, DriverManager};
+    use geotiff_lib::image_extractor::DatasetOptions;
+    use raycast::nadir_pose;
+    use std::collections::HashMap;
+
+    /// A 10 by 10 km mosaic in UTM zone 32N, which is dark in the western half and bright in the eastern half
+    fn projected_mosaic() -> MosaicedDataset {
+        let driver = DriverManager::get_driver_by_name("MEM").unwrap();
+        let mut dataset = driver.create("", 100, 100, 3).unwrap();
+        dataset
+            .set_geo_transform(&[545_000.0, 100.0, 0.0, 6_225_000.0, 0.0, -100.0])
+            .unwrap();
+        dataset
+            .set_spatial_ref(&SpatialRef::from_epsg(25832).unwrap())
+            .unwrap();
+
+        let data: Vec<u8> = (0..100 * 100)
+            .map(|i| match i % 100 < 50 {
+                true => 50,
+                false => 200,
+            })
+            .collect();
+        for index in 1..=3 {
+            let mut band = dataset.rasterband(index).unwrap();
+            band.write((0, 0), (100, 100), &Buffer::new((100, 100), data.clone()))
+                .unwrap();
+        }
+
+        MosaicedDataset {
+            dataset,
+            options: DatasetOptions::builder().build(),
+            min_max: None,
+            elevation: None,
+            mask: None,
+            band_min_max: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn render_projected_mosaic() {
+        let mut mosaic = projected_mosaic();
+
+        // The center of the mosaic.
+        let (mut longitude, mut latitude) = (vec![550_000.0], vec![6_220_000.0]);
+        transform_points(
+            &dataset_spatial_ref(&mosaic.dataset),
+            GEOGRAPHIC,
+            &mut longitude,
+            &mut latitude,
+        )
+        .unwrap();
+        let position = Geodetic {
+            latitude: latitude[0],
+            longitude: longitude[0],
+            altitude: 20_000.0,
+        };
+
+        // The frame covers 6.4 by 6.4 km.
+        let camera = CameraModel {
+            intrinsic: Cmat::from_2d_slice(&[
+                [100.0, 0.0, 15.5],
+                [0.0, 100.0, 15.5],
+                [0.0, 0.0, 1.0],
+            ])
+            .unwrap(),
+            dist_coeffs: vec![0.0; 4],
+        };
+        let options = RenderOptions {
+            width: 32,
+            height: 32,
+            ..Default::default()
+        };
+
+        let frame = render(
+            &mut mosaic,
+            &camera,
+            &nadir_pose(&position, 0.0, 0.0, 0.0),
+            &options,
+        )
+        .unwrap();
+
+        assert_eq!(frame.coverage, 1.0);
+        // 2.3 km west and east of the center.
+        assert!(frame.image.at_2d(16, 4).unwrap()[0] < 100);
+        assert!(frame.image.at_2d(16, 27).unwrap()[0] > 150);
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use clap::{ArgGroup, Parser, ValueEnum};
+use geotiff_lib::elevation::{check_scale, ElevationOptions, ElevationResampling, VerticalDatum};
+use geotiff_lib::geodesy::HeightReference;
+use geotiff_lib::image_extractor::{MosaicDataset, MosaicedDataset};
+use homographier::homographier::{Cmat, Pose};
+use localizer::ephemeris::Geodetic;
+use nalgebra::Vector3;
+use opencv::{core::Vector, imgcodecs::imwrite};
+use query_preprocessor::CameraModel;
+use synthesizer::{
+    effects::EffectOptions,
+    manifest::{append_manifest, ManifestEntry},
+    raycast::nadir_pose,
+    render, RenderOptions,
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Render a synthetic query image of the reference mosaic from a known pose", long_about = None)]
+#[command(group(ArgGroup::new("pose").required(true).args(["geodetic", "rvec"])))]
+struct Args {
+    /// The path to the reference mosaic
+    mosaic_path: String,
+
+    /// The path the rendered image is written to
+    output_path: PathBuf,
+
+    /// The path to a folder of elevation datasets, the frame is rendered onto the ellipsoid if not provided
+    #[arg(long)]
+    elevation: Option<String>,
+
+    /// The size of an elevation pixel in mosaic pixels, when the elevation is warped onto the grid of the mosaic
+    #[arg(long, default_value_t = ElevationOptions::default().scale, value_parser = parse_elevation_scale)]
+    elevation_scale: f64,
+
+    /// How the elevation is resampled when it is warped onto the grid of the mosaic
+    #[arg(long, value_enum, default_value_t = Resampling::Bilinear)]
+    elevation_resampling: Resampling,
+
+    /// The surface the heights of the elevation datasets are measured from, they are converted to heights above the ellipsoid before rendering
+    #[arg(long, value_enum, default_value_t = Heights::Ellipsoid)]
+    height_reference: Heights,
+
+    /// A grid of geoid heights above the ellipsoid, for elevation datasets measured from a national geoid such as DVR90 of the Danish DHM.
+    /// Replaces --height-reference
+    #[arg(long, conflicts_with = "height_reference")]
+    geoid_grid: Option<PathBuf>,
+
+    /// The camera calibration as found by the calibrator
+    #[arg(long, required = true, num_args = 4, value_names = ["FX", "FY", "CX", "CY"])]
+    intrinsics: Vec<f64>,
+
+    /// The distortion coefficients from the camera calibration
+    #[arg(long, num_args = 1.., default_values_t = [0.0, 0.0, 0.0, 0.0], allow_negative_numbers = true)]
+    dist_coeffs: Vec<f64>,
+
+    /// The size of the rendered image in pixels
+    #[arg(long, num_args = 2, value_names = ["WIDTH", "HEIGHT"], default_values_t = [1024, 1024])]
+    size: Vec<i32>,
+
+    /// The position of the camera as latitude and longitude in degrees, and height above the ellipsoid in meters.
+    /// The camera looks straight down with north up, rotated by --roll, --pitch and --yaw
+    #[arg(long, num_args = 3, value_names = ["LAT", "LON", "ALT"], conflicts_with_all = ["rvec", "tvec"], allow_negative_numbers = true)]
+    geodetic: Vec<f64>,
+
+    /// The rotation about the x axis of the camera in degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    roll: f64,
+
+    /// The rotation about the y axis of the camera in degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pitch: f64,
+
+    /// The rotation about the z axis of the camera in degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    yaw: f64,
+
+    /// The rotation vector of the camera, from earth centered, earth fixed coordinates to camera coordinates
+    #[arg(long, num_args = 3, value_names = ["RX", "RY", "RZ"], requires = "tvec", allow_negative_numbers = true)]
+    rvec: Vec<f64>,
+
+    /// The translation vector of the camera, from earth centered, earth fixed coordinates to camera coordinates
+    #[arg(long, num_args = 3, value_names = ["TX", "TY", "TZ"], requires = "rvec", allow_negative_numbers = true)]
+    tvec: Vec<f64>,
+
+    /// The standard deviation of the sensor noise in 8-bit values
+    #[arg(long, default_value_t = 0.0)]
+    noise: f64,
+
+    /// The standard deviation of the blur in pixels
+    #[arg(long, default_value_t = 0.0)]
+    blur: f64,
+
+    /// How much the corners are darkened, from 0.0 to 1.0
+    #[arg(long, default_value_t = 0.0)]
+    vignetting: f64,
+
+    /// The fraction of the image covered by clouds, from 0.0 to 1.0
+    #[arg(long, default_value_t = 0.0)]
+    clouds: f64,
+
+    /// Seeds the noise and clouds
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Appends the image and its pose to this manifest, for the accuracy benchmark
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum Resampling {
+    Nearest,
+    Bilinear,
+    Cubic,
+}
+
+impl From<Resampling> for ElevationResampling {
+    fn from(resampling: Resampling) -> Self {
+        match resampling {
+            Resampling::Nearest => ElevationResampling::Nearest,
+            Resampling::Bilinear => ElevationResampling::Bilinear,
+            Resampling::Cubic => ElevationResampling::Cubic,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum Heights {
+    Ellipsoid,
+    Egm96,
+    Egm2008,
+}
+
+impl From<Heights> for HeightReference {
+    fn from(heights: Heights) -> Self {
+        match heights {
+            Heights::Ellipsoid => HeightReference::Ellipsoid,
+            Heights::Egm96 => HeightReference::Egm96,
+            Heights::Egm2008 => HeightReference::Egm2008,
+        }
+    }
+}
+
+fn parse_elevation_scale(value: &str) -> Result<f64, String> {
+    let scale: f64 = value
+        .parse()
+        .map_err(|e: std::num::ParseFloatError| e.to_string())?;
+
+    check_scale(scale).map_err(|e| e.to_string())
+}
+
+impl Args {
+    /// The vertical datum of the elevation datasets, or [`None`] if they are already heights above the ellipsoid
+    fn vertical_datum(&self) -> Option<VerticalDatum> {
+        match (&self.geoid_grid, self.height_reference) {
+            (Some(path), _) => Some(VerticalDatum::GeoidGrid(path.clone())),
+            (None, Heights::Ellipsoid) => None,
+            (None, heights) => Some(VerticalDatum::Reference(heights.into())),
+        }
+    }
+
+    fn pose(&self) -> Pose {
+        match (&self.geodetic[..], &self.rvec[..], &self.tvec[..]) {
+            ([latitude, longitude, altitude], _, _) => nadir_pose(
+                &Geodetic {
+                    latitude: *latitude,
+                    longitude: *longitude,
+                    altitude: *altitude,
+                },
+                self.roll.to_radians(),
+                self.pitch.to_radians(),
+                self.yaw.to_radians(),
+            ),
+            (_, [rx, ry, rz], [tx, ty, tz]) => Pose {
+                rvec: Vector3::new(*rx, *ry, *rz),
+                tvec: Vector3::new(*tx, *ty, *tz),
+            },
+            _ => unreachable!("clap requires either --geodetic or --rvec and --tvec"),
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let mut mosaic =
+        MosaicedDataset::import_mosaic_dataset(&args.mosaic_path).expect("Could not open mosaic");
+
+    // The elevation datasets are warped onto the grid of the mosaic, which only has to live while rendering.
+    let elevation_dir = tempfile::tempdir().expect("Could not create temporary directory");
+    if let Some(elevation) = &args.elevation {
+        let options = ElevationOptions {
+            scale: args.elevation_scale,
+            resampling: args.elevation_resampling.into(),
+            vertical_datum: args.vertical_datum(),
+        };
+
+        mosaic
+            .set_elevation_dataset(elevation, &elevation_dir.path().to_string_lossy(), &options)
+            .expect("Could not open elevation datasets");
+    }
+
+    let (fx, fy, cx, cy) = (
+        args.intrinsics[0],
+        args.intrinsics[1],
+        args.intrinsics[2],
+        args.intrinsics[3],
+    );
+    let camera = CameraModel {
+        intrinsic: Cmat::from_2d_slice(&[[fx, 0.0, cx], [0.0, fy, cy], [0.0, 0.0, 1.0]])
+            .expect("Could not create camera matrix"),
+        dist_coeffs: args.dist_coeffs.clone(),
+    };
+
+    let options = RenderOptions {
+        width: args.size[0],
+        height: args.size[1],
+        effects: EffectOptions {
+            noise_std: args.noise,
+            blur_sigma: args.blur,
+            vignetting: args.vignetting,
+            cloud_cover: args.clouds,
+            seed: args.seed,
+        },
+    };
+
+    let pose = args.pose();
+    let frame = render(&mut mosaic, &camera, &pose, &options).expect("Could not render frame");
+
+    imwrite(
+        &args.output_path.to_string_lossy(),
+        &frame.image,
+        &Vector::new(),
+    )
+    .expect("Could not write frame");
+
+    println!("Coverage: {:.1}%", frame.coverage * 100.0);
+    println!("Rotation vector: {:?}", frame.pose.rvec);
+    println!("Translation vector: {:?}", frame.pose.tvec);
+
+    if let Some(manifest) = &args.manifest {
+        // Images are stored relative to the manifest when possible, so the manifest can be moved with them.
+        let image = fs::canonicalize(&args.output_path).expect("Could not find rendered frame");
+        let directory = manifest
+            .parent()
+            .filter(|directory| !directory.as_os_str().is_empty())
+            .map(|directory| {
+                fs::canonicalize(directory).expect("Could not find manifest directory")
+            })
+            .unwrap_or_else(|| env::current_dir().expect("Current directory not set"));
+
+        let entry = ManifestEntry {
+            image: image
+                .strip_prefix(&directory)
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|_| image.clone()),
+            pose: frame.pose,
+        };
+
+        append_manifest(manifest, &entry).expect("Could not write manifest");
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use homographier::homographier::Pose;
+use nalgebra::Vector3;
+
+/// The header of a manifest file
+const HEADER: &str = "image,rx,ry,rz,tx,ty,tz";
+
+/// A query image with a known pose.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub image: PathBuf,
+    /// The pose of the camera, from earth centered, earth fixed coordinates to camera coordinates
+    pub pose: Pose,
+}
+
+/// Reads a manifest of query images with known poses, as CSV with the columns `image,rx,ry,rz,tx,ty,tz`.
+///
+/// Relative image paths are relative to the directory of the manifest.
+pub fn read_manifest(path: &Path) -> io::Result<Vec<ManifestEntry>> {
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let reader = BufReader::new(File::open(path)?);
+
+    let mut entries = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty() || (i == 0 && line.trim() == HEADER) {
+            continue;
+        }
+
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid manifest entry on line {}: {}", i + 1, line),
+            )
+        };
+
+        let (image, values) = line.split_once(',').ok_or_else(invalid)?;
+        let values: Vec<f64> = values
+            .split(',')
+            .map(|value| value.trim().parse().map_err(|_| invalid()))
+            .collect::<Result<_, _>>()?;
+
+        if values.len() != 6 {
+            return Err(invalid());
+        }
+
+        entries.push(ManifestEntry {
+            image: directory.join(image.trim()),
+            pose: Pose {
+                rvec: Vector3::new(values[0], values[1], values[2]),
+                tvec: Vector3::new(values[3], values[4], values[5]),
+            },
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Appends an entry to a manifest, which is created with a header if it does not exist.
+pub fn append_manifest(path: &Path, entry: &ManifestEntry) -> io::Result<()> {
+    let exists = path.exists();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    if !exists {
+        writeln!(file, "{HEADER}")?;
+    }
+
+    let (r, t) = (entry.pose.rvec, entry.pose.tvec);
+
+    writeln!(
+        file,
+        "{},{},{},{},{},{},{}",
+        entry.image.display(),
+        r.x,
+        r.y,
+        r.z,
+        t.x,
+        t.y,
+        t.z
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_round_trip() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("manifest.csv");
+        let entry = ManifestEntry {
+            image: PathBuf::from("frame_0.png"),
+            pose: Pose {
+                rvec: Vector3::new(0.1, -0.2, 3.0),
+                tvec: Vector3::new(1e6, -2.5e6, 6.1e6),
+            },
+        };
+
+        append_manifest(&path, &entry).unwrap();
+        append_manifest(&path, &entry).unwrap();
+
+        let entries = read_manifest(&path).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].image, directory.path().join("frame_0.png"));
+        assert_eq!(entries[1].pose, entry.pose);
+    }
+
+    #[test]
+    fn invalid_manifest() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("manifest.csv");
+        std::fs::write(&path, "image,rx,ry,rz,tx,ty,tz\nframe.png,1,2,3\n").unwrap();
+
+        assert!(read_manifest(&path).is_err());
+    }
+}
@@ -0,0 +1,349 @@
+use gdal::{errors::GdalError, Dataset, GeoTransform, GeoTransformEx};
+use geotiff_lib::geodesy::{dataset_spatial_ref, transform_points, GEOGRAPHIC, WGS84_A, WGS84_F};
+use homographier::homographier::Pose;
+use localizer::ephemeris::{ecef_to_geodetic, geodetic_to_ecef, Geodetic};
+use nalgebra::{Matrix3, Rotation3, Vector3};
+
+/// The maximum amount of times a ray is intersected with the ellipsoid raised to the terrain height
+const MAX_ITERATIONS: usize = 10;
+/// The ray has converged when the terrain height changes less than this in meters
+const HEIGHT_TOLERANCE: f64 = 0.01;
+
+/// A window of an elevation dataset, read into memory so it can be sampled for every ray.
+pub struct ElevationModel {
+    /// The CRS of the dataset, which the positions are transformed to before sampling
+    spatial_ref: String,
+    /// The inverse geotransform of the window, from the CRS of the dataset to pixels
+    inverse: GeoTransform,
+    heights: Vec<f64>,
+    width: usize,
+    height: usize,
+    no_data: Option<f64>,
+}
+
+impl ElevationModel {
+    /// Reads the part of `dataset` within `bounds`, given as `(min_lon, min_lat, max_lon, max_lat)` in degrees.
+    ///
+    /// ## Notes
+    /// The heights are assumed to be above the ellipsoid. The dataset can be in any CRS, the bounds are transformed to it.
+    pub fn read(
+        dataset: &Dataset,
+        bounds: (f64, f64, f64, f64),
+    ) -> Result<ElevationModel, GdalError> {
+        let spatial_ref = dataset_spatial_ref(dataset);
+        let transform = dataset.geo_transform()?;
+        let inverse = transform.invert()?;
+        let (raster_width, raster_height) = dataset.raster_size();
+
+        // The edges of the bounds are curved in a projected CRS, so their midpoints are transformed as well as the corners.
+        let (mut x, mut y): (Vec<f64>, Vec<f64>) =
+            [bounds.0, (bounds.0 + bounds.2) / 2f64, bounds.2]
+                .iter()
+                .flat_map(|longitude| {
+                    [bounds.1, (bounds.1 + bounds.3) / 2f64, bounds.3]
+                        .map(|latitude| (*longitude, latitude))
+                })
+                .unzip();
+        transform_points(GEOGRAPHIC, &spatial_ref, &mut x, &mut y)?;
+
+        let (mut min_x, mut min_y) = (f64::INFINITY, f64::INFINITY);
+        let (mut max_x, mut max_y) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+
+        for (x, y) in x.iter().zip(&y) {
+            let (x, y) = inverse.apply(*x, *y);
+            min_x = min_x.min(x);
+            max_x = max_x.max(x);
+            min_y = min_y.min(y);
+            max_y = max_y.max(y);
+        }
+
+        let clamp = |value: f64, max: usize| (value.max(0f64) as usize).min(max);
+
+        let x_start = clamp(min_x.floor(), raster_width);
+        let x_end = clamp(max_x.ceil() + 1f64, raster_width);
+        let y_start = clamp(min_y.floor(), raster_height);
+        let y_end = clamp(max_y.ceil() + 1f64, raster_height);
+
+        let size = (x_end - x_start, y_end - y_start);
+        let band = dataset.rasterband(1)?;
+
+        let heights = match size {
+            (0, _) | (_, 0) => Vec::new(),
+            _ => {
+                band.read_as::<f64>((x_start as isize, y_start as isize), size, size, None)?
+                    .data
+            }
+        };
+
+        // Moves the origin of the transform to the start of the window.
+        let origin = transform.apply(x_start as f64, y_start as f64);
+        let window_transform = [
+            origin.0,
+            transform[1],
+            transform[2],
+            origin.1,
+            transform[4],
+            transform[5],
+        ];
+
+        Ok(ElevationModel {
+            spatial_ref,
+            inverse: window_transform.invert()?,
+            heights,
+            width: size.0,
+            height: size.1,
+            no_data: band.no_data_value(),
+        })
+    }
+
+    /// The bilinearly interpolated heights at a batch of longitudes and latitudes in degrees.
+    /// A height is [`None`] outside of the window, or if any of the neighbouring heights are nodata.
+    /// ## Errors
+    /// If the positions can not be transformed to the CRS of the dataset.
+    pub fn heights_at(
+        &self,
+        longitudes: &[f64],
+        latitudes: &[f64],
+    ) -> Result<Vec<Option<f64>>, GdalError> {
+        let (mut x, mut y) = (longitudes.to_vec(), latitudes.to_vec());
+        transform_points(GEOGRAPHIC, &self.spatial_ref, &mut x, &mut y)?;
+
+        Ok(x.iter().zip(&y).map(|(x, y)| self.sample(*x, *y)).collect())
+    }
+
+    /// The bilinearly interpolated height at a position in the CRS of the dataset.
+    fn sample(&self, x: f64, y: f64) -> Option<f64> {
+        let (x, y) = self.inverse.apply(x, y);
+
+        // Heights are sampled at the center of the pixels.
+        let (x, y) = (x - 0.5, y - 0.5);
+        let (x0, y0) = (x.floor(), y.floor());
+
+        if x0 < 0f64 || y0 < 0f64 {
+            return None;
+        }
+
+        let (x0, y0) = (x0 as usize, y0 as usize);
+
+        if x0 >= self.width || y0 >= self.height {
+            return None;
+        }
+
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+
+        let sample = |x: usize, y: usize| {
+            let height = self.heights[y * self.width + x];
+            match Some(height) == self.no_data || height.is_nan() {
+                true => None,
+                false => Some(height),
+            }
+        };
+
+        let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+        let top = sample(x0, y0)? * (1f64 - fx) + sample(x1, y0)? * fx;
+        let bottom = sample(x0, y1)? * (1f64 - fx) + sample(x1, y1)? * fx;
+
+        Some(top * (1f64 - fy) + bottom * fy)
+    }
+}
+
+/// The first intersection of a ray with the WGS84 ellipsoid raised by `height` meters,
+/// or [`None`] if the ray misses it or starts inside it.
+pub fn intersect_ellipsoid(
+    origin: &Vector3<f64>,
+    direction: &Vector3<f64>,
+    height: f64,
+) -> Option<Vector3<f64>> {
+    let a = WGS84_A + height;
+    let b = WGS84_A * (1f64 - WGS84_F) + height;
+
+    // Scales the ellipsoid to the unit sphere.
+    let scale = Vector3::new(1f64 / a, 1f64 / a, 1f64 / b);
+    let o = origin.component_mul(&scale);
+    let d = direction.component_mul(&scale);
+
+    let (qa, qb, qc) = (d.dot(&d), 2f64 * o.dot(&d), o.dot(&o) - 1f64);
+    let discriminant = qb * qb - 4f64 * qa * qc;
+
+    if discriminant < 0f64 || qc < 0f64 {
+        return None;
+    }
+
+    let s = (-qb - discriminant.sqrt()) / (2f64 * qa);
+
+    match s > 0f64 {
+        true => Some(origin + direction * s),
+        false => None,
+    }
+}
+
+/// Finds where a ray hits the terrain, by intersecting it with the ellipsoid raised to the terrain height until the height converges.
+///
+/// Without an elevation model, or outside of it, the ray is intersected with the ellipsoid.
+/// Returns [`None`] if the ray misses the ellipsoid.
+/// ## Errors
+/// If the hits can not be transformed to the CRS of the elevation.
+pub fn cast_ray(
+    origin: &Vector3<f64>,
+    direction: &Vector3<f64>,
+    elevation: Option<&ElevationModel>,
+) -> Result<Option<Geodetic>, GdalError> {
+    Ok(cast_rays(origin, &[*direction], elevation)?.pop().flatten())
+}
+
+/// Like [`cast_ray`] for every direction, where the hits of all rays are sampled from the elevation together.
+pub fn cast_rays(
+    origin: &Vector3<f64>,
+    directions: &[Vector3<f64>],
+    elevation: Option<&ElevationModel>,
+) -> Result<Vec<Option<Geodetic>>, GdalError> {
+    let intersect = |direction: &Vector3<f64>, height: f64| {
+        intersect_ellipsoid(origin, direction, height).map(|hit| ecef_to_geodetic(&hit))
+    };
+
+    let mut hits: Vec<Option<Geodetic>> = directions
+        .iter()
+        .map(|direction| intersect(direction, 0f64))
+        .collect();
+
+    let elevation = match elevation {
+        Some(elevation) => elevation,
+        None => return Ok(hits),
+    };
+
+    let mut heights = vec![0f64; directions.len()];
+    // The rays whose height has not converged yet.
+    let mut active: Vec<usize> = (0..hits.len()).filter(|i| hits[*i].is_some()).collect();
+
+    for _ in 0..MAX_ITERATIONS {
+        if active.is_empty() {
+            break;
+        }
+
+        let (longitudes, latitudes): (Vec<f64>, Vec<f64>) = active
+            .iter()
+            .filter_map(|i| hits[*i])
+            .map(|hit| (hit.longitude, hit.latitude))
+            .unzip();
+        let terrain = elevation.heights_at(&longitudes, &latitudes)?;
+
+        active = active
+            .into_iter()
+            .zip(terrain)
+            .filter_map(|(i, terrain)| {
+                let terrain = terrain.unwrap_or(0f64);
+
+                if (terrain - heights[i]).abs() < HEIGHT_TOLERANCE {
+                    return None;
+                }
+
+                heights[i] = terrain;
+                hits[i] = intersect(&directions[i], terrain);
+                hits[i].map(|_| i)
+            })
+            .collect();
+    }
+
+    Ok(hits)
+}
+
+/// The pose of a camera at `position` looking straight down, with the top of the image towards north,
+/// rotated by `roll`, `pitch` and `yaw` in radians about the x, y and z axes of the camera.
+///
+/// The pose maps earth centered, earth fixed coordinates to camera coordinates, like the poses found by PnP.
+pub fn nadir_pose(position: &Geodetic, roll: f64, pitch: f64, yaw: f64) -> Pose {
+    let (latitude, longitude) = (
+        position.latitude.to_radians(),
+        position.longitude.to_radians(),
+    );
+
+    let east = Vector3::new(-longitude.sin(), longitude.cos(), 0f64);
+    let north = Vector3::new(
+        -latitude.sin() * longitude.cos(),
+        -latitude.sin() * longitude.sin(),
+        latitude.cos(),
+    );
+    let up = Vector3::new(
+        latitude.cos() * longitude.cos(),
+        latitude.cos() * longitude.sin(),
+        latitude.sin(),
+    );
+
+    // The camera axes in ECEF: x is east, y is south and z is down.
+    let nadir = Rotation3::from_matrix_unchecked(Matrix3::from_columns(&[east, -north, -up]));
+    let camera_to_world = nadir * Rotation3::from_euler_angles(roll, pitch, yaw);
+    let world_to_camera = camera_to_world.inverse();
+
+    Pose {
+        rvec: world_to_camera.scaled_axis(),
+        tvec: -(world_to_camera * geodetic_to_ecef(position)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ray_hits_ellipsoid_below() {
+        let origin = Vector3::new(WGS84_A + 500_000.0, 0.0, 0.0);
+        let direction = Vector3::new(-1.0, 0.0, 0.0);
+
+        let hit = intersect_ellipsoid(&origin, &direction, 100.0).unwrap();
+
+        assert!((hit.x - (WGS84_A + 100.0)).abs() < 1e-6);
+        assert!(intersect_ellipsoid(&origin, &-direction, 0.0).is_none());
+    }
+
+    #[test]
+    fn nadir_ray_hits_below_camera() {
+        let position = Geodetic {
+            latitude: 56.1,
+            longitude: 9.7,
+            altitude: 500_000.0,
+        };
+        let pose = nadir_pose(&position, 0.0, 0.0, 0.0);
+
+        // The optical axis of the camera in ECEF.
+        let rotation = Rotation3::from_scaled_axis(pose.rvec);
+        let center = -(rotation.inverse() * pose.tvec);
+        let axis = rotation.inverse() * Vector3::z();
+
+        let hit = cast_ray(&center, &axis, None).unwrap().unwrap();
+
+        assert!((hit.latitude - position.latitude).abs() < 1e-9);
+        assert!((hit.longitude - position.longitude).abs() < 1e-9);
+        assert!(hit.altitude.abs() < 1e-3);
+    }
+
+    #[test]
+    fn nadir_image_is_north_up() {
+        let position = Geodetic {
+            latitude: 10.0,
+            longitude: 20.0,
+            altitude: 500_000.0,
+        };
+        let pose = nadir_pose(&position, 0.0, 0.0, 0.0);
+        let rotation = Rotation3::from_scaled_axis(pose.rvec);
+        let center = -(rotation.inverse() * pose.tvec);
+
+        // Rays towards the top and right of the image.
+        let top = cast_ray(
+            &center,
+            &(rotation.inverse() * Vector3::new(0.0, -0.1, 1.0)),
+            None,
+        )
+        .unwrap()
+        .unwrap();
+        let right = cast_ray(
+            &center,
+            &(rotation.inverse() * Vector3::new(0.1, 0.0, 1.0)),
+            None,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert!(top.latitude > position.latitude);
+        assert!(right.longitude > position.longitude);
+    }
+}